@@ -0,0 +1,90 @@
+//! Generates the tables of static assets and templates that are embedded into the binary when
+//! the `embed-assets` feature is enabled. Each entry is a `(path, contents, etag)` tuple, where
+//! the path is relative to the embedded directory and always uses `/` as a separator.
+//!
+//! Also generates the table of SQL migrations in `migrations/`, which are always embedded, as
+//! `(file name, sql)` pairs.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let enabled = env::var_os("CARGO_FEATURE_EMBED_ASSETS").is_some();
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let mut code = String::new();
+    for (name, dir) in &[("STATIC_ASSETS", "public"), ("TEMPLATES", "templates")] {
+        let root = manifest_dir.join(dir);
+        let mut files = Vec::new();
+        if enabled {
+            collect_files(&root, &root, &mut files);
+            println!("cargo:rerun-if-changed={}", root.display());
+        }
+        files.sort();
+
+        code.push_str(&format!("pub static {}: &[(&str, &[u8], &str)] = &[\n", name));
+        for (relative, path) in files {
+            let contents = fs::read(&path).unwrap();
+            code.push_str(&format!(
+                "    ({:?}, include_bytes!({:?}), {:?}),\n",
+                relative,
+                path.display().to_string(),
+                format!("\"{:016x}\"", fnv1a(&contents))
+            ));
+        }
+        code.push_str("];\n");
+    }
+
+    fs::write(out_dir.join("embedded.rs"), code).unwrap();
+
+    let migrations_dir = manifest_dir.join("migrations");
+    let mut migrations = Vec::new();
+    collect_files(&migrations_dir, &migrations_dir, &mut migrations);
+    migrations.retain(|(relative, _)| relative.ends_with(".sql") && !relative.contains('/'));
+    migrations.sort();
+    println!("cargo:rerun-if-changed={}", migrations_dir.display());
+
+    let mut code = String::from("pub static MIGRATIONS: &[(&str, &str)] = &[\n");
+    for (name, path) in migrations {
+        code.push_str(&format!("    ({:?}, include_str!({:?})),\n", name, path.display().to_string()));
+    }
+    code.push_str("];\n");
+    fs::write(out_dir.join("migrations.rs"), code).unwrap();
+}
+
+/// Find all of the files under `dir`, skipping hidden files and directories
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        if path.is_dir() {
+            collect_files(root, &path, files);
+        } else {
+            let relative = path
+                .strip_prefix(root)
+                .unwrap()
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((relative, path));
+        }
+    }
+}
+
+/// A 64-bit FNV-1a hash, used to derive stable ETags from file contents
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
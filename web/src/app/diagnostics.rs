@@ -0,0 +1,365 @@
+//! Warnings that come up while the app starts, collected so that they can be counted and
+//! listed together instead of scrolling past in the logs.
+//!
+//! `Settings::new` creates a `StartupDiagnostics` and records anything questionable that it
+//! runs into while loading the settings (e.g. a variable that looks like a typo). The same
+//! collector is shared by every clone of the settings, so `preflight` and the fairings (which
+//! find the settings in managed state) record into it too. Once the rocket instance has been
+//! built, `enforce_budget` fails the launch when there were more warnings than
+//! `Settings::max_startup_warnings` allows, and `web --check` prints everything that was found.
+use crate::app::Settings;
+
+use serde_derive::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// How serious a diagnostic is. Only warnings count towards `max_startup_warnings`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Info => f.write_str("info"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// Something that was noticed while starting up, and the part of startup that noticed it
+/// (`"settings"`, `"preflight"` or `"fairings"`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub source: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}] {}", self.severity, self.source, self.message)
+    }
+}
+
+/// The diagnostics recorded while starting up. Clones share the same list
+#[derive(Debug, Clone, Default)]
+pub struct StartupDiagnostics {
+    recorded: Arc<Mutex<Vec<Diagnostic>>>,
+}
+
+impl StartupDiagnostics {
+    pub fn new() -> StartupDiagnostics {
+        StartupDiagnostics::default()
+    }
+
+    /// Record a diagnostic. A diagnostic that has already been recorded is ignored, as the
+    /// fairings are attached once for each listener.
+    pub fn record<M: Into<String>>(&self, severity: Severity, source: &'static str, message: M) {
+        let diagnostic = Diagnostic {
+            severity,
+            source,
+            message: message.into(),
+        };
+
+        let mut recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        if !recorded.contains(&diagnostic) {
+            recorded.push(diagnostic);
+        }
+    }
+
+    pub fn info<M: Into<String>>(&self, source: &'static str, message: M) {
+        self.record(Severity::Info, source, message);
+    }
+
+    pub fn warn<M: Into<String>>(&self, source: &'static str, message: M) {
+        self.record(Severity::Warning, source, message);
+    }
+
+    /// Every diagnostic that has been recorded, in the order they were recorded
+    pub fn all(&self) -> Vec<Diagnostic> {
+        self.recorded.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.all()
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+            .count()
+    }
+
+    /// Fail when more warnings were recorded than `max` allows. `None` allows any number
+    pub fn enforce_budget(&self, max: Option<usize>) -> Result<(), WarningBudgetExceeded> {
+        match max {
+            Some(max) if self.warning_count() > max => Err(WarningBudgetExceeded {
+                max,
+                warnings: self
+                    .all()
+                    .into_iter()
+                    .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+                    .collect(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// A plain text listing, one diagnostic per line followed by a summary line
+    pub fn to_text(&self, max: Option<usize>) -> String {
+        let mut text: String = self.all().iter().map(|diagnostic| format!("{}\n", diagnostic)).collect();
+        let limit = max.map_or_else(|| String::from("unlimited"), |max| max.to_string());
+        text.push_str(&format!("{} warning(s), max_startup_warnings is {}\n", self.warning_count(), limit));
+        text
+    }
+
+    /// The diagnostics as JSON, for `web --check --format json`:
+    ///
+    /// ```json
+    /// {
+    ///   "warnings": 1,
+    ///   "max_startup_warnings": 0,
+    ///   "diagnostics": [{ "severity": "warning", "source": "preflight", "message": "..." }]
+    /// }
+    /// ```
+    pub fn to_json(&self, max: Option<usize>) -> serde_json::Value {
+        serde_json::json!({
+            "warnings": self.warning_count(),
+            "max_startup_warnings": max,
+            "diagnostics": self.all(),
+        })
+    }
+}
+
+/// More warnings were recorded while starting up than `Settings::max_startup_warnings` allows
+#[derive(Debug)]
+pub struct WarningBudgetExceeded {
+    pub max: usize,
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl fmt::Display for WarningBudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} startup warning(s), but max_startup_warnings is {}:",
+            self.warnings.len(),
+            self.max
+        )?;
+        for warning in &self.warnings {
+            write!(f, "\n  [{}] {}", warning.source, warning.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for WarningBudgetExceeded {}
+
+/// Check the parts of the environment that the settings point at, before the app is built:
+/// that the static and template directories can be read, and that there is a config file for
+/// the active environment. Problems are recorded in the settings' diagnostics.
+pub fn preflight(settings: &Settings) {
+    let diagnostics = settings.diagnostics();
+
+    for dir in settings.static_dirs() {
+        if fs::read_dir(&dir).is_err() {
+            diagnostics.warn(
+                "preflight",
+                format!("static directory {} doesn't exist or can't be read", dir.display()),
+            );
+        }
+    }
+
+    // Rocket's own default, relative to the working directory
+    let template_dir = settings.extra("template_dir").unwrap_or("templates");
+    if fs::read_dir(template_dir).is_err() {
+        diagnostics.warn(
+            "preflight",
+            format!("template directory {} doesn't exist or can't be read", template_dir),
+        );
+    }
+
+    if let Ok(env) = std::env::var("APP_ENV") {
+        let config_dir = Path::new(&settings.config_dir);
+        let extensions: &[&str] = if cfg!(feature = "json-config") {
+            &["toml", "json"]
+        } else {
+            &["toml"]
+        };
+        let found = extensions
+            .iter()
+            .any(|extension| config_dir.join(format!("config-{}.{}", env, extension)).is_file());
+        if !found {
+            diagnostics.warn(
+                "preflight",
+                format!("APP_ENV is {}, but there is no config-{}.toml in {}", env, env, config_dir.display()),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::ConfigFixture;
+    use rocket::config::{Config, Environment};
+
+    fn sources(diagnostics: &StartupDiagnostics) -> Vec<&'static str> {
+        let mut seen: Vec<&'static str> = diagnostics.all().iter().map(|diagnostic| diagnostic.source).collect();
+        seen.dedup();
+        seen
+    }
+
+    #[test]
+    fn clones_share_one_list() {
+        let diagnostics = StartupDiagnostics::new();
+        let shared = diagnostics.clone();
+
+        diagnostics.warn("settings", "one");
+        shared.info("preflight", "two");
+        // Attaching the fairings once per listener records the same warning again
+        shared.warn("settings", "one");
+
+        assert_eq!(
+            diagnostics.all(),
+            vec![
+                Diagnostic {
+                    severity: Severity::Warning,
+                    source: "settings",
+                    message: String::from("one"),
+                },
+                Diagnostic {
+                    severity: Severity::Info,
+                    source: "preflight",
+                    message: String::from("two"),
+                },
+            ]
+        );
+        assert_eq!(shared.warning_count(), 1);
+        assert_eq!(diagnostics.all()[0].to_string(), "warning [settings] one");
+    }
+
+    #[test]
+    fn collects_from_settings_preflight_and_fairings() {
+        let settings = ConfigFixture::new()
+            .var("ROCKET_ENV", "development")
+            .var("APP_PROT", "8080")
+            .var("APP_STATIC_DIR", "/nonexistent/static")
+            .var("APP_TEMPLATE_DIR", "/nonexistent/templates")
+            .var("APP_UNIX_SOCKET", "/tmp/web.sock")
+            .load()
+            .unwrap()
+            .unwrap();
+        let diagnostics = settings.diagnostics().clone();
+
+        preflight(&settings);
+        let rocket = rocket::custom(Config::new(Environment::Development)).manage(settings);
+        crate::app::with_unix_listener(rocket);
+
+        let messages: Vec<String> = diagnostics.all().into_iter().map(|diagnostic| diagnostic.message).collect();
+        assert!(messages.contains(&String::from("APP_PROT looks like a typo of APP_PORT")));
+        assert!(messages.iter().any(|message| message.starts_with("no secret_key was provided")));
+        assert!(messages.contains(&String::from(
+            "static directory /nonexistent/static doesn't exist or can't be read"
+        )));
+        assert!(messages.contains(&String::from(
+            "template directory /nonexistent/templates doesn't exist or can't be read"
+        )));
+        assert!(messages.contains(&String::from(
+            "unix socket /tmp/web.sock must be set up manually, as rocket can only listen on TCP"
+        )));
+        assert_eq!(sources(&diagnostics), vec!["settings", "preflight", "fairings"]);
+    }
+
+    fn three_warnings() -> StartupDiagnostics {
+        let diagnostics = StartupDiagnostics::new();
+        diagnostics.warn("settings", "generated a secret key");
+        diagnostics.warn("preflight", "missing config-production.toml");
+        diagnostics.info("fairings", "attached 12 fairings");
+        diagnostics.warn("fairings", "template_reload has no effect on this build");
+        diagnostics
+    }
+
+    #[test]
+    fn budget_aborts_when_exceeded() {
+        let diagnostics = three_warnings();
+        assert!(diagnostics.enforce_budget(None).is_ok());
+        assert!(diagnostics.enforce_budget(Some(3)).is_ok());
+
+        let exceeded = diagnostics.enforce_budget(Some(2)).unwrap_err();
+        assert_eq!(exceeded.max, 2);
+        assert_eq!(exceeded.warnings.len(), 3);
+        assert!(exceeded.warnings.iter().all(|warning| warning.severity == Severity::Warning));
+        assert_eq!(
+            exceeded.to_string(),
+            "3 startup warning(s), but max_startup_warnings is 2:\
+             \n  [settings] generated a secret key\
+             \n  [preflight] missing config-production.toml\
+             \n  [fairings] template_reload has no effect on this build"
+        );
+        assert!(StartupDiagnostics::new().enforce_budget(Some(0)).is_ok());
+    }
+
+    #[test]
+    fn budget_defaults_to_zero_in_production() {
+        let development = ConfigFixture::new()
+            .var("ROCKET_ENV", "development")
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(development.max_startup_warnings, None);
+
+        let production = ConfigFixture::new()
+            .var("ROCKET_ENV", "production")
+            .var("APP_SECRET_KEY", base64::encode(&[7u8; 32]))
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(production.max_startup_warnings, Some(0));
+
+        let relaxed = ConfigFixture::new()
+            .var("ROCKET_ENV", "production")
+            .var("APP_SECRET_KEY", base64::encode(&[7u8; 32]))
+            .var("APP_MAX_STARTUP_WARNINGS", "2")
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(relaxed.max_startup_warnings, Some(2));
+    }
+
+    #[test]
+    fn lists_diagnostics_as_text_and_json() {
+        let diagnostics = three_warnings();
+        assert_eq!(
+            diagnostics.to_text(None),
+            "warning [settings] generated a secret key\n\
+             warning [preflight] missing config-production.toml\n\
+             info [fairings] attached 12 fairings\n\
+             warning [fairings] template_reload has no effect on this build\n\
+             3 warning(s), max_startup_warnings is unlimited\n"
+        );
+
+        assert_eq!(
+            diagnostics.to_json(Some(0)),
+            serde_json::json!({
+                "warnings": 3,
+                "max_startup_warnings": 0,
+                "diagnostics": [
+                    { "severity": "warning", "source": "settings", "message": "generated a secret key" },
+                    { "severity": "warning", "source": "preflight", "message": "missing config-production.toml" },
+                    { "severity": "info", "source": "fairings", "message": "attached 12 fairings" },
+                    {
+                        "severity": "warning",
+                        "source": "fairings",
+                        "message": "template_reload has no effect on this build"
+                    },
+                ],
+            })
+        );
+        assert_eq!(StartupDiagnostics::new().to_json(None)["max_startup_warnings"], serde_json::Value::Null);
+    }
+}
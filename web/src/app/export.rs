@@ -0,0 +1,419 @@
+//! Exporting the app's public pages as a static site, e.g. for a CDN to fall back to.
+//!
+//! `web export --out <dir>` starts the app against an in-process client (nothing listens on a
+//! port), requests each of `Settings::export_seeds`, and follows same-origin links found in the
+//! HTML responses, up to `Settings::export_max_depth` links away from a seed. Each response is
+//! written to the output directory at the path it was requested from, with paths that look
+//! like directories (ending in `/`, or without an extension) written to an `index.html` inside
+//! them. The static directories are copied alongside, under `Settings::static_route`.
+//!
+//! Links with a query string can't be mirrored as files, and pages that require authentication
+//! shouldn't be published, so both are skipped with a note in the report, as are pages that
+//! didn't respond with `200 OK`.
+use crate::app::{self, RouteGroup, Settings};
+
+use rocket::http::{RawStr, Status};
+use rocket::local::Client;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What `export` did with each page that it found
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExportReport {
+    /// Pages that were written, with the file each was written to
+    pub written: Vec<(String, PathBuf)>,
+    /// Pages that responded with a status other than `200 OK`
+    pub failed: Vec<(String, u16)>,
+    /// Pages that were deliberately not requested or written, and why
+    pub skipped: Vec<(String, &'static str)>,
+}
+
+impl fmt::Display for ExportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Exported {} pages", self.written.len())?;
+        for (path, status) in &self.failed {
+            writeln!(f, "  failed  {} ({})", path, status)?;
+        }
+        for (path, reason) in &self.skipped {
+            writeln!(f, "  skipped {} ({})", path, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Export the app built from `settings` to `out`. See the module documentation.
+pub fn export(settings: Settings, out: &Path) -> Result<ExportReport, crate::Error> {
+    let routes = app::default_routes(&settings);
+    export_routes(settings, routes, out)
+}
+
+/// Export the app built from `settings` with `routes` mounted (as `app::build` mounts them)
+/// to `out`, for apps that mount their own pages alongside the default routes
+pub fn export_routes(settings: Settings, routes: Vec<RouteGroup>, out: &Path) -> Result<ExportReport, crate::Error> {
+    let static_route = settings.static_route.clone();
+    let static_dirs = settings.static_dirs();
+    let max_depth = settings.export_max_depth;
+    let base_url = settings.base_url.clone().map(|url| url.trim_end_matches('/').to_string());
+
+    let mut queue: VecDeque<(String, usize)> = settings.export_seeds.iter().map(|seed| (seed.clone(), 0)).collect();
+    // `LaunchError` panics if it's dropped without being inspected, which formatting it does
+    let client = Client::new(app::build(settings, routes)).map_err(|e| e.to_string())?;
+
+    fs::create_dir_all(out)?;
+    let mut report = ExportReport::default();
+    let mut seen = HashSet::new();
+
+    while let Some((path, depth)) = queue.pop_front() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        if path.contains('?') {
+            report.skipped.push((path, "has a query string"));
+            continue;
+        }
+        if path == static_route || path.starts_with(&format!("{}/", static_route)) {
+            // Copied with the rest of the static directory
+            continue;
+        }
+
+        let file = match output_path(out, &path) {
+            Some(file) => file,
+            None => {
+                report.skipped.push((path, "not a safe file path"));
+                continue;
+            }
+        };
+
+        let mut response = client.get(path.clone()).dispatch();
+        let status = response.status();
+        if status == Status::Unauthorized || status == Status::Forbidden {
+            report.skipped.push((path, "requires authentication"));
+            continue;
+        }
+        if status != Status::Ok {
+            report.failed.push((path, status.code));
+            continue;
+        }
+
+        let is_html = response.content_type().map(|content_type| content_type.is_html()).unwrap_or(false);
+        let body = response.body_bytes().unwrap_or_default();
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&file, &body)?;
+
+        if is_html && depth < max_depth {
+            let html = String::from_utf8_lossy(&body);
+            for link in links(&html) {
+                if let Some(link) = same_origin_path(&path, link, base_url.as_ref().map(String::as_str)) {
+                    queue.push_back((link, depth + 1));
+                }
+            }
+        }
+
+        report.written.push((path, file));
+    }
+
+    // Copied from the last directory to the first, so files in earlier directories win
+    for static_dir in static_dirs.iter().rev().filter(|dir| dir.is_dir()) {
+        copy_dir(static_dir, &out.join(static_route.trim_start_matches('/')))?;
+    }
+
+    Ok(report)
+}
+
+/// The file in `out` that the page at `path` should be written to, or `None` if a segment of
+/// the path can't be used as a file name
+fn output_path(out: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = out.to_path_buf();
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    for segment in &segments {
+        let decoded = RawStr::from_str(segment).percent_decode().ok()?;
+        if decoded == "." || decoded == ".." || decoded.contains('\\') || decoded.contains('/') {
+            return None;
+        }
+        file.push(decoded.as_ref());
+    }
+
+    let is_directory = path.ends_with('/') || segments.last().map(|last| !last.contains('.')).unwrap_or(true);
+    if is_directory {
+        file.push("index.html");
+    }
+    Some(file)
+}
+
+/// The targets of every `href` attribute in `html`
+fn links(html: &str) -> Vec<&str> {
+    let mut links = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("href=") {
+        rest = &rest[start + "href=".len()..];
+        let quote = match rest.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => continue,
+        };
+        rest = &rest[1..];
+        if let Some(end) = rest.find(quote) {
+            links.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+    }
+    links
+}
+
+/// The path of `link` (found on the page at `from`) if it points to the app itself, without
+/// any fragment. Absolute URLs are only followed when they start with `base_url`.
+fn same_origin_path(from: &str, link: &str, base_url: Option<&str>) -> Option<String> {
+    let link = link.split('#').next().unwrap_or("").trim();
+    let link = match base_url {
+        Some(base_url) if link.starts_with(base_url) => {
+            let rest = &link[base_url.len()..];
+            if rest.is_empty() {
+                "/"
+            } else if rest.starts_with('/') {
+                rest
+            } else {
+                return None;
+            }
+        }
+        _ => link,
+    };
+
+    if link.is_empty() || link.starts_with("//") || (link.contains(':') && !link.starts_with('/')) {
+        return None;
+    }
+    if link.starts_with('/') {
+        return Some(remove_dot_segments(link));
+    }
+
+    // A relative link, resolved against the directory of the page it was found on
+    let dir = match from.rfind('/') {
+        Some(i) => &from[..=i],
+        None => "/",
+    };
+    Some(remove_dot_segments(&format!("{}{}", dir, link)))
+}
+
+/// Resolve the `.` and `..` segments in an absolute `path`, as in RFC 3986 section 5.2.4
+fn remove_dot_segments(path: &str) -> String {
+    let (path, query) = match path.find('?') {
+        Some(i) => (&path[..i], &path[i..]),
+        None => (path, ""),
+    };
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/').skip(1) {
+        match segment {
+            "." => (),
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    // `a/.` and `a/..` refer to directories, so keep the trailing slash that they imply
+    let is_directory = path.ends_with("/.") || path.ends_with("/..");
+    let trailing = if is_directory && !segments.is_empty() { "/" } else { "" };
+    format!("/{}{}{}", segments.join("/"), trailing, query)
+}
+
+/// Copy the contents of `from` into `to`, recursively
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::handler::Outcome;
+    use rocket::http::Method;
+    use rocket::response::content::Html;
+    use rocket::{Data, Request, Route};
+    use rocket_contrib::templates::Template;
+    use serde_json::json;
+
+    #[test]
+    fn output_paths_mirror_the_url() {
+        let out = Path::new("/out");
+
+        assert_eq!(output_path(out, "/"), Some(PathBuf::from("/out/index.html")));
+        assert_eq!(output_path(out, "/about"), Some(PathBuf::from("/out/about/index.html")));
+        assert_eq!(output_path(out, "/docs/"), Some(PathBuf::from("/out/docs/index.html")));
+        assert_eq!(output_path(out, "/feed.xml"), Some(PathBuf::from("/out/feed.xml")));
+        assert_eq!(output_path(out, "/caf%C3%A9"), Some(PathBuf::from("/out/café/index.html")));
+        assert_eq!(output_path(out, "/a/../b"), None);
+        assert_eq!(output_path(out, "/%2e%2e/escaped"), None);
+        assert_eq!(output_path(out, "/a%2Fb"), None);
+        assert_eq!(output_path(out, "/a%5Cb"), None);
+    }
+
+    #[test]
+    fn links_are_read_from_href_attributes() {
+        let html = r#"<a href="/about">About</a> <link href='/static/site.css'> <a href=bare>x</a> <a href="">"#;
+        assert_eq!(links(html), vec!["/about", "/static/site.css", ""]);
+        assert_eq!(links("<p>no links</p>"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn only_same_origin_links_are_followed() {
+        let base = Some("https://example.com");
+
+        assert_eq!(same_origin_path("/", "/about#team", base), Some(String::from("/about")));
+        assert_eq!(same_origin_path("/docs/intro", "setup", base), Some(String::from("/docs/setup")));
+        assert_eq!(same_origin_path("/docs/intro", "../about", base), Some(String::from("/about")));
+        assert_eq!(same_origin_path("/docs/", "./", base), Some(String::from("/docs/")));
+        assert_eq!(same_origin_path("/", "https://example.com", base), Some(String::from("/")));
+        assert_eq!(same_origin_path("/", "https://example.com/pricing", base), Some(String::from("/pricing")));
+        assert_eq!(same_origin_path("/", "https://example.com.evil.com/", base), None);
+        assert_eq!(same_origin_path("/", "https://example.com/pricing", None), None);
+        assert_eq!(same_origin_path("/", "https://elsewhere.com/", base), None);
+        assert_eq!(same_origin_path("/", "//elsewhere.com/", base), None);
+        assert_eq!(same_origin_path("/", "mailto:team@example.com", base), None);
+        assert_eq!(same_origin_path("/", "#top", base), None);
+        assert_eq!(same_origin_path("/", "/search?q=book", base), Some(String::from("/search?q=book")));
+    }
+
+    #[test]
+    fn dot_segments_are_removed() {
+        assert_eq!(remove_dot_segments("/a/./b"), "/a/b");
+        assert_eq!(remove_dot_segments("/a/b/../c"), "/a/c");
+        assert_eq!(remove_dot_segments("/../../a"), "/a");
+        assert_eq!(remove_dot_segments("/a/.."), "/");
+        assert_eq!(remove_dot_segments("/a/b/."), "/a/b/");
+        assert_eq!(remove_dot_segments("/a/../b?next=/c/../d"), "/b?next=/c/../d");
+    }
+
+    fn home<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, Template::render("home", json!({ "title": "Home" })))
+    }
+
+    fn about<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, Template::render("about", json!({ "title": "About" })))
+    }
+
+    fn intro<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, Html("<h1>Introduction</h1>"))
+    }
+
+    fn team<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, Html("<h1>Team</h1>"))
+    }
+
+    fn private<'r>(_: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::failure(Status::Unauthorized)
+    }
+
+    /// Export a site of interlinked pages, following links at most `max_depth` away from `/`
+    fn export_site(max_depth: i64) -> (tempfile::TempDir, ExportReport) {
+        let dir = tempfile::tempdir().unwrap();
+        let static_dir = dir.path().join("public");
+        let template_dir = dir.path().join("templates");
+        fs::create_dir_all(static_dir.join("css")).unwrap();
+        fs::create_dir_all(&template_dir).unwrap();
+        fs::write(static_dir.join("css/site.css"), "body { color: red; }").unwrap();
+        fs::write(
+            template_dir.join("home.html.hbs"),
+            r##"<h1>{{title}}</h1>
+<a href="/about">About</a>
+<a href="docs/intro">Docs</a>
+<a href="/private">Account</a>
+<a href="/search?q=book">Search</a>
+<a href="https://elsewhere.com/">Elsewhere</a>
+<a href="/missing">Broken</a>
+<a href="#top">Top</a>
+<link href="/static/css/site.css" rel="stylesheet">"##,
+        )
+        .unwrap();
+        fs::write(
+            template_dir.join("about.html.hbs"),
+            r#"<h1>{{title}}</h1><a href="/">Home</a> <a href="./team">Team</a>"#,
+        )
+        .unwrap();
+
+        let settings = Settings::builder()
+            .unwrap()
+            .set("static_dir", static_dir.to_string_lossy().into_owned())
+            .unwrap()
+            .set("export_max_depth", max_depth)
+            .unwrap()
+            .extra("template_dir", template_dir.to_string_lossy().into_owned())
+            .build()
+            .unwrap();
+        let mut routes = app::default_routes(&settings);
+        routes.push((
+            String::from("/"),
+            vec![
+                Route::new(Method::Get, "/", home),
+                Route::new(Method::Get, "/about", about),
+                Route::new(Method::Get, "/docs/intro", intro),
+                Route::new(Method::Get, "/team", team),
+                Route::new(Method::Get, "/private", private),
+            ],
+        ));
+
+        let out = dir.path().join("out");
+        let report = export_routes(settings, routes, &out).unwrap();
+        (dir, report)
+    }
+
+    #[test]
+    fn exports_the_linked_pages() {
+        let (dir, report) = export_site(1);
+        let out = dir.path().join("out");
+
+        let written: Vec<&str> = report.written.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(written, vec!["/", "/about", "/docs/intro"]);
+        assert_eq!(report.failed, vec![(String::from("/missing"), 404)]);
+        assert_eq!(
+            report.skipped,
+            vec![
+                (String::from("/private"), "requires authentication"),
+                (String::from("/search?q=book"), "has a query string"),
+            ]
+        );
+
+        let home = fs::read_to_string(out.join("index.html")).unwrap();
+        assert!(home.starts_with("<h1>Home</h1>"));
+        assert_eq!(
+            fs::read_to_string(out.join("about/index.html")).unwrap(),
+            r#"<h1>About</h1><a href="/">Home</a> <a href="./team">Team</a>"#
+        );
+        assert_eq!(fs::read_to_string(out.join("docs/intro/index.html")).unwrap(), "<h1>Introduction</h1>");
+        assert_eq!(fs::read_to_string(out.join("static/css/site.css")).unwrap(), "body { color: red; }");
+        // Links beyond the maximum depth aren't followed
+        assert!(!out.join("team").exists());
+        assert!(!out.join("private").exists());
+        assert!(!out.join("missing").exists());
+
+        let summary = report.to_string();
+        assert!(summary.starts_with("Exported 3 pages\n"));
+        assert!(summary.contains("  failed  /missing (404)\n"));
+        assert!(summary.contains("  skipped /private (requires authentication)\n"));
+    }
+
+    #[test]
+    fn deeper_links_are_followed_up_to_the_maximum_depth() {
+        let (dir, report) = export_site(2);
+
+        let written: Vec<&str> = report.written.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(written, vec!["/", "/about", "/docs/intro", "/team"]);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("out/team/index.html")).unwrap(),
+            "<h1>Team</h1>"
+        );
+    }
+}
@@ -0,0 +1,789 @@
+//! Applying schema migrations before the app starts serving traffic.
+//!
+//! Migrations are SQL files in the crate's `migrations/` directory, named
+//! `{version}_{name}.sql` (e.g. `0003_add_users.sql`), and are applied in order of version.
+//! They are embedded into the binary when it is built, so a deployment applies the SQL that it
+//! was built with. `Settings::migrations_dir` reads them from a directory at runtime instead,
+//! which is handy while writing a new migration.
+//!
+//! With the `db` feature, migrations are applied to the sqlite database at `database_url`
+//! (e.g. `sqlite://data/app.db`) by `SqliteBackend`. `web migrate-status` and
+//! `web migrate --dry-run` list the migrations and whether each has been applied, and
+//! `web migrate` (or `Settings::run_migrations`, before launching) applies the pending ones.
+//! Without a backend, applying migrations fails at startup, rather than launching against a
+//! schema that may be out of date.
+//!
+//! A backend takes a lock for the whole run, so that replicas starting at the same time don't
+//! apply the same migrations twice, and records each version once it has been applied.
+use crate::app::Settings;
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+#[cfg(feature = "db")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "db")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Generated by build.rs, defining `MIGRATIONS` as `(file name, sql)` pairs
+include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+/// How long `SqliteBackend::lock` waits for another runner to finish
+#[cfg(feature = "db")]
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A single SQL migration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub version: u64,
+    pub name: String,
+    sql: String,
+}
+
+impl Migration {
+    /// The SQL to apply
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.version, self.name)
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Io(io::Error),
+    /// A migration file isn't named `{version}_{name}.sql`
+    InvalidName(String),
+    DuplicateVersion(u64),
+    /// A migration couldn't be applied
+    Failed { migration: String, message: String },
+    /// The app has no `MigrationBackend`, see the module docs
+    NoBackend,
+    /// `database_url` is for a database that migrations can't be applied to
+    UnsupportedDatabase(String),
+    /// The database failed, or its lock couldn't be taken
+    Database(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Io(e) => write!(f, "could not read migrations: {}", e),
+            MigrationError::InvalidName(name) => {
+                write!(f, "migration file `{}` isn't named {{version}}_{{name}}.sql", name)
+            }
+            MigrationError::DuplicateVersion(version) => write!(f, "more than one migration has version {}", version),
+            MigrationError::Failed { migration, message } => write!(f, "migration {} failed: {}", migration, message),
+            MigrationError::NoBackend => write!(f, "there is no database to apply migrations to"),
+            MigrationError::UnsupportedDatabase(url) => {
+                write!(f, "migrations can only be applied to sqlite databases, not `{}`", url)
+            }
+            MigrationError::Database(message) => write!(f, "database error: {}", message),
+        }
+    }
+}
+
+impl Error for MigrationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MigrationError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MigrationError {
+    fn from(e: io::Error) -> MigrationError {
+        MigrationError::Io(e)
+    }
+}
+
+#[cfg(feature = "db")]
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> MigrationError {
+        MigrationError::Database(e.to_string())
+    }
+}
+
+/// A database that migrations can be applied to
+pub trait MigrationBackend {
+    /// Take a lock that is held until `unlock`, waiting for any other runner to finish
+    fn lock(&mut self) -> Result<(), MigrationError>;
+
+    fn unlock(&mut self) -> Result<(), MigrationError>;
+
+    /// The versions that have already been applied
+    fn applied_versions(&mut self) -> Result<Vec<u64>, MigrationError>;
+
+    /// Apply `migration` and record its version, both or neither
+    fn apply(&mut self, migration: &Migration, sql: &str) -> Result<(), MigrationError>;
+}
+
+/// Applies migrations to a sqlite database, recording each applied version in a
+/// `schema_migrations` table.
+///
+/// The lock is an exclusive transaction around the whole run, so a second runner waits (for
+/// up to `LOCK_TIMEOUT`) until the first has finished, and sqlite releases the lock if the
+/// process dies part way through. Each migration is applied in a savepoint within it, so one
+/// that fails is rolled back while the ones before it stay applied.
+#[cfg(feature = "db")]
+pub struct SqliteBackend {
+    connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "db")]
+impl SqliteBackend {
+    /// Open (or create) the database at `path`, and its `schema_migrations` table
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteBackend, MigrationError> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.busy_timeout(LOCK_TIMEOUT)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at INTEGER NOT NULL
+            )",
+        )?;
+        Ok(SqliteBackend { connection })
+    }
+
+    /// Open the database for `database_url`, which is either `sqlite://{path}` or
+    /// `sqlite:{path}`
+    pub fn from_url(url: &str) -> Result<SqliteBackend, MigrationError> {
+        let path = if url.starts_with("sqlite://") {
+            &url["sqlite://".len()..]
+        } else if url.starts_with("sqlite:") {
+            &url["sqlite:".len()..]
+        } else {
+            return Err(MigrationError::UnsupportedDatabase(url.to_string()));
+        };
+        SqliteBackend::open(path)
+    }
+
+    pub fn connection(&self) -> &rusqlite::Connection {
+        &self.connection
+    }
+}
+
+#[cfg(feature = "db")]
+impl MigrationBackend for SqliteBackend {
+    fn lock(&mut self) -> Result<(), MigrationError> {
+        self.connection
+            .execute_batch("BEGIN EXCLUSIVE")
+            .map_err(|e| MigrationError::Database(format!("could not take the migration lock: {}", e)))
+    }
+
+    fn unlock(&mut self) -> Result<(), MigrationError> {
+        Ok(self.connection.execute_batch("COMMIT")?)
+    }
+
+    fn applied_versions(&mut self) -> Result<Vec<u64>, MigrationError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT version FROM schema_migrations ORDER BY version")?;
+        let versions = statement
+            .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(versions.into_iter().map(|version| version as u64).collect())
+    }
+
+    fn apply(&mut self, migration: &Migration, sql: &str) -> Result<(), MigrationError> {
+        let applied_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or(0);
+
+        self.connection.execute_batch("SAVEPOINT migration")?;
+        let applied = self.connection.execute_batch(sql).and_then(|_| {
+            self.connection.execute(
+                "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+                rusqlite::params![migration.version as i64, migration.name, applied_at],
+            )
+        });
+
+        match applied {
+            Ok(_) => Ok(self.connection.execute_batch("RELEASE migration")?),
+            Err(e) => {
+                self.connection.execute_batch("ROLLBACK TO migration; RELEASE migration")?;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Where a `MigrationRunner` finds its migrations
+#[derive(Debug, Clone)]
+enum MigrationSource {
+    /// `(file name, sql)` pairs, embedded into the binary
+    Embedded(&'static [(&'static str, &'static str)]),
+    /// A directory that is read at runtime
+    Dir(PathBuf),
+}
+
+/// Finds and applies migrations
+#[derive(Debug, Clone)]
+pub struct MigrationRunner {
+    source: MigrationSource,
+}
+
+impl MigrationRunner {
+    /// A runner for the migrations in `dir`, which are read when they are discovered
+    pub fn new<P: Into<PathBuf>>(dir: P) -> MigrationRunner {
+        MigrationRunner {
+            source: MigrationSource::Dir(dir.into()),
+        }
+    }
+
+    /// A runner for the migrations that were embedded into the binary from `migrations/`
+    pub fn embedded() -> MigrationRunner {
+        MigrationRunner {
+            source: MigrationSource::Embedded(MIGRATIONS),
+        }
+    }
+
+    /// The embedded migrations, or those in `Settings::migrations_dir` when it is set
+    pub fn from_settings(settings: &Settings) -> MigrationRunner {
+        match settings.migrations_dir {
+            Some(ref dir) => MigrationRunner::new(dir),
+            None => MigrationRunner::embedded(),
+        }
+    }
+
+    /// Where the migrations come from, for messages
+    pub fn describe(&self) -> String {
+        match self.source {
+            MigrationSource::Embedded(_) => String::from("the binary"),
+            MigrationSource::Dir(ref dir) => dir.display().to_string(),
+        }
+    }
+
+    /// Every migration, in order of version. A missing directory has none.
+    pub fn discover(&self) -> Result<Vec<Migration>, MigrationError> {
+        let mut migrations = Vec::new();
+        match self.source {
+            MigrationSource::Embedded(files) => {
+                for (file_name, sql) in files {
+                    migrations.push(parse_migration(file_name, sql.to_string())?);
+                }
+            }
+            MigrationSource::Dir(ref dir) if dir.is_dir() => {
+                for entry in fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.extension().and_then(|extension| extension.to_str()) != Some("sql") {
+                        continue;
+                    }
+                    let file_name = path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    migrations.push(parse_migration(&file_name, fs::read_to_string(&path)?)?);
+                }
+            }
+            MigrationSource::Dir(_) => (),
+        }
+
+        migrations.sort_by_key(|migration| migration.version);
+        if let Some(pair) = migrations.windows(2).find(|pair| pair[0].version == pair[1].version) {
+            return Err(MigrationError::DuplicateVersion(pair[0].version));
+        }
+        Ok(migrations)
+    }
+
+    /// The migrations that haven't been applied to `backend` yet
+    pub fn pending<B: MigrationBackend + ?Sized>(&self, backend: &mut B) -> Result<Vec<Migration>, MigrationError> {
+        let applied = backend.applied_versions()?;
+        Ok(self
+            .discover()?
+            .into_iter()
+            .filter(|migration| !applied.contains(&migration.version))
+            .collect())
+    }
+
+    /// Apply every pending migration to `backend`, in order, while holding its lock. Stops at
+    /// the first one that fails, leaving the ones before it applied. Returns the migrations
+    /// that were applied.
+    pub fn run<B: MigrationBackend + ?Sized>(&self, backend: &mut B) -> Result<Vec<Migration>, MigrationError> {
+        backend.lock()?;
+        let result = self.apply_pending(backend);
+        let unlocked = backend.unlock();
+        let applied = result?;
+        unlocked?;
+        Ok(applied)
+    }
+
+    fn apply_pending<B: MigrationBackend + ?Sized>(&self, backend: &mut B) -> Result<Vec<Migration>, MigrationError> {
+        let mut applied = Vec::new();
+        for migration in self.pending(backend)? {
+            backend.apply(&migration, migration.sql()).map_err(|e| MigrationError::Failed {
+                migration: migration.to_string(),
+                message: e.to_string(),
+            })?;
+            applied.push(migration);
+        }
+        Ok(applied)
+    }
+}
+
+/// Read a migration's version and name from its file name
+fn parse_migration(file_name: &str, sql: String) -> Result<Migration, MigrationError> {
+    let stem = file_name.trim_end_matches(".sql");
+
+    let mut parts = stem.splitn(2, '_');
+    match (parts.next().map(str::parse::<u64>), parts.next()) {
+        (Some(Ok(version)), Some(name)) if !name.is_empty() => Ok(Migration {
+            version,
+            name: name.to_string(),
+            sql,
+        }),
+        _ => Err(MigrationError::InvalidName(file_name.to_string())),
+    }
+}
+
+/// The backend for `database_url`, see the module docs
+#[cfg(feature = "db")]
+fn open_backend(settings: &Settings) -> Result<Box<dyn MigrationBackend>, MigrationError> {
+    match settings.connection_string("database") {
+        Some(url) => Ok(Box::new(SqliteBackend::from_url(url)?)),
+        None => Err(MigrationError::NoBackend),
+    }
+}
+
+#[cfg(not(feature = "db"))]
+fn open_backend(_: &Settings) -> Result<Box<dyn MigrationBackend>, MigrationError> {
+    Err(MigrationError::NoBackend)
+}
+
+/// Print the migrations and whether each has been applied, for `web migrate-status` and
+/// `web migrate --dry-run`
+pub fn print_status(settings: &Settings) -> Result<(), MigrationError> {
+    let runner = MigrationRunner::from_settings(settings);
+    let migrations = runner.discover()?;
+    if migrations.is_empty() {
+        println!("No migrations in {}", runner.describe());
+        return Ok(());
+    }
+
+    // Without a backend there is no record of which have been applied, so all are listed
+    let applied = match open_backend(settings) {
+        Ok(mut backend) => Some(backend.applied_versions()?),
+        Err(MigrationError::NoBackend) => None,
+        Err(e) => return Err(e),
+    };
+
+    println!("{} migrations in {}:", migrations.len(), runner.describe());
+    for migration in &migrations {
+        match applied {
+            Some(ref applied) if applied.contains(&migration.version) => println!("  {} (applied)", migration),
+            Some(_) => println!("  {} (pending)", migration),
+            None => println!("  {}", migration),
+        }
+    }
+    Ok(())
+}
+
+/// Apply any pending migrations, for `web migrate` and before launching when
+/// `Settings::run_migrations` is enabled. Fails with `NoBackend` if there are migrations to
+/// apply but no database to apply them to, see the module docs.
+pub fn migrate(settings: &Settings) -> Result<(), MigrationError> {
+    let runner = MigrationRunner::from_settings(settings);
+    if runner.discover()?.is_empty() {
+        return Ok(());
+    }
+
+    let mut backend = open_backend(settings)?;
+    for migration in runner.run(&mut *backend)? {
+        tracing::info!(version = migration.version, name = %migration.name, "Applied migration");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// A database shared by every `FakeBackend` made from it, standing in for a real one
+    #[derive(Debug, Default)]
+    struct Database {
+        locked: bool,
+        applied: Vec<u64>,
+        /// How many runners have held the lock at once, at most
+        most_holders: usize,
+        holders: usize,
+    }
+
+    #[derive(Clone, Default)]
+    struct FakeBackend {
+        database: Arc<(Mutex<Database>, Condvar)>,
+    }
+
+    impl FakeBackend {
+        fn applied(&self) -> Vec<u64> {
+            self.database.0.lock().unwrap().applied.clone()
+        }
+    }
+
+    impl MigrationBackend for FakeBackend {
+        fn lock(&mut self) -> Result<(), MigrationError> {
+            let (database, unlocked) = &*self.database;
+            let mut database = database.lock().unwrap();
+            while database.locked {
+                database = unlocked.wait(database).unwrap();
+            }
+            database.locked = true;
+            database.holders += 1;
+            database.most_holders = database.most_holders.max(database.holders);
+            Ok(())
+        }
+
+        fn unlock(&mut self) -> Result<(), MigrationError> {
+            let (database, unlocked) = &*self.database;
+            let mut database = database.lock().unwrap();
+            database.locked = false;
+            database.holders -= 1;
+            unlocked.notify_all();
+            Ok(())
+        }
+
+        fn applied_versions(&mut self) -> Result<Vec<u64>, MigrationError> {
+            Ok(self.applied())
+        }
+
+        fn apply(&mut self, migration: &Migration, sql: &str) -> Result<(), MigrationError> {
+            // Slow enough that a second runner would overlap without the lock
+            thread::sleep(Duration::from_millis(5));
+            if sql.contains("FAIL") {
+                return Err(MigrationError::Failed {
+                    migration: migration.to_string(),
+                    message: String::from("syntax error at FAIL"),
+                });
+            }
+            self.database.0.lock().unwrap().applied.push(migration.version);
+            Ok(())
+        }
+    }
+
+    /// A backend whose lock is always held by someone else
+    struct LockedOut {
+        applied: bool,
+    }
+
+    impl MigrationBackend for LockedOut {
+        fn lock(&mut self) -> Result<(), MigrationError> {
+            Err(MigrationError::Io(io::Error::new(io::ErrorKind::TimedOut, "migration lock is held")))
+        }
+
+        fn unlock(&mut self) -> Result<(), MigrationError> {
+            panic!("unlocked without holding the lock")
+        }
+
+        fn applied_versions(&mut self) -> Result<Vec<u64>, MigrationError> {
+            Ok(Vec::new())
+        }
+
+        fn apply(&mut self, _: &Migration, _: &str) -> Result<(), MigrationError> {
+            self.applied = true;
+            Ok(())
+        }
+    }
+
+    fn migrations_dir(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, sql) in files {
+            fs::write(dir.path().join(name), sql).unwrap();
+        }
+        dir
+    }
+
+    fn versions(migrations: &[Migration]) -> Vec<u64> {
+        migrations.iter().map(|migration| migration.version).collect()
+    }
+
+    #[test]
+    fn discovers_migrations_in_version_order() {
+        let dir = migrations_dir(&[
+            ("0010_add_orders.sql", "CREATE TABLE orders (id INTEGER);"),
+            ("0002_add_users.sql", "CREATE TABLE users (id INTEGER);"),
+            ("1_initial.sql", "CREATE TABLE meta (key TEXT);"),
+            ("README.md", "not a migration"),
+        ]);
+        let migrations = MigrationRunner::new(dir.path()).discover().unwrap();
+
+        assert_eq!(versions(&migrations), vec![1, 2, 10]);
+        assert_eq!(migrations[1].name, "add_users");
+        assert_eq!(migrations[1].to_string(), "2 add_users");
+        assert_eq!(migrations[1].sql(), "CREATE TABLE users (id INTEGER);");
+
+        let missing = MigrationRunner::new(dir.path().join("missing"));
+        assert_eq!(missing.discover().unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn discovers_embedded_migrations() {
+        let runner = MigrationRunner {
+            source: MigrationSource::Embedded(&[
+                ("0002_add_users.sql", "CREATE TABLE users (id INTEGER);"),
+                ("0001_initial.sql", "CREATE TABLE meta (key TEXT);"),
+            ]),
+        };
+        let migrations = runner.discover().unwrap();
+        assert_eq!(versions(&migrations), vec![1, 2]);
+        assert_eq!(migrations[0].sql(), "CREATE TABLE meta (key TEXT);");
+
+        // The migrations that were embedded when this crate was built are valid too
+        assert!(MigrationRunner::embedded().discover().is_ok());
+    }
+
+    #[test]
+    fn rejects_badly_named_migrations() {
+        for name in &["add_users.sql", "0001.sql", "0001_.sql", "v1_add_users.sql"] {
+            let dir = migrations_dir(&[(*name, "")]);
+            match MigrationRunner::new(dir.path()).discover() {
+                Err(MigrationError::InvalidName(invalid)) => assert_eq!(invalid, *name),
+                other => panic!("expected {} to be rejected, got {:?}", name, other),
+            }
+        }
+
+        let dir = migrations_dir(&[("0001_a.sql", ""), ("1_b.sql", "")]);
+        match MigrationRunner::new(dir.path()).discover() {
+            Err(MigrationError::DuplicateVersion(1)) => (),
+            other => panic!("expected a duplicate version, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn applies_pending_migrations_once() {
+        let dir = migrations_dir(&[
+            ("0002_add_users.sql", "CREATE TABLE users (id INTEGER);"),
+            ("0001_initial.sql", "CREATE TABLE meta (key TEXT);"),
+        ]);
+        let runner = MigrationRunner::new(dir.path());
+        let mut backend = FakeBackend::default();
+
+        assert_eq!(versions(&runner.pending(&mut backend).unwrap()), vec![1, 2]);
+        assert_eq!(versions(&runner.run(&mut backend).unwrap()), vec![1, 2]);
+        assert_eq!(backend.applied(), vec![1, 2]);
+
+        // Running again applies nothing, and then only migrations that were added since
+        assert_eq!(runner.run(&mut backend).unwrap(), Vec::new());
+        fs::write(dir.path().join("0003_add_orders.sql"), "CREATE TABLE orders (id INTEGER);").unwrap();
+        assert_eq!(versions(&runner.run(&mut backend).unwrap()), vec![3]);
+        assert_eq!(backend.applied(), vec![1, 2, 3]);
+        assert!(!backend.database.0.lock().unwrap().locked);
+    }
+
+    #[test]
+    fn failing_migration_stops_the_run() {
+        let dir = migrations_dir(&[
+            ("0001_initial.sql", "CREATE TABLE meta (key TEXT);"),
+            ("0002_broken.sql", "FAIL"),
+            ("0003_add_orders.sql", "CREATE TABLE orders (id INTEGER);"),
+        ]);
+        let runner = MigrationRunner::new(dir.path());
+        let mut backend = FakeBackend::default();
+
+        match runner.run(&mut backend) {
+            Err(MigrationError::Failed { migration, message }) => {
+                assert_eq!(migration, "2 broken");
+                assert!(message.contains("syntax error at FAIL"), "{}", message);
+            }
+            other => panic!("expected the migration to fail, got {:?}", other),
+        }
+        // The migrations before it stay applied, and the lock is released
+        assert_eq!(backend.applied(), vec![1]);
+        assert!(!backend.database.0.lock().unwrap().locked);
+        assert_eq!(versions(&runner.pending(&mut backend).unwrap()), vec![2, 3]);
+    }
+
+    #[test]
+    fn runners_take_turns_with_the_lock() {
+        let dir = migrations_dir(&[
+            ("0001_initial.sql", "CREATE TABLE meta (key TEXT);"),
+            ("0002_add_users.sql", "CREATE TABLE users (id INTEGER);"),
+            ("0003_add_orders.sql", "CREATE TABLE orders (id INTEGER);"),
+        ]);
+        let backend = FakeBackend::default();
+
+        let runners: Vec<_> = (0..2)
+            .map(|_| {
+                let runner = MigrationRunner::new(dir.path());
+                let mut backend = backend.clone();
+                thread::spawn(move || versions(&runner.run(&mut backend).unwrap()))
+            })
+            .collect();
+        let mut applied: Vec<Vec<u64>> = runners.into_iter().map(|runner| runner.join().unwrap()).collect();
+        applied.sort();
+
+        // Whichever runner got the lock first applied everything
+        assert_eq!(applied, vec![vec![], vec![1, 2, 3]]);
+        assert_eq!(backend.applied(), vec![1, 2, 3]);
+        assert_eq!(backend.database.0.lock().unwrap().most_holders, 1);
+    }
+
+    #[test]
+    fn nothing_is_applied_without_the_lock() {
+        let dir = migrations_dir(&[("0001_initial.sql", "CREATE TABLE meta (key TEXT);")]);
+        let mut backend = LockedOut { applied: false };
+
+        match MigrationRunner::new(dir.path()).run(&mut backend) {
+            Err(MigrationError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("expected the lock to fail, got {:?}", other),
+        }
+        assert!(!backend.applied);
+    }
+
+    #[test]
+    fn migrate_needs_a_backend_for_pending_migrations() {
+        let dir = migrations_dir(&[("0001_initial.sql", "CREATE TABLE meta (key TEXT);")]);
+        let with_migrations = Settings::builder()
+            .unwrap()
+            .set("migrations_dir", dir.path().to_string_lossy().into_owned())
+            .unwrap()
+            .build()
+            .unwrap();
+        match migrate(&with_migrations) {
+            Err(MigrationError::NoBackend) => (),
+            other => panic!("expected NoBackend, got {:?}", other),
+        }
+
+        let without = Settings::builder()
+            .unwrap()
+            .set("migrations_dir", dir.path().join("missing").to_string_lossy().into_owned())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(migrate(&without).is_ok());
+        assert!(print_status(&without).is_ok());
+    }
+
+    #[cfg(feature = "db")]
+    fn tables(backend: &SqliteBackend) -> Vec<String> {
+        let mut statement = backend
+            .connection()
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        let names = statement
+            .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<String>, _>>()
+            .unwrap();
+        names
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn sqlite_applies_migrations_in_order_once() {
+        let db = tempfile::tempdir().unwrap();
+        let dir = migrations_dir(&[
+            ("0002_add_users.sql", "CREATE TABLE users (id INTEGER, meta_key TEXT REFERENCES meta (key));"),
+            ("0001_initial.sql", "CREATE TABLE meta (key TEXT PRIMARY KEY);"),
+        ]);
+        let runner = MigrationRunner::new(dir.path());
+        let mut backend = SqliteBackend::open(db.path().join("app.db")).unwrap();
+
+        assert_eq!(versions(&runner.run(&mut backend).unwrap()), vec![1, 2]);
+        assert_eq!(backend.applied_versions().unwrap(), vec![1, 2]);
+        assert_eq!(tables(&backend), vec!["meta", "schema_migrations", "users"]);
+
+        // Running again, even from a new connection, applies nothing
+        let mut reopened = SqliteBackend::open(db.path().join("app.db")).unwrap();
+        assert_eq!(runner.run(&mut reopened).unwrap(), Vec::new());
+
+        fs::write(dir.path().join("0003_add_orders.sql"), "CREATE TABLE orders (id INTEGER);").unwrap();
+        assert_eq!(versions(&runner.run(&mut reopened).unwrap()), vec![3]);
+        assert_eq!(reopened.applied_versions().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn sqlite_rolls_back_a_failing_migration() {
+        let db = tempfile::tempdir().unwrap();
+        let dir = migrations_dir(&[
+            ("0001_initial.sql", "CREATE TABLE meta (key TEXT PRIMARY KEY);"),
+            ("0002_broken.sql", "CREATE TABLE half_done (id INTEGER); CREATE TABLE oops (;"),
+            ("0003_add_orders.sql", "CREATE TABLE orders (id INTEGER);"),
+        ]);
+        let runner = MigrationRunner::new(dir.path());
+        let mut backend = SqliteBackend::open(db.path().join("app.db")).unwrap();
+
+        match runner.run(&mut backend) {
+            Err(MigrationError::Failed { migration, .. }) => assert_eq!(migration, "2 broken"),
+            other => panic!("expected the migration to fail, got {:?}", other),
+        }
+
+        // The first migration was kept, none of the broken one was, and the lock was released
+        assert_eq!(backend.applied_versions().unwrap(), vec![1]);
+        assert_eq!(tables(&backend), vec!["meta", "schema_migrations"]);
+        let mut other = SqliteBackend::open(db.path().join("app.db")).unwrap();
+        other.lock().unwrap();
+        other.unlock().unwrap();
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn sqlite_runners_take_turns_with_the_lock() {
+        let db = tempfile::tempdir().unwrap();
+        let dir = migrations_dir(&[
+            ("0001_initial.sql", "CREATE TABLE meta (key TEXT PRIMARY KEY);"),
+            ("0002_add_users.sql", "CREATE TABLE users (id INTEGER);"),
+            ("0003_add_orders.sql", "CREATE TABLE orders (id INTEGER);"),
+        ]);
+
+        let backends: Vec<SqliteBackend> = (0..2)
+            .map(|_| SqliteBackend::open(db.path().join("app.db")).unwrap())
+            .collect();
+        let runners: Vec<_> = backends
+            .into_iter()
+            .map(|mut backend| {
+                let runner = MigrationRunner::new(dir.path());
+                thread::spawn(move || versions(&runner.run(&mut backend).unwrap()))
+            })
+            .collect();
+        let mut applied: Vec<Vec<u64>> = runners.into_iter().map(|runner| runner.join().unwrap()).collect();
+        applied.sort();
+
+        // Whichever runner got the lock first applied everything, and the other saw that
+        assert_eq!(applied, vec![vec![], vec![1, 2, 3]]);
+        let mut backend = SqliteBackend::open(db.path().join("app.db")).unwrap();
+        assert_eq!(backend.applied_versions().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn migrate_applies_to_the_database_url() {
+        let db = tempfile::tempdir().unwrap();
+        let path = db.path().join("app.db");
+        let dir = migrations_dir(&[("0001_initial.sql", "CREATE TABLE meta (key TEXT PRIMARY KEY);")]);
+        let settings = Settings::builder()
+            .unwrap()
+            .set("migrations_dir", dir.path().to_string_lossy().into_owned())
+            .unwrap()
+            .extra("database_url", format!("sqlite://{}", path.display()))
+            .build()
+            .unwrap();
+
+        migrate(&settings).unwrap();
+        assert!(print_status(&settings).is_ok());
+        assert_eq!(SqliteBackend::open(&path).unwrap().applied_versions().unwrap(), vec![1]);
+
+        let postgres = Settings::builder()
+            .unwrap()
+            .set("migrations_dir", dir.path().to_string_lossy().into_owned())
+            .unwrap()
+            .extra("database_url", "postgres://localhost/app")
+            .build()
+            .unwrap();
+        match migrate(&postgres) {
+            Err(MigrationError::UnsupportedDatabase(url)) => assert_eq!(url, "postgres://localhost/app"),
+            other => panic!("expected an unsupported database, got {:?}", other),
+        }
+    }
+}
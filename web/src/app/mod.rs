@@ -1,3 +1,404 @@
+pub mod diagnostics;
+pub mod export;
+pub mod migrations;
+mod registry;
 mod settings;
+pub mod state;
+mod units;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
-pub use self::settings::Settings;
+pub use self::registry::{FairingEntry, FairingRegistry, FairingRegistryError, ResolvedFairing};
+pub use self::settings::{
+    CookieOverride, EnvReport, EnvSource, FieldChange, ListenerSettings, Settings, SettingsBuilder, SettingsError,
+    DEFAULT_MAX_BODY_BYTES, DEFAULT_PER_PAGE, MAX_PER_PAGE,
+};
+pub(crate) use self::settings::active_environment;
+pub use self::state::AppState;
+pub use self::units::{ByteSizeSetting, DurationSetting};
+
+use crate::http::attribution::{Attribution, LogSink};
+use crate::http::csp::ContentSecurityPolicy;
+use crate::http::csrf::CsrfTokens;
+#[cfg(feature = "embed-assets")]
+use crate::http::embedded::{self, EmbeddedAssets};
+use crate::http::fairings::{
+    ClientConcurrencyLimit, Concurrency, CookiePolicy, CorsHeaderFairing, DefaultCacheControl, Idempotency,
+    PathNormalize, SecurityHeadersFairing, ServerHeader, StaticCacheControl, Templates, TimingFairing, TracingFairing,
+    WebSocketUpgrade,
+};
+use crate::http::guards::json_catchers;
+use crate::http::images::NegotiatedImages;
+use crate::http::keyring::KeyRing;
+use crate::http::log_context::{self, RequestLogContext};
+use crate::http::long_poll::ChangeFeed;
+use crate::http::policy::RoutePolicies;
+use crate::http::proxy::ReverseProxy;
+use crate::http::reporting::{error_catchers, ErrorContext, ErrorReporting};
+use crate::http::sessions::Sessions;
+#[cfg(feature = "metrics")]
+use crate::http::metrics::{DeadlineMetrics, FileMetrics};
+#[cfg(feature = "admin")]
+use crate::http::stats;
+use crate::http::stats::StatsRegistry;
+#[cfg(feature = "uploads")]
+use crate::http::uploads::UploadInspectors;
+use crate::http::wrappers::AdvertiseRanges;
+use rocket::{Rocket, Route};
+use rocket_contrib::serve::StaticFiles;
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// A base path, and the routes to mount under it
+pub type RouteGroup = (String, Vec<Route>);
+
+/// Create the rocket instance for the app from the given settings, ready to be launched, with
+/// the default route groups
+pub fn rocket(settings: Settings) -> Rocket {
+    let routes = default_routes(&settings);
+    build(settings, routes)
+}
+
+/// Create the rocket instance for the app from the given settings, mounting each of `routes`
+/// (under `Settings::mount_prefix`, unless the proxy strips it).
+/// Managed state and fairings are set up in the same way regardless of the routes, so this
+/// can be used to embed the app's setup in another app, or to test a handful of routes.
+pub fn build(settings: Settings, routes: Vec<RouteGroup>) -> Rocket {
+    #[cfg(feature = "embed-assets")]
+    let mut settings = settings;
+    #[cfg(feature = "embed-assets")]
+    let templates = embedded::prepare_templates(&mut settings);
+
+    // Rocket installs its own `log` logger when it's created, unless one is already installed
+    log_context::install_logger();
+
+    // State is managed before fairings are attached, so that it's available in `on_attach`
+    let rocket = Rocket::custom(settings.clone().into());
+    let rocket = crate::manage!(rocket, settings.clone());
+    let rocket = crate::manage!(rocket, KeyRing::new(&settings));
+    let reporting = ErrorReporting::from_settings(&settings);
+    let sessions = Sessions::from_settings(&settings);
+    sessions.spawn_sweeper(&reporting);
+    let rocket = crate::manage!(rocket, reporting);
+    let rocket = crate::manage!(rocket, sessions);
+    #[cfg(feature = "uploads")]
+    let rocket = crate::manage!(rocket, UploadInspectors::from_settings(&settings));
+    let stats = StatsRegistry::new();
+    #[cfg(feature = "metrics")]
+    let rocket = if settings.metrics_enabled {
+        let deadlines = DeadlineMetrics::default();
+        let files = FileMetrics::default();
+        stats.register("deadlines", Arc::new(deadlines.clone()));
+        stats.register("files", Arc::new(files.clone()));
+
+        let rocket = crate::manage!(rocket, deadlines);
+        crate::manage!(rocket, files)
+    } else {
+        rocket
+    };
+    let changes = ChangeFeed::new(&settings);
+    stats.register("long_poll", Arc::new(changes.clone()));
+    let rocket = crate::manage!(rocket, changes);
+    let rocket = crate::manage!(rocket, stats);
+    #[cfg(feature = "embed-assets")]
+    let rocket = match templates {
+        Some(templates) => crate::manage!(rocket, templates),
+        None => rocket,
+    };
+    let rocket = rocket.register(json_catchers()).register(error_catchers());
+
+    let rocket = fairings().attach(rocket, &settings);
+
+    let prefix = settings.route_prefix();
+    let rocket = routes.into_iter().fold(rocket, |rocket, (base, routes)| {
+        let base = match base.as_str() {
+            "/" if !prefix.is_empty() => prefix.to_string(),
+            base => format!("{}{}", prefix, base),
+        };
+        rocket.mount(&base, routes)
+    });
+
+    with_unix_listener(rocket)
+}
+
+/// Launch the app on each of `Settings::listeners`, each in its own thread, alongside the main
+/// instance (which the caller launches as usual). Each listener only mounts the route groups
+/// named in its `routes`.
+///
+/// Every listener is a separate rocket instance, built with `build` from the same settings, so
+/// managed state is not shared between them: each has its own idempotency store, concurrency
+/// counts, metrics and so on, and the stats endpoint only reports on the instance that serves
+/// it. Cookies work across listeners, as every instance uses the same secret keys (including a
+/// generated development key, which is created once when the settings are loaded).
+///
+/// A listener that fails to launch logs the error, without stopping the others.
+pub fn spawn_listeners(settings: &Settings) -> io::Result<Vec<JoinHandle<()>>> {
+    settings
+        .listeners
+        .iter()
+        .map(|listener| {
+            let name = listener.name.clone();
+            let bases = listener.routes.clone();
+            let settings = settings.for_listener(listener);
+
+            thread::Builder::new()
+                .name(format!("listener-{}", name))
+                .spawn(move || {
+                    let routes = default_routes(&settings)
+                        .into_iter()
+                        .filter(|(base, _)| bases.is_empty() || bases.contains(base))
+                        .collect();
+
+                    let e = build(settings, routes).launch();
+                    tracing::error!(listener = %name, "Listener failed to launch: {}", e);
+                })
+        })
+        .collect()
+}
+
+/// Set up listening on `Settings::bind_unix_socket`, when it is configured. Rocket 0.4 can
+/// only listen on TCP, so for now this warns that the socket has to be set up separately (e.g.
+/// by a proxy in front of the TCP port) and returns `rocket` unchanged.
+pub fn with_unix_listener(rocket: Rocket) -> Rocket {
+    let settings = match AppState::<Settings>::get(&rocket) {
+        Some(settings) => settings,
+        None => return rocket,
+    };
+    if let Some(socket) = settings.bind_unix_socket() {
+        settings.diagnostics().warn(
+            "fairings",
+            format!("unix socket {} must be set up manually, as rocket can only listen on TCP", socket.display()),
+        );
+        tracing::warn!(
+            socket = %socket.display(),
+            "unix sockets aren't supported by rocket, so the listener must be set up manually"
+        );
+    }
+
+    rocket
+}
+
+/// The fairings that the app attaches, with the settings that enable them and the order that
+/// they must run in
+pub fn fairings() -> FairingRegistry {
+    FairingRegistry::new()
+        // Installs the tracing subscriber on attach, so it must come before anything that logs
+        .register(FairingEntry::new("tracing", TracingFairing::new))
+        .register(FairingEntry::new("error_context", |_| ErrorContext).after("tracing"))
+        .register(FairingEntry::new("log_context", |_| RequestLogContext).after("tracing"))
+        .register(FairingEntry::new("templates", Templates::new).after("tracing"))
+        // Paths are normalized before anything else looks at them
+        .register(
+            FairingEntry::new("path_normalize", PathNormalize::new)
+                .enabled_when("path_normalize", |settings| settings.path_normalize.is_some())
+                .after("tracing")
+                .before("route_policies"),
+        )
+        // Attached early so that the time includes the other request fairings
+        .register(FairingEntry::new("timing", |_| TimingFairing).after("tracing"))
+        .register(FairingEntry::new("route_policies", RoutePolicies::from_settings).after("tracing"))
+        .register(
+            FairingEntry::new("websocket_upgrade", WebSocketUpgrade::new)
+                .enabled_when("websocket_paths", |settings| !settings.websocket_paths.is_empty())
+                .after("route_policies"),
+        )
+        .register(
+            FairingEntry::new("concurrency", Concurrency::new)
+                .enabled_when("max_concurrent_requests", |settings| settings.max_concurrent_requests.is_some())
+                .after("route_policies"),
+        )
+        .register(
+            FairingEntry::new("client_concurrency", ClientConcurrencyLimit::new)
+                .enabled_when("max_concurrent_per_ip", |settings| {
+                    settings.max_concurrent_per_ip.is_some() || settings.max_concurrent_per_api_key.is_some()
+                })
+                .after("route_policies"),
+        )
+        // Requests rejected by a route policy shouldn't use up their idempotency key
+        .register(FairingEntry::new("idempotency", Idempotency::new).after("route_policies"))
+        .register(
+            FairingEntry::new("cors", CorsHeaderFairing::new)
+                .enabled_when("cors_allowed_origins", |settings| !settings.cors_allowed_origins.is_empty())
+                .after("tracing"),
+        )
+        .register(
+            FairingEntry::new("default_cache_control", DefaultCacheControl::new)
+                .enabled_when("default_cache_control", |settings| !settings.default_cache_control.is_empty())
+                .after("tracing"),
+        )
+        .register(
+            FairingEntry::new("static_cache_control", StaticCacheControl::new)
+                .enabled_when("immutable_assets_pattern", |settings| settings.immutable_assets_pattern.is_some())
+                .after("tracing"),
+        )
+        .register(
+            FairingEntry::new("security_headers", SecurityHeadersFairing::new)
+                .enabled_when("security_headers_disabled", |settings| !settings.security_headers_disabled)
+                .after("cors"),
+        )
+        // Sets the nonce on request, so that it's ready before any template is rendered
+        .register(
+            FairingEntry::new("csp", |settings| {
+                ContentSecurityPolicy::new(settings.csp.as_ref().map(String::as_str).unwrap_or(""))
+            })
+            .enabled_when("csp", |settings| settings.csp.is_some())
+            .after("tracing"),
+        )
+        // Sets the token on request, for the `csrf_token` template helper
+        .register(FairingEntry::new("csrf", |_| CsrfTokens).after("tracing"))
+        .register(
+            FairingEntry::new("server_header", ServerHeader::new)
+                .enabled_when("server_header", |settings| settings.server_header.is_some())
+                .after("tracing"),
+        )
+        .register(
+            FairingEntry::new("attribution", |_| Attribution::new(Arc::new(LogSink)))
+                .enabled_when("attribution_enabled", |settings| settings.attribution_enabled)
+                .after("tracing"),
+        )
+        // Runs after every fairing that sets cookies, so that it sees all of them
+        .register(
+            FairingEntry::new("cookie_policy", CookiePolicy::new)
+                .after("attribution")
+                .after("csrf"),
+        )
+}
+
+/// The route groups that the app mounts by default, which serve the static directory on
+/// `Settings::static_route`
+pub fn default_routes(settings: &Settings) -> Vec<RouteGroup> {
+    let mut routes = vec![(settings.static_route.clone(), static_routes(settings))];
+    #[cfg(feature = "admin")]
+    routes.push((String::from("/admin"), stats::admin_routes()));
+    if let (Some(prefix), Some(proxy)) = (&settings.proxy_prefix, ReverseProxy::from_settings(settings)) {
+        routes.push((prefix.clone(), proxy.routes()));
+    }
+    routes
+}
+
+/// The routes that serve files from the static directories. Each directory's routes are
+/// ranked after the previous directory's, so a file missing from one falls through to the
+/// next. With the `embed-assets` feature, the assets embedded in the binary are served instead
+/// when none of the directories exist.
+fn static_routes(settings: &Settings) -> Vec<Route> {
+    #[cfg(feature = "embed-assets")]
+    {
+        if !settings.static_dirs().iter().any(|dir| dir.is_dir()) {
+            return EmbeddedAssets.into();
+        }
+    }
+
+    let options = settings.static_options();
+    let naming = settings.image_variant_naming();
+    let routes = settings
+        .static_dirs()
+        .iter()
+        .enumerate()
+        .flat_map(|(i, dir)| {
+            let routes: Vec<Route> = StaticFiles::new(dir, options).rank(STATIC_FILES_RANK + i as isize).into();
+            match naming {
+                Some(naming) => NegotiatedImages::wrap(routes, dir, naming),
+                None => routes,
+            }
+        })
+        .collect();
+    AdvertiseRanges::wrap(routes)
+}
+
+/// The rank of the routes for the first static directory, matching `StaticFiles`' default
+const STATIC_FILES_RANK: isize = 10;
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::TestApp;
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_are_managed_when_enabled() {
+        use crate::http::metrics::{DeadlineMetrics, FileMetrics};
+
+        let enabled = TestApp::builder().setting("metrics_enabled", "true").build().unwrap();
+        assert!(enabled.state::<DeadlineMetrics>().is_some());
+        assert!(enabled.state::<FileMetrics>().is_some());
+
+        let disabled = TestApp::builder().setting("metrics_enabled", "false").build().unwrap();
+        assert!(disabled.state::<DeadlineMetrics>().is_none());
+        assert!(disabled.state::<FileMetrics>().is_none());
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    #[test]
+    fn metrics_can_not_be_enabled_without_the_feature() {
+        assert!(TestApp::builder().setting("metrics_enabled", "true").build().is_err());
+        assert!(TestApp::builder().build().is_ok());
+    }
+
+    /// Two static layers, with `public` (overrides) searched before `dist` (build output)
+    fn layered_fixtures() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            ("public/app.css", "body { color: red; }"),
+            ("public/img/logo.svg", "<svg>override</svg>"),
+            ("dist/app.css", "body { color: blue; }"),
+            ("dist/app.js", "console.log(\"built\");"),
+            ("dist/img/logo.svg", "<svg>built</svg>"),
+            ("dist/img/icon.svg", "<svg>icon</svg>"),
+        ];
+        for (path, contents) in &files {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        std::fs::create_dir(dir.path().join("templates")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn static_dirs_are_searched_in_order() {
+        use rocket::http::Status;
+        use rocket::local::Client;
+
+        let dir = layered_fixtures();
+        let layer = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        let settings = super::Settings::builder()
+            .unwrap()
+            .set("static_dir", vec![layer("public"), layer("dist")])
+            .unwrap()
+            .extra("template_dir", layer("templates"))
+            .build()
+            .unwrap();
+        assert_eq!(settings.static_dirs(), vec![dir.path().join("public"), dir.path().join("dist")]);
+        let client = Client::new(super::build(settings.clone(), super::default_routes(&settings))).unwrap();
+
+        let get = |path: &str| {
+            let mut response = client.get(path).dispatch();
+            (response.status(), response.body_string())
+        };
+        // Files in both layers come from the first, and the rest fall through to the second
+        assert_eq!(get("/static/app.css"), (Status::Ok, Some(String::from("body { color: red; }"))));
+        assert_eq!(get("/static/img/logo.svg"), (Status::Ok, Some(String::from("<svg>override</svg>"))));
+        assert_eq!(get("/static/app.js"), (Status::Ok, Some(String::from("console.log(\"built\");"))));
+        assert_eq!(get("/static/img/icon.svg"), (Status::Ok, Some(String::from("<svg>icon</svg>"))));
+        assert_eq!(get("/static/missing.js").0, Status::NotFound);
+    }
+
+    #[test]
+    fn static_dir_is_one_path_or_several() {
+        let one = super::Settings::builder()
+            .unwrap()
+            .set("static_dir", "public")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(one.static_dir, vec![String::from("public")]);
+
+        let several = super::Settings::builder()
+            .unwrap()
+            .set("static_dir", vec!["public", "dist"])
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(several.static_dirs(), vec![std::path::PathBuf::from("public"), std::path::PathBuf::from("dist")]);
+    }
+}
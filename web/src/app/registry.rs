@@ -0,0 +1,360 @@
+//! Attaching fairings in a declared order, with each one enabled or disabled by the settings.
+//!
+//! Rocket runs request and response fairings in the order that they were attached, so some
+//! fairings depend on others having been attached first (e.g. the tracing subscriber must be
+//! installed before anything logs). Rather than relying on the order of `.attach()` calls, each
+//! fairing is registered with the names of the fairings it must run `before` or `after`, and
+//! the registry attaches them in an order that satisfies every constraint. Fairings with no
+//! constraints between them keep the order that they were registered in.
+//!
+//! Constraints still apply to disabled fairings, so that enabling a fairing can't change the
+//! order of the others.
+use super::Settings;
+
+use rocket::fairing::AdHoc;
+use rocket::Rocket;
+use std::error::Error;
+use std::fmt;
+
+/// A fairing that can be built from the settings, and its ordering constraints
+pub struct FairingEntry {
+    name: &'static str,
+    attach: Box<dyn Fn(Rocket, &Settings) -> Rocket>,
+    /// A description of the setting that enables this fairing, and the check for it
+    condition: Option<(&'static str, fn(&Settings) -> bool)>,
+    before: Vec<&'static str>,
+    after: Vec<&'static str>,
+}
+
+impl FairingEntry {
+    /// Register the fairing created by `make`, which is only called if the fairing is enabled
+    pub fn new<F, M>(name: &'static str, make: M) -> FairingEntry
+    where
+        F: rocket::fairing::Fairing,
+        M: Fn(&Settings) -> F + 'static,
+    {
+        FairingEntry {
+            name,
+            attach: Box::new(move |rocket, settings| rocket.attach(make(settings))),
+            condition: None,
+            before: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+
+    /// Only attach this fairing when `enabled` returns true. The `setting` is shown as the
+    /// reason when it's disabled, e.g. `"attribution_enabled"`.
+    pub fn enabled_when(mut self, setting: &'static str, enabled: fn(&Settings) -> bool) -> Self {
+        self.condition = Some((setting, enabled));
+        self
+    }
+
+    /// Attach this fairing before the fairing called `name`
+    pub fn before(mut self, name: &'static str) -> Self {
+        self.before.push(name);
+        self
+    }
+
+    /// Attach this fairing after the fairing called `name`
+    pub fn after(mut self, name: &'static str) -> Self {
+        self.after.push(name);
+        self
+    }
+
+    /// Why this fairing won't be attached with the given settings, if it won't be
+    fn disabled_reason(&self, settings: &Settings) -> Option<String> {
+        match self.condition {
+            Some((setting, enabled)) if !enabled(settings) => Some(format!("depends on `{}`", setting)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FairingRegistryError {
+    /// Two fairings were registered with the same name
+    Duplicate(&'static str),
+    /// A fairing is ordered relative to a fairing that hasn't been registered
+    UnknownReference {
+        fairing: &'static str,
+        reference: &'static str,
+    },
+    /// The ordering constraints between these fairings can't all be satisfied
+    Cycle(Vec<&'static str>),
+}
+
+impl fmt::Display for FairingRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FairingRegistryError::Duplicate(name) => write!(f, "fairing `{}` is registered twice", name),
+            FairingRegistryError::UnknownReference { fairing, reference } => write!(
+                f,
+                "fairing `{}` is ordered relative to `{}`, which is not registered",
+                fairing, reference
+            ),
+            FairingRegistryError::Cycle(names) => {
+                write!(f, "fairings have a cycle in their ordering: {}", names.join(", "))
+            }
+        }
+    }
+}
+
+impl Error for FairingRegistryError {}
+
+/// A fairing in the resolved attach order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedFairing {
+    pub name: &'static str,
+    /// Why the fairing won't be attached, or `None` if it will be
+    pub disabled: Option<String>,
+}
+
+/// The fairings that the app can attach, see the module docs
+#[derive(Default)]
+pub struct FairingRegistry {
+    entries: Vec<FairingEntry>,
+}
+
+impl FairingRegistry {
+    pub fn new() -> FairingRegistry {
+        FairingRegistry::default()
+    }
+
+    pub fn register(mut self, entry: FairingEntry) -> Self {
+        self.entries.push(entry);
+        self
+    }
+
+    /// Sort the fairings so that every ordering constraint is satisfied, as indices into
+    /// `entries`
+    fn order(&self) -> Result<Vec<usize>, FairingRegistryError> {
+        let index_of = |name: &str| self.entries.iter().position(|entry| entry.name == name);
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            if index_of(entry.name) != Some(i) {
+                return Err(FairingRegistryError::Duplicate(entry.name));
+            }
+        }
+
+        // `edges[a]` holds each fairing that must be attached after `a`
+        let mut edges = vec![Vec::new(); self.entries.len()];
+        for (i, entry) in self.entries.iter().enumerate() {
+            let unknown = |reference: &&'static str| FairingRegistryError::UnknownReference {
+                fairing: entry.name,
+                reference: *reference,
+            };
+
+            for reference in &entry.before {
+                edges[i].push(index_of(*reference).ok_or_else(|| unknown(reference))?);
+            }
+            for reference in &entry.after {
+                edges[index_of(*reference).ok_or_else(|| unknown(reference))?].push(i);
+            }
+        }
+
+        let mut incoming = vec![0; self.entries.len()];
+        for &to in edges.iter().flatten() {
+            incoming[to] += 1;
+        }
+
+        // Always take the first ready fairing in registration order, so that the result is stable
+        let mut order = Vec::with_capacity(self.entries.len());
+        let mut done = vec![false; self.entries.len()];
+        while let Some(next) = (0..self.entries.len()).find(|&i| !done[i] && incoming[i] == 0) {
+            done[next] = true;
+            order.push(next);
+            for &to in &edges[next] {
+                incoming[to] -= 1;
+            }
+        }
+
+        if order.len() < self.entries.len() {
+            let cycle = (0..self.entries.len())
+                .filter(|&i| !done[i])
+                .map(|i| self.entries[i].name)
+                .collect();
+            return Err(FairingRegistryError::Cycle(cycle));
+        }
+
+        Ok(order)
+    }
+
+    /// The order that fairings will be attached in, and whether each one is enabled
+    pub fn resolve(&self, settings: &Settings) -> Result<Vec<ResolvedFairing>, FairingRegistryError> {
+        Ok(self
+            .order()?
+            .into_iter()
+            .map(|i| ResolvedFairing {
+                name: self.entries[i].name,
+                disabled: self.entries[i].disabled_reason(settings),
+            })
+            .collect())
+    }
+
+    /// A listing of the resolved fairings, one per line, for operators to check the setup
+    pub fn describe(&self, settings: &Settings) -> Result<String, FairingRegistryError> {
+        let lines: Vec<String> = self
+            .resolve(settings)?
+            .iter()
+            .enumerate()
+            .map(|(i, fairing)| match &fairing.disabled {
+                None => format!("{}. {}", i + 1, fairing.name),
+                Some(reason) => format!("{}. {} (disabled: {})", i + 1, fairing.name, reason),
+            })
+            .collect();
+
+        Ok(lines.join("\n"))
+    }
+
+    /// Attach every enabled fairing to `rocket`, in order. If the constraints can't be
+    /// resolved, a fairing that fails on attach is added instead so that launching fails.
+    pub fn attach(self, rocket: Rocket, settings: &Settings) -> Rocket {
+        let order = match self.order() {
+            Ok(order) => order,
+            Err(e) => {
+                tracing::error!("Failed to order fairings: {}", e);
+                return rocket.attach(AdHoc::on_attach("Fairing Registry", |rocket| Err(rocket)));
+            }
+        };
+
+        let mut entries: Vec<Option<FairingEntry>> = self.entries.into_iter().map(Some).collect();
+        order.into_iter().fold(rocket, |rocket, i| match entries[i].take() {
+            Some(ref entry) if entry.disabled_reason(settings).is_none() => (entry.attach)(rocket, settings),
+            _ => rocket,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use std::sync::{Arc, Mutex};
+
+    /// A fairing that records its name in `attached` when it is attached
+    fn recording(name: &'static str, attached: &Arc<Mutex<Vec<&'static str>>>) -> FairingEntry {
+        let attached = attached.clone();
+        FairingEntry::new(name, move |_| {
+            let attached = attached.clone();
+            AdHoc::on_attach(name, move |rocket| {
+                attached.lock().unwrap().push(name);
+                Ok(rocket)
+            })
+        })
+    }
+
+    fn names(registry: &FairingRegistry, settings: &Settings) -> Vec<&'static str> {
+        registry
+            .resolve(settings)
+            .unwrap()
+            .into_iter()
+            .map(|fairing| fairing.name)
+            .collect()
+    }
+
+    fn settings() -> Settings {
+        Settings::builder().unwrap().build().unwrap()
+    }
+
+    #[test]
+    fn resolves_ordering_constraints() {
+        let attached = Arc::new(Mutex::new(Vec::new()));
+        let registry = FairingRegistry::new()
+            .register(recording("compression", &attached).after("cache"))
+            .register(recording("security_headers", &attached).after("cors"))
+            .register(recording("cache", &attached))
+            .register(recording("cors", &attached))
+            .register(recording("tracing", &attached).before("compression").before("cors"));
+
+        assert_eq!(
+            names(&registry, &settings()),
+            vec!["cache", "tracing", "compression", "cors", "security_headers"]
+        );
+    }
+
+    #[test]
+    fn keeps_registration_order_without_constraints() {
+        let attached = Arc::new(Mutex::new(Vec::new()));
+        let registry = FairingRegistry::new()
+            .register(recording("c", &attached))
+            .register(recording("a", &attached))
+            .register(recording("b", &attached));
+
+        assert_eq!(names(&registry, &settings()), vec!["c", "a", "b"]);
+    }
+
+    #[test]
+    fn rejects_cycles_and_unknown_references() {
+        let attached = Arc::new(Mutex::new(Vec::new()));
+        let cycle = FairingRegistry::new()
+            .register(recording("independent", &attached))
+            .register(recording("a", &attached).after("c"))
+            .register(recording("b", &attached).after("a"))
+            .register(recording("c", &attached).after("b"));
+        assert_eq!(
+            cycle.resolve(&settings()),
+            Err(FairingRegistryError::Cycle(vec!["a", "b", "c"]))
+        );
+
+        let unknown = FairingRegistry::new().register(recording("cors", &attached).after("missing"));
+        assert_eq!(
+            unknown.resolve(&settings()),
+            Err(FairingRegistryError::UnknownReference {
+                fairing: "cors",
+                reference: "missing",
+            })
+        );
+
+        let duplicate = FairingRegistry::new()
+            .register(recording("cors", &attached))
+            .register(recording("cors", &attached));
+        assert_eq!(duplicate.resolve(&settings()), Err(FairingRegistryError::Duplicate("cors")));
+    }
+
+    #[test]
+    fn settings_enable_fairings() {
+        let attached = Arc::new(Mutex::new(Vec::new()));
+        let registry = || {
+            FairingRegistry::new()
+                .register(recording("tracing", &attached))
+                .register(
+                    recording("cors", &attached)
+                        .enabled_when("cors_allowed_origins", |settings| !settings.cors_allowed_origins.is_empty())
+                        .after("tracing"),
+                )
+                .register(recording("security_headers", &attached).after("cors"))
+        };
+
+        let disabled = settings();
+        assert_eq!(
+            registry().describe(&disabled).unwrap(),
+            "1. tracing\n2. cors (disabled: depends on `cors_allowed_origins`)\n3. security_headers"
+        );
+        registry().attach(rocket::custom(Config::new(Environment::Development)), &disabled);
+        assert_eq!(*attached.lock().unwrap(), vec!["tracing", "security_headers"]);
+
+        attached.lock().unwrap().clear();
+        let enabled = Settings::builder()
+            .unwrap()
+            .set("cors_allowed_origins", vec!["https://example.com"])
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(registry().resolve(&enabled).unwrap().iter().all(|fairing| fairing.disabled.is_none()));
+        registry().attach(rocket::custom(Config::new(Environment::Development)), &enabled);
+        assert_eq!(*attached.lock().unwrap(), vec!["tracing", "cors", "security_headers"]);
+    }
+
+    #[test]
+    fn app_fairings_resolve() {
+        let resolved = super::super::fairings().resolve(&settings()).unwrap();
+        assert_eq!(resolved[0].name, "tracing");
+
+        let position = |name: &str| resolved.iter().position(|fairing| fairing.name == name).unwrap();
+        assert!(position("path_normalize") < position("route_policies"));
+        assert!(position("route_policies") < position("idempotency"));
+        assert!(position("cors") < position("security_headers"));
+        assert!(position("attribution") < position("cookie_policy"));
+        assert!(position("csrf") < position("cookie_policy"));
+    }
+}
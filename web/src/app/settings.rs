@@ -1,10 +1,25 @@
-use failure::Error;
+use super::diagnostics::StartupDiagnostics;
+use super::units::{ByteSizeSetting, DurationSetting};
+use crate::http::access_log::Rotation;
+use crate::http::images::VariantNaming;
+use crate::http::policy::{Cidr, RoutePolicy};
+#[cfg(feature = "uploads")]
+use crate::http::uploads::SniffStrictness;
 use rocket::config::Value;
+use rocket::http::SameSite;
 use rocket::Config;
+use rocket_contrib::serve::Options;
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::Into;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use tracing_subscriber::filter::EnvFilter;
 
 /// Map one or more settings value names to environment variables directly
 ///
@@ -52,6 +67,15 @@ use std::str::FromStr;
 ///     "dburl" => "DATABASE_URL"
 /// });
 /// ```
+///
+/// In tracked mode, `conf` is a `SourcedConfig`, which records the variable as the source of
+/// each setting that it sets (see `Settings::sources`):
+///
+/// ```
+/// map_to_env!(tracked conf, {
+///     "port" => "PORT"
+/// });
+/// ```
 macro_rules! map_to_env {
     ($settings:ident, {$( $setting_name:expr => $env_name:expr ),+}) => {
         {
@@ -67,12 +91,102 @@ macro_rules! map_to_env {
         {
             use std::env::var;
             $(
-                $settings.set($setting_name, var($env_name)?)?;
+                let env_var = var($env_name)
+                    .map_err(|_| SettingsError::MissingRequired(String::from($env_name)))?;
+                $settings.set($setting_name, env_var)?;
+            )+
+        }
+    };
+    (tracked $settings:ident, {$( $setting_name:expr => $env_name:expr ),+}) => {
+        {
+            use std::env::var;
+            $(
+            if let Ok(env_var) = var($env_name) {
+                $settings.set_from($setting_name, env_var, EnvSource::EnvVar(String::from($env_name)))?;
+            }
             )+
         }
     };
 }
 
+/// The ways in which loading or validating `Settings` can fail
+#[derive(Debug)]
+pub enum SettingsError {
+    /// A setting that must be provided was not found. Holds the name of the setting, or of
+    /// the environment variable that it is read from
+    MissingRequired(String),
+    /// A setting was provided, but its value is not acceptable
+    InvalidValue { field: String, value: String },
+    /// A config source could not be read
+    Io(io::Error),
+    /// The config sources could not be parsed or merged, or didn't match the shape of `Settings`
+    Parse(config::ConfigError),
+    /// A setting couldn't be read as the type of its field, e.g. a `port` that isn't a number.
+    /// `field` is the path to the setting, such as `port` or `route_policies[0].path`
+    InvalidField { field: String, message: String },
+    /// With `strict_env` enabled, these environment variables didn't match any setting or
+    /// allowed extra
+    UnknownVariables(Vec<String>),
+    /// A setting enables a subsystem that this binary was built without
+    FeatureNotCompiled {
+        setting: &'static str,
+        feature: &'static str,
+    },
+}
+
+impl SettingsError {
+    fn invalid(field: &str, value: &str) -> SettingsError {
+        SettingsError::InvalidValue {
+            field: String::from(field),
+            value: String::from(value),
+        }
+    }
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::MissingRequired(name) => write!(f, "missing required setting `{}`", name),
+            SettingsError::InvalidValue { field, value } => {
+                write!(f, "invalid value {:?} for setting `{}`", value, field)
+            }
+            SettingsError::Io(e) => write!(f, "could not read settings: {}", e),
+            SettingsError::Parse(e) => write!(f, "could not parse settings: {}", e),
+            SettingsError::InvalidField { field, message } => write!(f, "field `{}`: {}", field, message),
+            SettingsError::UnknownVariables(names) => {
+                write!(f, "unknown settings in the environment: {}", names.join(", "))
+            }
+            SettingsError::FeatureNotCompiled { setting, feature } => write!(
+                f,
+                "{} is set but this binary was built without the `{}` feature",
+                setting, feature
+            ),
+        }
+    }
+}
+
+impl Error for SettingsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SettingsError::Io(e) => Some(e),
+            SettingsError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SettingsError {
+    fn from(e: io::Error) -> SettingsError {
+        SettingsError::Io(e)
+    }
+}
+
+impl From<config::ConfigError> for SettingsError {
+    fn from(e: config::ConfigError) -> SettingsError {
+        SettingsError::Parse(e)
+    }
+}
+
 /// The prefix used to pull in environment variables. Any variable prefixed with this value that
 /// does not correlate to a property of `Settings` will be added to the `extras` map, which is
 /// provided to rocket.
@@ -96,10 +210,196 @@ pub const ENV_PREFIX: &'static str = "APP";
 /// requirements.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
-    /// The disk path that contains static assets
-    pub static_dir: String,
+    /// The disk paths that contain static assets, as one path or a list. Each request is
+    /// served from the first directory that has the file, so earlier directories override
+    /// later ones, e.g. `["public", "dist"]`. See `static_dirs()`
+    #[serde(deserialize_with = "one_or_many")]
+    pub static_dir: Vec<String>,
     /// The route prefix to use when mounting the static file handler
     pub static_route: String,
+    /// Options for the static file handler: `"index"` to serve `index.html` for directories,
+    /// and `"dot_files"` to serve hidden files. See `static_options()`
+    pub static_options: Option<Vec<String>>,
+    /// How the AVIF and WebP variants of static images are named: "suffix" for
+    /// `hero.jpg.avif`, or "replace" for `hero.avif`. Variants are only served when this is
+    /// set. See `http::images`
+    pub image_variant_naming: Option<String>,
+    /// The extensions of static files that Subresource Integrity hashes are computed for,
+    /// see `AssetIntegrity`
+    pub integrity_extensions: Vec<String>,
+    /// The directory that `config` and `config-{env}` files are read from, which can only be
+    /// set with the `APP_CONFIG_DIR` environment variable
+    pub config_dir: String,
+    /// Whether to also read a `config` file from the root of the Cargo workspace, for config
+    /// shared by every crate in it. Values in the crate's own config files take precedence.
+    /// See `workspace_root()`
+    pub workspace: Option<bool>,
+    /// The number of items per page used by the `Pagination` guard when none is requested
+    pub per_page_default: u32,
+    /// The largest number of items per page that the `Pagination` guard will allow
+    pub per_page_max: u32,
+    /// Whether the `MergePatch` guard accepts bodies sent as `application/json`, in addition
+    /// to `application/merge-patch+json`
+    pub merge_patch_accept_json: bool,
+    /// The keys accepted by the `ApiKey` guard
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// The usernames and passwords accepted by the `BasicAuth` guard
+    #[serde(default)]
+    pub basic_auth_users: HashMap<String, String>,
+    /// Access policies enforced for routes under each path prefix. See `http::policy`
+    #[serde(default)]
+    pub route_policies: HashMap<String, RoutePolicy>,
+    /// Whether the session cookie (and other cookies set alongside it) should only be sent
+    /// over HTTPS
+    pub cookie_secure: bool,
+    /// The `SameSite` policy for the session cookie (and other cookies set alongside it). One
+    /// of "strict" or "lax", or "none" to leave the attribute off
+    pub cookie_same_site: String,
+    /// Whether the `CookiePolicy` fairing adds `HttpOnly` to cookies set without it. Cookies can
+    /// opt out in `cookie_overrides`, e.g. one that JavaScript needs to read
+    pub cookie_http_only_default: bool,
+    /// The `SameSite` attribute that the `CookiePolicy` fairing adds to cookies set without
+    /// one, or `cookie_same_site` when unset
+    pub cookie_same_site_default: Option<String>,
+    /// Per-cookie exceptions to the `CookiePolicy` defaults, by cookie name
+    #[serde(default)]
+    pub cookie_overrides: HashMap<String, CookieOverride>,
+    /// Where session data is kept: "memory", or "file" for one file per session in
+    /// `session_dir`. See `http::sessions`
+    pub session_store: String,
+    /// The directory that session files are kept in when `session_store` is "file"
+    pub session_dir: String,
+    /// How long sessions are kept after they were last saved. A bare number is a number of
+    /// seconds
+    pub session_ttl: DurationSetting,
+    /// The path prefix that requests are proxied to `proxy_upstream` from, e.g. "/api/v1".
+    /// See `http::proxy`
+    pub proxy_prefix: Option<String>,
+    /// The base URL of the service that requests under `proxy_prefix` are proxied to
+    pub proxy_upstream: Option<String>,
+    /// The longest time that proxied `GET` responses are cached for, whatever the upstream's
+    /// `Cache-Control` allows
+    pub proxy_cache_max_ttl: DurationSetting,
+    /// The directory that `Upload` stores files in before handlers see them. See
+    /// `http::uploads`
+    #[cfg(feature = "uploads")]
+    pub upload_dir: String,
+    /// The largest file that can be uploaded
+    #[cfg(feature = "uploads")]
+    pub upload_max_size: ByteSizeSetting,
+    /// How closely an upload's contents must agree with its extension: "off", "lenient" or
+    /// "strict", see `SniffStrictness`
+    #[cfg(feature = "uploads")]
+    pub upload_sniff: String,
+    /// A command that scans each upload, e.g. `clamscan --no-summary {path}`. Files that it
+    /// exits non-zero for are quarantined and rejected
+    #[cfg(feature = "uploads")]
+    pub upload_scanner_command: Option<String>,
+    /// How long the scanner has to finish before the upload fails
+    #[cfg(feature = "uploads")]
+    pub upload_scanner_timeout: DurationSetting,
+    /// The directory that files rejected by the scanner are moved to
+    #[cfg(feature = "uploads")]
+    pub upload_quarantine_dir: String,
+    /// Whether pending schema migrations are applied before the app launches. See
+    /// `app::migrations`
+    pub run_migrations: bool,
+    /// A directory to read migration files from at runtime, instead of applying the ones that
+    /// were embedded into the binary from `migrations/`
+    pub migrations_dir: Option<String>,
+    /// The paths that `web export` starts crawling from. See `app::export`
+    pub export_seeds: Vec<String>,
+    /// How many links away from a seed `web export` follows links
+    pub export_max_depth: usize,
+    /// Whether first-touch attribution is recorded for visitors. See `http::attribution`
+    pub attribution_enabled: bool,
+    /// How long responses to requests with an `Idempotency-Key` header are kept. A bare
+    /// number is a number of seconds
+    pub idempotency_ttl: DurationSetting,
+    /// The maximum number of responses kept for requests with an `Idempotency-Key` header
+    pub idempotency_cache_size: usize,
+    /// How long handlers have to answer a request, as exposed by the `Deadline` guard. A bare
+    /// number is a number of seconds
+    pub request_deadline: DurationSetting,
+    /// The addresses of reverse proxies whose forwarded headers (such as `X-Request-Deadline`)
+    /// are trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>,
+    /// A file to write access log lines to, instead of sending them to the log output
+    pub access_log_file: Option<String>,
+    /// When to rotate `access_log_file`: `"daily"`, or a size such as `"size:100MB"`
+    pub access_log_rotate: Option<String>,
+    /// The path of a unix domain socket to listen on, see `Settings::bind_unix_socket`
+    pub unix_socket: Option<String>,
+    /// Whether to keep counters such as `DeadlineMetrics` in managed state
+    #[cfg(feature = "metrics")]
+    pub metrics_enabled: bool,
+    /// Whether the app sends mail. Nothing in this crate sends mail itself: this is for the
+    /// handlers and jobs that do, so that binaries built without the `mail` feature reject
+    /// configs that expect mail to be sent
+    #[cfg(feature = "mail")]
+    pub mail_enabled: bool,
+    /// Paths that WebSocket clients connect to, which are served by another service. Upgrade
+    /// requests for these paths that reach the app get `426 Upgrade Required`
+    #[serde(default)]
+    pub websocket_paths: Vec<String>,
+    /// The path that the app is served under behind a reverse proxy, e.g. `/myapp`. Routes
+    /// are mounted under it, and `BasePath` adds it to generated links
+    pub mount_prefix: Option<String>,
+    /// Whether the reverse proxy strips `mount_prefix` from request paths before forwarding
+    /// them. When set, routes are mounted at the root (so that paths aren't prefixed twice),
+    /// and the prefix is only added to generated links
+    pub strip_prefix_header: bool,
+    /// The public URL of the app, e.g. `https://example.com`. Its host is always allowed as
+    /// the target of a `SafeRedirect`
+    pub base_url: Option<String>,
+    /// Other hosts that a `SafeRedirect` can send users to, e.g. `accounts.example.com`
+    #[serde(default)]
+    pub allowed_redirect_hosts: Vec<String>,
+    /// The most requests that the app will handle at once, across every client. Requests over
+    /// the limit get `503 Service Unavailable`, see `http::fairings::Concurrency`
+    pub max_concurrent_requests: Option<usize>,
+    /// The most requests that a single IP address can have in flight at once
+    pub max_concurrent_per_ip: Option<usize>,
+    /// The most requests that a single API key can have in flight at once
+    pub max_concurrent_per_api_key: Option<usize>,
+    /// Path prefixes that aren't counted towards the concurrency limits, e.g. health checks
+    #[serde(default)]
+    pub concurrency_exempt_prefixes: Vec<String>,
+    /// Whether to leave out the default security headers, see `SecurityHeadersFairing`
+    pub security_headers_disabled: bool,
+    /// How requests for paths that aren't in canonical form (e.g. `/foo//bar`) are handled:
+    /// "redirect" to send clients to the canonical path, or "rewrite" to route them as though
+    /// they had asked for it. See `http::fairings::PathNormalize`
+    pub path_normalize: Option<String>,
+    /// The `Server` header to send instead of rocket's, or an empty string to send none
+    pub server_header: Option<String>,
+    /// The `Content-Security-Policy` header to send, with `{nonce}` in place of each
+    /// request's script nonce. See `http::csp`
+    pub csp: Option<String>,
+    /// The size of the chunks that `LargeFile` responses are written in
+    pub file_chunk_bytes: ByteSizeSetting,
+    /// The `Cache-Control` header for dynamic responses that don't set their own, or an
+    /// empty string to leave it unset
+    pub default_cache_control: String,
+    /// A regex matching the paths of static files whose names are fingerprinted by the build
+    /// (e.g. `"\\.[0-9a-f]{8,}\\.(js|css)$"` for `app.3f9a1c2e.js`), which can be cached
+    /// forever. See `http::fairings::StaticCacheControl`
+    pub immutable_assets_pattern: Option<String>,
+    /// How long other static files can be cached for when `immutable_assets_pattern` is set
+    pub static_max_age: DurationSetting,
+    /// The origins that may make cross-origin requests, or `"*"` for any origin
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    /// How long a long poll waits for a change before responding that there wasn't one
+    pub long_poll_timeout: DurationSetting,
+    /// The most long poll requests that can wait for a change at once
+    pub long_poll_max_parked: usize,
+    /// More addresses for the app to listen on, each served by its own rocket instance. See
+    /// `app::spawn_listeners`
+    #[serde(default)]
+    pub listeners: Vec<ListenerSettings>,
 
     // Rocket Variable are all optional
     // becuase rocket provides defaults
@@ -114,42 +414,308 @@ pub struct Settings {
     workers: Option<u16>,
     /// [Required] The app's secret key, used to sign cookies
     secret_key: Option<String>,
-    /// [Required] Additional config values for extensions of rocket
-    extras: HashMap<String, String>,
+    /// A file that the secret key is read from in staging and production when `secret_key`
+    /// isn't set, e.g. one mounted from a secrets manager. See `enforce_secret_policy`
+    pub secret_key_file: Option<String>,
+    /// Whether to generate a secret key for the current run when none is provided in
+    /// development. Defaults to `true` in development, and must be `false` in production
+    pub auto_secret_key_dev: bool,
+    /// Whether templates are reloaded when they change on disk. Defaults to `true` in
+    /// development and `false` in staging and production. rocket_contrib 0.4 only reloads in
+    /// debug builds, so the `Templates` fairing warns when this asks for something else. See
+    /// `template_reload()`
+    pub template_reload: Option<bool>,
+    /// Secret keys that were previously used to sign cookies, most recent first. Private
+    /// cookies sealed with these keys can still be read through `http::keyring::KeyRing`
+    /// after the secret key is rotated
+    #[serde(default)]
+    pub previous_secret_keys: Vec<String>,
+    /// Whether `Settings::new` should fail when an `APP_` environment variable doesn't match a
+    /// setting or one of `allowed_extras`, to catch typos in variable names
+    pub strict_env: bool,
+    /// Extras that may be set with `APP_` environment variables when `strict_env` is enabled.
+    /// Can be set as a comma separated list, e.g. `APP_ALLOWED_EXTRAS=template_dir,databases`
+    #[serde(default)]
+    pub allowed_extras: Vec<String>,
+    /// Extras that `DebugOverride` lets requests override with query params while debugging.
+    /// Only allowed in development and staging
+    #[serde(default)]
+    pub debug_override_extras: Vec<String>,
+    /// [Required] Additional config values for extensions of rocket. Extras from the
+    /// environment are always strings, but extras set in code can be numbers or booleans too
+    extras: HashMap<String, serde_json::Value>,
+    /// The most warnings that may be recorded while starting up before the launch is
+    /// aborted, or unset for no limit. Defaults to 0 in production and no limit otherwise. See
+    /// `app::diagnostics`
+    pub max_startup_warnings: Option<usize>,
+    #[serde(skip)]
+    env_report: Option<EnvReport>,
+    #[serde(skip)]
+    diagnostics: StartupDiagnostics,
+    #[serde(skip)]
+    sources: HashMap<String, EnvSource>,
+}
+
+/// Where the value of a setting came from, see `Settings::sources`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvSource {
+    /// The setting wasn't set anywhere, so it has its default value
+    Default,
+    /// The setting was read from this config file
+    File(PathBuf),
+    /// The setting was read from this environment variable, e.g. `APP_PORT`
+    EnvVar(String),
+}
+
+/// The port that rocket binds to when none has been configured
+const DEFAULT_PORT: u16 = 8000;
+
+/// The extra holding an age-encrypted secret key, see `decrypt_secrets`
+pub const ENCRYPTED_SECRET_KEY: &'static str = "secret_key_encrypted";
+/// The extra holding the path of the identity file that decrypts `secret_key_encrypted`
+pub const SECRET_KEY_IDENTITY: &'static str = "secret_key_identity";
+
+/// The extra that sets the largest JSON body that rocket will read, see `max_body_size`
+pub const MAX_BODY_BYTES: &'static str = "max_body_bytes";
+/// The largest JSON body that rocket will read when `max_body_bytes` isn't set
+pub const DEFAULT_MAX_BODY_BYTES: u64 = 8 * 1024 * 1024;
+
+/// The default value of `Settings::per_page_default`
+pub const DEFAULT_PER_PAGE: u32 = 20;
+/// The default value of `Settings::per_page_max`
+pub const MAX_PER_PAGE: u32 = 100;
+
+/// An additional listener for the app, e.g. to serve the admin routes on an internal port
+///
+/// # Examples
+///
+/// ```toml
+/// [[listeners]]
+/// name = "admin"
+/// address = "127.0.0.1"
+/// port = 9000
+/// routes = ["/admin"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenerSettings {
+    /// A name for the listener, used in log messages
+    pub name: String,
+    /// The address to listen on, or the main listener's address when unset
+    pub address: Option<String>,
+    pub port: u16,
+    /// The bases of the route groups to mount (e.g. `"/admin"`), or every group when empty
+    #[serde(default)]
+    pub routes: Vec<String>,
+}
+
+/// The attributes that the `CookiePolicy` fairing enforces for one cookie, instead of the
+/// defaults. Unset attributes use the defaults.
+///
+/// # Examples
+///
+/// ```toml
+/// # A CSRF token that the frontend reads from JavaScript
+/// [cookie_overrides.xsrf_token]
+/// http_only = false
+/// same_site = "strict"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CookieOverride {
+    pub http_only: Option<bool>,
+    pub secure: Option<bool>,
+    pub same_site: Option<String>,
+}
+
+/// How the `APP_` environment variables were used when loading the settings, see
+/// `Settings::env_report`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvReport {
+    /// Variables that were read as settings, e.g. `APP_PORT`
+    pub settings: Vec<String>,
+    /// Variables that didn't match a setting, and were added to the extras
+    pub extras: Vec<String>,
+    /// Variables that were set but empty, and so weren't read
+    pub ignored: Vec<String>,
+    /// `(variable, setting variable)` for extras that look like a typo of a setting, such as
+    /// `("APP_PROT", "APP_PORT")`
+    pub typos: Vec<(String, String)>,
+}
+
+impl EnvReport {
+    /// A one line summary, e.g. `environment: settings APP_PORT; extras APP_DB_URL; ignored -`
+    pub fn summary(&self) -> String {
+        let list = |names: &[String]| match names.len() {
+            0 => String::from("-"),
+            _ => names.join(", "),
+        };
+
+        format!(
+            "environment: settings {}; extras {}; ignored {}",
+            list(&self.settings),
+            list(&self.extras),
+            list(&self.ignored)
+        )
+    }
+}
+
+/// A setting that differs between two `Settings`, see `Settings::diff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    /// The name of the setting, or `extras.{key}` for an extra
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.old, self.new)
+    }
+}
+
+/// Settings and extras whose name contains any of these have their values redacted by
+/// `Settings::diff`
+const SECRET_NAME_PARTS: [&'static str; 5] = ["secret", "password", "token", "api_key", "dsn"];
+
+/// What `Settings::diff` shows in place of a secret value
+const REDACTED: &'static str = "[redacted]";
+
+/// The closest of `known` to `key`, if it's close enough that `key` is probably a typo of it:
+/// one edit for names of up to 4 characters, and two edits for longer names
+fn suggest_setting<'k>(key: &str, known: &'k [String]) -> Option<&'k str> {
+    let max_distance = if key.len() <= 4 { 1 } else { 2 };
+    known
+        .iter()
+        .map(|name| (edit_distance(key, name), name))
+        .filter(|(distance, _)| *distance > 0 && *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name.as_str())
+}
+
+/// The Levenshtein distance between `a` and `b`, counting a swap of two adjacent characters
+/// as a single edit (so that `prot` is one edit from `port`)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // `rows[i][j]` is the distance between the first `i` characters of `a` and `j` of `b`
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..=a.len() {
+        rows[i][0] = i;
+    }
+    for j in 0..=b.len() {
+        rows[0][j] = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+
+    rows[a.len()][b.len()]
+}
+
+/// The prefix of extras that are read as feature flags, see `Settings::feature`
+const FEATURE_FLAG_PREFIX: &'static str = "feature_";
+
+/// The text of an extra's value, as it would be written in an environment variable. Only
+/// strings, numbers and booleans have one.
+fn extra_text(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        serde_json::Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn is_flag_enabled(value: &str) -> bool {
+    let value = value.trim();
+    ["true", "1", "yes", "on"]
+        .iter()
+        .any(|enabled| value.eq_ignore_ascii_case(enabled))
 }
 
 /// Keys that should be filtered out of the extras map, because they are defined as fields on `Settings`
 const FILTER_EXTRA_KEYS: [&'static str; 5] = ["address", "port", "log", "workers", "secret_key"];
 
+/// List settings that can be set from an environment variable, as a comma separated list
+const ENV_LISTS: [&'static str; 3] = ["allowed_extras", "debug_override_extras", "static_dir"];
+
+/// Read a list setting that can also be given as a single value
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
+
+/// Variables with the `ENV_PREFIX` that are read directly, rather than being settings
+const DIRECT_ENV_KEYS: [&'static str; 1] = ["env"];
+
 impl Settings {
-    pub fn new() -> Result<Settings, Error> {
-        use config::{Config, Environment, File};
+    pub fn new() -> Result<Settings, SettingsError> {
+        use config::{Config, Environment};
         use std::env::var;
 
-        let mut conf = Config::new();
+        let mut conf = SourcedConfig::new();
+        let diagnostics = StartupDiagnostics::new();
 
-        conf.set_default("static_dir", concat!(env!("CARGO_MANIFEST_DIR"), "/public"))?;
-        conf.set_default("static_route", String::from("/static"))?;
+        set_defaults(&mut conf)?;
 
-        map_to_env!(conf, {
+        map_to_env!(tracked conf, {
             "port" => "PORT"
         });
 
-        conf.merge(File::with_name("config").required(false))?;
+        let config_dir = match var("APP_CONFIG_DIR") {
+            Ok(dir) => {
+                if !Path::new(&dir).is_dir() {
+                    return Err(SettingsError::invalid("config_dir", &dir));
+                }
+                dir
+            }
+            Err(_) => String::from("."),
+        };
+        if var("APP_CONFIG_DIR").is_ok() {
+            conf.set_from("config_dir", config_dir.clone(), EnvSource::EnvVar(String::from("APP_CONFIG_DIR")))?;
+        }
 
-        match var("APP_ENV").unwrap_or(String::from("")).as_str() {
-            env @ "development" | env @ "production" | env @ "staging" => {
-                conf.merge(File::with_name(&format!("config-{}", env)).required(false))?;
+        // `workspace` can be set in the crate's own config, which has to be read to find out
+        // whether the workspace config (with a lower priority) should be read before it
+        let mut probe = SourcedConfig::new();
+        merge_config_sources(&mut probe, &config_dir, None)?;
+        let workspace_dir = if probe.get_bool("workspace").unwrap_or(false) {
+            let dir = workspace_root();
+            if dir.is_none() {
+                diagnostics.warn("settings", "workspace is enabled, but no Cargo workspace root could be found");
             }
-            _ => (),
+            dir
+        } else {
+            None
         };
 
-        conf.merge(Environment::with_prefix(ENV_PREFIX).ignore_empty(true))?;
+        merge_config_sources(&mut conf, &config_dir, workspace_dir.as_ref().map(PathBuf::as_path))?;
 
         let mut extras_config = Config::new();
         extras_config.merge(Environment::with_prefix(ENV_PREFIX).ignore_empty(true))?;
 
         let mut extras_map: HashMap<String, String> = extras_config.try_into()?;
+        let env_keys: Vec<String> = extras_map.keys().cloned().collect();
 
         for key in FILTER_EXTRA_KEYS.iter() {
             extras_map.remove(&String::from(*key));
@@ -157,46 +723,1707 @@ impl Settings {
 
         conf.set("extras", extras_map)?;
 
-        Ok(conf.try_into()?)
+        // Lists can't be written directly in environment variables
+        for list in ENV_LISTS.iter() {
+            if let Ok(names) = conf.get_str(list) {
+                let names: Vec<String> = names
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(String::from)
+                    .collect();
+                conf.set(list, names)?;
+            }
+        }
+
+        reject_disabled_features(&conf)?;
+
+        let mut settings = deserialize_settings(&conf)?;
+        settings.diagnostics = diagnostics;
+        settings.sources = conf.sources_for(&settings);
+        let report = settings.env_report_for(&env_keys);
+        // No tracing subscriber is installed yet, so `TracingFairing` logs these once it is
+        for (variable, setting) in &report.typos {
+            let message = format!("{} looks like a typo of {}", variable, setting);
+            settings.diagnostics.warn("settings", message);
+        }
+        if settings.strict_env {
+            settings.check_env_keys(&env_keys)?;
+        }
+        settings.env_report = Some(report);
+        #[cfg(feature = "encrypted-secrets")]
+        {
+            if let Some(identity) = settings.extra(SECRET_KEY_IDENTITY).map(String::from) {
+                settings.decrypt_secrets(Path::new(&identity))?;
+            }
+        }
+        settings.enforce_secret_policy(active_environment())?;
+        settings.validate()?;
+        Ok(settings)
     }
-}
 
-impl Into<Config> for Settings {
-    fn into(self) -> Config {
-        use rocket::config::{Environment, LoggingLevel};
-        let env = Environment::active().unwrap_or(Environment::Production);
-        let mut conf = Config::new(env);
+    /// Decrypt the `secret_key_encrypted` extra (i.e. `APP_SECRET_KEY_ENCRYPTED`), a base64
+    /// encoded file encrypted with [age](https://age-encryption.org), using the identities in
+    /// the file at `identity_path`, and use the plaintext as the secret key. Does nothing when
+    /// the extra isn't set, so a `secret_key` set directly still works.
+    ///
+    /// `Settings::new` calls this when the `secret_key_identity` extra is set to the path of
+    /// the identity file. See the README for how to encrypt a key.
+    #[cfg(feature = "encrypted-secrets")]
+    pub fn decrypt_secrets(&mut self, identity_path: &Path) -> Result<(), SettingsError> {
+        use std::io::Read;
 
-        if let Some(address) = self.address {
-            conf.set_address(address);
+        let encrypted = match self.extra(ENCRYPTED_SECRET_KEY) {
+            Some(encrypted) => encrypted,
+            None => return Ok(()),
+        };
+        let failed = |message: String| SettingsError::InvalidField {
+            field: String::from(ENCRYPTED_SECRET_KEY),
+            message,
+        };
+
+        let ciphertext = base64::decode(encrypted.trim()).map_err(|e| failed(format!("not valid base64: {}", e)))?;
+        let identities = age::IdentityFile::from_file(identity_path.to_string_lossy().into_owned())?.into_identities();
+
+        let decryptor = match age::Decryptor::new(&ciphertext[..]).map_err(|e| failed(e.to_string()))? {
+            age::Decryptor::Recipients(decryptor) => decryptor,
+            // Files encrypted with a passphrase would need one to be typed in at startup
+            _ => return Err(failed(String::from("must be encrypted to a recipient, not a passphrase"))),
+        };
+
+        let mut plaintext = String::new();
+        decryptor
+            .decrypt(identities.iter().map(|identity| identity as &dyn age::Identity))
+            .map_err(|e| failed(e.to_string()))?
+            .read_to_string(&mut plaintext)?;
+
+        self.secret_key = Some(plaintext.trim().to_string());
+        Ok(())
+    }
+
+    /// Enforce the secret key rules for `env`:
+    ///
+    /// | Environment | No key provided | Key provided |
+    /// |-------------|-----------------|--------------|
+    /// | production  | error           | must be 32 bytes, as base64 |
+    /// | staging     | error           | accepted, with a warning unless it's 32 bytes, as base64 |
+    /// | development | generated, with a warning (unless `auto_secret_key_dev` is disabled) | accepted |
+    ///
+    /// In staging and production the key can be read from `secret_key_file` instead of being
+    /// set directly, and `auto_secret_key_dev` must be disabled in production. A generated key
+    /// stays the same for the rest of the run, so that everything using these settings (rocket,
+    /// and the `KeyRing`) shares one key, but cookies signed with it won't survive a restart.
+    pub fn enforce_secret_policy(&mut self, env: rocket::config::Environment) -> Result<(), SettingsError> {
+        use rocket::config::Environment;
+
+        if env.is_prod() && self.auto_secret_key_dev {
+            return Err(SettingsError::invalid("auto_secret_key_dev", "true"));
         }
-        if let Some(port) = self.port {
-            conf.set_port(port);
+
+        if self.secret_key.is_none() && !env.is_dev() {
+            if let Some(ref path) = self.secret_key_file {
+                let key = std::fs::read_to_string(path)?;
+                self.secret_key = Some(key.trim().to_string());
+            }
         }
-        if let Some(log) = self.log {
-            conf.set_log_level(LoggingLevel::from_str(&log).unwrap_or(LoggingLevel::Normal));
+
+        match (env, self.secret_key.as_ref()) {
+            (Environment::Production, Some(key)) => {
+                if crate::http::keyring::decode_key(key).is_none() {
+                    // The key itself is left out of the error, as it ends up in logs
+                    return Err(SettingsError::InvalidField {
+                        field: String::from("secret_key"),
+                        message: String::from("must be 32 bytes, encoded as base64"),
+                    });
+                }
+            }
+            (Environment::Staging, Some(key)) => {
+                if crate::http::keyring::decode_key(key).is_none() {
+                    let message = "secret_key isn't 32 bytes encoded as base64, which production requires";
+                    self.diagnostics.warn("settings", message);
+                }
+            }
+            (Environment::Production, None) | (Environment::Staging, None) => {
+                return Err(SettingsError::MissingRequired(String::from("secret_key")));
+            }
+            (Environment::Development, None) if self.auto_secret_key_dev => {
+                let key = cookie::Key::generate();
+                self.secret_key = Some(base64::encode(key.master()));
+                let message = "no secret_key was provided, so one has been generated for this run. \
+                               Cookies signed with it will be invalid after a restart.";
+                self.diagnostics.warn("settings", message);
+            }
+            _ => (),
         }
-        if let Some(workers) = self.workers {
-            conf.set_workers(workers);
+
+        Ok(())
+    }
+
+    /// Check that the values of any settings with a restricted set of valid values are
+    /// acceptable
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        use rocket::config::LoggingLevel;
+
+        if !self.static_route.starts_with('/') {
+            return Err(SettingsError::invalid("static_route", &self.static_route));
         }
-        if let Some(secret_key) = self.secret_key {
-            conf.set_secret_key(secret_key);
+        if let Some(ref log) = self.log {
+            if LoggingLevel::from_str(log).is_err() {
+                return Err(SettingsError::invalid("log", log));
+            }
+        }
+        match self.cookie_same_site.as_str() {
+            "strict" | "lax" | "none" => (),
+            _ => return Err(SettingsError::invalid("cookie_same_site", &self.cookie_same_site)),
+        }
+        let same_site_values = self
+            .cookie_same_site_default
+            .iter()
+            .map(|value| ("cookie_same_site_default", value))
+            .chain(
+                self.cookie_overrides
+                    .values()
+                    .filter_map(|cookie| cookie.same_site.as_ref())
+                    .map(|value| ("cookie_overrides", value)),
+            );
+        for (field, value) in same_site_values {
+            match value.as_str() {
+                "strict" | "lax" | "none" => (),
+                _ => return Err(SettingsError::invalid(field, value)),
+            }
+        }
+        match self.session_store.as_str() {
+            "memory" | "file" => (),
+            _ => return Err(SettingsError::invalid("session_store", &self.session_store)),
+        }
+        if let Some(ref mode) = self.path_normalize {
+            match mode.as_str() {
+                "redirect" | "rewrite" => (),
+                _ => return Err(SettingsError::invalid("path_normalize", mode)),
+            }
+        }
+        if let Some(ref naming) = self.image_variant_naming {
+            if VariantNaming::from_setting(naming).is_none() {
+                return Err(SettingsError::invalid("image_variant_naming", naming));
+            }
+        }
+        if let Some(ref prefix) = self.mount_prefix {
+            if !prefix.starts_with('/') || prefix.ends_with('/') {
+                return Err(SettingsError::invalid("mount_prefix", prefix));
+            }
+        }
+        match (&self.proxy_prefix, &self.proxy_upstream) {
+            (Some(prefix), Some(upstream)) => {
+                if !prefix.starts_with('/') || prefix.ends_with('/') {
+                    return Err(SettingsError::invalid("proxy_prefix", prefix));
+                }
+                if crate::http::redirect::url_host(upstream).is_none() {
+                    return Err(SettingsError::invalid("proxy_upstream", upstream));
+                }
+            }
+            (Some(_), None) => return Err(SettingsError::MissingRequired(String::from("proxy_upstream"))),
+            (None, Some(_)) => return Err(SettingsError::MissingRequired(String::from("proxy_prefix"))),
+            (None, None) => (),
+        }
+        self.optional_field::<u64>(MAX_BODY_BYTES)?;
+        #[cfg(feature = "uploads")]
+        {
+            if self.upload_sniff.parse::<SniffStrictness>().is_err() {
+                return Err(SettingsError::invalid("upload_sniff", &self.upload_sniff));
+            }
+            if let Some(ref command) = self.upload_scanner_command {
+                if command.trim().is_empty() {
+                    return Err(SettingsError::invalid("upload_scanner_command", command));
+                }
+            }
+        }
+        if let Some(ref url) = self.base_url {
+            if crate::http::redirect::url_host(url).is_none() {
+                return Err(SettingsError::invalid("base_url", url));
+            }
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let is_duplicate = self.listeners[..i].iter().any(|other| other.name == listener.name);
+            if listener.name.is_empty() || is_duplicate {
+                return Err(SettingsError::invalid("listeners", &listener.name));
+            }
+            if let Some(route) = listener.routes.iter().find(|route| !route.starts_with('/')) {
+                return Err(SettingsError::invalid("listeners", route));
+            }
+        }
+        if !self.debug_override_extras.is_empty() && active_environment().is_prod() {
+            return Err(SettingsError::InvalidField {
+                field: String::from("debug_override_extras"),
+                message: String::from("extras can't be overridden in production"),
+            });
+        }
+        if let Some(ref pattern) = self.immutable_assets_pattern {
+            if regex::Regex::new(pattern).is_err() {
+                return Err(SettingsError::invalid("immutable_assets_pattern", pattern));
+            }
+        }
+        if self.max_concurrent_requests == Some(0) {
+            return Err(SettingsError::invalid("max_concurrent_requests", "0"));
+        }
+        if self.workers == Some(0) {
+            return Err(SettingsError::invalid("workers", "0"));
+        }
+        if let Some(key) = self
+            .previous_secret_keys
+            .iter()
+            .find(|key| crate::http::keyring::decode_key(key).is_none())
+        {
+            return Err(SettingsError::invalid("previous_secret_keys", key));
+        }
+        if let Some(ref rotate) = self.access_log_rotate {
+            if rotate.parse::<Rotation>().is_err() {
+                return Err(SettingsError::invalid("access_log_rotate", rotate));
+            }
         }
 
-        let table = self
+        Ok(())
+    }
+
+    /// The names of the settings that can be read from `APP_` environment variables, in
+    /// lowercase and without the prefix
+    fn known_env_keys(&self) -> Vec<String> {
+        // Every field is serialized, so the known names can't get out of sync with the struct
+        let fields = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+
+        fields
+            .keys()
+            .filter(|key| key.as_str() != "extras")
+            .cloned()
+            .chain(DIRECT_ENV_KEYS.iter().map(|key| String::from(*key)))
+            .collect()
+    }
+
+    /// Sort `keys` (the names of `APP_` environment variables, without the prefix) into
+    /// settings and extras, and look for extras that are probably misspelt settings
+    fn env_report_for(&self, keys: &[String]) -> EnvReport {
+        let known = self.known_env_keys();
+        let variable = |key: &str| format!("{}_{}", ENV_PREFIX, key.to_uppercase());
+
+        let mut report = EnvReport::default();
+        for key in keys {
+            if known.contains(key) {
+                report.settings.push(variable(key));
+                continue;
+            }
+
+            report.extras.push(variable(key));
+            if !self.allowed_extras.contains(key) {
+                if let Some(setting) = suggest_setting(key, &known) {
+                    report.typos.push((variable(key), variable(setting)));
+                }
+            }
+        }
+
+        let prefix = format!("{}_", ENV_PREFIX);
+        report.ignored = std::env::vars()
+            .filter(|(name, value)| name.starts_with(&prefix) && value.is_empty())
+            .map(|(name, _)| name)
+            .collect();
+
+        report.settings.sort();
+        report.extras.sort();
+        report.ignored.sort();
+        report.typos.sort();
+        report
+    }
+
+    /// How the `APP_` environment variables were used, when the settings were loaded with
+    /// `Settings::new`
+    pub fn env_report(&self) -> Option<&EnvReport> {
+        self.env_report.as_ref()
+    }
+
+    /// Where each setting (and extra) with a value came from, by name, e.g. to find out which
+    /// config file set the port. Settings set in more than one place have the source that
+    /// took precedence (see `merge_config_sources`). Only known for settings loaded with
+    /// `Settings::new`, and empty otherwise.
+    pub fn sources(&self) -> HashMap<String, EnvSource> {
+        self.sources.clone()
+    }
+
+    /// The warnings recorded while starting up with these settings, shared by every clone of
+    /// them. See `app::diagnostics`
+    pub fn diagnostics(&self) -> &StartupDiagnostics {
+        &self.diagnostics
+    }
+
+    /// The settings (and extras) that have different values in `other`, sorted by name, e.g.
+    /// to log what changed when the settings are loaded again. Unset values are shown as
+    /// `(unset)`, and the values of secrets (settings and extras with names like
+    /// `secret_key`, `api_keys` or `smtp_password`) as `[redacted]`, although they are still
+    /// listed when they change.
+    pub fn diff(&self, other: &Settings) -> Vec<FieldChange> {
+        use serde_json::Value;
+
+        let fields = |settings: &Settings| match serde_json::to_value(settings) {
+            Ok(Value::Object(mut fields)) => {
+                fields.remove("extras");
+                fields
+            }
+            _ => serde_json::Map::new(),
+        };
+        let display = |value: Option<&Value>| match value {
+            None | Some(Value::Null) => String::from("(unset)"),
+            Some(Value::String(value)) => value.clone(),
+            Some(value) => value.to_string(),
+        };
+
+        let (old_fields, new_fields) = (fields(self), fields(other));
+        let old_extras: serde_json::Map<String, Value> = self
             .extras
             .iter()
-            .map(|(key, value)| match Value::try_from(value) {
-                Ok(v) => Ok((key.clone(), v)),
-                Err(e) => Err(e),
+            .map(|(key, value)| (format!("extras.{}", key), value.clone()))
+            .collect();
+        let new_extras: serde_json::Map<String, Value> = other
+            .extras
+            .iter()
+            .map(|(key, value)| (format!("extras.{}", key), value.clone()))
+            .collect();
+
+        let mut names: Vec<&String> = old_fields
+            .keys()
+            .chain(new_fields.keys())
+            .chain(old_extras.keys())
+            .chain(new_extras.keys())
+            .collect();
+        names.sort();
+        names.dedup();
+
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let old = old_fields.get(name).or_else(|| old_extras.get(name));
+                let new = new_fields.get(name).or_else(|| new_extras.get(name));
+                if old == new {
+                    return None;
+                }
+
+                let is_secret = SECRET_NAME_PARTS.iter().any(|part| name.contains(part));
+                let value = |value: Option<&Value>| match value {
+                    Some(value) if is_secret && !value.is_null() => String::from(REDACTED),
+                    value => display(value),
+                };
+                Some(FieldChange {
+                    field: name.clone(),
+                    old: value(old),
+                    new: value(new),
+                })
+            })
+            .collect()
+    }
+
+    /// Write these settings to `APP_` environment variables (e.g. `static_dir` to
+    /// `APP_STATIC_DIR`, and each extra to its own variable), so that subprocesses which call
+    /// `Settings::new` load the same values. Settings that are unset remove their variable, so
+    /// that a stale value isn't inherited.
+    ///
+    /// Lists are written comma separated, which `allowed_extras` and `debug_override_extras`
+    /// can be read back from. Other lists and tables can't be set from the environment, so they
+    /// are left for the subprocess to read from the same config files.
+    ///
+    /// Environment variables are shared by every thread, and are only copied into a
+    /// subprocess when it's started, so call this before spawning (`fork`/`exec`) and while no
+    /// other thread is reading the environment.
+    pub fn apply_to_env(&self) {
+        use serde_json::Value;
+        use std::env;
+
+        let fields = match serde_json::to_value(self) {
+            Ok(Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+
+        let variable = |key: &str| format!("{}_{}", ENV_PREFIX, key.to_uppercase());
+        for (key, value) in fields {
+            let value = match value {
+                Value::Null => {
+                    env::remove_var(variable(&key));
+                    continue;
+                }
+                Value::String(value) => value,
+                Value::Bool(value) => value.to_string(),
+                Value::Number(value) => value.to_string(),
+                Value::Array(ref items) if ENV_LISTS.contains(&key.as_str()) => items
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<&str>>()
+                    .join(","),
+                // `extras` are written individually below, and other tables are left out
+                Value::Array(_) | Value::Object(_) => continue,
+            };
+            env::set_var(variable(&key), value);
+        }
+
+        for (key, value) in &self.extras {
+            if let Some(value) = extra_text(value) {
+                env::set_var(variable(key), value);
+            }
+        }
+    }
+
+    /// Check that each of `keys` (the names of `APP_` environment variables, without the
+    /// prefix) is a setting, one of `allowed_extras`, or read directly (like `APP_ENV`).
+    /// Unknown variables that look like a typo of a setting say which one.
+    fn check_env_keys(&self, keys: &[String]) -> Result<(), SettingsError> {
+        let known = self.known_env_keys();
+
+        let mut unknown: Vec<String> = keys
+            .iter()
+            .filter(|key| !known.contains(key))
+            .filter(|key| !self.allowed_extras.contains(key))
+            .map(|key| {
+                let variable = format!("{}_{}", ENV_PREFIX, key.to_uppercase());
+                match suggest_setting(key, &known) {
+                    Some(setting) => format!(
+                        "{} (did you mean {}_{}?)",
+                        variable,
+                        ENV_PREFIX,
+                        setting.to_uppercase()
+                    ),
+                    None => variable,
+                }
             })
             .collect();
 
-        match table {
-            Ok(table) => conf.set_extras(table),
-            Err(e) => eprintln!("{}", e),
+        if unknown.is_empty() {
+            return Ok(());
         }
 
-        conf
+        unknown.sort();
+        Err(SettingsError::UnknownVariables(unknown))
+    }
+
+    /// Create a `SettingsBuilder` that starts from the default settings values. Settings
+    /// created this way never read config files or the environment.
+    pub fn builder() -> Result<SettingsBuilder, SettingsError> {
+        SettingsBuilder::new()
+    }
+
+    /// The settings for the rocket instance that serves `listener`, which are the same as
+    /// these apart from the address and port
+    pub fn for_listener(&self, listener: &ListenerSettings) -> Settings {
+        let mut settings = self.clone();
+        if let Some(ref address) = listener.address {
+            settings.address = Some(address.clone());
+        }
+        settings.port = Some(listener.port);
+        settings.listeners = Vec::new();
+        settings
+    }
+
+    /// The static directories, in the order that files are looked up in
+    pub fn static_dirs(&self) -> Vec<PathBuf> {
+        self.static_dir.iter().map(PathBuf::from).collect()
+    }
+
+    /// How the variants of static images are named, or `None` if they aren't served
+    pub fn image_variant_naming(&self) -> Option<VariantNaming> {
+        self.image_variant_naming.as_ref().and_then(|naming| VariantNaming::from_setting(naming))
+    }
+
+    /// The options for the static file handler, from `static_options`. Unknown options are
+    /// ignored with a warning.
+    pub fn static_options(&self) -> Options {
+        let names = match self.static_options {
+            Some(ref names) => names,
+            None => return Options::None,
+        };
+
+        names.iter().fold(Options::None, |options, name| {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "none" => options,
+                "index" => options | Options::Index,
+                "dot_files" | "dotfiles" => options | Options::DotFiles,
+                _ => {
+                    tracing::warn!(option = %name, "ignoring unknown static option");
+                    options
+                }
+            }
+        })
+    }
+
+    /// The prefix that routes are mounted under, which is empty when the app is served from
+    /// the root or the proxy strips the prefix (see `strip_prefix_header`)
+    pub fn route_prefix(&self) -> &str {
+        match self.mount_prefix {
+            Some(ref prefix) if !self.strip_prefix_header => prefix,
+            _ => "",
+        }
+    }
+
+    /// The unix domain socket that the app should listen on, from `unix_socket`. Rocket can
+    /// only listen on TCP, so this isn't used yet, see `app::with_unix_listener`.
+    pub fn bind_unix_socket(&self) -> Option<PathBuf> {
+        self.unix_socket.as_ref().map(PathBuf::from)
+    }
+
+    /// Whether templates should be reloaded when they change, from `template_reload` or the
+    /// default for the active environment. It is passed to rocket as the `template_reload`
+    /// extra.
+    pub fn template_reload(&self) -> bool {
+        use rocket::config::Environment;
+
+        self.template_reload
+            .unwrap_or_else(|| Environment::active().map(|env| env.is_dev()).unwrap_or(false))
+    }
+
+    /// The `SameSite` attribute for the session cookie, from `cookie_same_site`
+    pub fn cookie_same_site(&self) -> SameSite {
+        match self.cookie_same_site.as_str() {
+            "strict" => SameSite::Strict,
+            "none" => SameSite::None,
+            _ => SameSite::Lax,
+        }
+    }
+
+    /// Convert the `log` level, which uses rocket's names for log levels, into an equivalent
+    /// filter for `tracing` events. Without a log level, rocket's default of "normal" is used.
+    pub fn parse_log_filter(&self) -> EnvFilter {
+        let directive = match self.log.as_ref().map(String::as_str) {
+            Some("debug") => "debug",
+            Some("critical") => "error",
+            Some("off") => "off",
+            _ => "info",
+        };
+
+        EnvFilter::new(directive)
+    }
+
+    /// The base64 encoded secret key that rocket uses to sign cookies, if one is configured
+    pub fn secret_key(&self) -> Option<&str> {
+        self.secret_key.as_ref().map(String::as_str)
+    }
+
+    /// Get one of the extra values that are provided to rocket, if it's a string. Numbers and
+    /// booleans set in code are read with `extra_value`, or parsed with `optional_field`.
+    pub fn extra(&self, key: &str) -> Option<&str> {
+        self.extras.get(key).and_then(serde_json::Value::as_str)
+    }
+
+    /// Get one of the extra values that are provided to rocket, of any type
+    pub fn extra_value(&self, key: &str) -> Option<&serde_json::Value> {
+        self.extras.get(key)
+    }
+
+    /// The largest JSON body, in bytes, that rocket will read: the `max_body_bytes` extra (i.e.
+    /// `APP_MAX_BODY_BYTES`), or 8 MiB when it isn't set. It is passed to rocket as the `json`
+    /// data limit, which the `Json` and `StrictJson` guards enforce.
+    pub fn max_body_size(&self) -> u64 {
+        self.optional_field(MAX_BODY_BYTES)
+            .ok()
+            .and_then(|size| size)
+            .unwrap_or(DEFAULT_MAX_BODY_BYTES)
+    }
+
+    /// Get one of the extra values, parsed as a `T`. Strings (such as every extra from the
+    /// environment) are parsed with `FromStr`, and so are numbers and booleans set in code,
+    /// from their string form, so `APP_POOL_SIZE=10` and an extra of `10` give the same value.
+    /// Other values (lists and tables) are invalid.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // With APP_POOL_SIZE=10
+    /// let pool_size: u32 = settings.required_field("pool_size")?;
+    /// ```
+    pub fn required_field<T: FromStr>(&self, key: &str) -> Result<T, SettingsError> {
+        self.optional_field(key)?
+            .ok_or_else(|| SettingsError::MissingRequired(String::from(key)))
+    }
+
+    /// Like `required_field`, but gives `None` when the extra isn't set
+    pub fn optional_field<T: FromStr>(&self, key: &str) -> Result<Option<T>, SettingsError> {
+        use serde_json::Value;
+
+        let value = match self.extras.get(key) {
+            None | Some(Value::Null) => return Ok(None),
+            Some(Value::String(value)) => value.trim().to_string(),
+            Some(Value::Number(value)) => value.to_string(),
+            Some(Value::Bool(value)) => value.to_string(),
+            Some(value) => return Err(SettingsError::invalid(key, &value.to_string())),
+        };
+
+        match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(SettingsError::invalid(key, &value)),
+        }
+    }
+
+    /// Set one of the extra values that are provided to rocket, replacing any existing value
+    pub fn set_extra<K: Into<String>, V: Into<serde_json::Value>>(&mut self, key: K, value: V) {
+        self.extras.insert(key.into(), value.into());
+    }
+
+    /// Get all of the extra values with keys that start with `prefix`, with the prefix
+    /// removed from the returned keys. Numbers and booleans are given in their string form,
+    /// and lists and tables are left out.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // With extras of { "db_url": "postgres://localhost", "db_pool": "5", "port": "80" }
+    /// let db = settings.extra_prefix("db_");
+    /// assert_eq!(db.get("url").map(String::as_str), Some("postgres://localhost"));
+    /// assert_eq!(db.get("pool").map(String::as_str), Some("5"));
+    /// assert_eq!(db.len(), 2);
+    /// ```
+    pub fn extra_prefix(&self, prefix: &str) -> HashMap<String, String> {
+        self.extras
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .filter_map(|(key, value)| Some((key[prefix.len()..].to_string(), extra_text(value)?)))
+            .collect()
+    }
+
+    /// Whether the feature flag `name` is enabled, from the `feature_{name}` extra (i.e.
+    /// `APP_FEATURE_{NAME}`). Flags are enabled by `true`, `1`, `yes` or `on`, in any case;
+    /// any other value, or no value, disables them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // With APP_FEATURE_NEW_CHECKOUT=Yes
+    /// assert!(settings.feature("new_checkout"));
+    /// assert!(!settings.feature("dark_mode"));
+    /// ```
+    pub fn feature(&self, name: &str) -> bool {
+        self.extras
+            .get(&format!("{}{}", FEATURE_FLAG_PREFIX, name))
+            .and_then(extra_text)
+            .map(|value| is_flag_enabled(&value))
+            .unwrap_or(false)
+    }
+
+    /// Every feature flag in the extras, by name without the `feature_` prefix
+    pub fn features(&self) -> HashMap<String, bool> {
+        self.extra_prefix(FEATURE_FLAG_PREFIX)
+            .into_iter()
+            .map(|(name, value)| {
+                let enabled = is_flag_enabled(&value);
+                (name, enabled)
+            })
+            .collect()
+    }
+
+    /// The connection URL for `driver`, from the `{driver}_url` extra. `"database"` gives
+    /// the conventional `database_url` (i.e. `APP_DATABASE_URL`) for the primary database.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // With APP_DATABASE_URL=postgres://localhost/app and APP_REDIS_URL=redis://localhost
+    /// assert_eq!(settings.connection_string("redis"), Some("redis://localhost"));
+    /// assert_eq!(settings.connection_string("database"), Some("postgres://localhost/app"));
+    /// ```
+    pub fn connection_string(&self, driver: &str) -> Option<&str> {
+        self.extra(&format!("{}_url", driver))
+    }
+
+    /// Resolve the socket address that the app will bind to, applying the same defaults
+    /// that rocket uses for any of `address` or `port` that have not been set.
+    ///
+    /// Addresses are returned as they will be bound, so an unspecified address such as
+    /// `0.0.0.0` or `::` is preserved rather than being swapped for a loopback address.
+    /// Hostnames (e.g. rocket's default of `localhost` in development) are resolved to their
+    /// first address, falling back to the IPv4 loopback address if they can't be resolved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let settings = Settings::new()?;
+    /// println!("Listening on http://{}", settings.effective_address());
+    /// ```
+    pub fn effective_address(&self) -> SocketAddr {
+        use rocket::config::Environment;
+        use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+
+        let env = Environment::active().unwrap_or(Environment::Production);
+        let default_address = if env.is_dev() { "localhost" } else { "0.0.0.0" };
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+
+        // IPv6 addresses may have been provided in their bracketed URL form, e.g. "[::1]"
+        let address = self
+            .address
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or(default_address)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+
+        match address.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, port),
+            Err(_) => (address, port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)),
+        }
+    }
+}
+
+/// Deserialize the merged config sources into `Settings`. The `config` crate's own errors
+/// don't say which setting was wrong, so the path to the field that failed is tracked and added
+/// to the error.
+fn deserialize_settings(conf: &config::Config) -> Result<Settings, SettingsError> {
+    let table = config::Value::new(None, conf.collect()?);
+    serde_path_to_error::deserialize(table).map_err(|e| {
+        let field = e.path().to_string();
+        let error = e.into_inner();
+        match field.as_str() {
+            // Errors for the whole table, such as missing fields, already name the field
+            "" | "." => SettingsError::Parse(error),
+            _ => SettingsError::InvalidField {
+                field,
+                message: error.to_string(),
+            },
+        }
+    })
+}
+
+/// The environment that rocket is running in, assuming production if `ROCKET_ENV` is invalid
+pub(crate) fn active_environment() -> rocket::config::Environment {
+    use rocket::config::Environment;
+
+    Environment::active().unwrap_or(Environment::Production)
+}
+
+/// Apply the default values that are used for any settings that are not otherwise provided
+fn set_defaults(conf: &mut config::Config) -> Result<(), SettingsError> {
+    use rocket::config::Environment;
+
+    let env = Environment::active().unwrap_or(Environment::Production);
+
+    conf.set_default("static_dir", concat!(env!("CARGO_MANIFEST_DIR"), "/public"))?;
+    conf.set_default("static_route", String::from("/static"))?;
+    conf.set_default("integrity_extensions", vec!["js", "css"])?;
+    conf.set_default("config_dir", ".")?;
+    conf.set_default("per_page_default", i64::from(DEFAULT_PER_PAGE))?;
+    conf.set_default("per_page_max", i64::from(MAX_PER_PAGE))?;
+    conf.set_default("merge_patch_accept_json", false)?;
+    conf.set_default("cookie_secure", false)?;
+    conf.set_default("cookie_same_site", "lax")?;
+    conf.set_default("cookie_http_only_default", true)?;
+    conf.set_default("session_store", "memory")?;
+    conf.set_default("session_dir", "sessions")?;
+    conf.set_default("session_ttl", "1d")?;
+    conf.set_default("proxy_cache_max_ttl", "60s")?;
+    #[cfg(feature = "uploads")]
+    {
+        conf.set_default("upload_dir", "uploads")?;
+        conf.set_default("upload_max_size", "10MB")?;
+        conf.set_default("upload_sniff", "lenient")?;
+        conf.set_default("upload_scanner_timeout", "30s")?;
+        conf.set_default("upload_quarantine_dir", "quarantine")?;
+    }
+    conf.set_default("run_migrations", false)?;
+    conf.set_default("export_seeds", vec!["/"])?;
+    conf.set_default("export_max_depth", 3i64)?;
+    conf.set_default("attribution_enabled", false)?;
+    // Matching the retention that most payment APIs use for idempotency keys
+    conf.set_default("idempotency_ttl", "1d")?;
+    conf.set_default("idempotency_cache_size", 1000i64)?;
+    conf.set_default("auto_secret_key_dev", env.is_dev())?;
+    if env.is_prod() {
+        conf.set_default("max_startup_warnings", 0i64)?;
+    }
+    conf.set_default("request_deadline", "30s")?;
+    conf.set_default("long_poll_timeout", "25s")?;
+    conf.set_default("long_poll_max_parked", 32i64)?;
+    conf.set_default("strict_env", false)?;
+    conf.set_default("default_cache_control", "no-store")?;
+    conf.set_default("static_max_age", "1h")?;
+    conf.set_default("file_chunk_bytes", "64KiB")?;
+    conf.set_default("security_headers_disabled", false)?;
+    conf.set_default("strip_prefix_header", false)?;
+    #[cfg(feature = "metrics")]
+    conf.set_default("metrics_enabled", true)?;
+    #[cfg(feature = "mail")]
+    conf.set_default("mail_enabled", false)?;
+    Ok(())
+}
+
+/// Settings that enable optional subsystems, the cargo feature that each one needs, and
+/// whether that feature was enabled at build time. A setting enables its subsystem when it's
+/// set to anything other than `false`, so any of the `upload_*` settings need `uploads`.
+const FEATURE_SETTINGS: [(&'static str, &'static str, bool); 9] = [
+    ("metrics_enabled", "metrics", cfg!(feature = "metrics")),
+    ("run_migrations", "db", cfg!(feature = "db")),
+    ("mail_enabled", "mail", cfg!(feature = "mail")),
+    ("upload_dir", "uploads", cfg!(feature = "uploads")),
+    ("upload_max_size", "uploads", cfg!(feature = "uploads")),
+    ("upload_sniff", "uploads", cfg!(feature = "uploads")),
+    ("upload_scanner_command", "uploads", cfg!(feature = "uploads")),
+    ("upload_scanner_timeout", "uploads", cfg!(feature = "uploads")),
+    ("upload_quarantine_dir", "uploads", cfg!(feature = "uploads")),
+];
+
+/// Fail if `conf` enables a subsystem that wasn't compiled in, rather than silently ignoring it
+fn reject_disabled_features(conf: &config::Config) -> Result<(), SettingsError> {
+    for &(setting, feature, compiled) in FEATURE_SETTINGS.iter() {
+        // Settings that aren't flags, such as `upload_dir`, enable the subsystem by being set
+        let enabled = conf
+            .get_bool(setting)
+            .unwrap_or_else(|_| conf.get_str(setting).is_ok());
+        if !compiled && enabled {
+            return Err(SettingsError::FeatureNotCompiled { setting, feature });
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge the config files and `APP_` environment variables into `conf`. Sources are merged in
+/// order of increasing priority, so that values in later ones override those in earlier ones:
+///
+///   {workspace}/config.toml < config.json < config.toml < config-{env}.json < config-{env}.toml
+///
+/// Environment variables are merged last and take precedence over every file. JSON files are
+/// only read when the `json-config` feature is enabled.
+fn merge_config_sources(
+    conf: &mut SourcedConfig,
+    config_dir: &str,
+    workspace_dir: Option<&Path>,
+) -> Result<(), SettingsError> {
+    use config::Environment;
+    use std::env::var;
+
+    if let Some(dir) = workspace_dir {
+        merge_config_files(conf, &dir.join("config"))?;
+    }
+
+    merge_config_files(conf, &Path::new(config_dir).join("config"))?;
+
+    match var("APP_ENV").unwrap_or(String::from("")).as_str() {
+        env @ "development" | env @ "production" | env @ "staging" => {
+            merge_config_files(conf, &Path::new(config_dir).join(format!("config-{}", env)))?;
+        }
+        _ => (),
+    };
+
+    conf.merge_from(Environment::with_prefix(ENV_PREFIX).ignore_empty(true), |key| {
+        EnvSource::EnvVar(format!("{}_{}", ENV_PREFIX, key.to_uppercase()))
+    })?;
+
+    Ok(())
+}
+
+/// The root of the Cargo workspace that the app is in: `$CARGO_WORKSPACE_DIR` when it is set,
+/// otherwise the closest directory above the crate's manifest directory with a `Cargo.toml`
+/// that declares a `[workspace]`. The manifest directory is read from `$CARGO_MANIFEST_DIR`
+/// (set by `cargo run`), falling back to where the crate was built.
+pub fn workspace_root() -> Option<PathBuf> {
+    use std::env::var;
+    use std::fs;
+
+    if let Ok(dir) = var("CARGO_WORKSPACE_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+
+    let manifest_dir = var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| String::from(env!("CARGO_MANIFEST_DIR")));
+    Path::new(&manifest_dir)
+        .ancestors()
+        .skip(1)
+        .find(|dir| {
+            fs::read_to_string(dir.join("Cargo.toml"))
+                .map(|manifest| manifest.lines().any(|line| line.trim() == "[workspace]"))
+                .unwrap_or(false)
+        })
+        .map(Path::to_path_buf)
+}
+
+/// Merge the optional config files with the given base path (without an extension) into `conf`.
+/// When both formats are present, values from the TOML file take precedence over JSON.
+fn merge_config_files(conf: &mut SourcedConfig, path: &Path) -> Result<(), SettingsError> {
+    use config::{File, FileFormat};
+
+    let name = path.to_string_lossy();
+
+    #[cfg(feature = "json-config")]
+    conf.merge_from(File::new(&name, FileFormat::Json).required(false), |_| {
+        EnvSource::File(path.with_extension("json"))
+    })?;
+    conf.merge_from(File::new(&name, FileFormat::Toml).required(false), |_| {
+        EnvSource::File(path.with_extension("toml"))
+    })?;
+
+    Ok(())
+}
+
+/// A `config::Config` that remembers where each value came from, for `Settings::sources`.
+/// Values set or merged through the `Config` itself (it can be used as one) aren't tracked.
+struct SourcedConfig {
+    conf: config::Config,
+    /// The sources of values that were merged in, where later sources take precedence
+    merged: HashMap<String, EnvSource>,
+    /// The sources of values that were set directly, which take precedence over any merged
+    /// value, as with `Config::set`
+    overrides: HashMap<String, EnvSource>,
+}
+
+impl SourcedConfig {
+    fn new() -> SourcedConfig {
+        SourcedConfig {
+            conf: config::Config::new(),
+            merged: HashMap::new(),
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// `Config::set`, recording `origin` as the source of `key`
+    fn set_from<T>(&mut self, key: &str, value: T, origin: EnvSource) -> Result<(), SettingsError>
+    where
+        T: Into<config::Value>,
+    {
+        self.conf.set(key, value)?;
+        self.overrides.insert(key.to_lowercase(), origin);
+        Ok(())
+    }
+
+    /// `Config::merge`, recording `origin(key)` as the source of each key in `source`
+    fn merge_from<S, F>(&mut self, source: S, origin: F) -> Result<(), SettingsError>
+    where
+        S: config::Source + Send + Sync + 'static,
+        F: Fn(&str) -> EnvSource,
+    {
+        for key in source.collect()?.keys() {
+            self.merged.insert(key.clone(), origin(key));
+        }
+        self.conf.merge(source)?;
+        Ok(())
+    }
+
+    /// The sources of the fields and extras of `settings` that have a value. Fields that
+    /// weren't set anywhere have their default.
+    fn sources_for(&self, settings: &Settings) -> HashMap<String, EnvSource> {
+        use serde_json::Value;
+
+        let mut tracked = self.merged.clone();
+        tracked.extend(self.overrides.clone());
+
+        let fields = match serde_json::to_value(settings) {
+            Ok(Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+        let set_fields = fields
+            .into_iter()
+            .filter(|(name, value)| name != "extras" && !value.is_null())
+            .map(|(name, _)| name);
+
+        set_fields
+            .chain(settings.extras.keys().cloned())
+            .map(|name| {
+                let source = tracked.get(&name).cloned().unwrap_or(EnvSource::Default);
+                (name, source)
+            })
+            .collect()
+    }
+}
+
+impl std::ops::Deref for SourcedConfig {
+    type Target = config::Config;
+
+    fn deref(&self) -> &config::Config {
+        &self.conf
+    }
+}
+
+impl std::ops::DerefMut for SourcedConfig {
+    fn deref_mut(&mut self) -> &mut config::Config {
+        &mut self.conf
+    }
+}
+
+/// Builds `Settings` from explicitly provided values on top of the defaults. Unlike
+/// `Settings::new`, config files and environment variables are ignored, which makes this
+/// suitable for creating isolated settings (e.g. in tests, or when embedding the app).
+///
+/// # Examples
+///
+/// ```
+/// let settings = Settings::builder()?
+///     .set("static_dir", "/tmp/public")?
+///     .extra("template_dir", "/tmp/templates")
+///     .build()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct SettingsBuilder {
+    conf: config::Config,
+    extras: HashMap<String, serde_json::Value>,
+}
+
+impl SettingsBuilder {
+    fn new() -> Result<SettingsBuilder, SettingsError> {
+        let mut conf = config::Config::new();
+        set_defaults(&mut conf)?;
+
+        Ok(SettingsBuilder {
+            conf,
+            extras: HashMap::new(),
+        })
+    }
+
+    /// Set the value of a `Settings` field by name
+    pub fn set<T: Into<config::Value>>(mut self, key: &str, value: T) -> Result<SettingsBuilder, SettingsError> {
+        self.conf.set(key, value)?;
+        Ok(self)
+    }
+
+    /// Add a value to the `extras` map that is provided to rocket, e.g. a string, number or
+    /// boolean
+    pub fn extra<K: Into<String>, V: Into<serde_json::Value>>(mut self, key: K, value: V) -> SettingsBuilder {
+        self.extras.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn build(mut self) -> Result<Settings, SettingsError> {
+        // The extras can hold values that config can't, so they're added once deserialized
+        self.conf.set("extras", HashMap::<String, String>::new())?;
+        reject_disabled_features(&self.conf)?;
+
+        let mut settings = deserialize_settings(&self.conf)?;
+        settings.extras = self.extras;
+        settings.enforce_secret_policy(active_environment())?;
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+impl Into<Config> for Settings {
+    fn into(self) -> Config {
+        use rocket::config::{Environment, Limits, LoggingLevel};
+        let env = Environment::active().unwrap_or(Environment::Production);
+        let mut conf = Config::new(env);
+        let template_reload = self.template_reload();
+        conf.set_limits(Limits::default().limit("json", self.max_body_size()));
+
+        if let Some(address) = self.address {
+            conf.set_address(address);
+        }
+        if let Some(port) = self.port {
+            conf.set_port(port);
+        }
+        if let Some(log) = self.log {
+            conf.set_log_level(LoggingLevel::from_str(&log).unwrap_or(LoggingLevel::Normal));
+        }
+        if let Some(workers) = self.workers {
+            conf.set_workers(workers);
+        }
+        if let Some(secret_key) = self.secret_key {
+            conf.set_secret_key(secret_key);
+        }
+
+        let table = self
+            .extras
+            .iter()
+            // TOML has no null, and a missing extra reads the same
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| match Value::try_from(value) {
+                Ok(v) => Ok((key.clone(), v)),
+                Err(e) => Err(e),
+            })
+            .collect();
+
+        match table {
+            Ok(mut table) => {
+                table.insert(String::from("template_reload"), Value::Boolean(template_reload));
+                conf.set_extras(table)
+            }
+            Err(e) => {
+                let message = format!("extras couldn't be passed to rocket: {}", e);
+                self.diagnostics.warn("settings", message.clone());
+                tracing::error!("{}", message);
+            }
+        }
+
+        conf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::{ConfigFixture, EnvLock};
+    use serde_json::Value;
+
+    fn settings() -> Settings {
+        Settings::builder().unwrap().build().unwrap()
+    }
+
+    #[test]
+    fn typed_extras_from_numbers() {
+        let mut settings = settings();
+        settings.extras.insert(String::from("pool_size"), Value::Number(10.into()));
+        settings.extras.insert(String::from("ratio"), Value::Number(serde_json::Number::from_f64(0.5).unwrap()));
+
+        assert_eq!(settings.required_field::<u32>("pool_size").unwrap(), 10);
+        assert_eq!(settings.required_field::<u64>("pool_size").unwrap(), 10);
+        assert_eq!(settings.required_field::<String>("pool_size").unwrap(), "10");
+        assert!((settings.required_field::<f64>("ratio").unwrap() - 0.5).abs() < std::f64::EPSILON);
+        // Numbers aren't strings, so `extra` doesn't give them
+        assert_eq!(settings.extra("pool_size"), None);
+        assert_eq!(settings.extra_value("pool_size"), Some(&Value::Number(10.into())));
+    }
+
+    #[test]
+    fn typed_extras_from_bools_and_strings() {
+        let settings = Settings::builder()
+            .unwrap()
+            .extra("cache_enabled", true)
+            .extra("pool_size", " 12 ")
+            .extra("feature_beta", true)
+            .build()
+            .unwrap();
+
+        assert!(settings.required_field::<bool>("cache_enabled").unwrap());
+        assert_eq!(settings.required_field::<u32>("pool_size").unwrap(), 12);
+        assert!(settings.feature("beta"));
+        assert_eq!(settings.extra_prefix("feature_").get("beta").map(String::as_str), Some("true"));
+    }
+
+    #[test]
+    fn typed_extras_errors() {
+        let mut settings = settings();
+        settings.extras.insert(String::from("pool_size"), Value::Number((-1).into()));
+        settings.extras.insert(String::from("hosts"), serde_json::json!(["a", "b"]));
+
+        match settings.required_field::<u32>("pool_size") {
+            Err(SettingsError::InvalidValue { ref field, ref value }) if field == "pool_size" && value == "-1" => (),
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+        match settings.optional_field::<String>("hosts") {
+            Err(SettingsError::InvalidValue { ref field, .. }) if field == "hosts" => (),
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+        match settings.required_field::<u32>("missing") {
+            Err(SettingsError::MissingRequired(ref name)) if name == "missing" => (),
+            other => panic!("expected a missing value, got {:?}", other),
+        }
+        assert_eq!(settings.optional_field::<u32>("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn sources_name_the_port_variable() {
+        let loaded = ConfigFixture::new()
+            .config("per_page_default = 10")
+            .var("PORT", "8080")
+            .load()
+            .unwrap()
+            .unwrap();
+        let sources = loaded.sources();
+
+        assert_eq!(loaded.effective_address().port(), 8080);
+        assert_eq!(sources["port"], EnvSource::EnvVar(String::from("PORT")));
+        match sources.get("per_page_default") {
+            Some(EnvSource::File(path)) if path.file_stem().and_then(|stem| stem.to_str()) == Some("config") => (),
+            other => panic!("expected per_page_default to come from config.toml, got {:?}", other),
+        }
+        assert_eq!(sources.get("per_page_max"), Some(&EnvSource::Default));
+
+        // Settings that weren't loaded with `Settings::new` have no sources
+        assert!(settings().sources().is_empty());
+    }
+
+    /// Whether `key` is a variable that `Settings::new` reads
+    fn is_settings_var(key: &str) -> bool {
+        key.starts_with(&format!("{}_", ENV_PREFIX)) || key == "PORT"
+    }
+
+    #[test]
+    fn apply_to_env_round_trips() {
+        use std::env;
+
+        let original = Settings::builder()
+            .unwrap()
+            .set("per_page_default", 15)
+            .unwrap()
+            .set("static_dir", vec!["public", "vendor"])
+            .unwrap()
+            .set("mount_prefix", "/app")
+            .unwrap()
+            .set("cookie_same_site", "strict")
+            .unwrap()
+            .set("port", 8123)
+            .unwrap()
+            .extra("pool_size", "5")
+            .build()
+            .unwrap();
+
+        let _lock = EnvLock::acquire();
+        let previous: Vec<(String, String)> = env::vars().filter(|(key, _)| is_settings_var(key)).collect();
+        for (key, _) in &previous {
+            env::remove_var(key);
+        }
+        // A stale value, which should be removed as `csp` isn't set
+        env::set_var("APP_CSP", "default-src 'none'");
+
+        original.apply_to_env();
+        let loaded = Settings::new();
+
+        for (key, _) in env::vars().filter(|(key, _)| is_settings_var(key)) {
+            env::remove_var(key);
+        }
+        for (key, value) in previous {
+            env::set_var(key, value);
+        }
+
+        let loaded = loaded.unwrap();
+        // Every variable that was written is read back as an extra too, so only the fields
+        // are compared, and the extras that were set
+        let changed: Vec<FieldChange> = original
+            .diff(&loaded)
+            .into_iter()
+            .filter(|change| !change.field.starts_with("extras."))
+            .collect();
+        assert!(changed.is_empty(), "{:?}", changed);
+        assert_eq!(loaded.csp, None);
+        assert_eq!(loaded.effective_address().port(), 8123);
+        assert_eq!(loaded.static_dir, vec![String::from("public"), String::from("vendor")]);
+        assert_eq!(loaded.extra("pool_size"), Some("5"));
+    }
+
+    #[test]
+    fn max_body_size_from_the_environment() {
+        let loaded = ConfigFixture::new()
+            .var("APP_MAX_BODY_BYTES", "1048576")
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.max_body_size(), 1_048_576);
+
+        let config: Config = loaded.into();
+        assert_eq!(config.limits.get("json"), Some(1_048_576));
+
+        let default: Config = settings().into();
+        assert_eq!(settings().max_body_size(), DEFAULT_MAX_BODY_BYTES);
+        assert_eq!(default.limits.get("json"), Some(DEFAULT_MAX_BODY_BYTES));
+    }
+
+    #[test]
+    fn log_filter_levels() {
+        let filters = [
+            (Some("debug"), "debug"),
+            (Some("normal"), "info"),
+            (Some("critical"), "error"),
+            (Some("off"), "off"),
+            (None, "info"),
+        ];
+
+        for (log, expected) in filters.iter() {
+            let mut builder = Settings::builder().unwrap();
+            if let Some(log) = log {
+                builder = builder.set("log", *log).unwrap();
+            }
+            let settings = builder.build().unwrap();
+            assert_eq!(settings.parse_log_filter().to_string(), *expected, "log = {:?}", log);
+        }
+    }
+
+    #[test]
+    fn connection_strings_by_driver() {
+        let settings = Settings::builder()
+            .unwrap()
+            .extra("redis_url", "redis://localhost:6379")
+            .extra("database_url", "postgres://localhost/app")
+            .build()
+            .unwrap();
+
+        assert_eq!(settings.connection_string("redis"), Some("redis://localhost:6379"));
+        assert_eq!(settings.connection_string("database"), Some("postgres://localhost/app"));
+        assert_eq!(settings.connection_string("mongo"), None);
+        assert_eq!(self::settings().connection_string("database"), None);
+    }
+
+    #[test]
+    fn extra_prefix_strips_matching_keys() {
+        let settings = Settings::builder()
+            .unwrap()
+            .extra("db_url", "postgres://localhost/app")
+            .extra("db_pool", 5)
+            .extra("db_replicas", serde_json::json!(["a", "b"]))
+            .extra("redis_url", "redis://localhost")
+            .extra("dbx", "not a db_ key")
+            .build()
+            .unwrap();
+
+        let db = settings.extra_prefix("db_");
+        let mut expected = HashMap::new();
+        expected.insert(String::from("url"), String::from("postgres://localhost/app"));
+        expected.insert(String::from("pool"), String::from("5"));
+        assert_eq!(db, expected);
+
+        assert!(settings.extra_prefix("mongo_").is_empty());
+        assert_eq!(settings.extra_prefix("").len(), 4);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    #[test]
+    fn metrics_need_the_metrics_feature() {
+        let expected = "metrics_enabled is set but this binary was built without the `metrics` feature";
+
+        let built = Settings::builder().unwrap().set("metrics_enabled", true).unwrap().build();
+        match built {
+            Err(ref e @ SettingsError::FeatureNotCompiled { setting: "metrics_enabled", feature: "metrics" }) => {
+                assert_eq!(e.to_string(), expected)
+            }
+            other => panic!("expected the metrics setting to be rejected, got {:?}", other),
+        }
+
+        let loaded = ConfigFixture::new().var("APP_METRICS_ENABLED", "true").load().unwrap();
+        assert_eq!(loaded.map_err(|e| e.to_string()).err(), Some(String::from(expected)));
+
+        // Leaving the subsystem disabled is fine
+        let disabled = Settings::builder().unwrap().set("metrics_enabled", false).unwrap().build();
+        assert!(disabled.is_ok());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_are_allowed_with_the_metrics_feature() {
+        let built = Settings::builder().unwrap().set("metrics_enabled", true).unwrap().build();
+        assert!(built.unwrap().metrics_enabled);
+
+        let loaded = ConfigFixture::new().var("APP_METRICS_ENABLED", "true").load().unwrap();
+        assert!(loaded.unwrap().metrics_enabled);
+    }
+
+    /// The setting and feature of the `FeatureNotCompiled` error that `built` failed with
+    fn missing_feature(built: Result<Settings, SettingsError>) -> Option<(&'static str, &'static str)> {
+        match built {
+            Err(SettingsError::FeatureNotCompiled { setting, feature }) => Some((setting, feature)),
+            _ => None,
+        }
+    }
+
+    #[cfg(not(feature = "uploads"))]
+    #[test]
+    fn uploads_need_the_uploads_feature() {
+        let built = Settings::builder().unwrap().set("upload_dir", "incoming").unwrap().build();
+        assert_eq!(missing_feature(built), Some(("upload_dir", "uploads")));
+
+        let loaded = ConfigFixture::new()
+            .var("APP_UPLOAD_SCANNER_COMMAND", "clamscan {path}")
+            .load()
+            .unwrap();
+        assert_eq!(
+            loaded.map_err(|e| e.to_string()).err(),
+            Some(String::from(
+                "upload_scanner_command is set but this binary was built without the `uploads` feature"
+            ))
+        );
+
+        // Without any upload settings, nothing needs the feature
+        assert!(Settings::builder().unwrap().build().is_ok());
+    }
+
+    #[cfg(feature = "uploads")]
+    #[test]
+    fn uploads_are_allowed_with_the_uploads_feature() {
+        let built = Settings::builder().unwrap().set("upload_dir", "incoming").unwrap().build();
+        assert_eq!(built.unwrap().upload_dir, "incoming");
+
+        let loaded = ConfigFixture::new()
+            .var("APP_UPLOAD_SCANNER_COMMAND", "clamscan {path}")
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.upload_scanner_command, Some(String::from("clamscan {path}")));
+        assert_eq!(loaded.upload_dir, "uploads");
+
+        let strict = Settings::builder().unwrap().set("upload_sniff", "strict").unwrap().build();
+        assert_eq!(missing_feature(strict), None);
+    }
+
+    #[cfg(not(feature = "db"))]
+    #[test]
+    fn migrations_need_the_db_feature() {
+        let built = Settings::builder().unwrap().set("run_migrations", true).unwrap().build();
+        assert_eq!(missing_feature(built), Some(("run_migrations", "db")));
+
+        let loaded = ConfigFixture::new().var("APP_RUN_MIGRATIONS", "true").load().unwrap();
+        assert_eq!(missing_feature(loaded), Some(("run_migrations", "db")));
+
+        // Migrations aren't run by default, which is fine
+        assert!(!Settings::builder().unwrap().build().unwrap().run_migrations);
+    }
+
+    #[cfg(feature = "db")]
+    #[test]
+    fn migrations_are_allowed_with_the_db_feature() {
+        let built = Settings::builder().unwrap().set("run_migrations", true).unwrap().build();
+        assert!(built.unwrap().run_migrations);
+
+        let loaded = ConfigFixture::new().var("APP_RUN_MIGRATIONS", "true").load().unwrap();
+        assert_eq!(missing_feature(loaded), None);
+    }
+
+    #[cfg(not(feature = "mail"))]
+    #[test]
+    fn mail_needs_the_mail_feature() {
+        let built = Settings::builder().unwrap().set("mail_enabled", true).unwrap().build();
+        assert_eq!(missing_feature(built), Some(("mail_enabled", "mail")));
+
+        let disabled = Settings::builder().unwrap().set("mail_enabled", false).unwrap().build();
+        assert!(disabled.is_ok());
+    }
+
+    #[cfg(feature = "mail")]
+    #[test]
+    fn mail_is_allowed_with_the_mail_feature() {
+        assert!(!Settings::builder().unwrap().build().unwrap().mail_enabled);
+
+        let loaded = ConfigFixture::new().var("APP_MAIL_ENABLED", "true").load().unwrap();
+        assert!(loaded.unwrap().mail_enabled);
+
+        let built = Settings::builder().unwrap().set("mail_enabled", true).unwrap().build();
+        assert_eq!(missing_feature(built), None);
+    }
+
+    #[test]
+    fn edit_distance_counts_swaps_as_one_edit() {
+        assert_eq!(edit_distance("port", "port"), 0);
+        assert_eq!(edit_distance("prot", "port"), 1);
+        assert_eq!(edit_distance("ab", "ba"), 1);
+        assert_eq!(edit_distance("", "log"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn typo_suggestion_thresholds() {
+        let known: Vec<String> = ["port", "log", "workers", "per_page_max"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let suggest = |key: &str| suggest_setting(key, &known);
+
+        // Names of up to 4 characters get one edit
+        assert_eq!(suggest("prot"), Some("port"));
+        assert_eq!(suggest("prt"), Some("port"));
+        assert_eq!(suggest("lg"), Some("log"));
+        assert_eq!(suggest("pr"), None);
+        assert_eq!(suggest("wrks"), None);
+        // Longer names get two
+        assert_eq!(suggest("wrkers"), Some("workers"));
+        assert_eq!(suggest("wrkrs"), Some("workers"));
+        assert_eq!(suggest("wkrs_x"), None);
+        assert_eq!(suggest("per_page_mx"), Some("per_page_max"));
+        // Exact matches and unrelated names aren't typos
+        assert_eq!(suggest("port"), None);
+        assert_eq!(suggest("greeting"), None);
+    }
+
+    #[test]
+    fn env_variables_are_classified() {
+        let loaded = ConfigFixture::new()
+            .var("APP_PROT", "8080")
+            .var("APP_PER_PAGE_MAX", "200")
+            .var("APP_GREETING", "hello")
+            .var("APP_UNUSED_EXTRA", "")
+            .load()
+            .unwrap()
+            .unwrap();
+        let report = loaded.env_report().unwrap();
+
+        assert!(report.settings.contains(&String::from("APP_PER_PAGE_MAX")));
+        assert!(report.extras.contains(&String::from("APP_PROT")));
+        assert!(report.extras.contains(&String::from("APP_GREETING")));
+        assert!(!report.extras.contains(&String::from("APP_UNUSED_EXTRA")));
+        assert!(report.ignored.contains(&String::from("APP_UNUSED_EXTRA")));
+        assert_eq!(report.typos, vec![(String::from("APP_PROT"), String::from("APP_PORT"))]);
+        assert!(report.summary().starts_with("environment: settings "));
+
+        // The typo is still an extra, and the port is left alone
+        assert_eq!(loaded.extra("prot"), Some("8080"));
+        assert_ne!(loaded.effective_address().port(), 8080);
+        let warnings: Vec<String> = loaded.diagnostics().all().into_iter().map(|d| d.message).collect();
+        assert!(warnings.contains(&String::from("APP_PROT looks like a typo of APP_PORT")));
+    }
+
+    #[test]
+    fn env_summary_lists_each_kind() {
+        let report = EnvReport {
+            settings: vec![String::from("APP_PORT")],
+            extras: vec![String::from("APP_DB_URL"), String::from("APP_PROT")],
+            ignored: vec![],
+            typos: vec![(String::from("APP_PROT"), String::from("APP_PORT"))],
+        };
+        assert_eq!(
+            report.summary(),
+            "environment: settings APP_PORT; extras APP_DB_URL, APP_PROT; ignored -"
+        );
+    }
+
+    #[test]
+    fn strict_env_rejects_typos_unless_allowed() {
+        let strict = ConfigFixture::new()
+            .var("APP_STRICT_ENV", "true")
+            .var("APP_PROT", "8080")
+            .load()
+            .unwrap();
+        match strict {
+            Err(SettingsError::UnknownVariables(names)) => {
+                assert_eq!(names, vec![String::from("APP_PROT (did you mean APP_PORT?)")])
+            }
+            other => panic!("expected APP_PROT to be rejected, got {:?}", other.map(|_| ())),
+        }
+
+        let allowed = ConfigFixture::new()
+            .var("APP_STRICT_ENV", "true")
+            .var("APP_ALLOWED_EXTRAS", "prot")
+            .var("APP_PROT", "8080")
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(allowed.extra("prot"), Some("8080"));
+        assert!(allowed.env_report().unwrap().typos.is_empty());
+    }
+
+    #[test]
+    fn debug_override_extras_are_rejected_in_production() {
+        let production = ConfigFixture::new()
+            .var("ROCKET_ENV", "production")
+            .var("APP_SECRET_KEY", base64::encode(&[7u8; 32]))
+            .var("APP_DEBUG_OVERRIDE_EXTRAS", "beta_checkout")
+            .load()
+            .unwrap();
+        match production {
+            Err(SettingsError::InvalidField { field, .. }) => assert_eq!(field, "debug_override_extras"),
+            other => panic!("expected debug_override_extras to be rejected, got {:?}", other.map(|_| ())),
+        }
+
+        let development = ConfigFixture::new()
+            .var("ROCKET_ENV", "development")
+            .var("APP_DEBUG_OVERRIDE_EXTRAS", "beta_checkout,theme")
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            development.debug_override_extras,
+            vec![String::from("beta_checkout"), String::from("theme")]
+        );
+    }
+
+    fn secret_policy(env: &str, vars: &[(&str, String)]) -> Result<Settings, SettingsError> {
+        let fixture = vars
+            .iter()
+            .fold(ConfigFixture::new().var("ROCKET_ENV", env), |fixture, (key, value)| {
+                fixture.var(*key, value.clone())
+            });
+        fixture.load().unwrap()
+    }
+
+    fn secret_warnings(settings: &Settings) -> Vec<String> {
+        settings
+            .diagnostics()
+            .all()
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .filter(|message| message.contains("secret_key"))
+            .collect()
+    }
+
+    #[test]
+    fn secret_policy_in_development() {
+        let generated = secret_policy("development", &[]).unwrap();
+        assert!(generated.secret_key().is_some());
+        assert_eq!(secret_warnings(&generated).len(), 1);
+
+        let disabled = secret_policy("development", &[("APP_AUTO_SECRET_KEY_DEV", String::from("false"))]).unwrap();
+        assert_eq!(disabled.secret_key(), None);
+        assert!(secret_warnings(&disabled).is_empty());
+
+        let weak = secret_policy("development", &[("APP_SECRET_KEY", String::from("hunter2"))]).unwrap();
+        assert_eq!(weak.secret_key(), Some("hunter2"));
+        assert!(secret_warnings(&weak).is_empty());
+    }
+
+    #[test]
+    fn secret_policy_in_staging() {
+        match secret_policy("staging", &[]) {
+            Err(SettingsError::MissingRequired(field)) => assert_eq!(field, "secret_key"),
+            other => panic!("expected a missing secret_key, got {:?}", other.map(|_| ())),
+        }
+
+        let weak = secret_policy("staging", &[("APP_SECRET_KEY", String::from("hunter2"))]).unwrap();
+        assert_eq!(weak.secret_key(), Some("hunter2"));
+        assert_eq!(
+            secret_warnings(&weak),
+            vec![String::from("secret_key isn't 32 bytes encoded as base64, which production requires")]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret_key");
+        let key = base64::encode(&[7u8; 32]);
+        std::fs::write(&path, format!("{}\n", key)).unwrap();
+        let from_file = secret_policy("staging", &[("APP_SECRET_KEY_FILE", path.to_string_lossy().into_owned())]);
+        let from_file = from_file.unwrap();
+        assert_eq!(from_file.secret_key(), Some(key.as_str()));
+        assert!(secret_warnings(&from_file).is_empty());
+    }
+
+    #[test]
+    fn secret_policy_in_production() {
+        match secret_policy("production", &[]) {
+            Err(SettingsError::MissingRequired(field)) => assert_eq!(field, "secret_key"),
+            other => panic!("expected a missing secret_key, got {:?}", other.map(|_| ())),
+        }
+
+        match secret_policy("production", &[("APP_SECRET_KEY", String::from("hunter2"))]) {
+            Err(SettingsError::InvalidField { field, message }) => {
+                assert_eq!(field, "secret_key");
+                assert!(!message.contains("hunter2"));
+            }
+            other => panic!("expected a weak secret_key to be rejected, got {:?}", other.map(|_| ())),
+        }
+
+        let key = base64::encode(&[7u8; 32]);
+        let auto = [
+            ("APP_SECRET_KEY", key.clone()),
+            ("APP_AUTO_SECRET_KEY_DEV", String::from("true")),
+        ];
+        match secret_policy("production", &auto) {
+            Err(SettingsError::InvalidValue { field, .. }) => assert_eq!(field, "auto_secret_key_dev"),
+            other => panic!("expected auto_secret_key_dev to be rejected, got {:?}", other.map(|_| ())),
+        }
+
+        let valid = secret_policy("production", &[("APP_SECRET_KEY", key.clone())]).unwrap();
+        assert_eq!(valid.secret_key(), Some(key.as_str()));
+        assert!(secret_warnings(&valid).is_empty());
+    }
+
+    fn static_options(names: &[&str]) -> Options {
+        let mut settings = settings();
+        settings.static_options = Some(names.iter().map(|name| name.to_string()).collect());
+        settings.static_options()
+    }
+
+    /// Whether `options` has the `(index, dot_files)` flags
+    fn static_flags(options: Options) -> (bool, bool) {
+        (options.contains(Options::Index), options.contains(Options::DotFiles))
+    }
+
+    #[test]
+    fn static_options_map_to_flags() {
+        assert_eq!(static_flags(settings().static_options()), (false, false));
+        assert_eq!(static_flags(static_options(&[])), (false, false));
+        assert_eq!(static_flags(static_options(&["none"])), (false, false));
+        assert_eq!(static_flags(static_options(&["index"])), (true, false));
+        assert_eq!(static_flags(static_options(&["dot_files"])), (false, true));
+        assert_eq!(static_flags(static_options(&["dotfiles"])), (false, true));
+        assert_eq!(static_flags(static_options(&[" Index ", "DOT_FILES"])), (true, true));
+        assert_eq!(static_flags(static_options(&["none", "index"])), (true, false));
+    }
+
+    #[test]
+    fn unknown_static_options_are_ignored() {
+        assert_eq!(static_flags(static_options(&["listing"])), (false, false));
+        assert_eq!(static_flags(static_options(&["index", "hidden", ""])), (true, false));
     }
 }
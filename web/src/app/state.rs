@@ -0,0 +1,135 @@
+//! Typed access to the app's managed state.
+//!
+//! Handlers can take an `AppState<T>` instead of a `State<T>`, and fairings (which can't use
+//! request guards in `on_attach`) can use `AppState::get` with the rocket instance. State
+//! should be added with the `manage!` macro, which logs each managed type at debug level; these
+//! events are only visible once a `tracing` subscriber has been installed, so state managed
+//! before `TracingFairing` is attached isn't logged.
+use rocket::request::{self, FromRequest, Request, State};
+use rocket::{Outcome, Rocket};
+use std::ops::Deref;
+
+/// A value from the app's managed state, which is dereferenced to the value itself. Requests
+/// for state that isn't managed fail with `500 Internal Server Error`, as with `State`.
+pub struct AppState<'r, T: Send + Sync + 'static>(State<'r, T>);
+
+impl<'r, T: Send + Sync + 'static> AppState<'r, T> {
+    /// Get the managed value of type `T` from `rocket`, if there is one
+    pub fn get(rocket: &Rocket) -> Option<&T> {
+        rocket.state::<T>()
+    }
+
+    /// Get the managed value with the lifetime of the request, rather than of this guard
+    pub fn inner(&self) -> &'r T {
+        self.0.inner()
+    }
+}
+
+impl<'r, T: Send + Sync + 'static> Deref for AppState<'r, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.inner()
+    }
+}
+
+impl<'a, 'r, T: Send + Sync + 'static> FromRequest<'a, 'r> for AppState<'r, T> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.guard::<State<T>>() {
+            Outcome::Success(state) => Outcome::Success(AppState(state)),
+            Outcome::Failure((status, _)) => Outcome::Failure((status, ())),
+            Outcome::Forward(()) => Outcome::Forward(()),
+        }
+    }
+}
+
+/// The name of `T`, for use by `manage!`
+pub fn type_name_of<T>(_: &T) -> &'static str {
+    std::any::type_name::<T>()
+}
+
+/// Add `value` to the managed state of `rocket`, logging its type at debug level
+///
+/// # Examples
+///
+/// ```
+/// let rocket = manage!(rocket, KeyRing::new(&settings));
+/// ```
+#[macro_export]
+macro_rules! manage {
+    ($rocket:expr, $value:expr) => {{
+        let value = $value;
+        tracing::debug!(state = $crate::app::state::type_name_of(&value), "managing state");
+        $rocket.manage(value)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use crate::app::Settings;
+    use rocket::handler::Outcome as HandlerOutcome;
+    use rocket::http::{Method, Status};
+    use rocket::{Data, Route};
+
+    struct Greeting(&'static str);
+
+    /// A type that's never managed
+    type Unmanaged = Option<Greeting>;
+
+    fn greet<'r>(request: &'r Request, _: Data) -> HandlerOutcome<'r> {
+        let greeting = match request.guard::<AppState<Greeting>>() {
+            Outcome::Success(greeting) => greeting,
+            _ => return HandlerOutcome::failure(Status::InternalServerError),
+        };
+        let settings = match request.guard::<AppState<Settings>>() {
+            Outcome::Success(settings) => settings,
+            _ => return HandlerOutcome::failure(Status::InternalServerError),
+        };
+        HandlerOutcome::from(request, format!("{} {}", greeting.0, settings.per_page_default))
+    }
+
+    fn unmanaged<'r>(request: &'r Request, _: Data) -> HandlerOutcome<'r> {
+        match request.guard::<AppState<Unmanaged>>() {
+            Outcome::Success(_) => HandlerOutcome::from(request, "managed"),
+            Outcome::Failure((status, _)) => HandlerOutcome::failure(status),
+            Outcome::Forward(()) => HandlerOutcome::failure(Status::NotFound),
+        }
+    }
+
+    fn app() -> TestApp {
+        TestApp::builder()
+            .setting("per_page_default", "15")
+            .mount(
+                "/",
+                vec![Route::new(Method::Get, "/greet", greet), Route::new(Method::Get, "/unmanaged", unmanaged)],
+            )
+            .configure(|rocket| crate::manage!(rocket, Greeting("hello")))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn handlers_read_managed_state() {
+        let app = app();
+
+        let mut response = app.client().get("/greet").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some(String::from("hello 15")));
+
+        assert_eq!(app.client().get("/unmanaged").dispatch().status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn state_is_available_from_the_rocket_instance() {
+        let app = app();
+        let rocket = app.client().rocket();
+
+        assert_eq!(AppState::<Greeting>::get(rocket).map(|greeting| greeting.0), Some("hello"));
+        assert_eq!(AppState::<Settings>::get(rocket).map(|settings| settings.per_page_default), Some(15));
+        assert!(AppState::<Unmanaged>::get(rocket).is_none());
+    }
+}
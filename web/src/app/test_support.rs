@@ -0,0 +1,464 @@
+//! Helpers for building isolated instances of the app, enabled with the `test-support` feature.
+//!
+//! Each `TestApp` gets its own temporary directory for static files and templates, and builds
+//! its `Settings` without reading the process environment, so tests using it can safely run
+//! in parallel.
+//!
+//! To test how `Settings::new` reads config files and the environment, `ConfigFixture` writes
+//! config files to a temporary directory and loads settings from them.
+//!
+//! # Examples
+//!
+//! ```
+//! let app = TestApp::builder()
+//!     .static_file("foo.css", "body { color: red; }")
+//!     .build()?;
+//!
+//! let response = app.client().get("/static/foo.css").dispatch();
+//! assert_eq!(response.status(), Status::Ok);
+//! ```
+use super::{RouteGroup, Settings, SettingsBuilder, SettingsError};
+use crate::Error;
+use rocket::fairing::Fairing;
+use rocket::local::Client;
+use rocket::{Rocket, Route};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use tempfile::TempDir;
+
+/// A running instance of the app that can be sent local requests. The temporary fixture
+/// directory is removed when this is dropped.
+pub struct TestApp {
+    // Declared before `dir` so that the client is dropped before its files are removed
+    client: Client,
+    settings: Settings,
+    dir: TempDir,
+}
+
+impl TestApp {
+    pub fn builder() -> TestAppBuilder {
+        TestAppBuilder::default()
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Get a handle to a value in the app's managed state
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.client.rocket().state::<T>()
+    }
+
+    /// The root of the temporary directory holding this app's fixtures
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+/// Configures the fixtures, settings and fairings for a `TestApp`
+#[derive(Default)]
+pub struct TestAppBuilder {
+    static_files: Vec<(PathBuf, Vec<u8>)>,
+    templates: Vec<(PathBuf, Vec<u8>)>,
+    settings: Vec<(String, String)>,
+    extras: Vec<(String, String)>,
+    routes: Vec<RouteGroup>,
+    customisers: Vec<Box<dyn FnOnce(Rocket) -> Rocket>>,
+}
+
+impl TestAppBuilder {
+    /// Add a file to the static directory, relative to its root
+    pub fn static_file<P: Into<PathBuf>, C: Into<Vec<u8>>>(mut self, path: P, contents: C) -> Self {
+        self.static_files.push((path.into(), contents.into()));
+        self
+    }
+
+    /// Add a file to the template directory, relative to its root
+    pub fn template<P: Into<PathBuf>, C: Into<Vec<u8>>>(mut self, path: P, contents: C) -> Self {
+        self.templates.push((path.into(), contents.into()));
+        self
+    }
+
+    /// Set a `Settings` field by name, as it would be named in a config file
+    pub fn setting<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.settings.push((key.into(), value.into()));
+        self
+    }
+
+    /// Add a value to the extras that are provided to rocket
+    pub fn extra<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.extras.push((key.into(), value.into()));
+        self
+    }
+
+    /// Mount `routes` under `base`, alongside the app's default routes
+    pub fn mount<B: Into<String>>(mut self, base: B, routes: Vec<Route>) -> Self {
+        self.routes.push((base.into(), routes));
+        self
+    }
+
+    /// Attach a fairing to the app's rocket instance
+    pub fn attach<F: Fairing>(self, fairing: F) -> Self {
+        self.configure(move |rocket| rocket.attach(fairing))
+    }
+
+    /// Make arbitrary changes to the app's rocket instance before the client is created,
+    /// e.g. mounting extra routes or managing additional state
+    pub fn configure<F: FnOnce(Rocket) -> Rocket + 'static>(mut self, customiser: F) -> Self {
+        self.customisers.push(Box::new(customiser));
+        self
+    }
+
+    pub fn build(self) -> Result<TestApp, Error> {
+        let dir = tempfile::tempdir()?;
+        let static_dir = dir.path().join("public");
+        let template_dir = dir.path().join("templates");
+
+        write_fixtures(&static_dir, &self.static_files)?;
+        write_fixtures(&template_dir, &self.templates)?;
+
+        let mut builder: SettingsBuilder = Settings::builder()?
+            .set("static_dir", static_dir.to_string_lossy().into_owned())?
+            .extra("template_dir", template_dir.to_string_lossy().into_owned());
+
+        for (key, value) in self.settings {
+            builder = builder.set(&key, value)?;
+        }
+        for (key, value) in self.extras {
+            builder = builder.extra(key, value);
+        }
+
+        let settings = builder.build()?;
+
+        let mut routes = super::default_routes(&settings);
+        routes.extend(self.routes);
+
+        let rocket = self
+            .customisers
+            .into_iter()
+            .fold(super::build(settings.clone(), routes), |rocket, customise| customise(rocket));
+
+        // `LaunchError` panics if it's dropped without being inspected, which formatting it does
+        let client = Client::new(rocket).map_err(|e| e.to_string())?;
+        Ok(TestApp {
+            client,
+            settings,
+            dir,
+        })
+    }
+}
+
+fn write_fixtures(root: &Path, fixtures: &[(PathBuf, Vec<u8>)]) -> Result<(), Error> {
+    fs::create_dir_all(root)?;
+
+    for (path, contents) in fixtures {
+        let path = root.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+    }
+
+    Ok(())
+}
+
+/// Held while a `ConfigFixture` has changed the process environment, so that fixtures in
+/// tests running on other threads don't see each other's variables
+static ENV_LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Held while the process environment is being changed, released when dropped. Tests that
+/// set variables themselves (rather than through a `ConfigFixture`) take it too.
+pub(crate) struct EnvLock;
+
+impl EnvLock {
+    pub(crate) fn acquire() -> EnvLock {
+        while ENV_LOCKED
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            thread::yield_now();
+        }
+        EnvLock
+    }
+}
+
+impl Drop for EnvLock {
+    fn drop(&mut self) {
+        ENV_LOCKED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Loads `Settings` with `Settings::new` from config files written to a temporary directory,
+/// with `APP_CONFIG_DIR` pointing at it. Environment variables set for the fixture are only
+/// set while the settings are loaded, and any previous values are restored afterwards.
+///
+/// With `in_working_dir`, the process changes into the temporary directory instead, to load
+/// the files from the default config directory as the binary does when it is run.
+///
+/// Fixtures take a process-wide lock while they change the environment, so they can be used
+/// from tests running in parallel, but other tests that read the environment may still see
+/// the fixture's variables.
+///
+/// # Examples
+///
+/// ```
+/// let settings = ConfigFixture::new()
+///     .config("per_page_default = 10")
+///     .env_config("production", "per_page_default = 50")
+///     .var("APP_PER_PAGE_MAX", "200")
+///     .load()?;
+///
+/// assert_eq!(settings.per_page_default, 50);
+/// assert_eq!(settings.per_page_max, 200);
+/// ```
+#[derive(Debug, Default)]
+pub struct ConfigFixture {
+    files: Vec<(String, String)>,
+    env: Option<String>,
+    vars: Vec<(String, String)>,
+    in_working_dir: bool,
+}
+
+impl ConfigFixture {
+    pub fn new() -> ConfigFixture {
+        ConfigFixture::default()
+    }
+
+    /// Write `toml` to `config.toml`
+    pub fn config<S: Into<String>>(self, toml: S) -> Self {
+        self.file("config.toml", toml)
+    }
+
+    /// Write `toml` to `config-{env}.toml`, and set `APP_ENV` to `env`
+    pub fn env_config<E: Into<String>, S: Into<String>>(mut self, env: E, toml: S) -> Self {
+        let env = env.into();
+        let name = format!("config-{}.toml", env);
+        self.env = Some(env);
+        self.file(name, toml)
+    }
+
+    /// Write a file with any name (e.g. `config.json`) to the config directory
+    pub fn file<N: Into<String>, S: Into<String>>(mut self, name: N, contents: S) -> Self {
+        self.files.push((name.into(), contents.into()));
+        self
+    }
+
+    /// Set an environment variable while the settings are loaded
+    pub fn var<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.vars.push((key.into(), value.into()));
+        self
+    }
+
+    /// Load the files from the working directory, which is changed to the temporary directory
+    /// (with `APP_CONFIG_DIR` unset) while the settings are loaded
+    pub fn in_working_dir(mut self) -> Self {
+        self.in_working_dir = true;
+        self
+    }
+
+    /// Write the config files and load `Settings` from them. The outer result fails if the
+    /// fixture couldn't be set up, and the inner result is the outcome of `Settings::new`.
+    pub fn load(self) -> Result<Result<Settings, SettingsError>, Error> {
+        let dir = tempfile::tempdir()?;
+        for (name, contents) in &self.files {
+            fs::write(dir.path().join(name), contents)?;
+        }
+
+        // `None` unsets the variable while the settings are loaded
+        let config_dir = match self.in_working_dir {
+            true => None,
+            false => Some(dir.path().to_string_lossy().into_owned()),
+        };
+        let mut vars = vec![(String::from("APP_CONFIG_DIR"), config_dir)];
+        if let Some(env) = self.env {
+            vars.push((String::from("APP_ENV"), Some(env)));
+        }
+        vars.extend(self.vars.into_iter().map(|(key, value)| (key, Some(value))));
+
+        let _lock = EnvLock::acquire();
+        let previous: Vec<(String, Option<String>)> = vars
+            .iter()
+            .map(|(key, _)| (key.clone(), env::var(key).ok()))
+            .collect();
+
+        for (key, value) in &vars {
+            set_or_remove_var(key, value.as_ref());
+        }
+
+        let settings = match self.in_working_dir {
+            true => load_in_dir(dir.path()),
+            false => Ok(Settings::new()),
+        };
+
+        for (key, value) in previous.into_iter().rev() {
+            set_or_remove_var(&key, value.as_ref());
+        }
+
+        settings
+    }
+}
+
+/// Load the settings with `dir` as the working directory, changing back afterwards
+fn load_in_dir(dir: &Path) -> Result<Result<Settings, SettingsError>, Error> {
+    let previous_dir = env::current_dir()?;
+    env::set_current_dir(dir)?;
+    let settings = Settings::new();
+    env::set_current_dir(previous_dir)?;
+    Ok(settings)
+}
+
+fn set_or_remove_var(key: &str, value: Option<&String>) {
+    match value {
+        Some(value) => env::set_var(key, value),
+        None => env::remove_var(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::EnvSource;
+    use rocket::handler::Outcome;
+    use rocket::http::{ContentType, Method, Status};
+    use rocket::{Data, Request};
+
+    fn health<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, "ok")
+    }
+
+    #[test]
+    fn serves_static_fixtures() {
+        let app = TestApp::builder()
+            .static_file("css/site.css", "body { color: red; }")
+            .build()
+            .unwrap();
+
+        let mut response = app.client().get("/static/css/site.css").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::CSS));
+        assert_eq!(response.body_string(), Some(String::from("body { color: red; }")));
+    }
+
+    #[test]
+    fn missing_static_files_are_not_found() {
+        let app = TestApp::builder().build().unwrap();
+
+        let response = app.client().get("/static/missing.css").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn mounts_extra_routes() {
+        let app = TestApp::builder()
+            .mount("/", vec![Route::new(Method::Get, "/health", health)])
+            .build()
+            .unwrap();
+
+        let mut response = app.client().get("/health").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some(String::from("ok")));
+    }
+
+    #[test]
+    fn exposes_settings_and_state() {
+        let app = TestApp::builder().setting("per_page_default", "7").build().unwrap();
+
+        assert_eq!(app.settings().per_page_default, 7);
+        assert_eq!(app.state::<Settings>().map(|settings| settings.per_page_default), Some(7));
+    }
+
+    #[test]
+    fn parallel_apps_are_isolated_and_cleaned_up() {
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                thread::spawn(move || {
+                    let app = TestApp::builder().static_file("id.txt", i.to_string()).build().unwrap();
+                    let body = app.client().get("/static/id.txt").dispatch().body_string();
+                    assert_eq!(body, Some(i.to_string()));
+                    app.path().to_path_buf()
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let dir = handle.join().unwrap();
+            assert!(!dir.exists(), "{} was not removed", dir.display());
+        }
+    }
+
+    /// The name of the file that `key` was read from, if it came from a file
+    fn source_file(settings: &Settings, key: &str) -> Option<String> {
+        match settings.sources().get(key) {
+            Some(EnvSource::File(path)) => path.file_name().map(|name| name.to_string_lossy().into_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn env_config_is_merged_over_config() {
+        let settings = ConfigFixture::new()
+            .config("per_page_default = 10\nper_page_max = 200")
+            .env_config("staging", "per_page_default = 25")
+            .load()
+            .unwrap()
+            .unwrap();
+
+        // Values in the environment's file win, and the rest still come from `config.toml`
+        assert_eq!(settings.per_page_default, 25);
+        assert_eq!(settings.per_page_max, 200);
+        assert_eq!(source_file(&settings, "per_page_default"), Some(String::from("config-staging.toml")));
+        assert_eq!(source_file(&settings, "per_page_max"), Some(String::from("config.toml")));
+    }
+
+    #[test]
+    fn only_the_active_env_config_is_merged() {
+        let settings = ConfigFixture::new()
+            .config("per_page_default = 10")
+            .file("config-production.toml", "per_page_default = 99")
+            .env_config("development", "per_page_max = 150")
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.per_page_default, 10);
+        assert_eq!(settings.per_page_max, 150);
+
+        // Without `APP_ENV`, no environment's file is read
+        let without_env = ConfigFixture::new()
+            .config("per_page_default = 10")
+            .file("config-development.toml", "per_page_default = 99")
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(without_env.per_page_default, 10);
+    }
+
+    #[test]
+    fn env_vars_override_every_config_file() {
+        let settings = ConfigFixture::new()
+            .config("per_page_default = 10\nper_page_max = 200")
+            .env_config("production", "per_page_default = 50\nper_page_max = 300")
+            .var("APP_PER_PAGE_MAX", "400")
+            .load()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(settings.per_page_default, 50);
+        assert_eq!(settings.per_page_max, 400);
+        assert_eq!(
+            settings.sources().get("per_page_max"),
+            Some(&EnvSource::EnvVar(String::from("APP_PER_PAGE_MAX")))
+        );
+
+        // The previous values (here, none) are restored once the settings are loaded
+        let _lock = EnvLock::acquire();
+        assert!(env::var("APP_ENV").is_err());
+        assert!(env::var("APP_PER_PAGE_MAX").is_err());
+    }
+}
@@ -0,0 +1,403 @@
+//! Settings values with units, which can be written in a human friendly form.
+//!
+//! Durations can be written as a bare number of seconds, or as a string of one or more
+//! numbers with units, such as `"90s"`, `"5m"`, `"1h30m"` or `"1.5d"`. The supported units are
+//! `ms`, `s`, `m`, `h` and `d`.
+//!
+//! Byte sizes can be written as a bare number of bytes, or as a string with a unit, such as
+//! `"512KiB"` or `"2MB"`. Decimal units (`KB`, `MB`, `GB`, `TB`) are powers of 1000, and binary
+//! units (`KiB`, `MiB`, `GiB`, `TiB`) are powers of 1024. Units are case insensitive.
+//!
+//! When serialized (e.g. to print the effective config), both are written in their human
+//! friendly form.
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+const NANOS_PER_MILLI: u128 = 1_000_000;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Duration units, largest first, as `(suffix, nanoseconds)` pairs
+const DURATION_UNITS: [(&'static str, u128); 5] = [
+    ("d", 24 * 60 * 60 * NANOS_PER_SEC),
+    ("h", 60 * 60 * NANOS_PER_SEC),
+    ("m", 60 * NANOS_PER_SEC),
+    ("s", NANOS_PER_SEC),
+    ("ms", NANOS_PER_MILLI),
+];
+
+/// Byte size units, largest first within each family, as `(suffix, bytes)` pairs
+const BINARY_UNITS: [(&'static str, u128); 4] = [
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+];
+const DECIMAL_UNITS: [(&'static str, u128); 4] = [
+    ("TB", 1_000_000_000_000),
+    ("GB", 1_000_000_000),
+    ("MB", 1_000_000),
+    ("KB", 1_000),
+];
+
+/// A duration read from settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct DurationSetting(pub Duration);
+
+/// A number of bytes read from settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ByteSizeSetting(pub u64);
+
+impl DurationSetting {
+    pub fn from_secs(secs: u64) -> DurationSetting {
+        DurationSetting(Duration::from_secs(secs))
+    }
+
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl ByteSizeSetting {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Split a string into a leading decimal number and the rest of the string
+fn split_number(s: &str) -> (&str, &str) {
+    let end = s
+        .char_indices()
+        .find(|(_, c)| !(c.is_ascii_digit() || *c == '.'))
+        .map(|(i, _)| i)
+        .unwrap_or_else(|| s.len());
+    s.split_at(end)
+}
+
+/// Multiply a decimal number (which may have a fractional part) by a whole number of units,
+/// truncating any fraction that remains. Returns `None` for malformed numbers or on overflow.
+fn scale_decimal(number: &str, unit: u128) -> Option<u128> {
+    let mut parts = number.splitn(2, '.');
+    let whole = parts.next().unwrap_or("");
+    let fraction = parts.next().unwrap_or("");
+
+    if (whole.is_empty() && fraction.is_empty()) || fraction.contains('.') {
+        return None;
+    }
+
+    let whole: u128 = if whole.is_empty() { 0 } else { whole.parse().ok()? };
+    let mut total = whole.checked_mul(unit)?;
+
+    if !fraction.is_empty() {
+        // Digits beyond the precision of the unit can't change the result
+        let digits = &fraction[..fraction.len().min(30)];
+        let numerator: u128 = digits.parse().ok()?;
+        let denominator = 10u128.checked_pow(digits.len() as u32)?;
+        total = total.checked_add(numerator.checked_mul(unit)? / denominator)?;
+    }
+
+    Some(total)
+}
+
+impl FromStr for DurationSetting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DurationSetting, String> {
+        let invalid = || format!("expected a duration such as \"90s\" or \"1h30m\", got {:?}", s);
+        let trimmed = s.trim();
+        if trimmed.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut rest = trimmed;
+        let mut nanos: u128 = 0;
+        while !rest.is_empty() {
+            let (number, after) = split_number(rest);
+            let unit_len = after
+                .find(|c: char| c.is_ascii_digit() || c == '.')
+                .unwrap_or_else(|| after.len());
+            let (unit, after) = after.split_at(unit_len);
+
+            let unit = match unit.trim() {
+                // A bare number is a number of seconds, but only on its own
+                "" if rest == trimmed => NANOS_PER_SEC,
+                unit => DURATION_UNITS
+                    .iter()
+                    .find(|(suffix, _)| suffix.eq_ignore_ascii_case(unit))
+                    .map(|(_, nanos)| *nanos)
+                    .ok_or_else(invalid)?,
+            };
+
+            let value = scale_decimal(number.trim(), unit).ok_or_else(invalid)?;
+            nanos = nanos
+                .checked_add(value)
+                .ok_or_else(|| format!("duration {:?} is too large", s))?;
+            rest = after.trim_start();
+        }
+
+        let secs = nanos / NANOS_PER_SEC;
+        if secs > u128::from(u64::max_value()) {
+            return Err(format!("duration {:?} is too large", s));
+        }
+
+        Ok(DurationSetting(Duration::new(secs as u64, (nanos % NANOS_PER_SEC) as u32)))
+    }
+}
+
+impl fmt::Display for DurationSetting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut nanos = self.0.as_secs() as u128 * NANOS_PER_SEC + u128::from(self.0.subsec_nanos());
+        if nanos == 0 {
+            return write!(f, "0s");
+        }
+
+        for (suffix, unit) in DURATION_UNITS.iter() {
+            if nanos >= *unit {
+                write!(f, "{}{}", nanos / unit, suffix)?;
+                nanos %= unit;
+            }
+        }
+
+        // Anything finer than a millisecond is written as a fraction of one
+        if nanos > 0 {
+            write!(f, "0.{:06}ms", nanos)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ByteSizeSetting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ByteSizeSetting, String> {
+        let invalid = || format!("expected a size such as \"512KiB\" or \"2MB\", got {:?}", s);
+
+        let (number, unit) = split_number(s.trim());
+        let unit = match unit.trim() {
+            "" => 1,
+            unit if unit.eq_ignore_ascii_case("b") => 1,
+            unit => BINARY_UNITS
+                .iter()
+                .chain(DECIMAL_UNITS.iter())
+                .find(|(suffix, _)| suffix.eq_ignore_ascii_case(unit))
+                .map(|(_, bytes)| *bytes)
+                .ok_or_else(invalid)?,
+        };
+
+        let bytes = scale_decimal(number, unit).ok_or_else(invalid)?;
+        if bytes > u128::from(u64::max_value()) {
+            return Err(format!("size {:?} is too large", s));
+        }
+
+        Ok(ByteSizeSetting(bytes as u64))
+    }
+}
+
+impl fmt::Display for ByteSizeSetting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = u128::from(self.0);
+        let unit = BINARY_UNITS
+            .iter()
+            .chain(DECIMAL_UNITS.iter())
+            .filter(|(_, size)| bytes >= *size && bytes % size == 0)
+            .max_by_key(|(_, size)| *size);
+
+        match unit {
+            Some((suffix, size)) => write!(f, "{}{}", bytes / size, suffix),
+            None => write!(f, "{}B", bytes),
+        }
+    }
+}
+
+/// Deserializes a unit value from either a bare number (in the base unit) or a string
+struct UnitVisitor<T> {
+    expecting: &'static str,
+    from_number: fn(u64) -> T,
+}
+
+impl<'de, T: FromStr<Err = String>> Visitor<'de> for UnitVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        Ok((self.from_number)(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+        if value < 0 {
+            return Err(E::custom(format!("{} can't be negative, got {}", self.expecting, value)));
+        }
+        Ok((self.from_number)(value as u64))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<T, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+        value.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for DurationSetting {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DurationSetting, D::Error> {
+        deserializer.deserialize_any(UnitVisitor {
+            expecting: "a duration",
+            from_number: DurationSetting::from_secs,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSizeSetting {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ByteSizeSetting, D::Error> {
+        deserializer.deserialize_any(UnitVisitor {
+            expecting: "a size",
+            from_number: ByteSizeSetting,
+        })
+    }
+}
+
+impl Serialize for DurationSetting {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Serialize for ByteSizeSetting {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duration(s: &str) -> Duration {
+        s.parse::<DurationSetting>().unwrap().as_duration()
+    }
+
+    fn size(s: &str) -> u64 {
+        s.parse::<ByteSizeSetting>().unwrap().as_u64()
+    }
+
+    #[test]
+    fn parses_single_duration_units() {
+        assert_eq!(duration("90"), Duration::from_secs(90));
+        assert_eq!(duration("90s"), Duration::from_secs(90));
+        assert_eq!(duration("250ms"), Duration::from_millis(250));
+        assert_eq!(duration("5m"), Duration::from_secs(300));
+        assert_eq!(duration("2h"), Duration::from_secs(7200));
+        assert_eq!(duration("1d"), Duration::from_secs(86_400));
+        assert_eq!(duration(" 5M "), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn parses_fractional_durations() {
+        assert_eq!(duration("1.5d"), Duration::from_secs(129_600));
+        assert_eq!(duration("0.5s"), Duration::from_millis(500));
+        assert_eq!(duration(".5m"), Duration::from_secs(30));
+        assert_eq!(duration("1.5"), Duration::from_millis(1500));
+        assert_eq!(duration("0.0000000001s"), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn parses_mixed_duration_units() {
+        assert_eq!(duration("1h30m"), Duration::from_secs(5400));
+        assert_eq!(duration("1h 30m"), Duration::from_secs(5400));
+        assert_eq!(duration("1d2h3m4s5ms"), Duration::new(93_784, 5_000_000));
+    }
+
+    #[test]
+    fn duration_errors() {
+        let error = |s: &str| s.parse::<DurationSetting>().unwrap_err();
+
+        assert_eq!(error("abc"), "expected a duration such as \"90s\" or \"1h30m\", got \"abc\"");
+        assert_eq!(error(""), "expected a duration such as \"90s\" or \"1h30m\", got \"\"");
+        assert_eq!(error("5y"), "expected a duration such as \"90s\" or \"1h30m\", got \"5y\"");
+        assert_eq!(error("1.2.3s"), "expected a duration such as \"90s\" or \"1h30m\", got \"1.2.3s\"");
+        // A bare number is only allowed on its own
+        assert_eq!(error("1m30"), "expected a duration such as \"90s\" or \"1h30m\", got \"1m30\"");
+    }
+
+    #[test]
+    fn duration_overflow() {
+        assert_eq!(
+            "18446744073709551616s".parse::<DurationSetting>().unwrap_err(),
+            "duration \"18446744073709551616s\" is too large"
+        );
+        assert_eq!(duration("18446744073709551615s"), Duration::from_secs(u64::max_value()));
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(DurationSetting::from_secs(0).to_string(), "0s");
+        assert_eq!(DurationSetting::from_secs(5400).to_string(), "1h30m");
+        assert_eq!(DurationSetting(Duration::from_millis(1500)).to_string(), "1s500ms");
+        assert_eq!(DurationSetting::from_secs(129_600).to_string(), "1d12h");
+
+        let setting = DurationSetting(Duration::new(93_784, 5_000_000));
+        assert_eq!(setting.to_string().parse::<DurationSetting>(), Ok(setting));
+    }
+
+    #[test]
+    fn parses_binary_and_decimal_sizes() {
+        assert_eq!(size("512KiB"), 512 * 1024);
+        assert_eq!(size("2MB"), 2_000_000);
+        assert_eq!(size("2MiB"), 2 * 1024 * 1024);
+        assert_eq!(size("1GB"), 1_000_000_000);
+        assert_eq!(size("1TiB"), 1 << 40);
+        assert_eq!(size("2mb"), 2_000_000);
+        assert_eq!(size("100"), 100);
+        assert_eq!(size("100B"), 100);
+        assert_eq!(size("1.5KiB"), 1536);
+        assert_eq!(size("1.5"), 1);
+    }
+
+    #[test]
+    fn size_errors() {
+        let error = |s: &str| s.parse::<ByteSizeSetting>().unwrap_err();
+
+        assert_eq!(error("12 parsecs"), "expected a size such as \"512KiB\" or \"2MB\", got \"12 parsecs\"");
+        assert_eq!(error("KiB"), "expected a size such as \"512KiB\" or \"2MB\", got \"KiB\"");
+        assert_eq!(error("1KiB2"), "expected a size such as \"512KiB\" or \"2MB\", got \"1KiB2\"");
+        assert_eq!(error("17179869184GiB"), "size \"17179869184GiB\" is too large");
+        assert_eq!(size("18446744073709551615"), u64::max_value());
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(ByteSizeSetting(512 * 1024).to_string(), "512KiB");
+        assert_eq!(ByteSizeSetting(2_000_000).to_string(), "2MB");
+        assert_eq!(ByteSizeSetting(1536).to_string(), "1536B");
+        assert_eq!(ByteSizeSetting(0).to_string(), "0B");
+        // Both families divide 1024000 evenly, and the larger unit wins
+        assert_eq!(ByteSizeSetting(1_024_000).to_string(), "1000KiB");
+    }
+
+    #[test]
+    fn deserializes_numbers_and_strings() {
+        assert_eq!(
+            serde_json::from_str::<DurationSetting>("90").unwrap(),
+            DurationSetting::from_secs(90)
+        );
+        assert_eq!(
+            serde_json::from_str::<DurationSetting>("\"1h30m\"").unwrap(),
+            DurationSetting::from_secs(5400)
+        );
+        assert_eq!(
+            serde_json::from_str::<DurationSetting>("1.5").unwrap(),
+            DurationSetting(Duration::from_millis(1500))
+        );
+        assert_eq!(serde_json::from_str::<ByteSizeSetting>("\"2MB\"").unwrap(), ByteSizeSetting(2_000_000));
+
+        let negative = serde_json::from_str::<DurationSetting>("-1").unwrap_err();
+        assert!(negative.to_string().starts_with("a duration can't be negative, got -1"));
+    }
+}
@@ -0,0 +1,314 @@
+//! A dedicated file for per-request access log lines, see `Settings::access_log_file`.
+//!
+//! The file can be rotated in-process with `Settings::access_log_rotate`, either once a day
+//! (`"daily"`, in UTC) or when it would grow past a size (e.g. `"size:100MB"`). Rotated files
+//! are renamed with the unix timestamp of the rotation as a suffix, e.g. `access.log.1554076800`.
+//!
+//! For external tools such as `logrotate`, sending the process `SIGHUP` (on unix) makes it
+//! reopen the file at the configured path on the next request. If the file can't be opened,
+//! lines are logged as `tracing` events (with the `access_log` target) instead.
+use crate::app::ByteSizeSetting;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When the access log file should be rotated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// When the UTC date changes
+    Daily,
+    /// Before a line would take the file past this many bytes
+    Size(u64),
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rotation, String> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("daily") {
+            return Ok(Rotation::Daily);
+        }
+
+        match s.find(':') {
+            Some(i) if s[..i].eq_ignore_ascii_case("size") => {
+                let size: ByteSizeSetting = s[i + 1..].parse()?;
+                match size.as_u64() {
+                    0 => Err(String::from("rotation size must be more than 0 bytes")),
+                    size => Ok(Rotation::Size(size)),
+                }
+            }
+            _ => Err(format!("expected \"daily\" or a size such as \"size:100MB\", got {:?}", s)),
+        }
+    }
+}
+
+struct OpenLog {
+    writer: LineWriter<File>,
+    written: u64,
+    /// The UTC day (counted from the unix epoch) that the file was opened on
+    opened_day: u64,
+}
+
+/// Writes access log lines to a file, rotating it as configured
+pub struct AccessLog {
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    file: Mutex<Option<OpenLog>>,
+    reopen: Arc<AtomicBool>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+fn open_log(path: &Path) -> io::Result<OpenLog> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let written = file.metadata()?.len();
+
+    Ok(OpenLog {
+        writer: LineWriter::new(file),
+        written,
+        opened_day: now_secs() / SECS_PER_DAY,
+    })
+}
+
+impl AccessLog {
+    /// Open (or create) the access log at `path`. If it can't be opened, a warning is logged
+    /// and lines are logged as events until it is reopened.
+    pub fn new<P: Into<PathBuf>>(path: P, rotation: Option<Rotation>) -> AccessLog {
+        let path = path.into();
+        let file = match open_log(&path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    "Failed to open the access log, logging lines instead: {}",
+                    e
+                );
+                None
+            }
+        };
+
+        let reopen = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        {
+            if let Err(e) = signal_hook::flag::register(signal_hook::SIGHUP, reopen.clone()) {
+                tracing::warn!("Failed to listen for SIGHUP to reopen the access log: {}", e);
+            }
+        }
+
+        AccessLog {
+            path,
+            rotation,
+            file: Mutex::new(file),
+            reopen,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Close the file and open it again at the configured path, e.g. after it has been
+    /// moved by an external tool
+    pub fn reopen(&self) {
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *file = self.open_or_warn();
+    }
+
+    fn open_or_warn(&self) -> Option<OpenLog> {
+        match open_log(&self.path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                tracing::warn!(path = %self.path.display(), "Failed to reopen the access log: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Move the current file aside with a timestamp suffix, and start a new one
+    fn rotate(&self, file: &mut Option<OpenLog>) {
+        if let Some(open) = file.as_mut() {
+            let _ = open.writer.flush();
+        }
+        *file = None;
+
+        let timestamp = now_secs();
+        let mut rotated = PathBuf::from(format!("{}.{}", self.path.display(), timestamp));
+        // Several size rotations can happen within the same second
+        let mut attempt = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{}-{}", self.path.display(), timestamp, attempt));
+            attempt += 1;
+        }
+
+        if let Err(e) = fs::rename(&self.path, &rotated) {
+            tracing::warn!(path = %self.path.display(), "Failed to rotate the access log: {}", e);
+        }
+
+        *file = self.open_or_warn();
+    }
+
+    /// Write a single line to the log, which shouldn't include a trailing newline
+    pub fn write_line(&self, line: &str) {
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if self.reopen.swap(false, Ordering::SeqCst) {
+            *file = self.open_or_warn();
+        }
+
+        let line_len = line.len() as u64 + 1;
+        let should_rotate = match (self.rotation, file.as_ref()) {
+            (Some(Rotation::Daily), Some(open)) => now_secs() / SECS_PER_DAY != open.opened_day,
+            (Some(Rotation::Size(limit)), Some(open)) => open.written > 0 && open.written + line_len > limit,
+            _ => false,
+        };
+        if should_rotate {
+            self.rotate(&mut file);
+        }
+
+        let written = match file.as_mut() {
+            Some(open) => match writeln!(open.writer, "{}", line) {
+                Ok(()) => {
+                    open.written += line_len;
+                    true
+                }
+                Err(e) => {
+                    tracing::warn!(path = %self.path.display(), "Failed to write to the access log: {}", e);
+                    false
+                }
+            },
+            None => false,
+        };
+
+        if !written {
+            tracing::info!(target: "access_log", "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use rocket::http::Status;
+
+    /// The files in `dir`, sorted by name, with their lines
+    fn log_files(dir: &Path) -> Vec<(String, Vec<String>)> {
+        let mut files: Vec<(String, Vec<String>)> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let lines = fs::read_to_string(&path).unwrap().lines().map(String::from).collect();
+                (name, lines)
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn parses_rotation() {
+        assert_eq!("daily".parse(), Ok(Rotation::Daily));
+        assert_eq!(" Daily ".parse(), Ok(Rotation::Daily));
+        assert_eq!("size:2KiB".parse(), Ok(Rotation::Size(2048)));
+        assert_eq!("SIZE:100MB".parse(), Ok(Rotation::Size(100_000_000)));
+        assert!("size:0B".parse::<Rotation>().is_err());
+        assert!("size:lots".parse::<Rotation>().is_err());
+        assert!("weekly".parse::<Rotation>().is_err());
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::new(&path, Some(Rotation::Size(100)));
+
+        // Each line is 42 bytes with its newline, so only two fit in each file
+        for i in 0..5 {
+            log.write_line(&format!("{{\"line\":{},\"padding\":\"{}\"}}", i, "x".repeat(18)));
+        }
+
+        let files = log_files(dir.path());
+        assert_eq!(files.len(), 3, "{:?}", files);
+        // The current file has the newest line, and the rotated files the older ones
+        let current = files.iter().find(|(name, _)| name == "access.log").unwrap();
+        assert_eq!(current.1.len(), 1);
+        let mut lines: Vec<String> = files.iter().flat_map(|(_, lines)| lines.clone()).collect();
+        lines.sort();
+        assert_eq!(lines.len(), 5);
+        for (i, line) in lines.iter().enumerate() {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["line"], i);
+        }
+        for (name, lines) in &files {
+            assert!(name.starts_with("access.log"));
+            assert!(lines.len() <= 2);
+        }
+    }
+
+    #[test]
+    fn reopens_after_being_moved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::new(&path, None);
+
+        log.write_line("first");
+        let moved = dir.path().join("access.log.1");
+        fs::rename(&path, &moved).unwrap();
+        log.write_line("second");
+        log.reopen();
+        log.write_line("third");
+
+        assert_eq!(fs::read_to_string(&moved).unwrap(), "first\nsecond\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+    }
+
+    #[test]
+    fn request_lines_are_rotated() {
+        let logs = tempfile::tempdir().unwrap();
+        let path = logs.path().join("access.log");
+        let app = TestApp::builder()
+            .static_file("site.css", "body {}")
+            .setting("access_log_file", path.to_string_lossy().into_owned())
+            .setting("access_log_rotate", "size:1KB")
+            .build()
+            .unwrap();
+
+        for _ in 0..40 {
+            assert_eq!(app.client().get("/static/site.css").dispatch().status(), Status::Ok);
+        }
+
+        let files = log_files(logs.path());
+        assert!(files.len() >= 2, "expected the log to have been rotated, got {:?}", files);
+        let mut total = 0;
+        for (name, lines) in &files {
+            assert!(!lines.is_empty(), "{} is empty", name);
+            for line in lines {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert!(value.is_object(), "{}", line);
+            }
+            assert!(fs::metadata(logs.path().join(name)).unwrap().len() <= 1000);
+            total += lines.len();
+        }
+        assert_eq!(total, 40);
+    }
+}
@@ -0,0 +1,291 @@
+//! First-touch marketing attribution, without a client side tracker.
+//!
+//! The first HTML page that a visitor loads records where they came from (the `Referer`
+//! header and any `utm_*` query params) in an encrypted cookie, and sends the same record to an
+//! `AttributionSink`. Later visits by the same browser keep the original cookie, and aren't
+//! reported again. Requests from bots are ignored.
+use crate::app::Settings;
+use crate::http::guards::{is_bot_user_agent, RequestId};
+use crate::http::keyring::KeyRing;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Cookie, Method};
+use rocket::request::{FormItems, State};
+use rocket::{Request, Response};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// The name of the cookie that holds the first-touch attribution record
+pub const ATTRIBUTION_COOKIE: &'static str = "attribution";
+
+/// The marketing query params that are captured with each record
+pub const UTM_PARAMS: [&'static str; 5] = [
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+];
+
+/// Where a visitor came from when they first landed on the site
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttributionEvent {
+    pub request_id: String,
+    /// The path of the first page that the visitor loaded
+    pub landing_path: String,
+    pub referrer: Option<String>,
+    /// Any of the `utm_*` query params that were present on the landing page
+    pub utm: BTreeMap<String, String>,
+}
+
+/// Receives a new attribution record whenever a visitor is seen for the first time
+pub trait AttributionSink: Send + Sync + 'static {
+    fn record(&self, event: &AttributionEvent);
+}
+
+/// Logs each attribution record as JSON, in the `record` field of an `attribution` event
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogSink;
+
+impl AttributionSink for LogSink {
+    fn record(&self, event: &AttributionEvent) {
+        match serde_json::to_string(event) {
+            Ok(line) => tracing::info!(target: "attribution", record = %line, "attribution"),
+            Err(e) => tracing::error!("Failed to serialize attribution record: {}", e),
+        }
+    }
+}
+
+/// Keeps every attribution record in memory, so that they can be inspected in tests
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    events: Mutex<Vec<AttributionEvent>>,
+}
+
+impl MemorySink {
+    pub fn events(&self) -> Vec<AttributionEvent> {
+        self.events.lock().map(|events| events.clone()).unwrap_or_default()
+    }
+}
+
+impl AttributionSink for MemorySink {
+    fn record(&self, event: &AttributionEvent) {
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event.clone());
+        }
+    }
+}
+
+/// Captures first-touch attribution for successful HTML responses to `GET` requests. The
+/// cookie is encrypted with the `KeyRing`, and uses the same `Secure` and `SameSite`
+/// attributes as the session cookie (`Settings::cookie_secure` and
+/// `Settings::cookie_same_site`).
+pub struct Attribution {
+    sink: Arc<dyn AttributionSink>,
+}
+
+impl Attribution {
+    pub fn new(sink: Arc<dyn AttributionSink>) -> Attribution {
+        Attribution { sink }
+    }
+}
+
+impl Fairing for Attribution {
+    fn info(&self) -> Info {
+        Info {
+            name: "First-Touch Attribution",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let is_html = response.content_type().map(|ct| ct.is_html()).unwrap_or(false);
+        if request.method() != Method::Get || !is_html || !response.status().class().is_success() {
+            return;
+        }
+
+        let is_bot = request
+            .headers()
+            .get_one("User-Agent")
+            .map(is_bot_user_agent)
+            .unwrap_or(false);
+        if is_bot || request.cookies().get(ATTRIBUTION_COOKIE).is_some() {
+            return;
+        }
+
+        let keyring = request.guard::<State<KeyRing>>().succeeded();
+        let settings = request.guard::<State<Settings>>().succeeded();
+        let (keyring, settings) = match (keyring, settings) {
+            (Some(keyring), Some(settings)) => (keyring, settings),
+            _ => return,
+        };
+
+        let utm: BTreeMap<String, String> = request
+            .uri()
+            .query()
+            .map(|query| {
+                FormItems::from(query)
+                    .filter(|item| UTM_PARAMS.contains(&item.key.as_str()))
+                    .filter_map(|item| {
+                        let value = item.value.url_decode().ok()?;
+                        Some((item.key.as_str().to_string(), value))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let event = AttributionEvent {
+            request_id: RequestId::of(request).0,
+            landing_path: request.uri().path().to_string(),
+            referrer: request.headers().get_one("Referer").map(String::from),
+            utm,
+        };
+
+        let record = match serde_json::to_string(&event) {
+            Ok(record) => record,
+            Err(_) => return,
+        };
+
+        let cookie = Cookie::build(ATTRIBUTION_COOKIE, keyring.seal(ATTRIBUTION_COOKIE, &record))
+            .path("/")
+            .http_only(true)
+            .secure(settings.cookie_secure)
+            .same_site(settings.cookie_same_site())
+            .permanent()
+            .finish();
+
+        response.adjoin_header(cookie);
+        self.sink.record(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::guards::REQUEST_ID_HEADER;
+    use rocket::handler::Outcome;
+    use rocket::http::{Header, SameSite, Status};
+    use rocket::local::Client;
+    use rocket::response::content;
+    use rocket::{Data, Route};
+
+    fn page<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, content::Html("<p>Welcome</p>"))
+    }
+
+    fn json<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, content::Json("{}"))
+    }
+
+    fn client(sink: Arc<MemorySink>) -> Client {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("secret_key", base64::encode(&[7; 32]))
+            .unwrap()
+            .set("cookie_secure", true)
+            .unwrap()
+            .set("cookie_same_site", "strict")
+            .unwrap()
+            .build()
+            .unwrap();
+        let rocket = rocket::custom(settings.clone().into())
+            .manage(KeyRing::new(&settings))
+            .manage(settings)
+            .attach(Attribution::new(sink))
+            .mount(
+                "/",
+                vec![
+                    Route::new(Method::Get, "/landing", page),
+                    Route::new(Method::Post, "/landing", page),
+                    Route::new(Method::Get, "/about", page),
+                    Route::new(Method::Get, "/api", json),
+                ],
+            );
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn the_first_touch_is_recorded_and_kept() {
+        let sink = Arc::new(MemorySink::default());
+        let client = client(sink.clone());
+
+        let response = client
+            .get("/landing?utm_source=news%20letter&utm_campaign=launch&page=2")
+            .header(Header::new("Referer", "https://example.com/post"))
+            .header(Header::new(REQUEST_ID_HEADER, "first-visit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let cookie = response
+            .cookies()
+            .into_iter()
+            .find(|cookie| cookie.name() == ATTRIBUTION_COOKIE)
+            .expect("the attribution cookie is set")
+            .into_owned();
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert!(!cookie.value().contains("launch"), "the record is encrypted");
+
+        let mut utm = BTreeMap::new();
+        utm.insert(String::from("utm_campaign"), String::from("launch"));
+        utm.insert(String::from("utm_source"), String::from("news letter"));
+        let first_touch = AttributionEvent {
+            request_id: String::from("first-visit"),
+            landing_path: String::from("/landing"),
+            referrer: Some(String::from("https://example.com/post")),
+            utm,
+        };
+        assert_eq!(sink.events(), vec![first_touch]);
+
+        // The client sends the cookie back, so the second visit isn't a first touch
+        let response = client
+            .get("/about?utm_source=ads")
+            .header(Header::new("Referer", "https://search.example.com"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.cookies().iter().all(|cookie| cookie.name() != ATTRIBUTION_COOKIE));
+        assert_eq!(sink.events().len(), 1);
+        assert_eq!(sink.events()[0].landing_path, "/landing");
+    }
+
+    #[test]
+    fn bots_are_skipped() {
+        let sink = Arc::new(MemorySink::default());
+        let client = client(sink.clone());
+
+        for user_agent in &["Googlebot/2.1", "curl/7.64.0", "Mozilla/5.0 HeadlessChrome/79.0"] {
+            let response = client
+                .get("/landing?utm_source=ads")
+                .header(Header::new("User-Agent", *user_agent))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert!(response.cookies().is_empty(), "{}", user_agent);
+        }
+        assert!(sink.events().is_empty());
+
+        let response = client
+            .get("/landing?utm_source=ads")
+            .header(Header::new("User-Agent", "Mozilla/5.0 (X11; Linux x86_64) Firefox/70.0"))
+            .dispatch();
+        assert!(!response.cookies().is_empty());
+        assert_eq!(sink.events().len(), 1);
+    }
+
+    #[test]
+    fn only_html_get_responses_are_attributed() {
+        let sink = Arc::new(MemorySink::default());
+        let client = client(sink.clone());
+
+        let response = client.get("/api?utm_source=ads").dispatch();
+        assert!(response.cookies().is_empty());
+        let response = client.post("/landing?utm_source=ads").dispatch();
+        assert!(response.cookies().is_empty());
+        let response = client.get("/missing?utm_source=ads").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert!(response.cookies().is_empty());
+
+        assert!(sink.events().is_empty());
+    }
+}
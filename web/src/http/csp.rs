@@ -0,0 +1,219 @@
+//! A `Content-Security-Policy` with a fresh script nonce for every request, so that pages can
+//! run their own inline scripts without allowing `'unsafe-inline'`.
+//!
+//! `Settings::csp` is the policy to send, with `{nonce}` wherever the request's nonce goes:
+//!
+//! ```toml
+//! csp = "script-src 'nonce-{nonce}' 'strict-dynamic'; object-src 'none'; base-uri 'none'"
+//! ```
+//!
+//! Templates stamp the same nonce onto their script tags with the `csp_nonce` helper, e.g.
+//! `<script nonce="{{csp_nonce}}">`, and handlers can read it with the `CspNonce` guard. The
+//! nonce is the same everywhere within a request, and different for every request.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Data, Outcome, Response};
+use std::cell::RefCell;
+use uuid::Uuid;
+
+/// The placeholder in `Settings::csp` that is replaced with the request's nonce
+pub const NONCE_PLACEHOLDER: &'static str = "{nonce}";
+
+/// The CSP nonce for the current request: 128 random bits, base64 encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CspNonce(pub String);
+
+impl CspNonce {
+    pub fn of(request: &Request) -> CspNonce {
+        request
+            .local_cache(|| CspNonce(base64::encode(Uuid::new_v4().as_bytes())))
+            .clone()
+    }
+
+    /// The nonce of the request being handled on this thread, for template helpers, which
+    /// can't see the request
+    pub fn current() -> Option<String> {
+        CURRENT_NONCE.with(|current| current.borrow().clone())
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CspNonce {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(CspNonce::of(request))
+    }
+}
+
+thread_local! {
+    /// The nonce of the request being handled on this thread. Rocket handles each request on a
+    /// single thread, from the request fairings through to the response fairings, and renders
+    /// templates in between.
+    static CURRENT_NONCE: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Sends `Settings::csp` with every response that doesn't set its own policy, with the
+/// request's nonce in place of `{nonce}`
+pub struct ContentSecurityPolicy {
+    policy: String,
+}
+
+impl ContentSecurityPolicy {
+    pub fn new(policy: &str) -> ContentSecurityPolicy {
+        ContentSecurityPolicy {
+            policy: policy.to_string(),
+        }
+    }
+}
+
+impl Fairing for ContentSecurityPolicy {
+    fn info(&self) -> Info {
+        Info {
+            name: "Content Security Policy",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let nonce = CspNonce::of(request).0;
+        CURRENT_NONCE.with(|current| *current.borrow_mut() = Some(nonce));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        CURRENT_NONCE.with(|current| *current.borrow_mut() = None);
+
+        if !response.headers().contains("Content-Security-Policy") {
+            let policy = self.policy.replace(NONCE_PLACEHOLDER, &CspNonce::of(request).0);
+            response.set_header(Header::new("Content-Security-Policy", policy));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use rocket::config::{Config, Environment};
+    use rocket::handler::Outcome;
+    use rocket::http::{Method, Status};
+    use rocket::local::{Client, LocalResponse};
+    use rocket::Route;
+    use rocket_contrib::templates::Template;
+    use serde_json::json;
+
+    const POLICY: &str = "script-src 'nonce-{nonce}' 'strict-dynamic'; object-src 'none'";
+
+    /// The nonce from the guard, and the one that template helpers see, on separate lines
+    fn nonces<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let nonce = request.guard::<CspNonce>().unwrap();
+        Outcome::from(request, format!("{}\n{}", nonce.0, CspNonce::current().unwrap_or_default()))
+    }
+
+    fn own_policy<'r>(_: &'r Request, _: Data) -> Outcome<'r> {
+        let response = Response::build()
+            .header(Header::new("Content-Security-Policy", "default-src 'self'"))
+            .finalize();
+        Outcome::Success(response)
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .attach(ContentSecurityPolicy::new(POLICY))
+            .mount(
+                "/",
+                vec![Route::new(Method::Get, "/nonces", nonces), Route::new(Method::Get, "/own", own_policy)],
+            );
+        Client::new(rocket).unwrap()
+    }
+
+    /// The nonce in a response's policy header
+    fn header_nonce(response: &LocalResponse) -> String {
+        let policy = response.headers().get_one("Content-Security-Policy").unwrap();
+        let nonce = policy.split("'nonce-").nth(1).and_then(|rest| rest.split('\'').next()).unwrap();
+        assert_eq!(policy, POLICY.replace(NONCE_PLACEHOLDER, nonce));
+        nonce.to_string()
+    }
+
+    #[test]
+    fn nonce_is_fixed_for_a_request() {
+        let client = client();
+        let first = client.get("/");
+        let second = client.get("/");
+
+        let nonce = CspNonce::of(first.inner());
+        assert_eq!(CspNonce::of(first.inner()), nonce);
+        assert_ne!(CspNonce::of(second.inner()), nonce);
+        assert_eq!(base64::decode(&nonce.0).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn header_and_handler_share_the_nonce() {
+        let client = client();
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            let mut response = client.get("/nonces").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let nonce = header_nonce(&response);
+            assert_eq!(response.body_string(), Some(format!("{}\n{}", nonce, nonce)));
+            assert!(!seen.contains(&nonce), "nonce {} was reused", nonce);
+            seen.push(nonce);
+        }
+
+        // Outside of a request, there is no nonce
+        assert_eq!(CspNonce::current(), None);
+    }
+
+    #[test]
+    fn responses_can_set_their_own_policy() {
+        let response = client().get("/own").dispatch();
+        let policies: Vec<_> = response.headers().get("Content-Security-Policy").collect();
+        assert_eq!(policies, vec!["default-src 'self'"]);
+    }
+
+    fn page<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, Template::render("page", json!({})))
+    }
+
+    #[test]
+    fn templates_stamp_the_nonce() {
+        let app = TestApp::builder()
+            .setting("csp", POLICY)
+            .template(
+                "page.html.hbs",
+                "<script nonce=\"{{csp_nonce}}\">one()</script><script nonce=\"{{csp_nonce}}\">two()</script>",
+            )
+            .mount("/", vec![Route::new(Method::Get, "/page", page)])
+            .build()
+            .unwrap();
+
+        let render = || {
+            let mut response = app.client().get("/page").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let nonce = header_nonce(&response);
+            let body = response.body_string().unwrap();
+            assert_eq!(
+                body,
+                format!(
+                    "<script nonce=\"{0}\">one()</script><script nonce=\"{0}\">two()</script>",
+                    nonce
+                )
+            );
+            nonce
+        };
+        assert_ne!(render(), render());
+    }
+
+    #[test]
+    fn templates_render_no_nonce_without_a_policy() {
+        let app = TestApp::builder()
+            .template("page.html.hbs", "<script nonce=\"{{csp_nonce}}\"></script>")
+            .mount("/", vec![Route::new(Method::Get, "/page", page)])
+            .build()
+            .unwrap();
+
+        let mut response = app.client().get("/page").dispatch();
+        assert!(!response.headers().contains("Content-Security-Policy"));
+        assert_eq!(response.body_string(), Some(String::from("<script nonce=\"\"></script>")));
+    }
+}
@@ -0,0 +1,346 @@
+//! Protecting form submissions from cross-site request forgery (CSRF).
+//!
+//! Each client is given a random token in a private cookie, which is encrypted with the secret
+//! key so that it can't be read or forged by other sites. Pages with forms embed the token
+//! (from `csrf_token`, or the `csrf_token` template helper), and the `CsrfProtected` guard
+//! checks that the token submitted with an unsafe request matches the one in the cookie.
+//! Another site can make the browser send the cookie, but can't read the token to submit it.
+//!
+//! Scripts submit the token in an `X-CSRF-Token` header, which `CsrfProtected` checks. Plain
+//! forms submit it in a `csrf_token` field of the form body, which handlers check with
+//! `verify_csrf` once they have read the form. Tokens in the query string are never accepted,
+//! since URLs end up in logs, browser history and `Referer` headers.
+use crate::http::guards::constant_time_eq;
+use crate::http::keyring::KeyRing;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Cookie, Method, SameSite, Status};
+use rocket::request::{self, FromRequest, Request, State};
+use rocket::{Data, Outcome, Response};
+use std::cell::RefCell;
+use uuid::Uuid;
+
+/// The name of the private cookie that holds the CSRF token
+pub const CSRF_COOKIE: &'static str = "csrf_token";
+
+/// The header that scripts can submit the CSRF token in
+pub const CSRF_HEADER: &'static str = "X-CSRF-Token";
+
+/// The form field that forms submit the CSRF token in
+pub const CSRF_PARAM: &'static str = "csrf_token";
+
+struct CachedToken(String);
+
+/// The CSRF token of the request being handled on this thread, see `CsrfTokens`
+struct CurrentToken {
+    token: String,
+    /// Whether the client doesn't have a CSRF cookie yet, so one is needed if the token is used
+    is_new: bool,
+    used: bool,
+}
+
+thread_local! {
+    /// The CSRF token of the request being handled on this thread. Rocket handles each request
+    /// on a single thread, from the request fairings through to the response fairings, and
+    /// renders templates in between.
+    static CURRENT_TOKEN: RefCell<Option<CurrentToken>> = RefCell::new(None);
+}
+
+/// The CSRF token for the client making `request`, to embed in forms. A new token is created
+/// (and its cookie added to the response) if the client doesn't have one yet.
+///
+/// # Examples
+///
+/// ```
+/// #[get("/posts/new")]
+/// fn new_post(request: &Request) -> Json<Value> {
+///     Json(json!({ "csrf_token": csrf_token(request) }))
+/// }
+/// ```
+pub fn csrf_token(request: &Request) -> String {
+    request
+        .local_cache(|| {
+            if let Some(token) = stored_token(request) {
+                return CachedToken(token);
+            }
+            // `CsrfTokens` has already created a token, and sets the cookie for it
+            if let Some(token) = current_csrf_token() {
+                return CachedToken(token);
+            }
+
+            let token = Uuid::new_v4().to_simple().to_string();
+            request.cookies().add_private(csrf_cookie(token.clone()));
+            CachedToken(token)
+        })
+        .0
+        .clone()
+}
+
+/// The CSRF token of the request being handled on this thread, for template helpers, which
+/// can't see the request. It is only set while `CsrfTokens` is attached.
+pub fn current_csrf_token() -> Option<String> {
+    CURRENT_TOKEN.with(|current| {
+        current.borrow_mut().as_mut().map(|current| {
+            current.used = true;
+            current.token.clone()
+        })
+    })
+}
+
+/// The cookie that holds `value`, which is the token, or the token once it has been sealed
+fn csrf_cookie(value: String) -> Cookie<'static> {
+    Cookie::build(CSRF_COOKIE, value)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(time::Duration::weeks(1))
+        .finish()
+}
+
+/// The token from the client's CSRF cookie, opened with any of the app's secret keys
+fn stored_token(request: &Request) -> Option<String> {
+    let cookie = match request.guard::<State<KeyRing>>().succeeded() {
+        Some(keyring) => keyring.get_private_with(&mut request.cookies(), CSRF_COOKIE, csrf_cookie),
+        None => request.cookies().get_private(CSRF_COOKIE),
+    };
+
+    cookie.map(|cookie| cookie.value().to_string())
+}
+
+/// Whether `submitted` matches the CSRF token in the client's cookie
+pub fn verify_csrf(request: &Request, submitted: &str) -> bool {
+    match stored_token(request) {
+        Some(token) => constant_time_eq(token.as_bytes(), submitted.as_bytes()),
+        None => false,
+    }
+}
+
+/// A request that is safe from CSRF: either one with a safe method (`GET`, `HEAD` or
+/// `OPTIONS`), or one that submitted the token from its CSRF cookie in the `X-CSRF-Token`
+/// header. Other requests are rejected with `403 Forbidden`; forms that submit the token in
+/// their body should be checked with `verify_csrf` instead.
+///
+/// # Examples
+///
+/// ```
+/// #[post("/posts", data = "<post>")]
+/// fn create_post(_csrf: CsrfProtected, post: Json<NewPost>) -> Redirect {
+///     // ...
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsrfProtected;
+
+impl<'a, 'r> FromRequest<'a, 'r> for CsrfProtected {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.method() {
+            Method::Get | Method::Head | Method::Options => return Outcome::Success(CsrfProtected),
+            _ => (),
+        }
+
+        match request.headers().get_one(CSRF_HEADER) {
+            Some(submitted) if verify_csrf(request, submitted) => Outcome::Success(CsrfProtected),
+            _ => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
+
+/// Makes the request's CSRF token available to the `csrf_token` template helper, e.g.
+/// `<input type="hidden" name="csrf_token" value="{{csrf_token}}">`. Clients without a CSRF
+/// cookie are only sent one when a page uses the token, so that other responses (such as
+/// static files) stay free of cookies.
+pub struct CsrfTokens;
+
+impl Fairing for CsrfTokens {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF Tokens",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let current = match stored_token(request) {
+            Some(token) => CurrentToken {
+                token,
+                is_new: false,
+                used: false,
+            },
+            None => CurrentToken {
+                token: Uuid::new_v4().to_simple().to_string(),
+                is_new: true,
+                used: false,
+            },
+        };
+        CURRENT_TOKEN.with(|slot| *slot.borrow_mut() = Some(current));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let token = match CURRENT_TOKEN.with(|slot| slot.borrow_mut().take()) {
+            Some(CurrentToken {
+                token,
+                is_new: true,
+                used: true,
+            }) => token,
+            _ => return,
+        };
+
+        // Rocket has already added the request's cookies to the response, so the cookie is
+        // sealed here instead of being added with `Cookies::add_private`
+        match request.guard::<State<KeyRing>>().succeeded() {
+            Some(keyring) => response.adjoin_header(csrf_cookie(keyring.seal(CSRF_COOKIE, &token))),
+            None => tracing::error!("A CSRF token was used, but there is no KeyRing to seal its cookie with"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use rocket::handler::Outcome as HandlerOutcome;
+    use rocket::http::{ContentType, Header};
+    use rocket::request::FormItems;
+    use rocket::Route;
+    use rocket_contrib::templates::Template;
+    use serde_json::json;
+    use std::io::Read;
+
+    fn form<'r>(request: &'r Request, _: Data) -> HandlerOutcome<'r> {
+        HandlerOutcome::from(request, Template::render("form", json!({})))
+    }
+
+    fn protected<'r>(request: &'r Request, _: Data) -> HandlerOutcome<'r> {
+        match request.guard::<CsrfProtected>() {
+            Outcome::Success(_) => HandlerOutcome::from(request, "ok"),
+            _ => HandlerOutcome::failure(Status::Forbidden),
+        }
+    }
+
+    fn form_field<'r>(request: &'r Request, data: Data) -> HandlerOutcome<'r> {
+        let mut body = String::new();
+        if data.open().read_to_string(&mut body).is_err() {
+            return HandlerOutcome::failure(Status::BadRequest);
+        }
+
+        let submitted = FormItems::from(body.as_str())
+            .find(|item| item.key.as_str() == CSRF_PARAM)
+            .and_then(|item| item.value.url_decode().ok());
+        match submitted {
+            Some(ref submitted) if verify_csrf(request, submitted) => HandlerOutcome::from(request, "ok"),
+            _ => HandlerOutcome::failure(Status::Forbidden),
+        }
+    }
+
+    fn app() -> TestApp {
+        let routes = vec![
+            Route::new(Method::Get, "/form", form),
+            Route::new(Method::Get, "/protected", protected),
+            Route::new(Method::Head, "/protected", protected),
+            Route::new(Method::Options, "/protected", protected),
+            Route::new(Method::Post, "/protected", protected),
+            Route::new(Method::Post, "/form", form_field),
+        ];
+        TestApp::builder()
+            .template("form.html.hbs", "{{csrf_token}}")
+            .mount("/", routes)
+            .build()
+            .unwrap()
+    }
+
+    /// Render the form, which gives the client its CSRF cookie, and return the token
+    fn token(app: &TestApp) -> String {
+        let mut response = app.client().get("/form").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.cookies().iter().any(|cookie| cookie.name() == CSRF_COOKIE));
+
+        let token = response.body_string().unwrap();
+        assert_eq!(token.len(), 32);
+        token
+    }
+
+    #[test]
+    fn unsafe_requests_need_a_matching_token() {
+        let app = app();
+        assert_eq!(app.client().post("/protected").dispatch().status(), Status::Forbidden);
+
+        token(&app);
+        let mismatched = app
+            .client()
+            .post("/protected")
+            .header(Header::new(CSRF_HEADER, "0123456789abcdef0123456789abcdef"))
+            .dispatch();
+        assert_eq!(mismatched.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn safe_methods_need_no_token() {
+        let app = app();
+        assert_eq!(app.client().get("/protected").dispatch().status(), Status::Ok);
+        assert_eq!(app.client().head("/protected").dispatch().status(), Status::Ok);
+        assert_eq!(app.client().options("/protected").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn token_round_trips_in_a_header() {
+        let app = app();
+        let token = token(&app);
+
+        let response = app.client().post("/protected").header(Header::new(CSRF_HEADER, token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn token_round_trips_in_a_form_field() {
+        let app = app();
+        let token = token(&app);
+
+        let response = app
+            .client()
+            .post("/form")
+            .header(ContentType::Form)
+            .body(format!("title=Hello&{}={}", CSRF_PARAM, token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let wrong = app
+            .client()
+            .post("/form")
+            .header(ContentType::Form)
+            .body(format!("title=Hello&{}=guess", CSRF_PARAM))
+            .dispatch();
+        assert_eq!(wrong.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn token_is_stable_once_issued() {
+        let app = app();
+        let first = token(&app);
+
+        // The client already has a cookie, so the same token is rendered without a new one
+        let mut response = app.client().get("/form").dispatch();
+        assert!(!response.cookies().iter().any(|cookie| cookie.name() == CSRF_COOKIE));
+        assert_eq!(response.body_string(), Some(first));
+    }
+
+    #[test]
+    fn token_is_not_accepted_from_the_query() {
+        let app = app();
+        let token = token(&app);
+
+        let response = app
+            .client()
+            .post(format!("/protected?{}={}", CSRF_PARAM, token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn responses_that_do_not_use_the_token_set_no_cookie() {
+        let app = app();
+        let response = app.client().get("/protected").dispatch();
+        assert!(!response.cookies().iter().any(|cookie| cookie.name() == CSRF_COOKIE));
+    }
+}
@@ -0,0 +1,192 @@
+//! Static assets and templates embedded into the binary with the `embed-assets` feature, for
+//! deployments that ship a single executable without `public/` or `templates/` directories.
+//!
+//! The embedded copies are only used when the corresponding directory doesn't exist at
+//! runtime, so during development the files on disk stay editable.
+use crate::app::Settings;
+use crate::http::images::if_none_match;
+use crate::http::wrappers::accept_ranges;
+
+use rocket::handler::{Handler, Outcome};
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::response::Response;
+use rocket::{Data, Request, Route};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+// Generated by build.rs, defining `STATIC_ASSETS` and `TEMPLATES`
+include!(concat!(env!("OUT_DIR"), "/embedded.rs"));
+
+/// Find a file in one of the embedded tables, returning its contents and ETag
+fn find(table: &'static [(&str, &[u8], &str)], path: &str) -> Option<(&'static [u8], &'static str)> {
+    table
+        .iter()
+        .find(|(name, _, _)| *name == path)
+        .map(|(_, contents, etag)| (*contents, *etag))
+}
+
+/// A handler that serves the embedded static assets, in the same way as `StaticFiles` with
+/// `Options::None`. Responses carry an ETag computed when the binary was built, and
+/// conditional requests with a matching `If-None-Match` get `304 Not Modified`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EmbeddedAssets;
+
+impl Into<Vec<Route>> for EmbeddedAssets {
+    fn into(self) -> Vec<Route> {
+        vec![Route::ranked(10, Method::Get, "/<path..>", self)]
+    }
+}
+
+impl Handler for EmbeddedAssets {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        let path: PathBuf = match request.get_segments(0) {
+            Some(Ok(path)) => path,
+            _ => return Outcome::forward(data),
+        };
+
+        let key = path
+            .iter()
+            .map(|segment| segment.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let (contents, etag) = match find(STATIC_ASSETS, &key) {
+            Some(asset) => asset,
+            None => return Outcome::forward(data),
+        };
+
+        let mut response = Response::build();
+        response.header(Header::new("ETag", etag)).header(accept_ranges());
+
+        if if_none_match(request, etag) {
+            response.status(Status::NotModified);
+        } else {
+            let content_type = path
+                .extension()
+                .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+                .unwrap_or(ContentType::Binary);
+
+            response.header(content_type).sized_body(Cursor::new(contents));
+        }
+
+        Outcome::Success(response.finalize())
+    }
+}
+
+/// The private directory that the embedded templates were written to by `prepare_templates`,
+/// which is removed when this is dropped. It's kept in the managed state of the rocket instance
+/// that renders them, so it lives for as long as the app.
+#[derive(Debug)]
+pub struct EmbeddedTemplates(TempDir);
+
+impl EmbeddedTemplates {
+    /// The directory that the templates were written to
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+}
+
+/// When the template directory from `settings` doesn't exist, write the embedded templates to a
+/// new private temporary directory and point `template_dir` at it instead. The returned
+/// directory must be kept alive for as long as the templates are rendered.
+///
+/// rocket_contrib only renders templates that it discovered in `template_dir` when the app was
+/// launched, and looks them up by the files' names, so templates registered directly with
+/// handlebars can't be rendered with `Template::render`. Unlike static assets, the embedded
+/// templates have to be written to disk.
+pub fn prepare_templates(settings: &mut Settings) -> Option<EmbeddedTemplates> {
+    let template_dir = settings.extra("template_dir").unwrap_or("templates");
+    if TEMPLATES.is_empty() || Path::new(template_dir).is_dir() {
+        return None;
+    }
+
+    let written = tempfile::Builder::new()
+        .prefix(concat!(env!("CARGO_PKG_NAME"), "-templates-"))
+        .tempdir()
+        .and_then(|dir| {
+            for (name, contents, _) in TEMPLATES {
+                let path = dir.path().join(name);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, contents)?;
+            }
+            Ok(dir)
+        });
+
+    match written {
+        Ok(dir) => {
+            settings.set_extra("template_dir", dir.path().to_string_lossy().into_owned());
+            Some(EmbeddedTemplates(dir))
+        }
+        Err(e) => {
+            tracing::error!("Failed to write the embedded templates to a temporary directory: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{build, default_routes};
+    use rocket::local::Client;
+    use rocket_contrib::templates::Template;
+    use serde_json::json;
+
+    fn index<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, Template::render("index", json!({ "title": "Embedded" })))
+    }
+
+    /// The app, with `static_dir` and `template_dir` pointing at directories that don't exist
+    fn client(missing: &Path) -> Client {
+        let missing = missing.to_string_lossy().into_owned();
+        let settings = Settings::builder()
+            .unwrap()
+            .set("static_dir", missing.clone())
+            .unwrap()
+            .extra("template_dir", missing)
+            .build()
+            .unwrap();
+        let mut routes = default_routes(&settings);
+        routes.push((String::from("/"), vec![Route::new(Method::Get, "/", index)]));
+        Client::new(build(settings, routes)).unwrap()
+    }
+
+    #[test]
+    fn embedded_assets_are_served_without_a_static_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client(&dir.path().join("missing"));
+        let (contents, etag) = find(STATIC_ASSETS, "foo.css").expect("public/foo.css is embedded");
+
+        let mut response = client.get("/static/foo.css").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::CSS));
+        assert_eq!(response.headers().get_one("ETag"), Some(etag));
+        assert_eq!(response.body_bytes(), Some(contents.to_vec()));
+
+        let response = client
+            .get("/static/foo.css")
+            .header(Header::new("If-None-Match", etag))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+
+        assert_eq!(client.get("/static/missing.css").dispatch().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn embedded_templates_are_rendered_without_a_template_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client(&dir.path().join("missing"));
+
+        let templates = client.rocket().state::<EmbeddedTemplates>().expect("the templates were written out");
+        assert!(templates.path().join("index.html.hbs").is_file());
+
+        let mut response = client.get("/").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        assert!(response.body_string().unwrap().contains("<h1>Embedded</h1>"));
+    }
+}
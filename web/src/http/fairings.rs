@@ -0,0 +1,2126 @@
+use crate::app::diagnostics::Severity;
+use crate::app::{AppState, CookieOverride, Settings};
+use crate::http::access_log::AccessLog;
+use crate::http::csp::CspNonce;
+use crate::http::csrf;
+use crate::http::guards::{client_addr, routed_path, Session, API_KEY_HEADER};
+use crate::http::integrity::AssetIntegrity;
+use crate::http::stats::{Introspect, StatsRegistry};
+
+use regex::Regex;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::handler::Outcome;
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Method, RawStr, Status};
+use rocket::{Data, Request, Response, Rocket, Route};
+use rocket_contrib::templates::handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext};
+use rocket_contrib::templates::Template;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing_subscriber::filter::EnvFilter;
+
+/// The header that clients send to make a request idempotent
+pub const IDEMPOTENCY_KEY_HEADER: &'static str = "Idempotency-Key";
+
+/// The route that repeated idempotent requests are rewritten to, so that their handler isn't run
+const IDEMPOTENCY_REPLAY_ROUTE: &'static str = "/__idempotency/replay";
+
+/// Responses to idempotent requests are cached by method, path, client identity (see
+/// `client_identity`) and key
+type IdempotencyKey = (Method, String, String, String);
+
+/// Who made `request`, so that clients can't replay each other's responses by reusing a key:
+/// the API key, or else the session, that it was made with, or an empty string for anonymous
+/// requests
+fn client_identity(request: &Request) -> String {
+    if let Some(key) = request.headers().get_one(API_KEY_HEADER) {
+        return format!("api_key:{}", key);
+    }
+    match request.guard::<Session>().succeeded() {
+        Some(session) => format!("session:{}", session.0),
+        None => String::new(),
+    }
+}
+
+/// A response stored so that it can be sent again
+#[derive(Debug, Clone)]
+struct StoredResponse {
+    status: Status,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+enum StoreEntry {
+    /// The first request with this key is still being handled
+    Pending(Instant),
+    Complete(Instant, StoredResponse),
+}
+
+impl StoreEntry {
+    fn created(&self) -> Instant {
+        match self {
+            StoreEntry::Pending(created) | StoreEntry::Complete(created, _) => *created,
+        }
+    }
+}
+
+/// What the idempotency fairing decided to do with a request
+#[derive(Debug, Clone)]
+enum IdempotentRequest {
+    /// The key hasn't been seen before, so the response should be stored
+    First(IdempotencyKey),
+    /// The key has been seen before, so the stored response should be sent
+    Replay(StoredResponse),
+    /// The key has been seen before, but the first request hasn't finished yet
+    InProgress,
+}
+
+/// The responses stored by the `Idempotency` fairing, evicting the oldest entries once
+/// `capacity` is reached
+#[derive(Debug)]
+pub struct IdempotencyStore {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<IdempotencyKey, StoreEntry>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration, capacity: usize) -> IdempotencyStore {
+        IdempotencyStore {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The number of keys currently stored, including those still being handled
+    pub fn len(&self) -> usize {
+        self.entries.lock().map(|entries| entries.len()).unwrap_or(0)
+    }
+
+    /// Look up `key`, marking it as pending if it hasn't been seen within the TTL
+    fn begin(&self, key: IdempotencyKey) -> IdempotentRequest {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let now = Instant::now();
+        let ttl = self.ttl;
+        entries.retain(|_, entry| now.duration_since(entry.created()) < ttl);
+
+        match entries.get(&key) {
+            Some(StoreEntry::Complete(_, response)) => return IdempotentRequest::Replay(response.clone()),
+            Some(StoreEntry::Pending(_)) => return IdempotentRequest::InProgress,
+            None => (),
+        }
+
+        if entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.created())
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(key.clone(), StoreEntry::Pending(now));
+        IdempotentRequest::First(key)
+    }
+
+    fn complete(&self, key: &IdempotencyKey, response: Option<StoredResponse>) {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        match response {
+            Some(response) => {
+                let created = entries.get(key).map(StoreEntry::created).unwrap_or_else(Instant::now);
+                entries.insert(key.clone(), StoreEntry::Complete(created, response));
+            }
+            None => {
+                entries.remove(key);
+            }
+        }
+    }
+}
+
+impl Introspect for IdempotencyStore {
+    fn stats(&self) -> Value {
+        let (entries, pending) = match self.entries.lock() {
+            Ok(entries) => {
+                let pending = entries
+                    .values()
+                    .filter(|entry| match entry {
+                        StoreEntry::Pending(_) => true,
+                        StoreEntry::Complete(..) => false,
+                    })
+                    .count();
+                (entries.len(), pending)
+            }
+            Err(_) => (0, 0),
+        };
+
+        json!({ "entries": entries, "pending": pending, "capacity": self.capacity })
+    }
+}
+
+/// Makes requests with an `Idempotency-Key` header safe to retry. The first response for each
+/// combination of method, path, client (by API key or session) and key is stored for
+/// `Settings::idempotency_ttl`, and sent again (with an `Idempotent-Replayed: true` header)
+/// for any repeat of that request without running its handler. A repeat that arrives while
+/// the first request is still being handled gets `409 Conflict`. `Set-Cookie` headers are
+/// never stored, so a replay can't hand out the first client's cookies.
+///
+/// Only requests with unsafe methods (e.g. `POST`) are considered, and server errors are not
+/// stored so that the request can be retried. At most `Settings::idempotency_cache_size`
+/// responses are kept, evicting the oldest first.
+pub struct Idempotency {
+    store: Arc<IdempotencyStore>,
+}
+
+impl Idempotency {
+    pub fn new(settings: &Settings) -> Idempotency {
+        Idempotency {
+            store: Arc::new(IdempotencyStore::new(
+                settings.idempotency_ttl.as_duration(),
+                settings.idempotency_cache_size,
+            )),
+        }
+    }
+
+    pub fn store(&self) -> Arc<IdempotencyStore> {
+        self.store.clone()
+    }
+}
+
+impl Fairing for Idempotency {
+    fn info(&self) -> Info {
+        Info {
+            name: "Idempotency Keys",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        if let Some(stats) = AppState::<StatsRegistry>::get(&rocket) {
+            stats.register("idempotency", self.store.clone());
+        }
+        Ok(rocket.mount("/", vec![Route::new(Method::Get, IDEMPOTENCY_REPLAY_ROUTE, replay)]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        match request.method() {
+            Method::Get | Method::Head | Method::Options => return,
+            _ => (),
+        }
+
+        let key = match request.headers().get_one(IDEMPOTENCY_KEY_HEADER) {
+            Some(key) => (
+                request.method(),
+                request.uri().path().to_string(),
+                client_identity(request),
+                key.to_string(),
+            ),
+            None => return,
+        };
+
+        let state = self.store.begin(key);
+        let is_repeat = match state {
+            IdempotentRequest::First(_) => false,
+            _ => true,
+        };
+
+        request.local_cache(|| Some(state));
+        if is_repeat {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(IDEMPOTENCY_REPLAY_ROUTE).expect("valid replay route"));
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let key = match request.local_cache(|| None::<IdempotentRequest>) {
+            Some(IdempotentRequest::First(key)) => key,
+            _ => return,
+        };
+
+        if response.status().class().is_server_error() {
+            self.store.complete(key, None);
+            return;
+        }
+
+        let body = response.body_bytes().unwrap_or_default();
+        let stored = StoredResponse {
+            status: response.status(),
+            headers: response
+                .headers()
+                .iter()
+                .filter(|header| header.name() != "Content-Length" && header.name() != "Set-Cookie")
+                .map(|header| (header.name().as_str().to_string(), header.value().to_string()))
+                .collect(),
+            body: body.clone(),
+        };
+
+        response.set_sized_body(Cursor::new(body));
+        self.store.complete(key, Some(stored));
+    }
+}
+
+/// Respond to a request that was rewritten by `Idempotency` because its key was already used
+fn replay<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    match request.local_cache(|| None::<IdempotentRequest>) {
+        Some(IdempotentRequest::Replay(stored)) => {
+            let mut response = Response::build();
+            response.status(stored.status);
+            for (name, value) in &stored.headers {
+                response.header_adjoin(Header::new(name.clone(), value.clone()));
+            }
+
+            response
+                .header(Header::new("Idempotent-Replayed", "true"))
+                .sized_body(Cursor::new(stored.body.clone()));
+
+            Outcome::Success(response.finalize())
+        }
+        Some(IdempotentRequest::InProgress) => Outcome::Success(
+            Response::build()
+                .status(Status::Conflict)
+                .sized_body(Cursor::new("A request with this idempotency key is already in progress"))
+                .finalize(),
+        ),
+        // The route was requested directly, rather than by a repeated request
+        _ => Outcome::failure(Status::NotFound),
+    }
+}
+
+/// The route that WebSocket upgrade requests are rewritten to by `WebSocketUpgrade`
+const UPGRADE_REQUIRED_ROUTE: &'static str = "/__websocket/upgrade_required";
+
+/// Marks a request that `WebSocketUpgrade` rewrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UpgradeRequested(bool);
+
+/// Answers WebSocket upgrade requests for `Settings::websocket_paths` with `426 Upgrade
+/// Required`, rather than the `404 Not Found` that rocket would send.
+///
+/// This is not WebSocket support: rocket can't upgrade connections, so the WebSocket endpoint
+/// has to be served by something else (e.g. a sidecar that the reverse proxy routes upgrade
+/// requests to). This only gives clients a clear error when an upgrade request reaches the app
+/// directly, usually because the proxy isn't configured to route it.
+pub struct WebSocketUpgrade {
+    paths: Vec<String>,
+}
+
+impl WebSocketUpgrade {
+    pub fn new(settings: &Settings) -> WebSocketUpgrade {
+        WebSocketUpgrade {
+            paths: settings.websocket_paths.clone(),
+        }
+    }
+}
+
+/// Whether the request asks for its connection to be upgraded to a WebSocket
+fn is_websocket_upgrade(request: &Request) -> bool {
+    let headers = request.headers();
+    let connection_upgrade = headers
+        .get("Connection")
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let upgrade_websocket = headers
+        .get("Upgrade")
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"));
+
+    connection_upgrade && upgrade_websocket
+}
+
+impl Fairing for WebSocketUpgrade {
+    fn info(&self) -> Info {
+        Info {
+            name: "WebSocket Upgrade Detection",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket.mount("/", vec![Route::new(Method::Get, UPGRADE_REQUIRED_ROUTE, upgrade_required)]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let requested = routed_path(request);
+        let is_configured = self.paths.iter().any(|path| path.trim_end_matches('/') == requested);
+        if !is_configured || !is_websocket_upgrade(request) {
+            return;
+        }
+
+        request.local_cache(|| UpgradeRequested(true));
+        request.set_method(Method::Get);
+        request.set_uri(Origin::parse(UPGRADE_REQUIRED_ROUTE).expect("valid upgrade route"));
+    }
+}
+
+/// Respond to a request that was rewritten by `WebSocketUpgrade`
+fn upgrade_required<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    if !request.local_cache(|| UpgradeRequested(false)).0 {
+        return Outcome::failure(Status::NotFound);
+    }
+
+    Outcome::Success(
+        Response::build()
+            .status(Status::new(426, "Upgrade Required"))
+            .header(Header::new("Upgrade", "websocket"))
+            .header(Header::new("Connection", "Upgrade"))
+            .sized_body(Cursor::new(
+                "This server can't accept WebSocket connections directly; \
+                 they must be routed to the WebSocket service by the proxy in front of it",
+            ))
+            .finalize(),
+    )
+}
+
+/// Adds `Access-Control-Allow-Origin` to responses for requests from one of
+/// `Settings::cors_allowed_origins`, which may include `"*"` to allow any origin. Other CORS
+/// headers set by the response (such as those from a `Preflight`) are left as they are.
+pub struct CorsHeaderFairing {
+    allowed_origins: Vec<String>,
+}
+
+impl CorsHeaderFairing {
+    pub fn new(settings: &Settings) -> CorsHeaderFairing {
+        CorsHeaderFairing {
+            allowed_origins: settings.cors_allowed_origins.clone(),
+        }
+    }
+}
+
+impl Fairing for CorsHeaderFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS Headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) => origin,
+            None => return,
+        };
+
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        } else if self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            response.set_header(Header::new("Access-Control-Allow-Origin", origin.to_string()));
+            response.adjoin_header(Header::new("Vary", "Origin"));
+        }
+    }
+}
+
+/// Sets `Settings::default_cache_control` as the `Cache-Control` header of dynamic responses
+/// that don't set their own, so that pages (e.g. those for a logged in user) aren't cached by
+/// accident. Responses for paths under `Settings::static_route` are left alone.
+pub struct DefaultCacheControl {
+    value: String,
+    static_route: String,
+}
+
+impl DefaultCacheControl {
+    pub fn new(settings: &Settings) -> DefaultCacheControl {
+        DefaultCacheControl {
+            value: settings.default_cache_control.clone(),
+            static_route: mounted_static_route(settings),
+        }
+    }
+
+    fn is_static(&self, path: &str) -> bool {
+        is_under_route(&self.static_route, path)
+    }
+}
+
+/// The path that static files are served under, including the route prefix, without a
+/// trailing slash
+fn mounted_static_route(settings: &Settings) -> String {
+    format!("{}{}", settings.route_prefix(), settings.static_route)
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn is_under_route(route: &str, path: &str) -> bool {
+    // An empty static route means that static files are mounted at the root
+    route.is_empty() || path == route || path.starts_with(&format!("{}/", route))
+}
+
+/// How long a fingerprinted asset can be cached for, which is a year (the most that HTTP/1.1
+/// caches are expected to honour)
+pub const IMMUTABLE_MAX_AGE: u64 = 31_536_000;
+
+/// Sets the `Cache-Control` header of static files, when `Settings::immutable_assets_pattern`
+/// is set. Files whose paths match the pattern have names that change whenever their contents
+/// do (e.g. `app.3f9a1c2e.js`), so they are sent with
+/// `public, max-age=31536000, immutable`. Every other static file can only be cached for
+/// `Settings::static_max_age`, so that changes to it are picked up.
+///
+/// Only successful responses are marked as cacheable, and responses that set their own
+/// `Cache-Control` are left alone.
+pub struct StaticCacheControl {
+    pattern: Regex,
+    max_age: Duration,
+    static_route: String,
+}
+
+impl StaticCacheControl {
+    pub fn new(settings: &Settings) -> StaticCacheControl {
+        let pattern = settings.immutable_assets_pattern.as_ref().map_or("$^", String::as_str);
+        StaticCacheControl {
+            // The pattern is checked by `Settings::validate`, so the fallback (which matches
+            // nothing) is only used for settings that weren't validated
+            pattern: Regex::new(pattern).unwrap_or_else(|_| Regex::new("$^").unwrap()),
+            max_age: settings.static_max_age.as_duration(),
+            static_route: mounted_static_route(settings),
+        }
+    }
+
+    /// The `Cache-Control` value for a static file at `path`
+    pub fn value_for(&self, path: &str) -> String {
+        if self.pattern.is_match(path) {
+            format!("public, max-age={}, immutable", IMMUTABLE_MAX_AGE)
+        } else {
+            format!("public, max-age={}", self.max_age.as_secs())
+        }
+    }
+}
+
+impl Fairing for StaticCacheControl {
+    fn info(&self) -> Info {
+        Info {
+            name: "Static Cache-Control",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let path = request.uri().path();
+        let cacheable = response.status().class().is_success() || response.status() == Status::NotModified;
+        if !cacheable || response.headers().contains("Cache-Control") || !is_under_route(&self.static_route, path) {
+            return;
+        }
+
+        response.set_header(Header::new("Cache-Control", self.value_for(path)));
+    }
+}
+
+impl Fairing for DefaultCacheControl {
+    fn info(&self) -> Info {
+        Info {
+            name: "Default Cache-Control",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if response.headers().contains("Cache-Control") || self.is_static(request.uri().path()) {
+            return;
+        }
+
+        response.set_header(Header::new("Cache-Control", self.value.clone()));
+    }
+}
+
+/// The headers added by `SecurityHeadersFairing`, as `(name, extra, default value)`, where
+/// `extra` is the key in `Settings::extras` that overrides the value
+const SECURITY_HEADERS: [(&'static str, &'static str, &'static str); 4] = [
+    ("X-Content-Type-Options", "xcontent_type_options", "nosniff"),
+    ("X-Frame-Options", "xframe_options", "DENY"),
+    ("Referrer-Policy", "referrer_policy", "strict-origin-when-cross-origin"),
+    ("Permissions-Policy", "permissions_policy", "camera=(), microphone=()"),
+];
+
+/// Adds the security headers recommended by OWASP to every response that doesn't set them
+/// itself. Each value can be changed with an extra (e.g. `APP_XFRAME_OPTIONS=SAMEORIGIN`), and
+/// setting an extra to an empty string leaves that header out. The fairing is attached unless
+/// `Settings::security_headers_disabled` is set.
+pub struct SecurityHeadersFairing {
+    headers: Vec<Header<'static>>,
+}
+
+impl SecurityHeadersFairing {
+    pub fn new(settings: &Settings) -> SecurityHeadersFairing {
+        SecurityHeadersFairing {
+            headers: SECURITY_HEADERS
+                .iter()
+                .map(|&(name, extra, default)| (name, settings.extra(extra).unwrap_or(default)))
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(name, value)| Header::new(name, value.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Fairing for SecurityHeadersFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security Headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        for header in &self.headers {
+            if !response.headers().contains(header.name()) {
+                response.set_header(header.clone());
+            }
+        }
+    }
+}
+
+/// Replaces the `Server` header that rocket adds to every response with
+/// `Settings::server_header`, or removes it when that is an empty string. Rocket adds its
+/// header before response fairings run, so this also overrides any `Server` header set by a
+/// handler.
+pub struct ServerHeader {
+    value: Option<String>,
+}
+
+impl ServerHeader {
+    pub fn new(settings: &Settings) -> ServerHeader {
+        ServerHeader {
+            value: settings.server_header.clone(),
+        }
+    }
+}
+
+impl Fairing for ServerHeader {
+    fn info(&self) -> Info {
+        Info {
+            name: "Server Header",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        match self.value.as_ref().map(String::as_str) {
+            None => (),
+            Some("") => response.remove_header("Server"),
+            Some(value) => {
+                response.set_header(Header::new("Server", value.to_string()));
+            }
+        }
+    }
+}
+
+/// Enforces consistent attributes on every cookie that the app sets, whichever code set it.
+/// For each `Set-Cookie` header on a response:
+///
+/// - `HttpOnly` is added when `Settings::cookie_http_only_default` is enabled
+/// - `Secure` is added when `Settings::cookie_secure` is enabled
+/// - `SameSite` is added when it's missing, from `Settings::cookie_same_site_default`
+///
+/// with `Settings::cookie_overrides` replacing any of those for particular cookies. Attributes
+/// that were set explicitly are left alone, except that a cookie with `SameSite=None` is given
+/// `Secure` (with a logged error), as browsers reject it otherwise.
+///
+/// Attributes are appended to the header as it was sent, rather than parsing and re-serializing
+/// the cookie, so values containing `=`, `,` or encoded characters are passed through exactly.
+/// Each cookie is a separate `Set-Cookie` header, and header values are never split on commas.
+pub struct CookiePolicy {
+    http_only: bool,
+    secure: bool,
+    same_site: String,
+    overrides: HashMap<String, CookieOverride>,
+}
+
+impl CookiePolicy {
+    pub fn new(settings: &Settings) -> CookiePolicy {
+        CookiePolicy {
+            http_only: settings.cookie_http_only_default,
+            secure: settings.cookie_secure,
+            same_site: settings
+                .cookie_same_site_default
+                .clone()
+                .unwrap_or_else(|| settings.cookie_same_site.clone()),
+            overrides: settings.cookie_overrides.clone(),
+        }
+    }
+
+    /// `header` (the value of a `Set-Cookie` header) with any missing attributes added
+    fn apply(&self, header: &str) -> String {
+        let mut parts = header.split(';');
+        let name = parts
+            .next()
+            .and_then(|pair| pair.splitn(2, '=').next())
+            .map(str::trim)
+            .unwrap_or("");
+        let attributes: Vec<(String, String)> = parts
+            .map(|attribute| {
+                let mut pair = attribute.splitn(2, '=');
+                let name = pair.next().unwrap_or("").trim().to_ascii_lowercase();
+                let value = pair.next().unwrap_or("").trim().to_ascii_lowercase();
+                (name, value)
+            })
+            .collect();
+        let has = |attribute: &str| attributes.iter().any(|(name, _)| name == attribute);
+
+        let cookie = self.overrides.get(name);
+        let http_only = cookie.and_then(|cookie| cookie.http_only).unwrap_or(self.http_only);
+        let secure = cookie.and_then(|cookie| cookie.secure).unwrap_or(self.secure);
+        let same_site = cookie
+            .and_then(|cookie| cookie.same_site.as_ref())
+            .unwrap_or(&self.same_site);
+
+        let mut header = header.trim_end().trim_end_matches(';').to_string();
+        if http_only && !has("httponly") {
+            header.push_str("; HttpOnly");
+        }
+
+        let explicit_same_site = attributes
+            .iter()
+            .find(|(name, _)| name == "samesite")
+            .map(|(_, value)| value.as_str());
+        let mut is_secure = has("secure");
+        if secure && !is_secure {
+            header.push_str("; Secure");
+            is_secure = true;
+        }
+        // "none" leaves the attribute off, as with `Settings::cookie_same_site`
+        if explicit_same_site.is_none() && same_site.as_str() != "none" {
+            header.push_str(if same_site.as_str() == "strict" { "; SameSite=Strict" } else { "; SameSite=Lax" });
+        }
+        if explicit_same_site == Some("none") && !is_secure {
+            tracing::error!(cookie = name, "Cookie was set with SameSite=None but without Secure, so Secure was added");
+            header.push_str("; Secure");
+        }
+
+        header
+    }
+}
+
+impl Fairing for CookiePolicy {
+    fn info(&self) -> Info {
+        Info {
+            name: "Cookie Policy",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        let cookies: Vec<String> = response.headers().get("Set-Cookie").map(|cookie| self.apply(cookie)).collect();
+        if cookies.is_empty() {
+            return;
+        }
+
+        response.remove_header("Set-Cookie");
+        for cookie in cookies {
+            response.adjoin_raw_header("Set-Cookie", cookie);
+        }
+    }
+}
+
+/// The header that `TimingFairing` reports the time taken to handle each request in
+pub const RESPONSE_TIME_HEADER: &'static str = "X-Response-Time";
+
+/// When the request fairings first saw a request, kept in the request's local cache
+struct RequestStart(Instant);
+
+/// Adds an `X-Response-Time` header to every response, with the time in milliseconds from when
+/// the request reached this fairing to when the response was ready, e.g. `X-Response-Time:
+/// 1.204ms`. The time taken to send the body isn't included.
+pub struct TimingFairing;
+
+impl Fairing for TimingFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response Timing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        // Requests that skipped `on_request` (e.g. failed to parse) get a time of zero
+        let start = request.local_cache(|| RequestStart(Instant::now())).0;
+        let elapsed = start.elapsed();
+        let millis = elapsed.as_secs() as f64 * 1000.0 + f64::from(elapsed.subsec_nanos()) / 1_000_000.0;
+        response.set_header(Header::new(RESPONSE_TIME_HEADER, format!("{:.3}ms", millis)));
+    }
+}
+
+/// The route that requests with a path that `PathNormalize` rejects or redirects are
+/// rewritten to
+const PATH_NORMALIZE_ROUTE: &'static str = "/__path_normalize/respond";
+
+/// What `PathNormalize` decided about a request, kept in the request's local cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathDecision {
+    Unchanged,
+    /// Redirect to the canonical path (and query)
+    Redirect(String),
+    /// The path has a `..` that goes above the root
+    Escapes,
+}
+
+/// Collapse the empty and `.` segments in `path`, and resolve its `..` segments (including
+/// percent-encoded ones, like `%2e%2e`), keeping any trailing slash. `None` if a `..` would go
+/// above the root.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut last = "";
+    for segment in path.split('/') {
+        last = segment;
+        let decoded = RawStr::from_str(segment).url_decode_lossy();
+        match decoded.as_str() {
+            "" | "." => (),
+            ".." => {
+                segments.pop()?;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let decoded_last = RawStr::from_str(last).url_decode_lossy();
+    let trailing = !segments.is_empty() && (last.is_empty() || decoded_last == "." || decoded_last == "..");
+    Some(format!("/{}{}", segments.join("/"), if trailing { "/" } else { "" }))
+}
+
+/// Puts request paths into a canonical form before they are routed, so that `/foo//bar` and
+/// `/foo/./baz/../bar` reach the same route as `/foo/bar`. See `normalize_path`.
+///
+/// With `Settings::path_normalize` set to `"redirect"`, clients are redirected to the canonical
+/// path, with `301 Moved Permanently` for `GET` and `HEAD` requests and `308 Permanent
+/// Redirect` (which keeps the method and body) otherwise. With `"rewrite"`, the request is
+/// routed as though it had been made for the canonical path. Either way, paths whose `..`
+/// segments would go above the root are rejected with `400 Bad Request`, so that they can't
+/// be used to reach files outside of a static directory.
+pub struct PathNormalize {
+    redirect: bool,
+}
+
+impl PathNormalize {
+    pub fn new(settings: &Settings) -> PathNormalize {
+        PathNormalize {
+            redirect: settings.path_normalize.as_ref().map(String::as_str) == Some("redirect"),
+        }
+    }
+}
+
+impl Fairing for PathNormalize {
+    fn info(&self) -> Info {
+        Info {
+            name: "Path Normalization",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket.mount("/", vec![Route::new(Method::Get, PATH_NORMALIZE_ROUTE, path_normalized)]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let path = request.uri().path().to_string();
+        let decision = match normalize_path(&path) {
+            None => PathDecision::Escapes,
+            Some(ref normalized) if *normalized == path => return,
+            Some(normalized) => {
+                let target = match request.uri().query() {
+                    Some(query) => format!("{}?{}", normalized, query),
+                    None => normalized,
+                };
+                if self.redirect {
+                    PathDecision::Redirect(target)
+                } else {
+                    match Origin::parse_owned(target) {
+                        Ok(uri) => return request.set_uri(uri),
+                        Err(_) => PathDecision::Escapes,
+                    }
+                }
+            }
+        };
+
+        let redirect_status = match request.method() {
+            Method::Get | Method::Head => Status::MovedPermanently,
+            _ => Status::PermanentRedirect,
+        };
+        request.local_cache(|| (decision, redirect_status));
+        request.set_method(Method::Get);
+        request.set_uri(Origin::parse(PATH_NORMALIZE_ROUTE).expect("valid normalize route"));
+    }
+}
+
+/// Respond to a request that was rewritten by `PathNormalize`
+fn path_normalized<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    match request.local_cache(|| (PathDecision::Unchanged, Status::MovedPermanently)) {
+        (PathDecision::Redirect(target), status) => Outcome::Success(
+            Response::build()
+                .status(*status)
+                .header(Header::new("Location", target.clone()))
+                .finalize(),
+        ),
+        (PathDecision::Escapes, _) => Outcome::failure(Status::BadRequest),
+        (PathDecision::Unchanged, _) => Outcome::failure(Status::NotFound),
+    }
+}
+
+/// The route that requests over the app's concurrency limit are rewritten to
+const CONCURRENCY_OVERLOADED_ROUTE: &'static str = "/__concurrency/overloaded";
+
+/// One request counted by `Concurrency`, which is released when the request is dropped, like
+/// `ClientSlots`. Requests over the limit are released as soon as they are turned away.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Caps the number of requests that the app handles at once (`Settings::max_concurrent_requests`),
+/// to shed load when it's under pressure rather than queueing requests until they time out.
+/// Requests over the cap get `503 Service Unavailable` with a `Retry-After` header. Requests for
+/// paths under one of `Settings::concurrency_exempt_prefixes` (such as health checks) are
+/// neither counted nor turned away.
+pub struct Concurrency {
+    max: usize,
+    exempt_prefixes: Vec<String>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Concurrency {
+    pub fn new(settings: &Settings) -> Concurrency {
+        Concurrency {
+            max: settings.max_concurrent_requests.unwrap_or(usize::max_value()),
+            exempt_prefixes: settings.concurrency_exempt_prefixes.clone(),
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// The number of counted requests currently in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+}
+
+/// Reports the requests counted by a `Concurrency` fairing to the `StatsRegistry`
+struct ConcurrencyStats {
+    in_flight: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl Introspect for ConcurrencyStats {
+    fn stats(&self) -> Value {
+        json!({ "in_flight": self.in_flight.load(Ordering::SeqCst), "max": self.max })
+    }
+}
+
+/// Whether a request was turned away by `Concurrency`, kept in the request's local cache
+struct Overloaded(bool);
+
+impl Fairing for Concurrency {
+    fn info(&self) -> Info {
+        Info {
+            name: "Concurrency Limit",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        if let Some(stats) = AppState::<StatsRegistry>::get(&rocket) {
+            let in_flight = self.in_flight.clone();
+            stats.register("concurrency", Arc::new(ConcurrencyStats { in_flight, max: self.max }));
+        }
+        Ok(rocket.mount("/", vec![Route::new(Method::Get, CONCURRENCY_OVERLOADED_ROUTE, concurrency_overloaded)]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let path = routed_path(request);
+        if self.exempt_prefixes.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+            return;
+        }
+
+        let slot = InFlight(self.in_flight.clone());
+        if self.in_flight.fetch_add(1, Ordering::SeqCst) < self.max {
+            // Cached with the request, so that the count is released when it is dropped
+            request.local_cache(|| Some(slot));
+            return;
+        }
+
+        drop(slot);
+        request.local_cache(|| Overloaded(true));
+        request.set_method(Method::Get);
+        request.set_uri(Origin::parse(CONCURRENCY_OVERLOADED_ROUTE).expect("valid concurrency route"));
+    }
+}
+
+/// Respond to a request that was rewritten by `Concurrency`
+fn concurrency_overloaded<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    if !request.local_cache(|| Overloaded(false)).0 {
+        return Outcome::failure(Status::NotFound);
+    }
+
+    Outcome::Success(
+        Response::build()
+            .status(Status::ServiceUnavailable)
+            .header(ContentType::JSON)
+            .header(Header::new("Retry-After", "1"))
+            .sized_body(Cursor::new(
+                "{\"error\":\"overloaded\",\"message\":\"the server is handling too many requests\"}",
+            ))
+            .finalize(),
+    )
+}
+
+/// The route that requests over a client's concurrency limit are rewritten to
+const CONCURRENCY_LIMITED_ROUTE: &'static str = "/__concurrency/limited";
+
+/// The identities that `ClientConcurrencyLimit` counts requests for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ClientKey {
+    Ip(IpAddr),
+    ApiKey(String),
+}
+
+type ClientCounts = Arc<Mutex<HashMap<ClientKey, usize>>>;
+
+/// The clients that one request is counted against, which are released when it is dropped.
+/// Rocket drops the request after its response has been written, or when the handler
+/// panics or the client disconnects, so the counts can't leak.
+struct ClientSlots {
+    counts: ClientCounts,
+    keys: Vec<ClientKey>,
+}
+
+impl Drop for ClientSlots {
+    fn drop(&mut self) {
+        let mut counts = match self.counts.lock() {
+            Ok(counts) => counts,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        for key in &self.keys {
+            let remaining = match counts.get_mut(key) {
+                Some(count) => {
+                    *count = count.saturating_sub(1);
+                    *count
+                }
+                None => continue,
+            };
+            if remaining == 0 {
+                counts.remove(key);
+            }
+        }
+    }
+}
+
+struct ClientConcurrencyStats(ClientCounts);
+
+impl Introspect for ClientConcurrencyStats {
+    fn stats(&self) -> Value {
+        let (clients, in_flight) = match self.0.lock() {
+            Ok(counts) => (counts.len(), counts.values().sum::<usize>()),
+            Err(_) => (0, 0),
+        };
+
+        json!({ "clients": clients, "in_flight": in_flight })
+    }
+}
+
+enum ClientConcurrency {
+    Admitted(ClientSlots),
+    Limited,
+}
+
+/// Limits the number of requests that each client can have in flight at once, by IP address
+/// (`Settings::max_concurrent_per_ip`) and by API key (`Settings::max_concurrent_per_api_key`).
+/// The IP address is the peer's, or the one forwarded by a trusted proxy (see `client_addr`),
+/// so that clients can't spread their requests over made up addresses.
+/// This is separate from rate limiting: it stops one client from tying up many workers with
+/// slow requests, such as large downloads, however few requests it makes.
+///
+/// Requests over either limit get `429 Too Many Requests` with a `concurrency_limit` error,
+/// so that clients can tell it apart from a rate limit. Requests for paths under one of
+/// `Settings::concurrency_exempt_prefixes` aren't counted.
+pub struct ClientConcurrencyLimit {
+    per_ip: Option<usize>,
+    per_api_key: Option<usize>,
+    exempt_prefixes: Vec<String>,
+    counts: ClientCounts,
+}
+
+impl ClientConcurrencyLimit {
+    pub fn new(settings: &Settings) -> ClientConcurrencyLimit {
+        ClientConcurrencyLimit {
+            per_ip: settings.max_concurrent_per_ip,
+            per_api_key: settings.max_concurrent_per_api_key,
+            exempt_prefixes: settings.concurrency_exempt_prefixes.clone(),
+            counts: Arc::default(),
+        }
+    }
+
+    /// The total number of counted requests currently in flight, across every client
+    pub fn in_flight(&self) -> usize {
+        self.counts
+            .lock()
+            .map(|counts| counts.values().sum())
+            .unwrap_or(0)
+    }
+
+    /// Count the request against each of `limits`, unless any of them is already full
+    fn admit(&self, limits: Vec<(ClientKey, usize)>) -> ClientConcurrency {
+        let mut counts = match self.counts.lock() {
+            Ok(counts) => counts,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let is_full = limits
+            .iter()
+            .any(|(key, max)| counts.get(key).cloned().unwrap_or(0) >= *max);
+        if is_full {
+            return ClientConcurrency::Limited;
+        }
+
+        for (key, _) in &limits {
+            *counts.entry(key.clone()).or_insert(0) += 1;
+        }
+
+        ClientConcurrency::Admitted(ClientSlots {
+            counts: self.counts.clone(),
+            keys: limits.into_iter().map(|(key, _)| key).collect(),
+        })
+    }
+}
+
+impl Fairing for ClientConcurrencyLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Client Concurrency Limit",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        if let Some(stats) = AppState::<StatsRegistry>::get(&rocket) {
+            stats.register("client_concurrency", Arc::new(ClientConcurrencyStats(self.counts.clone())));
+        }
+        Ok(rocket.mount("/", vec![Route::new(Method::Get, CONCURRENCY_LIMITED_ROUTE, concurrency_limited)]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let path = routed_path(request);
+        if self.exempt_prefixes.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+            return;
+        }
+
+        let mut limits = Vec::new();
+        if let (Some(max), Some(ip)) = (self.per_ip, client_addr(request)) {
+            limits.push((ClientKey::Ip(ip), max));
+        }
+        if let (Some(max), Some(key)) = (self.per_api_key, request.headers().get_one(API_KEY_HEADER)) {
+            limits.push((ClientKey::ApiKey(key.to_string()), max));
+        }
+        if limits.is_empty() {
+            return;
+        }
+
+        let state = self.admit(limits);
+        let is_limited = match state {
+            ClientConcurrency::Limited => true,
+            ClientConcurrency::Admitted(_) => false,
+        };
+
+        // Cached with the request, so that the slots are released when it is dropped
+        request.local_cache(|| Some(state));
+        if is_limited {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(CONCURRENCY_LIMITED_ROUTE).expect("valid concurrency route"));
+        }
+    }
+}
+
+/// Respond to a request that was rewritten by `ClientConcurrencyLimit`
+fn concurrency_limited<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    match request.local_cache(|| None::<ClientConcurrency>) {
+        Some(ClientConcurrency::Limited) => Outcome::Success(
+            Response::build()
+                .status(Status::TooManyRequests)
+                .header(ContentType::JSON)
+                .sized_body(Cursor::new(
+                    "{\"error\":\"concurrency_limit\",\"message\":\"too many requests from this client are in progress\"}",
+                ))
+                .finalize(),
+        ),
+        _ => Outcome::failure(Status::NotFound),
+    }
+}
+
+/// Attaches rocket's template fairing, with the app's template helpers registered:
+///
+/// - `asset_integrity`, which renders the Subresource Integrity hash of a static asset (see
+///   `AssetIntegrity`), or nothing if the asset has no hash
+/// - `csp_nonce`, which renders the request's `CspNonce` when `Settings::csp` is set, or
+///   nothing otherwise
+/// - `csrf_token`, which renders the request's CSRF token when `CsrfTokens` is attached, for
+///   forms to submit in a `csrf_token` field (see `http::csrf`)
+///
+/// The `AssetIntegrity` is also added to managed state, for handlers that set the hashes
+/// themselves (e.g. in a `Link` header).
+pub struct Templates {
+    integrity: AssetIntegrity,
+    reload: bool,
+}
+
+impl Templates {
+    pub fn new(settings: &Settings) -> Templates {
+        Templates {
+            integrity: AssetIntegrity::new(settings),
+            reload: settings.template_reload(),
+        }
+    }
+}
+
+impl Fairing for Templates {
+    fn info(&self) -> Info {
+        Info {
+            name: "Templates",
+            kind: Kind::Attach,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        // rocket_contrib decides whether to reload templates when it is built, reloading them
+        // in debug builds only, so a `template_reload` that disagrees can only be pointed out
+        if self.reload != cfg!(debug_assertions) {
+            if let Some(settings) = AppState::<Settings>::get(&rocket) {
+                settings
+                    .diagnostics()
+                    .warn("fairings", "template_reload has no effect on this build");
+            }
+            tracing::warn!(
+                template_reload = self.reload,
+                "Templates are only reloaded in debug builds, so template_reload has no effect on this build"
+            );
+        }
+
+        let rocket = crate::manage!(rocket, self.integrity.clone());
+        let integrity = self.integrity.clone();
+
+        Ok(rocket.attach(Template::custom(move |engines| {
+            let integrity = integrity.clone();
+            let asset_integrity = move |helper: &Helper,
+                                        _: &Handlebars,
+                                        _: &Context,
+                                        _: &mut RenderContext,
+                                        out: &mut dyn Output|
+                  -> HelperResult {
+                let path = helper.param(0).and_then(|param| param.value().as_str());
+                if let Some(value) = path.and_then(|path| integrity.get(path)) {
+                    out.write(&value)?;
+                }
+                Ok(())
+            };
+
+            let csp_nonce = |_: &Helper,
+                             _: &Handlebars,
+                             _: &Context,
+                             _: &mut RenderContext,
+                             out: &mut dyn Output|
+             -> HelperResult {
+                if let Some(nonce) = CspNonce::current() {
+                    out.write(&nonce)?;
+                }
+                Ok(())
+            };
+
+            let csrf_token = |_: &Helper,
+                              _: &Handlebars,
+                              _: &Context,
+                              _: &mut RenderContext,
+                              out: &mut dyn Output|
+             -> HelperResult {
+                if let Some(token) = csrf::current_csrf_token() {
+                    out.write(&token)?;
+                }
+                Ok(())
+            };
+
+            engines
+                .handlebars
+                .register_helper("asset_integrity", Box::new(asset_integrity));
+            engines.handlebars.register_helper("csp_nonce", Box::new(csp_nonce));
+            engines.handlebars.register_helper("csrf_token", Box::new(csrf_token));
+        })))
+    }
+}
+
+/// Installs a global `tracing` subscriber that writes events to stdout, filtered by the `log`
+/// setting, and logs a line for each response (see `http::log_context` for the fields that it
+/// carries). If another subscriber has already been installed (e.g. by a test harness), it is
+/// left in place.
+///
+/// When `Settings::access_log_file` is set, the line for each response is written to that
+/// file as JSON instead of being emitted as a `tracing` event.
+#[derive(Default)]
+pub struct TracingFairing {
+    access_log: Option<AccessLog>,
+}
+
+impl TracingFairing {
+    pub fn new(settings: &Settings) -> TracingFairing {
+        let rotation = settings
+            .access_log_rotate
+            .as_ref()
+            .and_then(|rotate| rotate.parse().ok());
+
+        TracingFairing {
+            access_log: settings
+                .access_log_file
+                .as_ref()
+                .map(|path| AccessLog::new(path, rotation)),
+        }
+    }
+}
+
+impl Fairing for TracingFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Tracing",
+            kind: Kind::Attach | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let filter = match AppState::<Settings>::get(&rocket) {
+            Some(settings) => settings.parse_log_filter(),
+            None => EnvFilter::new("info"),
+        };
+
+        // Failing here means a subscriber is already installed, which is fine. `log` records
+        // reach the subscriber through `ContextLogger`, so `try_init` (which would install a
+        // `log` logger of its own) isn't used.
+        let subscriber = tracing_subscriber::fmt().with_env_filter(filter).finish();
+        let installed = tracing::subscriber::set_global_default(subscriber).is_ok();
+
+        // Settings are loaded (and checked by `preflight`) before there's a subscriber, so what
+        // they recorded is logged once the subscriber is installed. Listeners share the
+        // diagnostics, so only the instance that installs it logs them.
+        if let Some(settings) = AppState::<Settings>::get(&rocket).filter(|_| installed) {
+            for diagnostic in settings.diagnostics().all() {
+                match diagnostic.severity {
+                    Severity::Warning => tracing::warn!(source = diagnostic.source, "{}", diagnostic.message),
+                    Severity::Info => tracing::info!(source = diagnostic.source, "{}", diagnostic.message),
+                }
+            }
+        }
+
+        if let Some(report) = AppState::<Settings>::get(&rocket).and_then(Settings::env_report) {
+            tracing::info!("{}", report.summary());
+        }
+
+        Ok(rocket)
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        match self.access_log {
+            Some(ref access_log) => {
+                let line = serde_json::json!({
+                    "method": request.method().as_str(),
+                    "path": request.uri().path(),
+                    "status": response.status().code,
+                });
+                access_log.write_line(&line.to_string());
+            }
+            None => tracing::info!(
+                method = %request.method(),
+                path = %request.uri().path(),
+                status = response.status().code,
+                "request completed"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use rocket::local::Client;
+    use rocket::State;
+
+    fn ok<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, "ok")
+    }
+
+    fn client(settings: Settings, fairing: impl Fairing) -> Client {
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(settings)
+            .attach(fairing)
+            .mount("/", vec![Route::new(Method::Get, "/<path..>", ok)]);
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn client_concurrency_ignores_spoofed_addresses() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("max_concurrent_per_ip", 1)
+            .unwrap()
+            .set("trusted_proxies", vec!["10.0.0.1"])
+            .unwrap()
+            .build()
+            .unwrap();
+        let client = client(settings.clone(), ClientConcurrencyLimit::new(&settings));
+        let outsider = "203.0.113.5:4000".parse().unwrap();
+
+        // The slot is held for as long as the response (and so the request) is alive
+        let first = client.get("/slow").remote(outsider).dispatch();
+        assert_eq!(first.status(), Status::Ok);
+
+        let spoofed = client
+            .get("/slow")
+            .remote(outsider)
+            .header(Header::new("X-Real-IP", "198.51.100.7"))
+            .dispatch();
+        assert_eq!(spoofed.status(), Status::TooManyRequests);
+
+        // Clients behind a trusted proxy are told apart by their forwarded address
+        let proxy = "10.0.0.1:4000".parse().unwrap();
+        let behind_proxy = |ip: &str| {
+            client
+                .get("/slow")
+                .remote(proxy)
+                .header(Header::new("X-Real-IP", ip.to_string()))
+                .dispatch()
+        };
+        let a = behind_proxy("198.51.100.7");
+        let b = behind_proxy("198.51.100.8");
+        assert_eq!(a.status(), Status::Ok);
+        assert_eq!(b.status(), Status::Ok);
+        assert_eq!(behind_proxy("198.51.100.7").status(), Status::TooManyRequests);
+
+        drop(first);
+        assert_eq!(client.get("/slow").remote(outsider).dispatch().status(), Status::Ok);
+    }
+
+    fn counted<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let calls = match request.guard::<State<AtomicUsize>>().succeeded() {
+            Some(calls) => calls.fetch_add(1, Ordering::SeqCst) + 1,
+            None => return Outcome::failure(Status::InternalServerError),
+        };
+
+        Outcome::Success(
+            Response::build()
+                .status(Status::Created)
+                .header(Header::new("Set-Cookie", "visited=1"))
+                .sized_body(Cursor::new(format!("call {}", calls)))
+                .finalize(),
+        )
+    }
+
+    fn idempotent_client() -> (Client, Arc<IdempotencyStore>) {
+        let settings = Settings::builder().unwrap().build().unwrap();
+        let fairing = Idempotency::new(&settings);
+        let store = fairing.store();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(AtomicUsize::new(0))
+            .attach(fairing)
+            .mount("/", vec![Route::new(Method::Post, "/orders", counted)]);
+        (Client::new(rocket).unwrap(), store)
+    }
+
+    #[test]
+    fn idempotency_replays_without_cookies() {
+        let (client, _) = idempotent_client();
+        let order = || client.post("/orders").header(Header::new(IDEMPOTENCY_KEY_HEADER, "order-1"));
+
+        let mut first = order().dispatch();
+        assert_eq!(first.status(), Status::Created);
+        assert_eq!(first.headers().get_one("Set-Cookie"), Some("visited=1"));
+        assert_eq!(first.body_string(), Some(String::from("call 1")));
+
+        let mut replayed = order().dispatch();
+        assert_eq!(replayed.status(), Status::Created);
+        assert_eq!(replayed.headers().get_one("Idempotent-Replayed"), Some("true"));
+        assert_eq!(replayed.headers().get_one("Set-Cookie"), None);
+        assert_eq!(replayed.body_string(), Some(String::from("call 1")));
+
+        // Another key runs the handler again
+        let mut other = client
+            .post("/orders")
+            .header(Header::new(IDEMPOTENCY_KEY_HEADER, "order-2"))
+            .dispatch();
+        assert_eq!(other.body_string(), Some(String::from("call 2")));
+    }
+
+    #[test]
+    fn idempotency_keys_are_per_client() {
+        let (client, _) = idempotent_client();
+        let order = |api_key: &str| {
+            client
+                .post("/orders")
+                .header(Header::new(IDEMPOTENCY_KEY_HEADER, "order-1"))
+                .header(Header::new(API_KEY_HEADER, api_key.to_string()))
+                .dispatch()
+        };
+
+        assert_eq!(order("alice").body_string(), Some(String::from("call 1")));
+        let mut bob = order("bob");
+        assert_eq!(bob.body_string(), Some(String::from("call 2")));
+        assert!(!bob.headers().contains("Idempotent-Replayed"));
+        assert_eq!(order("alice").headers().get_one("Idempotent-Replayed"), Some("true"));
+    }
+
+    #[test]
+    fn idempotency_rejects_repeats_in_progress() {
+        let (client, store) = idempotent_client();
+        // As though the first request with this key were still being handled
+        let key = (Method::Post, String::from("/orders"), String::new(), String::from("order-1"));
+        match store.begin(key) {
+            IdempotentRequest::First(_) => (),
+            _ => panic!("expected the key to be new"),
+        }
+
+        let mut repeat = client
+            .post("/orders")
+            .header(Header::new(IDEMPOTENCY_KEY_HEADER, "order-1"))
+            .dispatch();
+        assert_eq!(repeat.status(), Status::Conflict);
+        assert_eq!(
+            repeat.body_string(),
+            Some(String::from("A request with this idempotency key is already in progress"))
+        );
+    }
+
+    fn sleepy<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        std::thread::sleep(Duration::from_millis(5));
+        Outcome::from(request, "ok")
+    }
+
+    #[test]
+    fn timing_header_reports_the_handling_time() {
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .attach(TimingFairing)
+            .mount("/", vec![Route::new(Method::Get, "/sleepy", sleepy)]);
+        let client = Client::new(rocket).unwrap();
+
+        let response = client.get("/sleepy").dispatch();
+        let header = response.headers().get_one(RESPONSE_TIME_HEADER).unwrap();
+        assert!(header.ends_with("ms"), "{}", header);
+        let millis: f64 = header.trim_end_matches("ms").parse().unwrap();
+        assert!(millis >= 5.0, "{}", header);
+
+        // Responses that no route handled are timed too
+        let missing = client.get("/missing").dispatch();
+        assert_eq!(missing.status(), Status::NotFound);
+        assert!(missing.headers().contains(RESPONSE_TIME_HEADER));
+    }
+
+    fn cookie_policy(
+        http_only: bool,
+        secure: bool,
+        same_site: &str,
+        overrides: Vec<(&str, CookieOverride)>,
+    ) -> CookiePolicy {
+        CookiePolicy {
+            http_only,
+            secure,
+            same_site: same_site.to_string(),
+            overrides: overrides
+                .into_iter()
+                .map(|(name, cookie)| (name.to_string(), cookie))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn cookie_policy_adds_missing_attributes() {
+        let policy = cookie_policy(true, true, "lax", vec![]);
+
+        assert_eq!(policy.apply("theme=dark"), "theme=dark; HttpOnly; Secure; SameSite=Lax");
+        assert_eq!(policy.apply("theme=dark; Path=/; "), "theme=dark; Path=/; HttpOnly; Secure; SameSite=Lax");
+        // Attributes that were set are left alone, whatever their case
+        assert_eq!(
+            policy.apply("theme=dark; httponly; SECURE; samesite=strict"),
+            "theme=dark; httponly; SECURE; samesite=strict"
+        );
+
+        let strict = cookie_policy(false, false, "strict", vec![]);
+        assert_eq!(strict.apply("theme=dark"), "theme=dark; SameSite=Strict");
+        let none = cookie_policy(false, false, "none", vec![]);
+        assert_eq!(none.apply("theme=dark"), "theme=dark");
+    }
+
+    #[test]
+    fn cookie_policy_secures_same_site_none() {
+        let policy = cookie_policy(false, false, "lax", vec![]);
+
+        assert_eq!(policy.apply("embed=1; SameSite=None"), "embed=1; SameSite=None; Secure");
+        assert_eq!(policy.apply("embed=1; SameSite=None; Secure"), "embed=1; SameSite=None; Secure");
+    }
+
+    #[test]
+    fn cookie_policy_overrides() {
+        let overrides = vec![
+            (
+                "xsrf_token",
+                CookieOverride {
+                    http_only: Some(false),
+                    ..CookieOverride::default()
+                },
+            ),
+            (
+                "session",
+                CookieOverride {
+                    secure: Some(true),
+                    same_site: Some(String::from("strict")),
+                    ..CookieOverride::default()
+                },
+            ),
+        ];
+        let policy = cookie_policy(true, false, "lax", overrides);
+
+        assert_eq!(policy.apply("xsrf_token=abc"), "xsrf_token=abc; SameSite=Lax");
+        assert_eq!(policy.apply("session=id"), "session=id; HttpOnly; Secure; SameSite=Strict");
+        assert_eq!(policy.apply("theme=dark"), "theme=dark; HttpOnly; SameSite=Lax");
+        // Names are matched exactly
+        assert_eq!(policy.apply("XSRF_TOKEN=abc"), "XSRF_TOKEN=abc; HttpOnly; SameSite=Lax");
+    }
+
+    #[test]
+    fn cookie_policy_preserves_values() {
+        let policy = cookie_policy(true, true, "lax", vec![]);
+
+        for cookie in &[
+            "data=a=b==",
+            "list=one,two,three",
+            "quoted=\"x=1, y=2\"",
+            "encoded=caf%C3%A9%3B%20ok",
+            "expiring=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT; Max-Age=3600",
+            "empty=",
+        ] {
+            let applied = policy.apply(cookie);
+            assert_eq!(applied, format!("{}; HttpOnly; Secure; SameSite=Lax", cookie));
+            // Applying the policy again changes nothing
+            assert_eq!(policy.apply(&applied), applied);
+        }
+    }
+
+    fn set_cookies<'r>(_: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::Success(
+            Response::build()
+                .header_adjoin(Header::new("Set-Cookie", "list=one,two"))
+                .header_adjoin(Header::new("Set-Cookie", "xsrf_token=abc=="))
+                .header_adjoin(Header::new("Set-Cookie", "embed=1; SameSite=None"))
+                .sized_body(Cursor::new("ok"))
+                .finalize(),
+        )
+    }
+
+    #[test]
+    fn cookie_policy_applies_to_every_set_cookie() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("cookie_same_site_default", "strict")
+            .unwrap()
+            .set("cookie_overrides.xsrf_token.http_only", false)
+            .unwrap()
+            .build()
+            .unwrap();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .attach(CookiePolicy::new(&settings))
+            .mount("/", vec![Route::new(Method::Get, "/cookies", set_cookies)]);
+        let client = Client::new(rocket).unwrap();
+
+        let response = client.get("/cookies").dispatch();
+        let cookies: Vec<&str> = response.headers().get("Set-Cookie").collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "list=one,two; HttpOnly; SameSite=Strict",
+                "xsrf_token=abc==; SameSite=Strict",
+                "embed=1; SameSite=None; HttpOnly; Secure",
+            ]
+        );
+    }
+
+    #[test]
+    fn app_enforces_the_cookie_policy() {
+        let app = crate::app::test_support::TestApp::builder()
+            .setting("cookie_secure", "true")
+            .mount("/", vec![Route::new(Method::Get, "/cookies", set_cookies)])
+            .build()
+            .unwrap();
+
+        let response = app.client().get("/cookies").dispatch();
+        let cookies: Vec<&str> = response.headers().get("Set-Cookie").collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "list=one,two; HttpOnly; Secure; SameSite=Lax",
+                "xsrf_token=abc==; HttpOnly; Secure; SameSite=Lax",
+                "embed=1; SameSite=None; HttpOnly; Secure",
+            ]
+        );
+    }
+
+    const FINGERPRINTED: &str = r"\.[0-9a-f]{8,}\.(js|css)$";
+
+    fn static_cache_settings(static_route: &str) -> Settings {
+        Settings::builder()
+            .unwrap()
+            .set("immutable_assets_pattern", FINGERPRINTED)
+            .unwrap()
+            .set("static_max_age", "10m")
+            .unwrap()
+            .set("static_route", static_route)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn fingerprinted_paths_are_immutable() {
+        let cache = StaticCacheControl::new(&static_cache_settings("/static"));
+        assert_eq!(cache.value_for("/static/app.3f9a1c2e.js"), "public, max-age=31536000, immutable");
+        assert_eq!(cache.value_for("/static/css/site.0123456789abcdef.css"), "public, max-age=31536000, immutable");
+
+        for path in &["/static/app.js", "/static/app.3f9a1c.js", "/static/app.3f9a1c2e.js.map", "/static/logo.png"] {
+            assert_eq!(cache.value_for(path), "public, max-age=600", "{}", path);
+        }
+    }
+
+    fn cached_elsewhere<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let response = Response::build()
+            .raw_header("Cache-Control", "no-cache")
+            .sized_body(Cursor::new("revalidate me"))
+            .finalize();
+        Outcome::from(request, response)
+    }
+
+    fn missing<'r>(_: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::failure(Status::NotFound)
+    }
+
+    fn static_cache_client(settings: Settings) -> Client {
+        let fairing = StaticCacheControl::new(&settings);
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(settings)
+            .attach(fairing)
+            .mount(
+                "/",
+                vec![
+                    Route::ranked(1, Method::Get, "/assets/own.3f9a1c2e.js", cached_elsewhere),
+                    Route::ranked(1, Method::Get, "/assets/gone.3f9a1c2e.js", missing),
+                    Route::ranked(2, Method::Get, "/<path..>", ok),
+                ],
+            );
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn static_cache_control_matches_request_paths() {
+        let client = static_cache_client(static_cache_settings("/assets"));
+        let cache_control = |path: &str| {
+            client
+                .get(path)
+                .dispatch()
+                .headers()
+                .get_one("Cache-Control")
+                .map(String::from)
+        };
+
+        assert_eq!(
+            cache_control("/assets/app.3f9a1c2e.js"),
+            Some(String::from("public, max-age=31536000, immutable"))
+        );
+        assert_eq!(cache_control("/assets/app.js"), Some(String::from("public, max-age=600")));
+        // Only static files are covered, even when their names look fingerprinted
+        assert_eq!(cache_control("/downloads/app.3f9a1c2e.js"), None);
+        assert_eq!(cache_control("/assetsish/app.3f9a1c2e.js"), None);
+        // Responses that set their own header, and errors, are left alone
+        assert_eq!(cache_control("/assets/own.3f9a1c2e.js"), Some(String::from("no-cache")));
+        assert_eq!(cache_control("/assets/gone.3f9a1c2e.js"), None);
+    }
+
+    #[test]
+    fn app_marks_fingerprinted_assets_immutable() {
+        let app = crate::app::test_support::TestApp::builder()
+            .setting("immutable_assets_pattern", FINGERPRINTED)
+            .setting("static_max_age", "1h")
+            .static_file("app.3f9a1c2e.js", "console.log(\"built\");")
+            .static_file("logo.png", "not really a png")
+            .build()
+            .unwrap();
+
+        let fingerprinted = app.client().get("/static/app.3f9a1c2e.js").dispatch();
+        assert_eq!(fingerprinted.status(), Status::Ok);
+        assert_eq!(
+            fingerprinted.headers().get_one("Cache-Control"),
+            Some("public, max-age=31536000, immutable")
+        );
+
+        let plain = app.client().get("/static/logo.png").dispatch();
+        assert_eq!(plain.status(), Status::Ok);
+        assert_eq!(plain.headers().get_one("Cache-Control"), Some("public, max-age=3600"));
+    }
+
+    #[test]
+    fn normalize_path_collapses_and_resolves_segments() {
+        let cases = [
+            ("/", Some("/")),
+            ("/foo/bar", Some("/foo/bar")),
+            ("//foo///bar", Some("/foo/bar")),
+            ("/foo/./bar", Some("/foo/bar")),
+            ("/foo/baz/../bar", Some("/foo/bar")),
+            ("/foo/..", Some("/")),
+            ("/foo/%2e%2e/bar", Some("/bar")),
+            ("/foo/%2E%2e/bar", Some("/bar")),
+            ("/foo/.%2E/bar", Some("/bar")),
+            ("/Foo/BAR", Some("/Foo/BAR")),
+            ("/foo/bar/", Some("/foo/bar/")),
+            ("/foo/bar//", Some("/foo/bar/")),
+            ("/foo/bar/.", Some("/foo/bar/")),
+            ("/foo/bar/..", Some("/foo/")),
+            ("/..", None),
+            ("/foo/../..", None),
+            ("/%2e%2e/etc/passwd", None),
+            ("/foo/%2E%2E/%2e%2E/etc", None),
+        ];
+
+        for (path, expected) in cases.iter() {
+            assert_eq!(normalize_path(path).as_ref().map(String::as_str), *expected, "{}", path);
+        }
+    }
+
+    fn echo_uri<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, request.uri().to_string())
+    }
+
+    fn normalize_client(mode: &str) -> Client {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("path_normalize", mode)
+            .unwrap()
+            .build()
+            .unwrap();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .attach(PathNormalize::new(&settings))
+            .manage(settings)
+            .mount(
+                "/",
+                vec![
+                    Route::new(Method::Get, "/<path..>", echo_uri),
+                    Route::new(Method::Post, "/<path..>", echo_uri),
+                ],
+            );
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn path_normalize_rewrites_requests() {
+        let client = normalize_client("rewrite");
+
+        let mut rewritten = client.get("/foo//bar/../baz?x=1").dispatch();
+        assert_eq!(rewritten.status(), Status::Ok);
+        assert_eq!(rewritten.body_string(), Some(String::from("/foo/baz?x=1")));
+
+        let mut encoded = client.post("/a/%2E%2e/b/./").dispatch();
+        assert_eq!(encoded.body_string(), Some(String::from("/b/")));
+
+        let mut canonical = client.get("/Foo/Bar").dispatch();
+        assert_eq!(canonical.body_string(), Some(String::from("/Foo/Bar")));
+
+        assert_eq!(client.get("/foo/../../etc/passwd").dispatch().status(), Status::BadRequest);
+        assert_eq!(client.get("/%2e%2e/etc/passwd").dispatch().status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn path_normalize_redirects_requests() {
+        let client = normalize_client("redirect");
+
+        let get = client.get("/foo//bar/.?x=1").dispatch();
+        assert_eq!(get.status(), Status::MovedPermanently);
+        assert_eq!(get.headers().get_one("Location"), Some("/foo/bar/?x=1"));
+
+        // Other methods are redirected with a status that keeps the method and body
+        let post = client.post("/foo/%2e%2e/bar").dispatch();
+        assert_eq!(post.status(), Status::PermanentRedirect);
+        assert_eq!(post.headers().get_one("Location"), Some("/bar"));
+
+        let mut canonical = client.get("/foo/bar").dispatch();
+        assert_eq!(canonical.status(), Status::Ok);
+        assert_eq!(canonical.body_string(), Some(String::from("/foo/bar")));
+
+        assert_eq!(client.get("/..").dispatch().status(), Status::BadRequest);
+    }
+
+    fn fails<'r>(_: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::failure(Status::InternalServerError)
+    }
+
+    #[test]
+    fn concurrency_limit_releases_every_slot() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("max_concurrent_requests", 2)
+            .unwrap()
+            .set("concurrency_exempt_prefixes", vec!["/health"])
+            .unwrap()
+            .build()
+            .unwrap();
+        let concurrency = Concurrency::new(&settings);
+        let in_flight = concurrency.in_flight.clone();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(settings)
+            .attach(concurrency)
+            .mount(
+                "/",
+                vec![
+                    Route::new(Method::Get, "/<path..>", ok),
+                    Route::new(Method::Get, "/fail", fails),
+                ],
+            );
+        let client = Client::new(rocket).unwrap();
+
+        // Each response holds its request, and so its slot, until it is dropped
+        let first = client.get("/posts").dispatch();
+        let failed = client.get("/fail").dispatch();
+        assert_eq!(first.status(), Status::Ok);
+        assert_eq!(failed.status(), Status::InternalServerError);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 2);
+
+        let overloaded = client.get("/posts").dispatch();
+        assert_eq!(overloaded.status(), Status::ServiceUnavailable);
+        assert_eq!(overloaded.headers().get_one("Retry-After"), Some("1"));
+        drop(overloaded);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 2);
+
+        // Exempt paths are served even when the app is saturated, and aren't counted
+        assert_eq!(client.get("/health").dispatch().status(), Status::Ok);
+        assert_eq!(client.get("//health/db").dispatch().status(), Status::Ok);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 2);
+
+        // An error response gives its slot back, like any other
+        drop(failed);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 1);
+        let freed = client.get("/posts").dispatch();
+        assert_eq!(freed.status(), Status::Ok);
+
+        drop(freed);
+        drop(first);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    /// Holds requests to `/parked` until it is opened, so that several can be in flight at once
+    #[derive(Default)]
+    struct Park {
+        arrived: AtomicUsize,
+        open: Mutex<bool>,
+        opened: std::sync::Condvar,
+    }
+
+    fn parked<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let park = match request.guard::<State<Park>>().succeeded() {
+            Some(park) => park,
+            None => return Outcome::failure(Status::InternalServerError),
+        };
+
+        park.arrived.fetch_add(1, Ordering::SeqCst);
+        let mut open = park.open.lock().unwrap();
+        while !*open {
+            open = park.opened.wait(open).unwrap();
+        }
+        Outcome::from(request, "ok")
+    }
+
+    /// Wait for `done` to return true, failing the test if it takes more than a few seconds
+    fn wait_until(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn client_concurrency_limits_simultaneous_requests() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("max_concurrent_per_ip", 2)
+            .unwrap()
+            .set("concurrency_exempt_prefixes", vec!["/health"])
+            .unwrap()
+            .build()
+            .unwrap();
+        let limit = ClientConcurrencyLimit::new(&settings);
+        let counts = limit.counts.clone();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(settings)
+            .manage(Park::default())
+            .attach(limit)
+            .mount(
+                "/",
+                vec![
+                    Route::new(Method::Get, "/parked", parked),
+                    Route::new(Method::Get, "/<path..>", ok),
+                ],
+            );
+        let shared = Arc::new(Client::new(rocket).unwrap());
+        let state = || shared.rocket().state::<Park>().unwrap();
+
+        let finished = Arc::new(AtomicUsize::new(0));
+        let requests: Vec<_> = (0..3)
+            .map(|_| {
+                let shared = shared.clone();
+                let finished = finished.clone();
+                std::thread::spawn(move || {
+                    let status = shared
+                        .get("/parked")
+                        .remote("203.0.113.5:4000".parse().unwrap())
+                        .dispatch()
+                        .status();
+                    finished.fetch_add(1, Ordering::SeqCst);
+                    status
+                })
+            })
+            .collect();
+
+        // Two requests are parked, and the third is turned away without reaching the handler
+        wait_until(|| state().arrived.load(Ordering::SeqCst) == 2 && finished.load(Ordering::SeqCst) == 1);
+        let in_flight = || counts.lock().unwrap().values().sum::<usize>();
+        assert_eq!(in_flight(), 2);
+
+        // Exempt paths are served while the client is at its limit, and aren't counted
+        let health = shared.get("/health").remote("203.0.113.5:4000".parse().unwrap()).dispatch();
+        assert_eq!(health.status(), Status::Ok);
+        drop(health);
+        assert_eq!(in_flight(), 2);
+
+        *state().open.lock().unwrap() = true;
+        state().opened.notify_all();
+
+        let mut statuses: Vec<u16> = requests
+            .into_iter()
+            .map(|request| request.join().unwrap().code)
+            .collect();
+        statuses.sort();
+        assert_eq!(statuses, vec![200, 200, 429]);
+
+        // Every slot was released, and clients with nothing in flight are forgotten
+        assert_eq!(in_flight(), 0);
+        assert!(counts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn client_concurrency_limits_each_api_key() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("max_concurrent_per_api_key", 1)
+            .unwrap()
+            .build()
+            .unwrap();
+        let limit = ClientConcurrencyLimit::new(&settings);
+        let counts = limit.counts.clone();
+        let client = client(settings, limit);
+        let with_key = |key: &str| {
+            client
+                .get("/slow")
+                .header(Header::new(API_KEY_HEADER, key.to_string()))
+                .dispatch()
+        };
+
+        let first = with_key("one");
+        assert_eq!(first.status(), Status::Ok);
+
+        let mut limited = with_key("one");
+        assert_eq!(limited.status(), Status::TooManyRequests);
+        assert!(limited.body_string().unwrap().contains("\"concurrency_limit\""));
+        drop(limited);
+
+        // Other keys, and requests without a key, have their own limits or none
+        let other = with_key("two");
+        assert_eq!(other.status(), Status::Ok);
+        assert_eq!(client.get("/slow").dispatch().status(), Status::Ok);
+        assert_eq!(counts.lock().unwrap().values().sum::<usize>(), 2);
+
+        drop(first);
+        assert_eq!(with_key("one").status(), Status::Ok);
+
+        drop(other);
+        assert!(counts.lock().unwrap().is_empty());
+    }
+
+    fn framed<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let response = Response::build()
+            .header(Header::new("X-Frame-Options", "SAMEORIGIN"))
+            .sized_body(Cursor::new("framed"))
+            .finalize();
+        Outcome::from(request, response)
+    }
+
+    fn security_headers(app: &crate::app::test_support::TestApp, path: &str) -> Vec<Option<String>> {
+        let response = app.client().get(path).dispatch();
+        SECURITY_HEADERS
+            .iter()
+            .map(|(name, _, _)| response.headers().get_one(name).map(String::from))
+            .collect()
+    }
+
+    #[test]
+    fn security_headers_are_added_to_every_response() {
+        let app = crate::app::test_support::TestApp::builder()
+            .mount("/", vec![Route::new(Method::Get, "/ok", ok), Route::new(Method::Get, "/framed", framed)])
+            .build()
+            .unwrap();
+        let defaults = vec![
+            Some(String::from("nosniff")),
+            Some(String::from("DENY")),
+            Some(String::from("strict-origin-when-cross-origin")),
+            Some(String::from("camera=(), microphone=()")),
+        ];
+
+        assert_eq!(security_headers(&app, "/ok"), defaults);
+        // Including the responses from catchers
+        assert_eq!(security_headers(&app, "/missing"), defaults);
+
+        // Headers that the handler set are left alone
+        let response = app.client().get("/framed").dispatch();
+        assert_eq!(response.headers().get_one("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(response.headers().get_one("X-Content-Type-Options"), Some("nosniff"));
+    }
+
+    #[test]
+    fn security_headers_can_be_overridden_or_disabled() {
+        let app = crate::app::test_support::TestApp::builder()
+            .extra("xframe_options", "SAMEORIGIN")
+            .extra("referrer_policy", "no-referrer")
+            .extra("permissions_policy", "")
+            .mount("/", vec![Route::new(Method::Get, "/ok", ok)])
+            .build()
+            .unwrap();
+        assert_eq!(
+            security_headers(&app, "/ok"),
+            vec![
+                Some(String::from("nosniff")),
+                Some(String::from("SAMEORIGIN")),
+                Some(String::from("no-referrer")),
+                None,
+            ]
+        );
+
+        let disabled = crate::app::test_support::TestApp::builder()
+            .setting("security_headers_disabled", "true")
+            .mount("/", vec![Route::new(Method::Get, "/ok", ok)])
+            .build()
+            .unwrap();
+        assert_eq!(security_headers(&disabled, "/ok"), vec![None, None, None, None]);
+    }
+}
@@ -0,0 +1,1597 @@
+use crate::app::{Settings, DEFAULT_MAX_BODY_BYTES, DEFAULT_PER_PAGE, MAX_PER_PAGE};
+use crate::http::keyring::KeyRing;
+use crate::http::negotiation;
+use crate::http::sessions::session_cookie;
+
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::request::{self, FromRequest, Request, State};
+use rocket::response::{self, Redirect, Response};
+use rocket::{Catcher, Outcome};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The body size limit used for JSON data when none has been configured, matching rocket
+const DEFAULT_JSON_LIMIT: u64 = 1 << 20;
+
+/// The request deadline used when `Settings` aren't being managed, matching its default
+const DEFAULT_REQUEST_DEADLINE_SECS: u64 = 30;
+
+/// The range of items that a list endpoint should return, parsed from the `page` and
+/// `per_page` query params. Pages are numbered from 1.
+///
+/// When `per_page` is not given, `Settings::per_page_default` is used, and any value larger
+/// than `Settings::per_page_max` is clamped to that maximum. Values that are zero, negative
+/// or not numbers are rejected with `400 Bad Request`.
+///
+/// # Examples
+///
+/// ```
+/// #[get("/posts")]
+/// fn list_posts(pagination: Pagination) -> Json<Vec<Post>> {
+///     Json(posts::list(pagination.offset, pagination.limit))
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    /// The number of items to skip
+    pub offset: u64,
+    /// The maximum number of items to return
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaginationError {
+    InvalidPage,
+    InvalidPerPage,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Pagination {
+    type Error = PaginationError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let settings = request.guard::<State<Settings>>().succeeded();
+        let per_page_max = settings.as_ref().map(|s| s.per_page_max).unwrap_or(MAX_PER_PAGE);
+        let per_page_default = settings.as_ref().map(|s| s.per_page_default).unwrap_or(DEFAULT_PER_PAGE);
+
+        let page = match positive_query_value(request, "page") {
+            Ok(page) => page.unwrap_or(1),
+            Err(()) => return Outcome::Failure((Status::BadRequest, PaginationError::InvalidPage)),
+        };
+        let per_page = match positive_query_value(request, "per_page") {
+            Ok(per_page) => per_page.unwrap_or(per_page_default).min(per_page_max),
+            Err(()) => return Outcome::Failure((Status::BadRequest, PaginationError::InvalidPerPage)),
+        };
+
+        Outcome::Success(Pagination {
+            offset: u64::from(page - 1) * u64::from(per_page),
+            limit: per_page,
+        })
+    }
+}
+
+/// Read a query param that must be a number greater than zero, if it is present
+fn positive_query_value(request: &Request, name: &str) -> Result<Option<u32>, ()> {
+    match request.get_query_value::<u32>(name) {
+        None => Ok(None),
+        Some(Ok(value)) if value > 0 => Ok(Some(value)),
+        Some(_) => Err(()),
+    }
+}
+
+/// A JSON Merge Patch ([RFC 7396](https://tools.ietf.org/html/rfc7396)) request body, for
+/// partially updating a `T`.
+///
+/// Fields that are absent from the patch are left untouched, fields that are explicitly
+/// `null` are removed (which clears an `Option` field), nested objects are merged recursively
+/// and any other value (including arrays) replaces the existing value entirely.
+///
+/// The body must be sent as `application/merge-patch+json`, or as `application/json` when
+/// `Settings::merge_patch_accept_json` is enabled; other content types are rejected with
+/// `415 Unsupported Media Type`. Bodies are limited by the "json" data limit, and larger
+/// bodies are rejected with `413 Payload Too Large` rather than being cut short.
+///
+/// # Examples
+///
+/// ```
+/// #[patch("/posts/<id>", data = "<patch>")]
+/// fn update_post(id: Uuid, patch: MergePatch<Post>) -> Result<Json<Post>, Status> {
+///     let mut post = posts::find(id).ok_or(Status::NotFound)?;
+///     patch.apply_to(&mut post).map_err(|_| Status::UnprocessableEntity)?;
+///     Ok(Json(posts::save(post)))
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MergePatch<T> {
+    patch: Value,
+    target: PhantomData<fn() -> T>,
+}
+
+#[derive(Debug)]
+pub enum MergePatchError {
+    UnsupportedMediaType,
+    /// The body was larger than the given limit, in bytes
+    TooLarge(u64),
+    Io(io::Error),
+    Parse(serde_json::Error),
+}
+
+impl<T> MergePatch<T> {
+    pub fn new(patch: Value) -> MergePatch<T> {
+        MergePatch {
+            patch,
+            target: PhantomData,
+        }
+    }
+
+    /// The names of the top level fields that this patch sets or clears
+    pub fn fields(&self) -> Vec<&str> {
+        match self.patch {
+            Value::Object(ref fields) => fields.keys().map(String::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn patch(&self) -> &Value {
+        &self.patch
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> MergePatch<T> {
+    /// Apply this patch to `target`. If the patched value can't be converted back into a `T`
+    /// (e.g. a required field was set to `null`), `target` is left unchanged.
+    pub fn apply_to(&self, target: &mut T) -> Result<(), serde_json::Error> {
+        let mut value = serde_json::to_value(&*target)?;
+        merge(&mut value, &self.patch);
+        *target = serde_json::from_value(value)?;
+        Ok(())
+    }
+}
+
+/// Merge `patch` into `target` following the algorithm in RFC 7396
+fn merge(target: &mut Value, patch: &Value) {
+    if let Value::Object(ref patch_fields) = *patch {
+        if !target.is_object() {
+            *target = Value::Object(Map::new());
+        }
+
+        if let Value::Object(ref mut target_fields) = *target {
+            for (key, value) in patch_fields {
+                if value.is_null() {
+                    target_fields.remove(key);
+                } else {
+                    merge(target_fields.entry(key.as_str()).or_insert(Value::Null), value);
+                }
+            }
+        }
+    } else {
+        *target = patch.clone();
+    }
+}
+
+impl<T> FromDataSimple for MergePatch<T> {
+    type Error = MergePatchError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let accept_json = request
+            .guard::<State<Settings>>()
+            .succeeded()
+            .map(|settings| settings.merge_patch_accept_json)
+            .unwrap_or(false);
+
+        let acceptable = request
+            .content_type()
+            .map(|ct| (ct.top() == "application" && ct.sub() == "merge-patch+json") || (accept_json && ct.is_json()))
+            .unwrap_or(false);
+
+        if !acceptable {
+            return Outcome::Failure((Status::UnsupportedMediaType, MergePatchError::UnsupportedMediaType));
+        }
+
+        let limit = request.limits().get("json").unwrap_or(DEFAULT_JSON_LIMIT);
+        let mut body = Vec::new();
+        if let Err(e) = data.open().take(limit + 1).read_to_end(&mut body) {
+            return Outcome::Failure((Status::BadRequest, MergePatchError::Io(e)));
+        }
+        if body.len() as u64 > limit {
+            return Outcome::Failure((Status::PayloadTooLarge, MergePatchError::TooLarge(limit)));
+        }
+
+        match serde_json::from_slice(&body) {
+            Ok(patch) => Outcome::Success(MergePatch::new(patch)),
+            Err(e) => Outcome::Failure((Status::BadRequest, MergePatchError::Parse(e))),
+        }
+    }
+}
+
+/// A JSON request body, like `rocket_contrib::json::Json`, but with errors that tell the
+/// client what was wrong:
+///
+///  - Content types other than `application/json` (optionally with `charset=utf-8`) get
+///    `415 Unsupported Media Type`
+///  - Bodies over the `json` limit get `413 Payload Too Large`, naming the limit
+///  - Bodies that aren't valid JSON, or don't match `T`, get `422 Unprocessable Entity`, with
+///    the path to the value that couldn't be read (e.g. `items[3].qty`)
+///
+/// The error bodies are JSON, written by the catchers from `json_catchers`, which the app
+/// registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrictJson<T>(pub T);
+
+impl<T> StrictJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for StrictJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub enum StrictJsonError {
+    UnsupportedMediaType,
+    TooLarge(u64),
+    Io(io::Error),
+    Parse(serde_path_to_error::Error<serde_json::Error>),
+}
+
+/// The details of why a `StrictJson` body was rejected, kept for the error catchers
+#[derive(Debug, Clone, Default)]
+struct JsonRejection(Option<Value>);
+
+impl<T: DeserializeOwned> FromDataSimple for StrictJson<T> {
+    type Error = StrictJsonError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let reject = |status: Status, details: Value, error: StrictJsonError| {
+            request.local_cache(|| JsonRejection(Some(details)));
+            Outcome::Failure((status, error))
+        };
+
+        let acceptable = request
+            .content_type()
+            .map(|ct| {
+                ct.is_json()
+                    && ct
+                        .params()
+                        .all(|(name, value)| !name.eq_ignore_ascii_case("charset") || value.eq_ignore_ascii_case("utf-8"))
+            })
+            .unwrap_or(false);
+
+        if !acceptable {
+            let details = json!({
+                "error": "unsupported_media_type",
+                "message": "expected a body with content type application/json; charset=utf-8",
+            });
+            return reject(Status::UnsupportedMediaType, details, StrictJsonError::UnsupportedMediaType);
+        }
+
+        let limit = request.limits().get("json").unwrap_or(DEFAULT_JSON_LIMIT);
+        let mut body = Vec::new();
+        if let Err(e) = data.open().take(limit + 1).read_to_end(&mut body) {
+            let details = json!({ "error": "invalid_body", "message": e.to_string() });
+            return reject(Status::BadRequest, details, StrictJsonError::Io(e));
+        }
+
+        if body.len() as u64 > limit {
+            let details = json!({
+                "error": "payload_too_large",
+                "message": format!("the request body is larger than the limit of {} bytes", limit),
+                "limit": limit,
+            });
+            return reject(Status::PayloadTooLarge, details, StrictJsonError::TooLarge(limit));
+        }
+
+        let mut deserializer = serde_json::Deserializer::from_slice(&body);
+        match serde_path_to_error::deserialize(&mut deserializer) {
+            Ok(value) => Outcome::Success(StrictJson(value)),
+            Err(e) => {
+                let details = json!({
+                    "error": "invalid_json",
+                    "message": e.inner().to_string(),
+                    "path": e.path().to_string(),
+                });
+                reject(Status::UnprocessableEntity, details, StrictJsonError::Parse(e))
+            }
+        }
+    }
+}
+
+/// The whole request body, read into memory (up to `Settings::max_body_size()`, or
+/// `413 Payload Too Large`) so that it can be read more than once, e.g. to check a signature
+/// over the raw bytes and then parse them as JSON. The body is kept in the request's local
+/// cache, so request guards and fairings that run after this guard can read it again with
+/// `RequestBody::cached`.
+///
+/// # Examples
+///
+/// ```
+/// #[post("/webhooks", data = "<body>")]
+/// fn webhook(body: RequestBody, signature: Signature) -> Result<Status, Status> {
+///     signature.verify(&body).map_err(|_| Status::Unauthorized)?;
+///     let event: Event = body.json().map_err(|_| Status::UnprocessableEntity)?;
+///     events::handle(event);
+///     Ok(Status::NoContent)
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestBody(pub bytes::Bytes);
+
+/// The body read by `RequestBody`, kept in the request's local cache
+#[derive(Debug, Clone, Default)]
+struct CachedBody(Option<bytes::Bytes>);
+
+impl RequestBody {
+    /// The body of `request`, if a `RequestBody` guard has already read it. Cloning `Bytes`
+    /// doesn't copy the body.
+    pub fn cached(request: &Request) -> Option<bytes::Bytes> {
+        request.local_cache(CachedBody::default).0.clone()
+    }
+
+    /// Parse the body as JSON
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_slice(&self.0)
+    }
+
+    pub fn into_inner(self) -> bytes::Bytes {
+        self.0
+    }
+}
+
+impl Deref for RequestBody {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestBodyError {
+    TooLarge(u64),
+    Io(io::Error),
+}
+
+impl FromDataSimple for RequestBody {
+    type Error = RequestBodyError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let limit = request
+            .guard::<State<Settings>>()
+            .succeeded()
+            .map(|settings| settings.max_body_size())
+            .unwrap_or(DEFAULT_MAX_BODY_BYTES);
+
+        // Read one byte over the limit, to tell a body that fits exactly from one that's over
+        let mut body = Vec::new();
+        if let Err(e) = data.open().take(limit + 1).read_to_end(&mut body) {
+            return Outcome::Failure((Status::BadRequest, RequestBodyError::Io(e)));
+        }
+        if body.len() as u64 > limit {
+            return Outcome::Failure((Status::PayloadTooLarge, RequestBodyError::TooLarge(limit)));
+        }
+
+        let body = bytes::Bytes::from(body);
+        let cached = request.local_cache(|| CachedBody(Some(body.clone())));
+        Outcome::Success(RequestBody(cached.0.clone().unwrap_or(body)))
+    }
+}
+
+/// Catchers that send the details of a `StrictJson` rejection as a JSON body. Errors that
+/// weren't caused by `StrictJson` get a JSON body with the reason for the status.
+pub fn json_catchers() -> Vec<Catcher> {
+    vec![
+        Catcher::new(400, bad_request),
+        Catcher::new(413, payload_too_large),
+        Catcher::new(415, unsupported_media_type),
+        Catcher::new(422, unprocessable_entity),
+    ]
+}
+
+// Catchers aren't told which status they're handling, so each needs its own function
+fn bad_request<'r>(request: &'r Request) -> response::Result<'r> {
+    json_rejection(Status::BadRequest, request)
+}
+
+fn payload_too_large<'r>(request: &'r Request) -> response::Result<'r> {
+    json_rejection(Status::PayloadTooLarge, request)
+}
+
+fn unsupported_media_type<'r>(request: &'r Request) -> response::Result<'r> {
+    json_rejection(Status::UnsupportedMediaType, request)
+}
+
+fn unprocessable_entity<'r>(request: &'r Request) -> response::Result<'r> {
+    json_rejection(Status::UnprocessableEntity, request)
+}
+
+fn json_rejection<'r>(status: Status, request: &'r Request) -> response::Result<'r> {
+    let details = match request.local_cache(JsonRejection::default) {
+        JsonRejection(Some(details)) => details.clone(),
+        JsonRejection(None) => json!({ "error": status.reason }),
+    };
+
+    Response::build()
+        .status(status)
+        .header(ContentType::JSON)
+        .sized_body(Cursor::new(details.to_string()))
+        .ok()
+}
+
+/// The header that API keys are read from by the `ApiKey` guard
+pub const API_KEY_HEADER: &'static str = "X-Api-Key";
+
+/// The name of the private cookie that holds the session ID read by the `Session` guard
+pub const SESSION_COOKIE: &'static str = "session";
+
+/// A request that provided one of `Settings::api_keys` in the `X-Api-Key` header. Requests
+/// with a missing or unknown key are rejected with `401 Unauthorized`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiKey {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let provided = match request.headers().get_one(API_KEY_HEADER) {
+            Some(key) => key,
+            None => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        let settings = match request.guard::<State<Settings>>() {
+            Outcome::Success(settings) => settings,
+            _ => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        if settings
+            .api_keys
+            .iter()
+            .any(|key| constant_time_eq(key.as_bytes(), provided.as_bytes()))
+        {
+            Outcome::Success(ApiKey(String::from(provided)))
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// A request that provided HTTP basic auth credentials matching one of
+/// `Settings::basic_auth_users`. Requests without valid credentials are rejected with
+/// `401 Unauthorized`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicAuth {
+    pub username: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for BasicAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let credentials = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| {
+                let mut parts = header.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(encoded)) if scheme.eq_ignore_ascii_case("basic") => {
+                        base64::decode(encoded.trim()).ok()
+                    }
+                    _ => None,
+                }
+            })
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        let mut parts = credentials.splitn(2, ':');
+        let (username, password) = match (parts.next(), parts.next()) {
+            (Some(username), Some(password)) => (username, password),
+            _ => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        let authorised = request
+            .guard::<State<Settings>>()
+            .succeeded()
+            .and_then(|settings| {
+                settings
+                    .basic_auth_users
+                    .get(username)
+                    .map(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes()))
+            })
+            .unwrap_or(false);
+
+        if authorised {
+            Outcome::Success(BasicAuth {
+                username: String::from(username),
+            })
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// A request with a session, identified by the value of the private `session` cookie. The
+/// cookie can be sealed with the current secret key or any of the previous keys.
+/// Requests without a valid session cookie are rejected with `401 Unauthorized`. Data for the
+/// session is kept server-side, and read with the `SessionData` guard in `http::sessions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Session {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let cookie = match request.guard::<State<KeyRing>>() {
+            Outcome::Success(keyring) => {
+                keyring.get_private_with(&mut request.cookies(), SESSION_COOKIE, |id| session_cookie(request, id))
+            }
+            _ => request.cookies().get_private(SESSION_COOKIE),
+        };
+
+        match cookie {
+            Some(cookie) => Outcome::Success(Session(String::from(cookie.value()))),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Compare two byte strings in time that depends only on their lengths, so that secrets
+/// can't be discovered by timing how long comparisons take
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The languages that the client prefers, parsed from the `Accept-Language` header as
+/// `(language tag, quality)` pairs and sorted from most to least preferred. Languages with a
+/// quality of `0` are not acceptable to the client, and are left out.
+///
+/// When the header is missing (or contains no acceptable languages), the client is assumed to
+/// prefer English.
+///
+/// # Examples
+///
+/// ```
+/// // Accept-Language: en-US,en;q=0.9,fr;q=0.8
+/// Language(vec![
+///     ("en-US".into(), 1.0),
+///     ("en".into(), 0.9),
+///     ("fr".into(), 0.8),
+/// ])
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Language(pub Vec<(String, f32)>);
+
+impl Language {
+    /// Parse the value of an `Accept-Language` header
+    pub fn parse(header: &str) -> Language {
+        let mut languages: Vec<(String, f32)> = negotiation::parse_weighted(header)
+            .into_iter()
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+
+        // A stable sort keeps languages with the same quality in the order that they were sent
+        languages.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+        if languages.is_empty() {
+            Language::default()
+        } else {
+            Language(languages)
+        }
+    }
+
+    /// The language tag that the client most prefers
+    pub fn best(&self) -> &str {
+        self.0.first().map(|(tag, _)| tag.as_str()).unwrap_or("en")
+    }
+}
+
+impl Default for Language {
+    fn default() -> Language {
+        Language(vec![(String::from("en"), 1.0)])
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Language {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("Accept-Language") {
+            Some(header) => Outcome::Success(Language::parse(header)),
+            None => Outcome::Success(Language::default()),
+        }
+    }
+}
+
+/// The header that request IDs are read from by the `RequestId` guard
+pub const REQUEST_ID_HEADER: &'static str = "X-Request-Id";
+
+/// An ID for the current request, taken from the `X-Request-Id` header (e.g. when set by a
+/// reverse proxy) or generated otherwise. The ID is the same for every use within a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of(request: &Request) -> RequestId {
+        request
+            .local_cache(|| {
+                let id = request
+                    .headers()
+                    .get_one(REQUEST_ID_HEADER)
+                    .map(String::from)
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
+                RequestId(id)
+            })
+            .clone()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(request))
+    }
+}
+
+/// Case insensitive fragments of `User-Agent` headers that identify bots and other automated
+/// clients
+pub const BOT_USER_AGENT_MARKERS: [&'static str; 10] = [
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "curl",
+    "wget",
+    "python-requests",
+    "headlesschrome",
+    "facebookexternalhit",
+    "monitor",
+];
+
+/// Whether a `User-Agent` header value looks like it was sent by a bot
+pub fn is_bot_user_agent(user_agent: &str) -> bool {
+    let user_agent = user_agent.to_lowercase();
+    BOT_USER_AGENT_MARKERS
+        .iter()
+        .any(|marker| user_agent.contains(marker))
+}
+
+/// The client's `User-Agent` header, and whether it looks like a bot (see
+/// `is_bot_user_agent`). Requests without the header get a raw value of `"unknown"`, and
+/// aren't treated as bots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAgent {
+    pub raw: String,
+    pub is_bot: bool,
+}
+
+impl UserAgent {
+    pub fn parse(header: &str) -> UserAgent {
+        UserAgent {
+            raw: String::from(header),
+            is_bot: is_bot_user_agent(header),
+        }
+    }
+}
+
+impl Default for UserAgent {
+    fn default() -> UserAgent {
+        UserAgent {
+            raw: String::from("unknown"),
+            is_bot: false,
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for UserAgent {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("User-Agent") {
+            Some(header) => Outcome::Success(UserAgent::parse(header)),
+            None => Outcome::Success(UserAgent::default()),
+        }
+    }
+}
+
+/// The header that trusted proxies can use to pass on the time remaining for a request, in
+/// milliseconds
+pub const REQUEST_DEADLINE_HEADER: &'static str = "X-Request-Deadline";
+
+/// The point in time by which the current request should have been answered, so that handlers
+/// can bound the time spent on slow work (e.g. by passing `remaining()` as the timeout for
+/// outbound HTTP calls).
+///
+/// The deadline starts when the guard is first used in a request and lasts for
+/// `Settings::request_deadline`. Requests from one of `Settings::trusted_proxies` can shorten
+/// it with an `X-Request-Deadline` header, holding the number of milliseconds remaining;
+/// the header is ignored for any other client, and can never extend the configured deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    expires: Instant,
+}
+
+impl Deadline {
+    pub fn after(duration: Duration) -> Deadline {
+        Deadline {
+            expires: Instant::now() + duration,
+        }
+    }
+
+    /// The time left until the deadline, which is zero once it has passed
+    pub fn remaining(&self) -> Duration {
+        let now = Instant::now();
+        if now >= self.expires {
+            Duration::from_secs(0)
+        } else {
+            self.expires - now
+        }
+    }
+
+    pub fn has_expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+
+    pub fn of(request: &Request) -> Deadline {
+        *request.local_cache(|| {
+            let settings = request.guard::<State<Settings>>().succeeded();
+            let configured = settings
+                .as_ref()
+                .map(|settings| settings.request_deadline.as_duration())
+                .unwrap_or_else(|| Duration::from_secs(DEFAULT_REQUEST_DEADLINE_SECS));
+
+            let from_trusted_proxy = settings
+                .as_ref()
+                .map(|settings| is_from_trusted_proxy(request, settings))
+                .unwrap_or(false);
+
+            let requested = request
+                .headers()
+                .get_one(REQUEST_DEADLINE_HEADER)
+                .filter(|_| from_trusted_proxy)
+                .and_then(|ms| ms.trim().parse::<u64>().ok())
+                .map(Duration::from_millis);
+
+            match requested {
+                Some(requested) if requested < configured => Deadline::after(requested),
+                _ => Deadline::after(configured),
+            }
+        })
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Deadline {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(Deadline::of(request))
+    }
+}
+
+/// Whether the request was made by one of `Settings::trusted_proxies`, so that the headers it
+/// forwards can be believed
+pub fn is_from_trusted_proxy(request: &Request, settings: &Settings) -> bool {
+    match request.remote() {
+        Some(remote) => settings.trusted_proxies.iter().any(|cidr| cidr.contains(remote.ip())),
+        None => false,
+    }
+}
+
+/// The address of the client that made the request. This is the address of the peer, unless
+/// the peer is one of `Settings::trusted_proxies`, in which case it's the address that the
+/// proxy forwarded in `X-Real-IP` (or the last address in `X-Forwarded-For`). Unlike
+/// `Request::client_ip`, which believes `X-Real-IP` from anyone, clients that connect directly
+/// can't claim another address.
+pub fn client_addr(request: &Request) -> Option<IpAddr> {
+    let remote = request.remote()?.ip();
+    let trusted = request
+        .guard::<State<Settings>>()
+        .succeeded()
+        .map(|settings| is_from_trusted_proxy(request, &settings))
+        .unwrap_or(false);
+    if !trusted {
+        return Some(remote);
+    }
+
+    let headers = request.headers();
+    let real_ip = headers.get_one("X-Real-IP").and_then(|ip| ip.trim().parse().ok());
+    let forwarded_for = || {
+        headers
+            .get("X-Forwarded-For")
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|ip| ip.trim().parse().ok())
+    };
+    Some(real_ip.or_else(forwarded_for).unwrap_or(remote))
+}
+
+/// The path of `request` as rocket routes it, with empty segments (e.g. from `//admin` or a
+/// trailing slash) dropped. Anything that decides what applies to a request by its path should
+/// look at this rather than the raw path, which a client could pad with extra slashes to slip
+/// past a prefix that the same request would still be routed under.
+pub fn routed_path(request: &Request) -> String {
+    format!("/{}", request.uri().segments().collect::<Vec<&str>>().join("/"))
+}
+
+/// Whether the request was made over HTTPS, as reported in `X-Forwarded-Proto` by one of
+/// `Settings::trusted_proxies`. The header is ignored when anyone else sends it, and the app
+/// doesn't terminate TLS itself, so every other request is treated as plain HTTP.
+pub fn is_forwarded_https(request: &Request) -> bool {
+    let trusted = request
+        .guard::<State<Settings>>()
+        .succeeded()
+        .map(|settings| is_from_trusted_proxy(request, &settings))
+        .unwrap_or(false);
+
+    trusted
+        && request
+            .headers()
+            .get_one("X-Forwarded-Proto")
+            .map(|proto| proto.trim().eq_ignore_ascii_case("https"))
+            .unwrap_or(false)
+}
+
+/// The header that trusted proxies can use to pass on the path prefix that the app is served
+/// under
+pub const FORWARDED_PREFIX_HEADER: &'static str = "X-Forwarded-Prefix";
+
+/// The path prefix that the app is served under for the current request, for building links
+/// and redirects that work behind a reverse proxy. This is `Settings::mount_prefix`, unless a
+/// request from one of `Settings::trusted_proxies` has an `X-Forwarded-Prefix` header.
+///
+/// # Examples
+///
+/// ```
+/// #[post("/login")]
+/// fn login(base: BasePath) -> Redirect {
+///     // Redirects to /myapp/dashboard when mounted under /myapp
+///     base.redirect_to("/dashboard")
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BasePath(String);
+
+impl BasePath {
+    /// Create a base path, which should either be empty or start with `/`
+    pub fn new<S: Into<String>>(prefix: S) -> BasePath {
+        BasePath(prefix.into().trim_end_matches('/').to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The URL for `path` (relative to the root of the app) under this base path
+    pub fn url_for(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            format!("{}{}", self.0, path)
+        } else {
+            format!("{}/{}", self.0, path)
+        }
+    }
+
+    /// The location of a redirect to `target`. Paths are relative to the root of the app and
+    /// are put under this base path, unless they are already under it (e.g. a `?next=` target
+    /// taken from a link that the app generated). Absolute URLs are left as they are.
+    pub fn location_for(&self, target: &str) -> String {
+        let is_under_base = target.starts_with(self.0.as_str())
+            && match target[self.0.len()..].chars().next() {
+                None | Some('/') | Some('?') | Some('#') => true,
+                Some(_) => false,
+            };
+
+        if !target.starts_with('/') || self.0.is_empty() || is_under_base {
+            target.to_string()
+        } else {
+            self.url_for(target)
+        }
+    }
+
+    /// Redirect to `path` (relative to the root of the app) under this base path
+    pub fn redirect_to(&self, path: &str) -> Redirect {
+        Redirect::to(self.url_for(path))
+    }
+
+    pub fn of(request: &Request) -> BasePath {
+        request
+            .local_cache(|| {
+                let settings = match request.guard::<State<Settings>>().succeeded() {
+                    Some(settings) => settings,
+                    None => return BasePath::default(),
+                };
+
+                let forwarded = request
+                    .headers()
+                    .get_one(FORWARDED_PREFIX_HEADER)
+                    .map(str::trim)
+                    .filter(|prefix| prefix.is_empty() || prefix.starts_with('/'))
+                    .filter(|_| is_from_trusted_proxy(request, &settings));
+
+                match forwarded {
+                    Some(prefix) => BasePath::new(prefix),
+                    None => BasePath::new(settings.mount_prefix.clone().unwrap_or_default()),
+                }
+            })
+            .clone()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for BasePath {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(BasePath::of(request))
+    }
+}
+
+/// The prefix of query params that `DebugOverride` reads, e.g. `?__beta_checkout=true`
+pub const DEBUG_OVERRIDE_PREFIX: &'static str = "__";
+
+/// The app's extras, with any of `Settings::debug_override_extras` replaced by query params
+/// for this request, so that feature flags can be toggled while debugging without restarting.
+/// A param named with `DEBUG_OVERRIDE_PREFIX` and an allowed extra (e.g. `?__beta_checkout=true`
+/// for the `beta_checkout` extra) overrides that extra.
+///
+/// Overrides only apply to debug builds running in the development environment. In any other
+/// build or environment, params are ignored and this is the same as reading the extras from
+/// `Settings`, and the settings fail to load in production if any extras are allowed.
+///
+/// # Examples
+///
+/// ```
+/// #[get("/checkout")]
+/// fn checkout(flags: DebugOverride) -> Template {
+///     if flags.extra("beta_checkout") == Some("true") {
+///         Template::render("checkout_beta", json!({}))
+///     } else {
+///         Template::render("checkout", json!({}))
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DebugOverride<'r> {
+    settings: &'r Settings,
+    overrides: HashMap<String, String>,
+}
+
+impl<'r> DebugOverride<'r> {
+    /// Whether overrides are applied in this build and environment
+    pub fn is_available() -> bool {
+        cfg!(debug_assertions) && crate::app::active_environment().is_dev()
+    }
+
+    /// The value of the `key` extra for this request
+    pub fn extra(&self, key: &str) -> Option<&str> {
+        self.overrides
+            .get(key)
+            .map(String::as_str)
+            .or_else(|| self.settings.extra(key))
+    }
+
+    /// The extras that were overridden for this request, and their values
+    pub fn overrides(&self) -> &HashMap<String, String> {
+        &self.overrides
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for DebugOverride<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let settings = match request.guard::<State<'r, Settings>>() {
+            Outcome::Success(settings) => settings.inner(),
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let mut overrides = HashMap::new();
+        if DebugOverride::is_available() {
+            let params = request.uri().query().into_iter().flat_map(|query| query.split('&'));
+            for param in params {
+                let mut parts = param.splitn(2, '=');
+                let name = parts.next().unwrap_or("");
+                let value = parts.next().unwrap_or("");
+                if !name.starts_with(DEBUG_OVERRIDE_PREFIX) {
+                    continue;
+                }
+
+                let key = RawStr::from_str(&name[DEBUG_OVERRIDE_PREFIX.len()..]).url_decode_lossy();
+                if settings.debug_override_extras.contains(&key) {
+                    overrides.insert(key, RawStr::from_str(value).url_decode_lossy());
+                }
+            }
+        }
+
+        if !overrides.is_empty() {
+            tracing::debug!(overrides = ?overrides, "Overriding extras for this request");
+        }
+        Outcome::Success(DebugOverride { settings, overrides })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment, Limits};
+    use rocket::handler::Outcome as HandlerOutcome;
+    use rocket::http::{Header, Method};
+    use rocket::local::Client;
+    use rocket::Route;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Post {
+        title: String,
+        summary: Option<String>,
+        meta: Value,
+    }
+
+    fn post() -> Post {
+        Post {
+            title: String::from("Hello"),
+            summary: Some(String::from("A first post")),
+            meta: json!({ "tags": ["intro"], "seo": { "title": "Hello", "index": true } }),
+        }
+    }
+
+    #[test]
+    fn merge_patch_null_removes_fields() {
+        let mut target = post();
+        MergePatch::new(json!({ "summary": null, "meta": { "seo": null } }))
+            .apply_to(&mut target)
+            .unwrap();
+
+        assert_eq!(target.summary, None);
+        assert_eq!(target.meta, json!({ "tags": ["intro"] }));
+        assert_eq!(target.title, "Hello");
+    }
+
+    #[test]
+    fn merge_patch_merges_nested_objects() {
+        let mut target = post();
+        let patch = MergePatch::<Post>::new(json!({ "meta": { "seo": { "index": false }, "tags": ["a", "b"] } }));
+        patch.apply_to(&mut target).unwrap();
+
+        assert_eq!(
+            target.meta,
+            json!({ "tags": ["a", "b"], "seo": { "title": "Hello", "index": false } })
+        );
+        assert_eq!(patch.fields(), vec!["meta"]);
+    }
+
+    #[test]
+    fn merge_patch_leaves_target_when_invalid() {
+        let mut target = post();
+        let result = MergePatch::new(json!({ "title": null })).apply_to(&mut target);
+
+        assert!(result.is_err());
+        assert_eq!(target, post());
+    }
+
+    fn patch_handler<'r>(request: &'r Request, data: Data) -> HandlerOutcome<'r> {
+        match MergePatch::<Post>::from_data(request, data) {
+            Outcome::Success(patch) => HandlerOutcome::from(request, patch.patch().to_string()),
+            Outcome::Failure((status, _)) => HandlerOutcome::Failure(status),
+            Outcome::Forward(data) => HandlerOutcome::Forward(data),
+        }
+    }
+
+    fn client(json_limit: u64) -> Client {
+        let config = Config::build(Environment::Development)
+            .limits(Limits::new().limit("json", json_limit))
+            .finalize()
+            .unwrap();
+        let rocket = rocket::custom(config).mount("/", vec![Route::new(Method::Patch, "/posts", patch_handler)]);
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn merge_patch_body_limit() {
+        let client = client(16);
+        let merge_patch = ContentType::new("application", "merge-patch+json");
+
+        let mut small = client.patch("/posts").header(merge_patch.clone()).body(r#"{"title":"Hi"}"#).dispatch();
+        assert_eq!(small.status(), Status::Ok);
+        assert_eq!(small.body_string(), Some(String::from(r#"{"title":"Hi"}"#)));
+
+        // Exactly at the limit is still allowed
+        let at_limit = client.patch("/posts").header(merge_patch.clone()).body(r#"{"title":"Hey!"}"#).dispatch();
+        assert_eq!(at_limit.status(), Status::Ok);
+
+        let large = client
+            .patch("/posts")
+            .header(merge_patch.clone())
+            .body(r#"{"title":"Hello, world"}"#)
+            .dispatch();
+        assert_eq!(large.status(), Status::PayloadTooLarge);
+
+        let wrong_type = client.patch("/posts").header(ContentType::Plain).body("{}").dispatch();
+        assert_eq!(wrong_type.status(), Status::UnsupportedMediaType);
+    }
+
+    fn tags(language: &Language) -> Vec<&str> {
+        language.0.iter().map(|(tag, _)| tag.as_str()).collect()
+    }
+
+    #[test]
+    fn language_is_sorted_by_quality() {
+        let language = Language::parse("fr;q=0.8,en-US,de;q=0,en;q=0.9");
+        assert_eq!(tags(&language), vec!["en-US", "en", "fr"]);
+        assert_eq!(language.best(), "en-US");
+
+        let qualities: Vec<f32> = language.0.iter().map(|(_, quality)| *quality).collect();
+        for (quality, expected) in qualities.iter().zip(&[1.0, 0.9, 0.8]) {
+            assert!((quality - expected).abs() < 1e-6, "{:?}", qualities);
+        }
+
+        // Languages with the same quality keep the order they were sent in
+        assert_eq!(tags(&Language::parse("nl;q=0.5,fr,es;q=0.5")), vec!["fr", "nl", "es"]);
+    }
+
+    #[test]
+    fn language_defaults_to_english() {
+        assert_eq!(Language::parse("fr;q=0").best(), "en");
+        assert_eq!(Language::parse("").best(), "en");
+
+        let client = Client::new(rocket::custom(Config::new(Environment::Development))).unwrap();
+        let without_header = client.get("/");
+        let language = without_header.inner().guard::<Language>().unwrap();
+        assert_eq!(tags(&language), vec!["en"]);
+
+        let with_header = client.get("/").header(Header::new("Accept-Language", "de-CH,de;q=0.9"));
+        assert_eq!(with_header.inner().guard::<Language>().unwrap().best(), "de-CH");
+    }
+
+    #[test]
+    fn user_agent_detects_bots() {
+        let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        assert_eq!(
+            UserAgent::parse(googlebot),
+            UserAgent {
+                raw: String::from(googlebot),
+                is_bot: true,
+            }
+        );
+        assert!(UserAgent::parse("curl/7.64.1").is_bot);
+        assert!(UserAgent::parse("Wget/1.20.3 (linux-gnu)").is_bot);
+
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:68.0) Gecko/20100101 Firefox/68.0";
+        assert!(!UserAgent::parse(firefox).is_bot);
+    }
+
+    #[test]
+    fn user_agent_guard() {
+        let client = Client::new(rocket::custom(Config::new(Environment::Development))).unwrap();
+
+        let googlebot = client.get("/").header(Header::new("User-Agent", "Googlebot/2.1"));
+        let user_agent = googlebot.inner().guard::<UserAgent>().unwrap();
+        assert!(user_agent.is_bot);
+        assert_eq!(user_agent.raw, "Googlebot/2.1");
+
+        let anonymous = client.get("/");
+        assert_eq!(anonymous.inner().guard::<UserAgent>().unwrap(), UserAgent::default());
+        assert_eq!(UserAgent::default().raw, "unknown");
+        assert!(!UserAgent::default().is_bot);
+    }
+
+    fn deadline_client() -> Client {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("request_deadline", "10s")
+            .unwrap()
+            .set("trusted_proxies", vec!["10.0.0.1"])
+            .unwrap()
+            .build()
+            .unwrap();
+        Client::new(rocket::custom(Config::new(Environment::Development)).manage(settings)).unwrap()
+    }
+
+    #[test]
+    fn deadline_header_only_from_trusted_proxies() {
+        let client = deadline_client();
+        let proxy = "10.0.0.1:4000".parse().unwrap();
+        let outsider = "203.0.113.5:4000".parse().unwrap();
+
+        let direct = client.get("/");
+        let remaining = Deadline::of(direct.inner()).remaining();
+        assert!(remaining > Duration::from_secs(9) && remaining <= Duration::from_secs(10));
+
+        let proxied = client
+            .get("/")
+            .remote(proxy)
+            .header(Header::new(REQUEST_DEADLINE_HEADER, "500"));
+        assert!(Deadline::of(proxied.inner()).remaining() <= Duration::from_millis(500));
+
+        let spoofed = client
+            .get("/")
+            .remote(outsider)
+            .header(Header::new(REQUEST_DEADLINE_HEADER, "500"));
+        assert!(Deadline::of(spoofed.inner()).remaining() > Duration::from_secs(9));
+
+        // A trusted proxy can shorten the deadline, but not extend it
+        let extended = client
+            .get("/")
+            .remote(proxy)
+            .header(Header::new(REQUEST_DEADLINE_HEADER, "60000"));
+        assert!(Deadline::of(extended.inner()).remaining() <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn deadline_is_the_same_within_a_request() {
+        let client = deadline_client();
+        let request = client.get("/");
+        let first = Deadline::of(request.inner());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(Deadline::of(request.inner()), first);
+        assert!(!first.has_expired());
+        assert!(Deadline::after(Duration::from_secs(0)).has_expired());
+        assert_eq!(Deadline::after(Duration::from_secs(0)).remaining(), Duration::from_secs(0));
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Order {
+        items: Vec<OrderItem>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct OrderItem {
+        #[allow(dead_code)]
+        sku: String,
+        qty: u64,
+    }
+
+    fn create_order<'r>(request: &'r Request, data: Data) -> HandlerOutcome<'r> {
+        match StrictJson::<Order>::from_data(request, data) {
+            Outcome::Success(order) => {
+                let total: u64 = order.items.iter().map(|item| item.qty).sum();
+                HandlerOutcome::from(request, total.to_string())
+            }
+            Outcome::Failure((status, _)) => HandlerOutcome::Failure(status),
+            Outcome::Forward(data) => HandlerOutcome::Forward(data),
+        }
+    }
+
+    fn strict_client() -> Client {
+        let config = Config::build(Environment::Development)
+            .limits(Limits::new().limit("json", 128))
+            .finalize()
+            .unwrap();
+        let rocket = rocket::custom(config)
+            .mount("/", vec![Route::new(Method::Post, "/orders", create_order)])
+            .register(json_catchers());
+        Client::new(rocket).unwrap()
+    }
+
+    fn body_json(response: &mut rocket::local::LocalResponse) -> Value {
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn strict_json_accepts_json() {
+        let client = strict_client();
+        let order = r#"{"items":[{"sku":"a","qty":2},{"sku":"b","qty":3}]}"#;
+
+        let mut plain = client.post("/orders").header(ContentType::JSON).body(order).dispatch();
+        assert_eq!(plain.status(), Status::Ok);
+        assert_eq!(plain.body_string(), Some(String::from("5")));
+
+        let with_charset = client
+            .post("/orders")
+            .header(Header::new("Content-Type", "application/json; charset=UTF-8"))
+            .body(order)
+            .dispatch();
+        assert_eq!(with_charset.status(), Status::Ok);
+    }
+
+    #[test]
+    fn strict_json_rejects_other_content_types() {
+        let client = strict_client();
+        for content_type in &["text/plain", "application/json; charset=latin1", "application/x-www-form-urlencoded"] {
+            let mut response = client
+                .post("/orders")
+                .header(Header::new("Content-Type", *content_type))
+                .body(r#"{"items":[]}"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::UnsupportedMediaType, "{}", content_type);
+            assert_eq!(body_json(&mut response)["error"], "unsupported_media_type");
+        }
+
+        let missing = client.post("/orders").body(r#"{"items":[]}"#).dispatch();
+        assert_eq!(missing.status(), Status::UnsupportedMediaType);
+    }
+
+    #[test]
+    fn strict_json_names_the_limit() {
+        let client = strict_client();
+        let items = vec![r#"{"sku":"a","qty":1}"#; 10].join(",");
+
+        let mut response = client
+            .post("/orders")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"items":[{}]}}"#, items))
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        let body = body_json(&mut response);
+        assert_eq!(body["error"], "payload_too_large");
+        assert_eq!(body["limit"], 128);
+        assert!(body["message"].as_str().unwrap().contains("128 bytes"));
+    }
+
+    #[test]
+    fn strict_json_points_at_the_invalid_value() {
+        let client = strict_client();
+        let order = r#"{"items":[{"sku":"a","qty":1},{"sku":"b","qty":"two"}]}"#;
+
+        let mut response = client.post("/orders").header(ContentType::JSON).body(order).dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body = body_json(&mut response);
+        assert_eq!(body["error"], "invalid_json");
+        assert_eq!(body["path"], "items[1].qty");
+        assert!(body["message"].as_str().unwrap().contains("expected u64"), "{}", body);
+
+        let mut malformed = client.post("/orders").header(ContentType::JSON).body("{\"items\":").dispatch();
+        assert_eq!(malformed.status(), Status::UnprocessableEntity);
+        assert_eq!(body_json(&mut malformed)["error"], "invalid_json");
+    }
+
+    /// The signature that a webhook sender would send for `body`
+    fn sign(body: &[u8]) -> String {
+        use sha2::{Digest, Sha256};
+        base64::encode(&Sha256::digest(body))
+    }
+
+    /// Checks the signature over the raw body, then parses the same body as JSON
+    fn webhook<'r>(request: &'r Request, data: Data) -> HandlerOutcome<'r> {
+        let body = match RequestBody::from_data(request, data) {
+            Outcome::Success(body) => body,
+            Outcome::Failure((status, _)) => return HandlerOutcome::Failure(status),
+            Outcome::Forward(data) => return HandlerOutcome::Forward(data),
+        };
+        if request.headers().get_one("X-Signature") != Some(sign(&body).as_str()) {
+            return HandlerOutcome::Failure(Status::Unauthorized);
+        }
+        let event: Value = match body.json() {
+            Ok(event) => event,
+            Err(_) => return HandlerOutcome::Failure(Status::UnprocessableEntity),
+        };
+
+        let cached = RequestBody::cached(request).unwrap();
+        assert_eq!(cached, body.0);
+        HandlerOutcome::from(request, format!("{} {}", event["kind"].as_str().unwrap_or(""), cached.len()))
+    }
+
+    fn body_client(max_body_bytes: Option<&str>) -> Client {
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .mount("/", vec![Route::new(Method::Post, "/webhooks", webhook)]);
+        let rocket = match max_body_bytes {
+            Some(size) => rocket.manage(
+                Settings::builder()
+                    .unwrap()
+                    .extra("max_body_bytes", size)
+                    .build()
+                    .unwrap(),
+            ),
+            None => rocket,
+        };
+        Client::new(rocket).unwrap()
+    }
+
+    fn deliver<'c>(client: &'c Client, body: &str) -> rocket::local::LocalResponse<'c> {
+        client
+            .post("/webhooks")
+            .header(Header::new("X-Signature", sign(body.as_bytes())))
+            .body(body)
+            .dispatch()
+    }
+
+    #[test]
+    fn request_body_can_be_read_twice() {
+        let client = body_client(None);
+        let event = r#"{"kind":"order.paid","id":7}"#;
+
+        let mut response = deliver(&client, event);
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some(format!("order.paid {}", event.len())));
+
+        let tampered = client
+            .post("/webhooks")
+            .header(Header::new("X-Signature", sign(event.as_bytes())))
+            .body(r#"{"kind":"order.refunded","id":7}"#)
+            .dispatch();
+        assert_eq!(tampered.status(), Status::Unauthorized);
+        assert_eq!(deliver(&client, "not json").status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn request_body_limit() {
+        let client = body_client(Some("16"));
+        assert_eq!(deliver(&client, r#"{"kind":"ping"}"#).status(), Status::Ok);
+        assert_eq!(deliver(&client, r#"{"kind":"ping!"}"#).status(), Status::Ok);
+        assert_eq!(deliver(&client, r#"{"kind":"ping!!"}"#).status(), Status::PayloadTooLarge);
+
+        // Without settings, the default limit applies
+        let big = format!(r#"{{"kind":"{}"}}"#, "a".repeat(64 * 1024));
+        assert_eq!(deliver(&body_client(None), &big).status(), Status::Ok);
+    }
+
+    #[test]
+    fn app_reads_request_bodies() {
+        let app = crate::app::test_support::TestApp::builder()
+            .extra("max_body_bytes", "32")
+            .mount("/", vec![Route::new(Method::Post, "/webhooks", webhook)])
+            .build()
+            .unwrap();
+
+        let mut response = deliver(app.client(), r#"{"kind":"user.created"}"#);
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some(String::from("user.created 23")));
+
+        let oversized = deliver(app.client(), r#"{"kind":"user.created","id":12345}"#);
+        assert_eq!(oversized.status(), Status::PayloadTooLarge);
+    }
+
+    fn flags<'r>(request: &'r Request, _: Data) -> HandlerOutcome<'r> {
+        match request.guard::<DebugOverride>().succeeded() {
+            Some(flags) => {
+                let body = format!("{} {}", flags.extra("beta_checkout").unwrap_or("-"), flags.overrides().len());
+                HandlerOutcome::from(request, body)
+            }
+            None => HandlerOutcome::failure(Status::InternalServerError),
+        }
+    }
+
+    fn override_client() -> Client {
+        // Held so that the settings aren't validated while another test has set `ROCKET_ENV`
+        let _lock = crate::app::test_support::EnvLock::acquire();
+        let settings = Settings::builder()
+            .unwrap()
+            .set("debug_override_extras", vec!["beta_checkout"])
+            .unwrap()
+            .extra("beta_checkout", "false")
+            .extra("theme", "light")
+            .build()
+            .unwrap();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(settings)
+            .mount("/", vec![Route::new(Method::Get, "/checkout", flags)]);
+        Client::new(rocket).unwrap()
+    }
+
+    /// Dispatch each of `paths` with `ROCKET_ENV` set to `environment`, which is restored before
+    /// the bodies are returned
+    fn with_environment(client: &Client, environment: &str, paths: &[&str]) -> Vec<String> {
+        let _lock = crate::app::test_support::EnvLock::acquire();
+        let previous = std::env::var("ROCKET_ENV").ok();
+        std::env::set_var("ROCKET_ENV", environment);
+
+        let bodies = paths
+            .iter()
+            .map(|path| client.get(*path).dispatch().body_string().unwrap_or_default())
+            .collect();
+
+        match previous {
+            Some(previous) => std::env::set_var("ROCKET_ENV", previous),
+            None => std::env::remove_var("ROCKET_ENV"),
+        }
+        bodies
+    }
+
+    #[test]
+    fn debug_overrides_apply_while_developing() {
+        let client = override_client();
+        let bodies = with_environment(
+            &client,
+            "development",
+            &[
+                "/checkout",
+                "/checkout?__beta_checkout=true",
+                "/checkout?__beta_checkout=on%20sale&page=2",
+                "/checkout?beta_checkout=true",
+                "/checkout?__theme=dark",
+            ],
+        );
+
+        if cfg!(debug_assertions) {
+            assert_eq!(bodies, vec!["false 0", "true 1", "on sale 1", "false 0", "false 0"]);
+        } else {
+            assert!(bodies.iter().all(|body| body == "false 0"), "{:?}", bodies);
+        }
+    }
+
+    #[test]
+    fn debug_overrides_are_ignored_in_other_environments() {
+        let client = override_client();
+        let bodies = with_environment(&client, "staging", &["/checkout?__beta_checkout=true"]);
+        assert_eq!(bodies, vec!["false 0"]);
+    }
+
+    fn list<'r>(request: &'r Request, _: Data) -> HandlerOutcome<'r> {
+        match request.guard::<Pagination>() {
+            Outcome::Success(page) => HandlerOutcome::from(request, format!("{} {}", page.offset, page.limit)),
+            Outcome::Failure((status, _)) => HandlerOutcome::failure(status),
+            Outcome::Forward(()) => HandlerOutcome::failure(Status::InternalServerError),
+        }
+    }
+
+    fn pagination_client(settings: Option<Settings>) -> Client {
+        let rocket = rocket::custom(Config::new(Environment::Development));
+        let rocket = match settings {
+            Some(settings) => rocket.manage(settings),
+            None => rocket,
+        };
+        Client::new(rocket.mount("/", vec![Route::new(Method::Get, "/posts", list)])).unwrap()
+    }
+
+    fn paginate(client: &Client, path: &str) -> (Status, Option<String>) {
+        let mut response = client.get(path.to_string()).dispatch();
+        (response.status(), response.body_string())
+    }
+
+    #[test]
+    fn pagination_defaults() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("per_page_default", 10)
+            .unwrap()
+            .build()
+            .unwrap();
+        let client = pagination_client(Some(settings));
+        assert_eq!(paginate(&client, "/posts"), (Status::Ok, Some(String::from("0 10"))));
+        assert_eq!(paginate(&client, "/posts?page=3"), (Status::Ok, Some(String::from("20 10"))));
+        assert_eq!(paginate(&client, "/posts?page=2&per_page=5"), (Status::Ok, Some(String::from("5 5"))));
+
+        let unmanaged = pagination_client(None);
+        assert_eq!(
+            paginate(&unmanaged, "/posts"),
+            (Status::Ok, Some(format!("0 {}", DEFAULT_PER_PAGE)))
+        );
+    }
+
+    #[test]
+    fn pagination_is_clamped_to_the_maximum() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("per_page_max", 50)
+            .unwrap()
+            .build()
+            .unwrap();
+        let client = pagination_client(Some(settings));
+        assert_eq!(paginate(&client, "/posts?per_page=50"), (Status::Ok, Some(String::from("0 50"))));
+        assert_eq!(paginate(&client, "/posts?per_page=51"), (Status::Ok, Some(String::from("0 50"))));
+        assert_eq!(paginate(&client, "/posts?page=2&per_page=1000"), (Status::Ok, Some(String::from("50 50"))));
+
+        let unmanaged = pagination_client(None);
+        assert_eq!(
+            paginate(&unmanaged, "/posts?per_page=1000000"),
+            (Status::Ok, Some(format!("0 {}", MAX_PER_PAGE)))
+        );
+    }
+
+    #[test]
+    fn pagination_rejects_invalid_values() {
+        let client = pagination_client(None);
+        for query in &["page=0", "page=-1", "page=two", "per_page=0", "per_page=-5", "per_page=ten", "page=1.5"] {
+            let (status, _) = paginate(&client, &format!("/posts?{}", query));
+            assert_eq!(status, Status::BadRequest, "{}", query);
+        }
+    }
+}
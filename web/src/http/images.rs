@@ -0,0 +1,323 @@
+//! Serving pre-generated AVIF and WebP versions of static images to the clients that accept
+//! them, in place of the JPEG, PNG or GIF that was requested.
+//!
+//! The variants sit next to the original image, named in one of two ways (chosen by
+//! `Settings::image_variant_naming`):
+//!
+//! | Naming      | `hero.jpg` has variants        |
+//! |-------------|--------------------------------|
+//! | `"suffix"`  | `hero.jpg.avif`, `hero.jpg.webp` |
+//! | `"replace"` | `hero.avif`, `hero.webp`         |
+//!
+//! A variant is only served when the client's `Accept` header names its type, as browsers that
+//! can't decode it still send `*/*`. When both are named with the same quality, AVIF (which is
+//! usually smaller) wins over WebP, and either wins over the original. Responses for images
+//! with variants carry `Vary: Accept`, so that caches keep them apart, and every image response
+//! has an ETag that differs between the variants.
+use crate::http::negotiation::{parse_weighted, preferred_content_type};
+use crate::http::wrappers::accept_ranges;
+
+use rocket::handler::{Handler, Outcome};
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::{NamedFile, Responder, Response};
+use rocket::{Data, Request, Route};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The extensions of images that variants are looked for
+pub const IMAGE_EXTENSIONS: [&'static str; 4] = ["jpg", "jpeg", "png", "gif"];
+
+/// The variant formats, as `(extension, subtype of image/*)`, most preferred first
+const VARIANTS: [(&'static str, &'static str); 2] = [("avif", "avif"), ("webp", "webp")];
+
+/// How the variants of an image are named, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariantNaming {
+    /// The variant's extension is added to the image's name
+    Suffix,
+    /// The variant's extension replaces the image's
+    Replace,
+}
+
+impl VariantNaming {
+    /// The naming scheme for a value of `Settings::image_variant_naming`
+    pub fn from_setting(value: &str) -> Option<VariantNaming> {
+        match value {
+            "suffix" => Some(VariantNaming::Suffix),
+            "replace" => Some(VariantNaming::Replace),
+            _ => None,
+        }
+    }
+
+    /// The path of the `extension` variant of the image at `original`
+    pub fn variant_path(self, original: &Path, extension: &str) -> PathBuf {
+        match self {
+            VariantNaming::Suffix => {
+                let mut name = OsString::from(original.as_os_str());
+                name.push(".");
+                name.push(extension);
+                PathBuf::from(name)
+            }
+            VariantNaming::Replace => original.with_extension(extension),
+        }
+    }
+}
+
+/// The ETag of the file at `path`, from its size and modification time. The extension is
+/// included, so that an image and its variants never share a tag.
+pub fn file_etag(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    let extension = path.extension().map(|ext| ext.to_string_lossy()).unwrap_or_default();
+
+    Some(format!("\"{:x}-{:x}-{}\"", modified.as_secs(), metadata.len(), extension))
+}
+
+/// Whether the `If-None-Match` header of `request` matches `etag`, being either `*` or a list
+/// of tags that includes it. Tags are compared weakly, as RFC 7232 asks for `If-None-Match`,
+/// so `W/"abc"` matches `"abc"`.
+pub fn if_none_match(request: &Request, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+
+    request
+        .headers()
+        .get("If-None-Match")
+        .flat_map(|header| header.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || opaque(tag) == etag)
+}
+
+/// Wraps the handler that serves the static files in `root` (i.e. `StaticFiles`), serving the
+/// best variant of each requested image instead of the original, see the module docs. Requests
+/// for anything else are passed to the wrapped handler untouched.
+#[derive(Clone)]
+pub struct NegotiatedImages {
+    root: PathBuf,
+    naming: VariantNaming,
+    inner: Box<dyn Handler>,
+}
+
+impl NegotiatedImages {
+    /// Wrap the handler of each route in `routes`, which serve the files in `root`
+    pub fn wrap(routes: Vec<Route>, root: &Path, naming: VariantNaming) -> Vec<Route> {
+        routes
+            .into_iter()
+            .map(|mut route| {
+                route.handler = Box::new(NegotiatedImages {
+                    root: root.to_path_buf(),
+                    naming,
+                    inner: route.handler,
+                });
+                route
+            })
+            .collect()
+    }
+
+    /// The image in `root` that `request` is for, if it's for an image that exists
+    fn requested_image(&self, request: &Request) -> Option<PathBuf> {
+        let path: PathBuf = request.get_segments(0)?.ok()?;
+        let extension = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        if !IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            return None;
+        }
+
+        Some(self.root.join(path)).filter(|image| image.is_file())
+    }
+}
+
+/// Whether the `Accept` header of `request` names `image/{subtype}` with a quality above 0
+fn names_type(request: &Request, subtype: &str) -> bool {
+    let header = request.headers().get_one("Accept").unwrap_or("");
+    let media_type = format!("image/{}", subtype);
+    parse_weighted(header)
+        .iter()
+        .any(|(range, quality)| range.eq_ignore_ascii_case(&media_type) && *quality > 0.0)
+}
+
+impl Handler for NegotiatedImages {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        let image = match self.requested_image(request) {
+            Some(image) => image,
+            None => return self.inner.handle(request, data),
+        };
+
+        let variants: Vec<(PathBuf, ContentType)> = VARIANTS
+            .iter()
+            .map(|(extension, subtype)| {
+                let path = self.naming.variant_path(&image, extension);
+                (path, ContentType::new("image", *subtype))
+            })
+            .filter(|(path, _)| path.is_file())
+            .collect();
+        let has_variants = !variants.is_empty();
+
+        // The original is offered last, so that it only wins when it's preferred outright
+        let original_type = image
+            .extension()
+            .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+            .unwrap_or(ContentType::Binary);
+        let mut offered: Vec<ContentType> = variants
+            .iter()
+            .filter(|(_, content_type)| names_type(request, content_type.sub().as_str()))
+            .map(|(_, content_type)| content_type.clone())
+            .collect();
+        offered.push(original_type);
+
+        let chosen = preferred_content_type(request, &offered)
+            .and_then(|chosen| variants.iter().find(|(_, content_type)| *content_type == chosen));
+        let served = chosen.map_or(&image, |(path, _)| path);
+        let etag = file_etag(served);
+
+        if let Some(ref etag) = etag {
+            if if_none_match(request, etag) {
+                let mut response = Response::build();
+                response
+                    .status(Status::NotModified)
+                    .header(Header::new("ETag", etag.clone()))
+                    .header(accept_ranges());
+                if has_variants {
+                    response.header(Header::new("Vary", "Accept"));
+                }
+                return Outcome::Success(response.finalize());
+            }
+        }
+
+        let outcome = match chosen {
+            Some((path, content_type)) => match NamedFile::open(path).map(|file| file.respond_to(request)) {
+                Ok(Ok(mut response)) => {
+                    response.set_header(content_type.clone());
+                    response.set_header(accept_ranges());
+                    Outcome::Success(response)
+                }
+                Ok(Err(status)) => Outcome::Failure(status),
+                Err(_) => Outcome::Failure(Status::InternalServerError),
+            },
+            None => self.inner.handle(request, data),
+        };
+
+        match outcome {
+            Outcome::Success(mut response) => {
+                if let Some(etag) = etag {
+                    response.set_header(Header::new("ETag", etag));
+                }
+                if has_variants {
+                    response.adjoin_header(Header::new("Vary", "Accept"));
+                }
+                Outcome::Success(response)
+            }
+            outcome => outcome,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use rocket::local::{Client, LocalResponse};
+    use rocket_contrib::serve::StaticFiles;
+
+    /// A static directory with `hero.jpg` and its variants (named with `naming`), and `logo.png`
+    /// without any
+    fn images(naming: VariantNaming) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("hero.jpg"), "jpeg").unwrap();
+        fs::write(naming.variant_path(&dir.path().join("hero.jpg"), "avif"), "avif").unwrap();
+        fs::write(naming.variant_path(&dir.path().join("hero.jpg"), "webp"), "webp").unwrap();
+        fs::write(dir.path().join("logo.png"), "png").unwrap();
+        dir
+    }
+
+    fn client(root: &Path, naming: VariantNaming) -> Client {
+        let routes = NegotiatedImages::wrap(StaticFiles::from(root).into(), root, naming);
+        Client::new(rocket::custom(Config::new(Environment::Development)).mount("/", routes)).unwrap()
+    }
+
+    fn get<'c>(client: &'c Client, path: &'static str, accept: &'static str) -> LocalResponse<'c> {
+        client.get(path).header(Header::new("Accept", accept)).dispatch()
+    }
+
+    fn etag(response: &LocalResponse) -> String {
+        response.headers().get_one("ETag").unwrap().to_string()
+    }
+
+    #[test]
+    fn variants_are_chosen_from_accept() {
+        for naming in &[VariantNaming::Suffix, VariantNaming::Replace] {
+            let dir = images(*naming);
+            let client = client(dir.path(), *naming);
+
+            let cases = [
+                ("image/avif,image/webp,*/*", "avif", "image/avif"),
+                ("image/webp,*/*", "webp", "image/webp"),
+                ("image/avif;q=0,image/webp,*/*", "webp", "image/webp"),
+                ("image/webp;q=0.5,image/jpeg", "jpeg", "image/jpeg"),
+                ("*/*", "jpeg", "image/jpeg"),
+                ("", "jpeg", "image/jpeg"),
+            ];
+            for (accept, body, content_type) in cases.iter() {
+                let mut response = get(&client, "/hero.jpg", accept);
+                assert_eq!(response.status(), Status::Ok, "{}", accept);
+                assert_eq!(response.content_type().map(|ct| ct.to_string()), Some(content_type.to_string()));
+                assert_eq!(response.headers().get_one("Vary"), Some("Accept"), "{}", accept);
+                assert_eq!(response.body_string(), Some(body.to_string()), "{}", accept);
+            }
+        }
+    }
+
+    #[test]
+    fn images_without_variants_do_not_vary() {
+        let dir = images(VariantNaming::Suffix);
+        let client = client(dir.path(), VariantNaming::Suffix);
+
+        let mut response = get(&client, "/logo.png", "image/avif,image/webp,*/*");
+        assert_eq!(response.content_type(), Some(ContentType::PNG));
+        assert!(!response.headers().contains("Vary"));
+        assert!(response.headers().contains("ETag"));
+        assert_eq!(response.body_string(), Some(String::from("png")));
+    }
+
+    #[test]
+    fn each_variant_has_its_own_etag() {
+        let dir = images(VariantNaming::Suffix);
+        let client = client(dir.path(), VariantNaming::Suffix);
+
+        let avif = etag(&get(&client, "/hero.jpg", "image/avif"));
+        let webp = etag(&get(&client, "/hero.jpg", "image/webp"));
+        let original = etag(&get(&client, "/hero.jpg", "*/*"));
+        assert_ne!(avif, webp);
+        assert_ne!(avif, original);
+        assert_ne!(webp, original);
+    }
+
+    #[test]
+    fn if_none_match_accepts_lists_and_wildcards() {
+        let dir = images(VariantNaming::Suffix);
+        let client = client(dir.path(), VariantNaming::Suffix);
+        let webp = etag(&get(&client, "/hero.jpg", "image/webp"));
+
+        let conditional = |if_none_match: String| {
+            client
+                .get("/hero.jpg")
+                .header(Header::new("Accept", "image/webp"))
+                .header(Header::new("If-None-Match", if_none_match))
+                .dispatch()
+        };
+
+        let listed = conditional(format!("\"other\", {}", webp));
+        assert_eq!(listed.status(), Status::NotModified);
+        assert_eq!(listed.headers().get_one("Vary"), Some("Accept"));
+        assert_eq!(listed.headers().get_one("ETag"), Some(webp.as_str()));
+
+        assert_eq!(conditional(String::from("*")).status(), Status::NotModified);
+        assert_eq!(conditional(format!("W/{}", webp)).status(), Status::NotModified);
+        assert_eq!(conditional(String::from("\"other\", \"another\"")).status(), Status::Ok);
+
+        // The original's tag doesn't match the variant that this client is sent
+        let original = etag(&get(&client, "/hero.jpg", "*/*"));
+        assert_eq!(conditional(original).status(), Status::Ok);
+    }
+}
@@ -0,0 +1,368 @@
+//! Subresource Integrity (SRI) hashes for static assets, so that templates can add
+//! `integrity="sha384-…"` attributes to `<script>` and `<link>` tags.
+//!
+//! Hashes are read from `manifest-integrity.json` in the first static directory that has one
+//! (a JSON object of asset paths to `sha384-…` values, e.g. as written by a frontend build),
+//! and are otherwise computed at startup for each file with one of
+//! `Settings::integrity_extensions`. In development, computed hashes are checked against the
+//! file's modification time and recomputed when it changes, so that editing an asset doesn't
+//! leave the page with a stale hash that the browser refuses to load. With more than one
+//! static directory, each asset is hashed from the first directory that has it, which is the
+//! file that is served.
+//!
+//! Templates use the `asset_integrity` helper, e.g.
+//! `<script src="/static/app.js" integrity="{{asset_integrity "app.js"}}"></script>`.
+use crate::app::Settings;
+
+use rocket::config::Environment;
+use sha2::{Digest, Sha384};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// The file in the static directory that precomputed hashes are read from
+pub const INTEGRITY_MANIFEST: &'static str = "manifest-integrity.json";
+
+#[derive(Debug, Clone)]
+struct AssetHash {
+    integrity: String,
+    /// When the file was last modified, or `None` for hashes from the manifest
+    modified: Option<SystemTime>,
+}
+
+/// The integrity hashes of the static assets, keyed by their path relative to the static
+/// directories (with `/` separators). Clones share the same hashes.
+#[derive(Debug, Clone)]
+pub struct AssetIntegrity {
+    roots: Vec<PathBuf>,
+    extensions: Vec<String>,
+    recompute: bool,
+    hashes: Arc<RwLock<HashMap<String, AssetHash>>>,
+}
+
+impl AssetIntegrity {
+    /// Read the manifest, or hash the assets in `Settings::static_dir`
+    pub fn new(settings: &Settings) -> AssetIntegrity {
+        let roots = settings.static_dirs();
+        let extensions = settings
+            .integrity_extensions
+            .iter()
+            .map(|extension| extension.trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+
+        let manifest = roots.iter().find_map(|root| read_manifest(&root.join(INTEGRITY_MANIFEST)));
+        let (hashes, from_manifest) = match manifest {
+            Some(hashes) => (hashes, true),
+            None => (HashMap::new(), false),
+        };
+
+        let integrity = AssetIntegrity {
+            roots,
+            extensions,
+            recompute: !from_manifest && Environment::active().map(|env| env.is_dev()).unwrap_or(false),
+            hashes: Arc::new(RwLock::new(hashes)),
+        };
+
+        if !from_manifest {
+            integrity.hash_all();
+        }
+        integrity
+    }
+
+    /// The `sha384-…` integrity value for the asset at `path`, relative to the static
+    /// directory, or `None` with a warning if there isn't one
+    pub fn get(&self, path: &str) -> Option<String> {
+        let path = path.trim_start_matches('/');
+        if self.recompute {
+            self.refresh(path);
+        }
+
+        let integrity = self
+            .hashes
+            .read()
+            .ok()
+            .and_then(|hashes| hashes.get(path).map(|hash| hash.integrity.clone()));
+
+        if integrity.is_none() {
+            tracing::warn!(asset = %path, "no integrity hash for asset");
+        }
+        integrity
+    }
+
+    /// Hash every matching file under the static directories, skipping files shadowed by one
+    /// with the same name in an earlier directory
+    fn hash_all(&self) {
+        let mut hashes = HashMap::new();
+        for root in &self.roots {
+            let mut files = Vec::new();
+            collect_files(root, &mut files);
+
+            for file in files.into_iter().filter(|file| self.is_hashed(file)) {
+                let name = match relative_name(root, &file) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                if hashes.contains_key(&name) {
+                    continue;
+                }
+                if let Some(hash) = hash_file(&file) {
+                    hashes.insert(name, hash);
+                }
+            }
+        }
+
+        if let Ok(mut existing) = self.hashes.write() {
+            *existing = hashes;
+        }
+    }
+
+    /// Hash `path` again if it has been modified since it was last hashed
+    fn refresh(&self, path: &str) {
+        if path.split('/').any(|part| part == "..") || !self.is_hashed(Path::new(path)) {
+            return;
+        }
+        // The file that is served for `path`, or the last directory's (missing) file
+        let file = self
+            .roots
+            .iter()
+            .map(|root| root.join(path))
+            .find(|file| file.is_file())
+            .unwrap_or_else(|| self.roots.last().map(|root| root.join(path)).unwrap_or_default());
+
+        let modified = fs::metadata(&file).and_then(|metadata| metadata.modified()).ok();
+        let is_current = self
+            .hashes
+            .read()
+            .ok()
+            .and_then(|hashes| hashes.get(path).map(|hash| hash.modified == modified))
+            .unwrap_or(false);
+        if is_current {
+            return;
+        }
+
+        if let Ok(mut hashes) = self.hashes.write() {
+            match hash_file(&file) {
+                Some(hash) => hashes.insert(path.to_string(), hash),
+                None => hashes.remove(path),
+            };
+        }
+    }
+
+    fn is_hashed(&self, file: &Path) -> bool {
+        file.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| self.extensions.contains(&extension.to_ascii_lowercase()))
+            .unwrap_or(false)
+    }
+}
+
+/// The name of `file` relative to `root`, with `/` separators
+fn relative_name(root: &Path, file: &Path) -> Option<String> {
+    let relative = file.strip_prefix(root).ok()?;
+    let parts: Vec<&str> = relative.iter().map(|part| part.to_str()).collect::<Option<_>>()?;
+    Some(parts.join("/"))
+}
+
+/// The `sha384-…` integrity value for `contents`
+pub fn integrity_of(contents: &[u8]) -> String {
+    format!("sha384-{}", base64::encode(&Sha384::digest(contents)))
+}
+
+fn hash_file(file: &Path) -> Option<AssetHash> {
+    let contents = fs::read(file).ok()?;
+    Some(AssetHash {
+        integrity: integrity_of(&contents),
+        modified: fs::metadata(file).and_then(|metadata| metadata.modified()).ok(),
+    })
+}
+
+fn read_manifest(path: &Path) -> Option<HashMap<String, AssetHash>> {
+    let contents = fs::read_to_string(path).ok()?;
+    let manifest: HashMap<String, String> = match serde_json::from_str(&contents) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            tracing::warn!(manifest = %path.display(), "Failed to read the manifest, hashing assets instead: {}", e);
+            return None;
+        }
+    };
+
+    Some(
+        manifest
+            .into_iter()
+            .map(|(name, integrity)| {
+                let name = name.trim_start_matches('/').to_string();
+                (name, AssetHash { integrity, modified: None })
+            })
+            .collect(),
+    )
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => collect_files(&path, files),
+            Ok(file_type) if file_type.is_file() => files.push(path),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use rocket::handler::Outcome;
+    use rocket::http::{Method, Status};
+    use rocket::{Data, Request, Route};
+    use rocket_contrib::templates::Template;
+    use serde_json::json;
+    use std::thread;
+    use std::time::Duration;
+
+    const APP_JS: &str = "console.log(\"hi\");\n";
+    const APP_JS_INTEGRITY: &str = "sha384-tdnWtBkj5+038HkeFOzlN0GdBuwDpXaWFs1Dhs560d67HmXunuEg4R3e+iEGk0Ho";
+
+    fn write(root: &Path, name: &str, contents: &str) {
+        let path = root.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn integrity(static_dirs: Vec<&Path>) -> AssetIntegrity {
+        let dirs: Vec<String> = static_dirs
+            .iter()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .collect();
+        let settings = Settings::builder()
+            .unwrap()
+            .set("static_dir", dirs)
+            .unwrap()
+            .build()
+            .unwrap();
+        AssetIntegrity::new(&settings)
+    }
+
+    #[test]
+    fn integrity_is_a_base64_sha384() {
+        assert_eq!(integrity_of(APP_JS.as_bytes()), APP_JS_INTEGRITY);
+    }
+
+    #[test]
+    fn hashes_files_with_the_configured_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "app.js", APP_JS);
+        write(dir.path(), "css/site.css", "body { color: red; }");
+        write(dir.path(), "logo.png", "not really a png");
+
+        let assets = integrity(vec![dir.path()]);
+        assert_eq!(assets.get("app.js"), Some(String::from(APP_JS_INTEGRITY)));
+        assert_eq!(assets.get("/app.js"), Some(String::from(APP_JS_INTEGRITY)));
+        assert_eq!(
+            assets.get("css/site.css"),
+            Some(integrity_of(b"body { color: red; }"))
+        );
+        assert_eq!(assets.get("logo.png"), None);
+        assert_eq!(assets.get("missing.js"), None);
+        assert_eq!(assets.get("../app.js"), None);
+    }
+
+    #[test]
+    fn earlier_static_dirs_shadow_later_ones() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        write(first.path(), "app.js", APP_JS);
+        write(second.path(), "app.js", "console.log(\"shadowed\");\n");
+        write(second.path(), "vendor.js", "vendor();\n");
+
+        let assets = integrity(vec![first.path(), second.path()]);
+        assert_eq!(assets.get("app.js"), Some(String::from(APP_JS_INTEGRITY)));
+        assert_eq!(assets.get("vendor.js"), Some(integrity_of(b"vendor();\n")));
+    }
+
+    #[test]
+    fn manifest_is_used_instead_of_hashing() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "app.js", APP_JS);
+        write(dir.path(), "other.js", "other();\n");
+        write(dir.path(), INTEGRITY_MANIFEST, r#"{ "/app.js": "sha384-fromthebuild" }"#);
+
+        let assets = integrity(vec![dir.path()]);
+        assert!(!assets.recompute);
+        assert_eq!(assets.get("app.js"), Some(String::from("sha384-fromthebuild")));
+        assert_eq!(assets.get("other.js"), None);
+
+        // An unreadable manifest falls back to hashing
+        write(dir.path(), INTEGRITY_MANIFEST, "not json");
+        let assets = integrity(vec![dir.path()]);
+        assert_eq!(assets.get("app.js"), Some(String::from(APP_JS_INTEGRITY)));
+    }
+
+    #[test]
+    fn changed_files_are_only_rehashed_in_development() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "app.js", APP_JS);
+        let development = AssetIntegrity {
+            recompute: true,
+            ..integrity(vec![dir.path()])
+        };
+        let production = AssetIntegrity {
+            recompute: false,
+            ..integrity(vec![dir.path()])
+        };
+        assert_eq!(development.get("app.js"), Some(String::from(APP_JS_INTEGRITY)));
+
+        // Far enough apart that the modification time changes
+        thread::sleep(Duration::from_millis(50));
+        write(dir.path(), "app.js", "console.log(\"edited\");\n");
+        write(dir.path(), "new.js", "added();\n");
+
+        assert_eq!(development.get("app.js"), Some(integrity_of(b"console.log(\"edited\");\n")));
+        assert_eq!(development.get("new.js"), Some(integrity_of(b"added();\n")));
+        assert_eq!(production.get("app.js"), Some(String::from(APP_JS_INTEGRITY)));
+        assert_eq!(production.get("new.js"), None);
+
+        fs::remove_file(dir.path().join("new.js")).unwrap();
+        assert_eq!(development.get("new.js"), None);
+    }
+
+    fn index<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, Template::render("index", json!({})))
+    }
+
+    #[test]
+    fn helper_renders_the_integrity_attribute() {
+        let app = TestApp::builder()
+            .static_file("app.js", APP_JS)
+            .template(
+                "index.html.hbs",
+                "<script src=\"/static/app.js\" integrity=\"{{asset_integrity \"app.js\"}}\"></script>\
+                 <link href=\"/static/missing.css\" integrity=\"{{asset_integrity \"missing.css\"}}\">",
+            )
+            .mount("/", vec![Route::new(Method::Get, "/", index)])
+            .build()
+            .unwrap();
+
+        let mut response = app.client().get("/").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.body_string(),
+            Some(format!(
+                "<script src=\"/static/app.js\" integrity=\"{}\"></script>\
+                 <link href=\"/static/missing.css\" integrity=\"\">",
+                APP_JS_INTEGRITY
+            ))
+        );
+        assert_eq!(
+            app.state::<AssetIntegrity>().and_then(|integrity| integrity.get("app.js")),
+            Some(String::from(APP_JS_INTEGRITY))
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Reading private cookies across secret key rotations.
+//!
+//! Rocket encrypts private cookies with the single `secret_key` from its config, and
+//! `Cookies::get_private` can only decrypt cookies sealed with that key. Changing the key
+//! therefore invalidates every private cookie (and so every session) at once.
+//!
+//! `KeyRing` works around this by keeping the keys from `Settings::previous_secret_keys` and
+//! trying each of them when a cookie can't be opened with the current key. A cookie that could
+//! only be opened with a previous key is re-issued in the response, sealed with the current
+//! key, so once every active client has made a request since the rotation the previous key can
+//! be removed. New cookies should still be added with `Cookies::add_private`, so they are
+//! always sealed with the current key.
+//! Code that can't use `Cookies` (e.g. response fairings, which run after rocket has already
+//! written the request's cookies to the response) can seal values with `KeyRing::seal` instead.
+//!
+//! This only helps code that reads private cookies through `KeyRing::get_private`; anything
+//! that calls `Cookies::get_private` directly (including other crates) only sees the current
+//! key. Previous keys are derived the same way that rocket derives its key, so cookies read
+//! with them must have been created by rocket's own private cookie implementation.
+use crate::app::Settings;
+
+use cookie::{CookieJar, Key};
+use rocket::http::{Cookie, Cookies};
+
+/// The current and previous secret keys, used to read private cookies created before the
+/// current key was introduced
+pub struct KeyRing {
+    current: Key,
+    previous: Vec<Key>,
+}
+
+impl KeyRing {
+    /// Create a key ring from `Settings::secret_key` and `Settings::previous_secret_keys`. Keys
+    /// that aren't valid base64 encoded 256-bit keys are skipped; `Settings::validate` reports
+    /// them as errors. Without a secret key, a random key is generated for the lifetime of the
+    /// key ring (as rocket does in development).
+    pub fn new(settings: &Settings) -> KeyRing {
+        KeyRing {
+            current: settings.secret_key().and_then(decode_key).unwrap_or_else(Key::generate),
+            previous: settings
+                .previous_secret_keys
+                .iter()
+                .filter_map(|key| decode_key(key))
+                .collect(),
+        }
+    }
+
+    /// Get and decrypt the private cookie `name`, trying the current secret key first and
+    /// then each of the previous keys, in order. If a previous key was needed, the cookie is
+    /// re-issued with the current key and rocket's default attributes for private cookies; use
+    /// `get_private_with` for cookies that were issued with other attributes.
+    pub fn get_private(&self, cookies: &mut Cookies, name: &str) -> Option<Cookie<'static>> {
+        self.get_private_with(cookies, name, |value| Cookie::new(name.to_string(), value))
+    }
+
+    /// Like `get_private`, but a cookie that is re-issued is built by `reissue` from its value,
+    /// so that it keeps the path, expiry and other attributes that it was first issued with.
+    /// Requests only carry the name and value of each cookie, so the attributes can't be
+    /// copied from the cookie that was sent.
+    pub fn get_private_with<F>(&self, cookies: &mut Cookies, name: &str, reissue: F) -> Option<Cookie<'static>>
+    where
+        F: FnOnce(String) -> Cookie<'static>,
+    {
+        if let Some(cookie) = cookies.get_private(name) {
+            return Some(cookie);
+        }
+
+        let (cookie, is_current) = {
+            let sealed = cookies.get(name)?;
+            self.open_with(sealed.name(), sealed.value())?
+        };
+
+        if !is_current {
+            cookies.add_private(reissue(cookie.value().to_string()));
+        }
+
+        Some(cookie)
+    }
+
+    /// Encrypt the value for a cookie called `name` with the current key. The result can be
+    /// read back with `KeyRing::get_private` or `KeyRing::open`.
+    pub fn seal(&self, name: &str, value: &str) -> String {
+        let mut jar = CookieJar::new();
+        jar.private(&self.current)
+            .add(cookie::Cookie::new(name.to_string(), value.to_string()));
+
+        jar.get(name)
+            .map(|sealed| sealed.value().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Try to decrypt a sealed cookie value with the current key, and then each of the
+    /// previous keys
+    pub fn open(&self, name: &str, sealed: &str) -> Option<Cookie<'static>> {
+        self.open_with(name, sealed).map(|(cookie, _)| cookie)
+    }
+
+    /// Like `open`, but also returns whether the cookie was opened with the current key
+    fn open_with(&self, name: &str, sealed: &str) -> Option<(Cookie<'static>, bool)> {
+        Some(&self.current)
+            .into_iter()
+            .chain(self.previous.iter())
+            .enumerate()
+            .find_map(|(i, key)| {
+                let mut jar = CookieJar::new();
+                jar.add_original(cookie::Cookie::new(name.to_string(), sealed.to_string()));
+
+                jar.private(key).get(name).map(|opened| {
+                    let cookie = Cookie::new(opened.name().to_string(), opened.value().to_string());
+                    (cookie, i == 0)
+                })
+            })
+    }
+}
+
+/// Decode a secret key in the same base64 encoded, 256-bit format that rocket requires
+pub fn decode_key(key: &str) -> Option<Key> {
+    match base64::decode(key) {
+        Ok(ref bytes) if bytes.len() == 32 => Some(Key::from_master(bytes)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::handler::Outcome;
+    use rocket::http::{Method, SameSite, Status};
+    use rocket::local::Client;
+    use rocket::{Data, Request, Route, State};
+
+    fn key(byte: u8) -> String {
+        base64::encode(&[byte; 32])
+    }
+
+    fn settings(current: &str, previous: Vec<String>) -> Settings {
+        Settings::builder()
+            .unwrap()
+            .set("secret_key", current)
+            .unwrap()
+            .set("previous_secret_keys", previous)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    /// Reads the `token` cookie, re-issuing it with attributes that differ from rocket's defaults
+    fn read<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let keyring = match request.guard::<State<KeyRing>>().succeeded() {
+            Some(keyring) => keyring,
+            None => return Outcome::failure(Status::InternalServerError),
+        };
+        let reissue = |value| {
+            Cookie::build("token", value)
+                .path("/app")
+                .secure(true)
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .max_age(time::Duration::days(30))
+                .finish()
+        };
+
+        match keyring.get_private_with(&mut request.cookies(), "token", reissue) {
+            Some(cookie) => Outcome::from(request, cookie.value().to_string()),
+            None => Outcome::failure(Status::Unauthorized),
+        }
+    }
+
+    fn client_for(settings: Settings) -> Client {
+        let rocket = rocket::custom(settings.clone().into())
+            .manage(KeyRing::new(&settings))
+            .mount("/", vec![Route::new(Method::Get, "/read", read)]);
+        Client::untracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn cookies_from_a_previous_key_are_reissued_with_the_current_key() {
+        let (a, b) = (key(1), key(2));
+        let sealed_by_a = KeyRing::new(&settings(&a, vec![])).seal("token", "value");
+
+        let rotated = client_for(settings(&b, vec![a.clone()]));
+        let mut response = rotated.get("/read").cookie(Cookie::new("token", sealed_by_a)).dispatch();
+        assert_eq!(response.body_string(), Some(String::from("value")));
+
+        let reissued = response
+            .cookies()
+            .into_iter()
+            .find(|cookie| cookie.name() == "token")
+            .expect("the cookie is re-issued")
+            .into_owned();
+        assert_eq!(reissued.path(), Some("/app"));
+        assert_eq!(reissued.secure(), Some(true));
+        assert_eq!(reissued.http_only(), Some(true));
+        assert_eq!(reissued.same_site(), Some(SameSite::Lax));
+        assert_eq!(reissued.max_age(), Some(time::Duration::days(30)));
+
+        // The re-issued cookie is sealed with B, so it can still be read once A is retired
+        let retired = client_for(settings(&b, vec![]));
+        let mut response = retired
+            .get("/read")
+            .cookie(Cookie::new("token", reissued.value().to_string()))
+            .dispatch();
+        assert_eq!(response.body_string(), Some(String::from("value")));
+        assert!(!response.cookies().iter().any(|cookie| cookie.name() == "token"));
+        assert!(KeyRing::new(&settings(&a, vec![])).open("token", reissued.value()).is_none());
+    }
+
+    #[test]
+    fn cookies_from_unknown_keys_are_rejected() {
+        let sealed = KeyRing::new(&settings(&key(3), vec![])).seal("token", "value");
+
+        let client = client_for(settings(&key(2), vec![key(1)]));
+        let response = client.get("/read").cookie(Cookie::new("token", sealed)).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(response.cookies().is_empty());
+    }
+}
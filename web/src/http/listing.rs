@@ -0,0 +1,335 @@
+//! Listings of the files in a directory, for programmatic access to a folder without enabling
+//! `Options::Index` on the static file handler (which serves `index.html` files rather than
+//! listing anything).
+//!
+//! API clients that prefer JSON get an array of entries:
+//!
+//! ```json
+//! [{ "name": "report.pdf", "is_dir": false, "size": 48213, "modified": 1554076800 }]
+//! ```
+//!
+//! where `modified` is in seconds since the unix epoch. Browsers get a simple HTML list of
+//! links instead (see `http::negotiation`). Hidden files are left out, as they are by
+//! `StaticFiles`.
+//!
+//! A listing can cover several layered directories, like `Settings::static_dir`, by listing
+//! the same path in each of them. Entries are merged, and an entry in an earlier directory
+//! hides one with the same name in a later directory.
+use crate::http::negotiation;
+use crate::http::wrappers::escape_html;
+
+use rocket::handler::{Handler, Outcome};
+use rocket::http::uri::{Segments, Uri};
+use rocket::http::{ContentType, Method, RawStr, Status};
+use rocket::response::Response;
+use rocket::{Data, Request, Route};
+use serde_derive::Serialize;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// A single file or directory in a listing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListingEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// The size of the file in bytes, or `0` for directories
+    pub size: u64,
+    /// When the entry was last modified, in seconds since the unix epoch, if the platform
+    /// records it
+    pub modified: Option<u64>,
+}
+
+/// A handler that lists the contents of `root` and its subdirectories. Paths that point outside
+/// of `root` (with a `..` segment) are rejected with `400 Bad Request`, and paths that aren't
+/// directories (in any of the roots, see `layered`) with `404 Not Found`.
+///
+/// # Examples
+///
+/// ```
+/// rocket.mount("/exports", DirectoryListing::new("/var/lib/app/exports").routes())
+/// ```
+#[derive(Debug, Clone)]
+pub struct DirectoryListing {
+    roots: Vec<PathBuf>,
+}
+
+impl DirectoryListing {
+    pub fn new<P: Into<PathBuf>>(root: P) -> DirectoryListing {
+        DirectoryListing::layered(vec![root.into()])
+    }
+
+    /// A listing that merges the same path in each of `roots`, with earlier roots winning
+    /// when two have an entry with the same name
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// rocket.mount("/static", DirectoryListing::layered(settings.static_dirs()).routes())
+    /// ```
+    pub fn layered(roots: Vec<PathBuf>) -> DirectoryListing {
+        DirectoryListing { roots }
+    }
+
+    /// `GET` routes for the mount point itself and every path beneath it
+    pub fn routes(self) -> Vec<Route> {
+        vec![
+            Route::ranked(10, Method::Get, "/", self.clone()),
+            Route::ranked(10, Method::Get, "/<path..>", self),
+        ]
+    }
+
+    /// The directories that `request` is for, in the order of the roots that they are in, or
+    /// `Err` with the status to fail with
+    fn directories(&self, request: &Request) -> Result<Vec<PathBuf>, Status> {
+        let segments = match request.get_segments::<Segments>(0) {
+            Some(Ok(segments)) => segments.collect::<Vec<&str>>(),
+            _ => Vec::new(),
+        };
+
+        let mut relative = PathBuf::new();
+        for segment in segments {
+            let decoded = RawStr::from_str(segment).percent_decode().map_err(|_| Status::BadRequest)?;
+            let is_unsafe = decoded == ".." || decoded.contains('/') || decoded.contains('\\');
+            if is_unsafe || decoded.starts_with('.') {
+                return Err(Status::BadRequest);
+            }
+            relative.push(decoded.as_ref());
+        }
+
+        let dirs: Vec<PathBuf> = self
+            .roots
+            .iter()
+            .map(|root| root.join(&relative))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        if dirs.is_empty() {
+            Err(Status::NotFound)
+        } else {
+            Ok(dirs)
+        }
+    }
+}
+
+/// The visible entries in each of `dirs`, sorted by name with directories first. Entries in
+/// earlier directories hide entries with the same name in later ones.
+pub fn list_layered(dirs: &[PathBuf]) -> io::Result<Vec<ListingEntry>> {
+    let mut entries: Vec<ListingEntry> = Vec::new();
+    for dir in dirs {
+        for entry in list_directory(dir)? {
+            if !entries.iter().any(|existing| existing.name == entry.name) {
+                entries.push(entry);
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// The visible entries in `dir`, sorted by name with directories first
+pub fn list_directory(dir: &Path) -> io::Result<Vec<ListingEntry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        entries.push(ListingEntry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs()),
+        });
+    }
+
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// An HTML list of links to `entries`, which are in the directory at `base` (a request path)
+fn html_listing(base: &str, entries: &[ListingEntry]) -> String {
+    let base = base.trim_end_matches('/');
+    let items: String = entries
+        .iter()
+        .map(|entry| {
+            let suffix = if entry.is_dir { "/" } else { "" };
+            format!(
+                "<li><a href=\"{}/{}{}\">{}{}</a></li>",
+                escape_html(base),
+                escape_html(&Uri::percent_encode(&entry.name)),
+                suffix,
+                escape_html(&entry.name),
+                suffix
+            )
+        })
+        .collect();
+
+    format!("<ul>{}</ul>", items)
+}
+
+impl Handler for DirectoryListing {
+    fn handle<'r>(&self, request: &'r Request, _: Data) -> Outcome<'r> {
+        let dirs = match self.directories(request) {
+            Ok(dirs) => dirs,
+            Err(status) => return Outcome::Failure(status),
+        };
+
+        let entries = match list_layered(&dirs) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!(dir = %dirs[0].display(), "Failed to list directory: {}", e);
+                return Outcome::Failure(Status::InternalServerError);
+            }
+        };
+
+        let offered = [ContentType::HTML, ContentType::JSON];
+        let response = if negotiation::preferred_content_type(request, &offered) == Some(ContentType::JSON) {
+            let body = serde_json::to_string(&entries).unwrap_or_else(|_| String::from("[]"));
+            Response::build()
+                .header(ContentType::JSON)
+                .raw_header("Vary", "Accept")
+                .sized_body(Cursor::new(body))
+                .finalize()
+        } else {
+            Response::build()
+                .header(ContentType::HTML)
+                .raw_header("Vary", "Accept")
+                .sized_body(Cursor::new(html_listing(request.uri().path(), &entries)))
+                .finalize()
+        };
+
+        Outcome::Success(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use rocket::http::Accept;
+    use rocket::local::Client;
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    /// Two layers, `first` and `second`, with `readme.txt` and `docs/` in both
+    fn layers() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let files: [(&str, &str); 7] = [
+            ("first/readme.txt", "first"),
+            ("first/docs/guide.md", "first guide"),
+            ("first/.hidden", "secret"),
+            ("second/readme.txt", "the second readme"),
+            ("second/report.pdf", "%PDF-1.7"),
+            ("second/docs/guide.md", "second guide"),
+            ("second/docs/faq.md", "faq"),
+        ];
+        for (path, contents) in &files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn roots(dir: &TempDir) -> Vec<PathBuf> {
+        vec![dir.path().join("first"), dir.path().join("second")]
+    }
+
+    fn names(entries: &[ListingEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn lists_one_directory() {
+        let dir = layers();
+        let entries = list_directory(&dir.path().join("second")).unwrap();
+        assert_eq!(names(&entries), vec!["docs", "readme.txt", "report.pdf"]);
+        assert!(entries[0].is_dir);
+        assert_eq!(entries[0].size, 0);
+        assert_eq!(entries[1].size, "the second readme".len() as u64);
+        assert!(entries[1].modified.is_some());
+    }
+
+    #[test]
+    fn earlier_layers_hide_later_entries() {
+        let dir = layers();
+        let entries = list_layered(&roots(&dir)).unwrap();
+
+        assert_eq!(names(&entries), vec!["docs", "readme.txt", "report.pdf"]);
+        // The readme is the first layer's, and the report falls through from the second
+        assert_eq!(entries[1].size, "first".len() as u64);
+        assert_eq!(entries[2].size, "%PDF-1.7".len() as u64);
+
+        let docs: Vec<PathBuf> = roots(&dir).iter().map(|root| root.join("docs")).collect();
+        assert_eq!(names(&list_layered(&docs).unwrap()), vec!["faq.md", "guide.md"]);
+    }
+
+    fn client(dir: &TempDir) -> Client {
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .mount("/files", DirectoryListing::layered(roots(dir)).routes());
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn merged_listing_as_json() {
+        let dir = layers();
+        let client = client(&dir);
+
+        let mut response = client.get("/files").header(Accept::JSON).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let entries: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let listed: Vec<(&str, bool, u64)> = entries
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry["name"].as_str().unwrap(),
+                    entry["is_dir"].as_bool().unwrap(),
+                    entry["size"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(listed, vec![("docs", true, 0), ("readme.txt", false, 5), ("report.pdf", false, 8)]);
+
+        // A directory that is only in one layer is still listed
+        fs::create_dir(dir.path().join("second/archive")).unwrap();
+        let response = client.get("/files/archive").header(Accept::JSON).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn merged_listing_as_html() {
+        let dir = layers();
+        let mut response = client(&dir).get("/files/docs").header(Accept::HTML).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.body_string(),
+            Some(String::from(
+                "<ul><li><a href=\"/files/docs/faq.md\">faq.md</a></li>\
+                 <li><a href=\"/files/docs/guide.md\">guide.md</a></li></ul>"
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_layers() {
+        let dir = layers();
+        let client = client(&dir);
+        assert_eq!(client.get("/files/missing").dispatch().status(), Status::NotFound);
+        assert_eq!(client.get("/files/readme.txt").dispatch().status(), Status::NotFound);
+        assert_eq!(client.get("/files/%2E%2E").dispatch().status(), Status::BadRequest);
+        assert_eq!(client.get("/files/.hidden").dispatch().status(), Status::BadRequest);
+        assert_eq!(client.get("/files/docs%2F..").dispatch().status(), Status::BadRequest);
+    }
+}
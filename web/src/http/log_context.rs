@@ -0,0 +1,110 @@
+//! Tagging every log line with the request that it was logged for, so that the lines for one
+//! request can be found together without passing its ID to every call.
+//!
+//! Logs go through `tracing`, written to stdout by the subscriber that `TracingFairing`
+//! installs. While a request is being handled, `RequestLogContext` enters a `request` span with
+//! the request's `request_id` (see `RequestId`), `method` and `path`, so the fields are printed
+//! with every event in the request:
+//!
+//! ```text
+//! INFO request{request_id=6f1c… method=GET path=/about}: web::routes: rendered page
+//! ```
+//!
+//! Rocket and most dependencies log with the `log` crate instead. `ContextLogger` forwards
+//! those records to `tracing`, from the thread that logged them, so they land in the same span
+//! and carry the same fields. It's installed by `install_logger`, which must run before the
+//! rocket instance is created, as rocket installs its own logger otherwise.
+use crate::http::guards::RequestId;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use std::cell::RefCell;
+use tracing::span::EnteredSpan;
+use tracing_log::AsTrace;
+
+/// The fields that are attached to log lines for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogContext {
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+}
+
+impl LogContext {
+    pub fn of(request: &Request) -> LogContext {
+        LogContext {
+            request_id: RequestId::of(request).0,
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+        }
+    }
+
+    /// The context of the request being handled on this thread, if there is one
+    pub fn current() -> Option<LogContext> {
+        CURRENT.with(|current| current.borrow().as_ref().map(|(context, _)| context.clone()))
+    }
+}
+
+thread_local! {
+    /// The request being handled on this thread, and its entered span
+    static CURRENT: RefCell<Option<(LogContext, EnteredSpan)>> = RefCell::new(None);
+}
+
+/// Sets the `LogContext` for each request, from the request fairings through to the response
+/// fairings. Rocket handles each request on a single thread, as for `ErrorContext`.
+pub struct RequestLogContext;
+
+impl Fairing for RequestLogContext {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Log Context",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        // Leave the span of any earlier request that didn't reach `on_response` (e.g. one whose
+        // handler panicked) before entering the new one
+        CURRENT.with(|current| current.borrow_mut().take());
+
+        let context = LogContext::of(request);
+        let span = tracing::info_span!(
+            "request",
+            request_id = %context.request_id,
+            method = %context.method,
+            path = %context.path
+        )
+        .entered();
+        CURRENT.with(|current| *current.borrow_mut() = Some((context, span)));
+    }
+
+    fn on_response(&self, _: &Request, _: &mut Response) {
+        CURRENT.with(|current| current.borrow_mut().take());
+    }
+}
+
+/// A `log` implementation that forwards every record to `tracing`, see the module docs
+pub struct ContextLogger;
+
+impl log::Log for ContextLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        tracing::dispatcher::get_default(|dispatch| dispatch.enabled(&metadata.as_trace()))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            tracing_log::format_trace(record).ok();
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install `ContextLogger` as the `log` logger. Records are filtered by the `tracing`
+/// subscriber (i.e. the `log` setting), so every level is let through here. Does nothing if a
+/// logger has already been installed.
+pub fn install_logger() {
+    if log::set_logger(&ContextLogger).is_ok() {
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+}
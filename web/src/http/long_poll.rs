@@ -0,0 +1,342 @@
+//! Long polling, for clients that can't use server-sent events (e.g. behind proxies that buffer
+//! streamed responses).
+//!
+//! Each topic in the `ChangeFeed` has a version that increases every time data is published to
+//! it. Clients send back the token (version) from their last response; if the topic has changed
+//! since, the new data is returned straight away, otherwise the request waits until something
+//! is published or `Settings::long_poll_timeout` passes.
+//!
+//! Every waiting request holds a rocket worker thread, so at most
+//! `Settings::long_poll_max_parked` requests can wait at once, and any more get
+//! `503 Service Unavailable` so that the other routes stay responsive.
+use crate::app::Settings;
+use crate::http::stats::Introspect;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The header that unchanged long poll responses send the client's token back in
+pub const CHANGE_TOKEN_HEADER: &'static str = "X-Change-Token";
+
+#[derive(Debug, Default)]
+struct Topics {
+    /// The latest data published to each topic, with the version it was published as
+    latest: Mutex<HashMap<String, (u64, Value)>>,
+    published: Condvar,
+    parked: AtomicUsize,
+}
+
+/// The topics that clients can long poll for changes to, kept in managed state. Clones share
+/// the same topics, so a clone can be moved to a background thread to publish from.
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    topics: Arc<Topics>,
+    max_parked: usize,
+    timeout: Duration,
+}
+
+/// The outcome of a long poll
+#[derive(Debug, Clone, PartialEq)]
+pub enum LongPoll {
+    /// The topic changed after the client's token. Responds with `200 OK` and a JSON body of
+    /// `{ "token": "...", "data": ... }`
+    Changed { token: u64, data: Value },
+    /// Nothing was published before the timeout. Responds with `204 No Content`, with the
+    /// unchanged token in an `X-Change-Token` header
+    Unchanged { token: u64 },
+    /// Too many requests were already waiting. Responds with `503 Service Unavailable`
+    Overloaded,
+}
+
+/// Releases a parked slot when the waiting request finishes, however it finishes
+struct Parked<'f>(&'f AtomicUsize);
+
+impl<'f> Drop for Parked<'f> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ChangeFeed {
+    pub fn new(settings: &Settings) -> ChangeFeed {
+        ChangeFeed {
+            topics: Arc::default(),
+            max_parked: settings.long_poll_max_parked,
+            timeout: settings.long_poll_timeout.as_duration(),
+        }
+    }
+
+    /// The configured time that a long poll waits for before giving up
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// The number of requests currently waiting for a change
+    pub fn parked(&self) -> usize {
+        self.topics.parked.load(Ordering::SeqCst)
+    }
+
+    /// Replace the data for `topic`, and wake the requests waiting for it. Returns the new
+    /// token for the topic.
+    pub fn publish(&self, topic: &str, data: Value) -> u64 {
+        let mut latest = match self.topics.latest.lock() {
+            Ok(latest) => latest,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let version = latest.get(topic).map(|(version, _)| version + 1).unwrap_or(1);
+        latest.insert(topic.to_string(), (version, data));
+        self.topics.published.notify_all();
+        version
+    }
+
+    /// Wait for `topic` to change after `client_token` (the token from the client's last
+    /// response, or `None` for its first request), for at most `timeout`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[get("/changes/<topic>?<token>")]
+    /// fn changes(topic: Slug, token: Option<u64>, feed: AppState<ChangeFeed>) -> LongPoll {
+    ///     feed.long_poll(topic.as_str(), token, feed.timeout())
+    /// }
+    /// ```
+    pub fn long_poll(&self, topic: &str, client_token: Option<u64>, timeout: Duration) -> LongPoll {
+        let mut latest = match self.topics.latest.lock() {
+            Ok(latest) => latest,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let client_token = client_token.unwrap_or(0);
+        let changed = |latest: &HashMap<String, (u64, Value)>| match latest.get(topic) {
+            Some((version, data)) if *version > client_token => Some(LongPoll::Changed {
+                token: *version,
+                data: data.clone(),
+            }),
+            _ => None,
+        };
+
+        if let Some(response) = changed(&latest) {
+            return response;
+        }
+
+        if self.topics.parked.fetch_add(1, Ordering::SeqCst) >= self.max_parked {
+            self.topics.parked.fetch_sub(1, Ordering::SeqCst);
+            return LongPoll::Overloaded;
+        }
+        let _parked = Parked(&self.topics.parked);
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return LongPoll::Unchanged { token: client_token };
+            }
+
+            latest = match self.topics.published.wait_timeout(latest, deadline - now) {
+                Ok((latest, _)) => latest,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+
+            if let Some(response) = changed(&latest) {
+                return response;
+            }
+        }
+    }
+}
+
+impl Introspect for ChangeFeed {
+    fn stats(&self) -> Value {
+        let topics = self.topics.latest.lock().map(|latest| latest.len()).unwrap_or(0);
+        json!({ "topics": topics, "parked": self.parked(), "max_parked": self.max_parked })
+    }
+}
+
+impl<'r> Responder<'r> for LongPoll {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        match self {
+            LongPoll::Changed { token, data } => Response::build()
+                .header(ContentType::JSON)
+                .sized_body(Cursor::new(
+                    json!({ "token": token.to_string(), "data": data }).to_string(),
+                ))
+                .ok(),
+            LongPoll::Unchanged { token } => Response::build()
+                .status(Status::NoContent)
+                .header(Header::new(CHANGE_TOKEN_HEADER, token.to_string()))
+                .ok(),
+            LongPoll::Overloaded => Response::build()
+                .status(Status::ServiceUnavailable)
+                .header(Header::new("Retry-After", "1"))
+                .ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use rocket::config::{Config, Environment};
+    use rocket::handler::Outcome;
+    use rocket::http::Method;
+    use rocket::local::Client;
+    use rocket::request::State;
+    use rocket::{Data, Route};
+    use std::thread;
+
+    fn feed(max_parked: usize) -> ChangeFeed {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("long_poll_max_parked", max_parked as i64)
+            .unwrap()
+            .build()
+            .unwrap();
+        ChangeFeed::new(&settings)
+    }
+
+    /// Wait for `count` requests to be parked on `feed`
+    fn wait_until_parked(feed: &ChangeFeed, count: usize) {
+        let started = Instant::now();
+        while feed.parked() != count {
+            assert!(started.elapsed() < Duration::from_secs(5), "requests were never parked");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn returns_immediately_when_changed() {
+        let feed = feed(4);
+        assert_eq!(feed.publish("news", json!(["first"])), 1);
+        assert_eq!(feed.publish("news", json!(["first", "second"])), 2);
+
+        let started = Instant::now();
+        let first = LongPoll::Changed { token: 2, data: json!(["first", "second"]) };
+        assert_eq!(feed.long_poll("news", None, Duration::from_secs(5)), first);
+        assert_eq!(feed.long_poll("news", Some(1), Duration::from_secs(5)), first);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(feed.parked(), 0);
+    }
+
+    #[test]
+    fn times_out_with_the_same_token() {
+        let feed = feed(4);
+        feed.publish("news", json!(1));
+
+        let started = Instant::now();
+        let unchanged = feed.long_poll("news", Some(1), Duration::from_millis(50));
+        assert_eq!(unchanged, LongPoll::Unchanged { token: 1 });
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // Topics that were never published to wait too
+        let unchanged = feed.long_poll("weather", None, Duration::from_millis(10));
+        assert_eq!(unchanged, LongPoll::Unchanged { token: 0 });
+        assert_eq!(feed.parked(), 0);
+    }
+
+    #[test]
+    fn wakes_on_publish() {
+        let feed = feed(4);
+        feed.publish("news", json!("old"));
+
+        let waiting = feed.clone();
+        let started = Instant::now();
+        let poll = thread::spawn(move || waiting.long_poll("news", Some(1), Duration::from_secs(10)));
+        wait_until_parked(&feed, 1);
+
+        // Other topics don't wake it
+        feed.publish("weather", json!("rain"));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(feed.parked(), 1);
+
+        feed.publish("news", json!("new"));
+        assert_eq!(poll.join().unwrap(), LongPoll::Changed { token: 2, data: json!("new") });
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(feed.parked(), 0);
+    }
+
+    #[test]
+    fn parked_requests_are_capped() {
+        let feed = feed(2);
+        let polls: Vec<_> = (0..2)
+            .map(|_| {
+                let waiting = feed.clone();
+                thread::spawn(move || waiting.long_poll("news", None, Duration::from_secs(10)))
+            })
+            .collect();
+        wait_until_parked(&feed, 2);
+
+        assert_eq!(feed.long_poll("news", None, Duration::from_secs(10)), LongPoll::Overloaded);
+        assert_eq!(feed.parked(), 2);
+
+        // Changes are still returned at the cap, as they don't need to wait
+        feed.publish("weather", json!("sun"));
+        assert_eq!(
+            feed.long_poll("weather", None, Duration::from_secs(10)),
+            LongPoll::Changed { token: 1, data: json!("sun") }
+        );
+
+        feed.publish("news", json!("released"));
+        for poll in polls {
+            assert_eq!(poll.join().unwrap(), LongPoll::Changed { token: 1, data: json!("released") });
+        }
+        assert_eq!(feed.parked(), 0);
+        assert_eq!(feed.stats(), json!({ "topics": 2, "parked": 0, "max_parked": 2 }));
+    }
+
+    #[test]
+    fn responses() {
+        let client = Client::new(rocket::custom(Config::new(Environment::Development))).unwrap();
+        let request = client.get("/");
+
+        let changed = LongPoll::Changed { token: 3, data: json!({ "headline": "hello" }) };
+        let mut response = changed.respond_to(request.inner()).unwrap();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(body, json!({ "token": "3", "data": { "headline": "hello" } }));
+
+        let mut response = LongPoll::Unchanged { token: 3 }.respond_to(request.inner()).unwrap();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one(CHANGE_TOKEN_HEADER), Some("3"));
+        assert_eq!(response.body_bytes(), None);
+
+        let response = LongPoll::Overloaded.respond_to(request.inner()).unwrap();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("1"));
+    }
+
+    fn news<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let feed = request.guard::<State<ChangeFeed>>().unwrap();
+        let token = request.get_query_value::<u64>("token").and_then(Result::ok);
+        Outcome::from(request, feed.long_poll("news", token, feed.timeout()))
+    }
+
+    #[test]
+    fn app_long_polls_with_the_configured_timeout() {
+        let app = TestApp::builder()
+            .setting("long_poll_timeout", "50ms")
+            .mount("/", vec![Route::new(Method::Get, "/changes/news", news)])
+            .build()
+            .unwrap();
+        let feed = app.state::<ChangeFeed>().unwrap();
+        assert_eq!(feed.timeout(), Duration::from_millis(50));
+        feed.publish("news", json!("hello"));
+
+        let mut response = app.client().get("/changes/news").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(body, json!({ "token": "1", "data": "hello" }));
+
+        let response = app.client().get("/changes/news?token=1").dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one(CHANGE_TOKEN_HEADER), Some("1"));
+    }
+}
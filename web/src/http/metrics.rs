@@ -0,0 +1,56 @@
+//! Counters for the app's own behaviour, kept in managed state. Enabled with the `metrics`
+//! feature and `Settings::metrics_enabled`.
+use crate::http::stats::Introspect;
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Counts the requests that `TimeBound` gave up on, for each route
+#[derive(Debug, Default, Clone)]
+pub struct DeadlineMetrics {
+    exceeded: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl DeadlineMetrics {
+    /// The number of times each route (as `"METHOD /uri"`) has exceeded its deadline
+    pub fn exceeded(&self) -> HashMap<String, u64> {
+        self.exceeded.lock().map(|exceeded| exceeded.clone()).unwrap_or_default()
+    }
+
+    pub(crate) fn record(&self, route: String) {
+        if let Ok(mut exceeded) = self.exceeded.lock() {
+            *exceeded.entry(route).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Counts the bytes of file bodies written by `LargeFile`
+#[derive(Debug, Default, Clone)]
+pub struct FileMetrics {
+    bytes_served: Arc<AtomicU64>,
+}
+
+impl FileMetrics {
+    pub fn bytes_served(&self) -> u64 {
+        self.bytes_served.load(Ordering::Relaxed)
+    }
+
+    /// A handle to the counter, for response bodies that outlive the request's borrow of it
+    pub(crate) fn counter(&self) -> Arc<AtomicU64> {
+        self.bytes_served.clone()
+    }
+}
+
+impl Introspect for DeadlineMetrics {
+    fn stats(&self) -> Value {
+        json!({ "exceeded": self.exceeded() })
+    }
+}
+
+impl Introspect for FileMetrics {
+    fn stats(&self) -> Value {
+        json!({ "bytes_served": self.bytes_served() })
+    }
+}
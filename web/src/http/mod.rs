@@ -1 +1,29 @@
+pub mod access_log;
+pub mod attribution;
+pub mod csp;
+pub mod csrf;
+#[cfg(feature = "embed-assets")]
+pub mod embedded;
+pub mod fairings;
+pub mod guards;
+pub mod images;
+pub mod integrity;
+pub mod keyring;
+pub mod listing;
+pub mod log_context;
+pub mod long_poll;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod negotiation;
+pub mod params;
+pub mod policy;
+pub mod proxy;
+pub mod redirect;
+pub mod reporting;
+pub mod sessions;
+#[cfg(feature = "sse")]
+pub mod sse;
+pub mod stats;
+#[cfg(feature = "uploads")]
+pub mod uploads;
 pub mod wrappers;
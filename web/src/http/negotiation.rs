@@ -0,0 +1,281 @@
+//! Choosing between the representations that a route can offer, from the client's `Accept` and
+//! `Accept-Language` headers.
+//!
+//! Both headers are lists of ranges with optional quality values, e.g.
+//! `text/html, application/json;q=0.9, */*;q=0.1`. Each offered value is weighted by the most
+//! specific range that matches it (so `text/html;q=0` excludes HTML even with `*/*`), and the
+//! offer with the highest weight wins, with ties going to the earliest offer. Entries with a
+//! malformed range or quality are ignored, and offers that no range matches, or that only match
+//! with `q=0`, are never chosen. A missing header (or one with no valid entries) accepts
+//! anything, so the first offer is chosen.
+use rocket::http::ContentType;
+use rocket::request::Request;
+
+/// Split a header into `(range, quality)` pairs, in the order they were sent. Ranges without a
+/// `q` parameter have a quality of `1.0`; entries with a quality that isn't a number from `0`
+/// to `1` are left out.
+pub fn parse_weighted(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let range = parts.next().filter(|range| !range.is_empty())?;
+            let quality = parts
+                .find_map(|param| {
+                    let mut pair = param.splitn(2, '=').map(str::trim);
+                    match (pair.next(), pair.next()) {
+                        (Some(name), Some(q)) if name.eq_ignore_ascii_case("q") => Some(q.parse::<f32>().ok()),
+                        _ => None,
+                    }
+                })
+                .unwrap_or(Some(1.0))?;
+
+            if quality >= 0.0 && quality <= 1.0 {
+                Some((String::from(range), quality))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The best of `weights` (one per offer, `None` if no range matched), or `None` if every offer
+/// was excluded
+fn best_offer(weights: &[Option<f32>]) -> Option<usize> {
+    weights
+        .iter()
+        .enumerate()
+        .filter_map(|(i, weight)| weight.filter(|weight| *weight > 0.0).map(|weight| (i, weight)))
+        // `max_by` keeps the last of equal elements, so compare against earlier offers first
+        .fold(None, |best: Option<(usize, f32)>, (i, weight)| match best {
+            Some((_, best_weight)) if best_weight >= weight => best,
+            _ => Some((i, weight)),
+        })
+        .map(|(i, _)| i)
+}
+
+/// The weight of `ranges` for the media type `top/sub`, from the most specific matching range
+fn content_type_weight(ranges: &[(String, String, f32)], top: &str, sub: &str) -> Option<f32> {
+    ranges
+        .iter()
+        .filter_map(|(range_top, range_sub, quality)| {
+            let specificity = match (range_top.as_str(), range_sub.as_str()) {
+                ("*", "*") => 0,
+                (range_top, "*") if range_top.eq_ignore_ascii_case(top) => 1,
+                (range_top, range_sub) if range_top.eq_ignore_ascii_case(top) && range_sub.eq_ignore_ascii_case(sub) => 2,
+                _ => return None,
+            };
+            Some((specificity, *quality))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, quality)| quality)
+}
+
+/// The content type from `offered` that the client most prefers, from its `Accept` header
+///
+/// # Examples
+///
+/// ```
+/// // Accept: application/json;q=0.9, text/html
+/// let offered = [ContentType::JSON, ContentType::HTML];
+/// assert_eq!(preferred_content_type(request, &offered), Some(ContentType::HTML));
+/// ```
+pub fn preferred_content_type(request: &Request, offered: &[ContentType]) -> Option<ContentType> {
+    let header = request.headers().get_one("Accept").unwrap_or("");
+    let ranges: Vec<(String, String, f32)> = parse_weighted(header)
+        .into_iter()
+        .filter_map(|(range, quality)| {
+            let mut parts = range.splitn(2, '/').map(str::trim);
+            match (parts.next(), parts.next()) {
+                (Some(top), Some(sub)) if !top.is_empty() && !sub.is_empty() => {
+                    Some((String::from(top), String::from(sub), quality))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+
+    if ranges.is_empty() {
+        return offered.first().cloned();
+    }
+
+    let weights: Vec<Option<f32>> = offered
+        .iter()
+        .map(|content_type| content_type_weight(&ranges, content_type.top().as_str(), content_type.sub().as_str()))
+        .collect();
+
+    best_offer(&weights).map(|i| offered[i].clone())
+}
+
+/// The weight of `ranges` for the language tag `tag`, from the most specific matching range.
+/// A range matches its own tag and any tag that it is a prefix of, so `en` matches `en-GB`.
+fn language_weight(ranges: &[(String, f32)], tag: &str) -> Option<f32> {
+    let tag = tag.to_ascii_lowercase();
+    ranges
+        .iter()
+        .filter_map(|(range, quality)| {
+            let range = range.to_ascii_lowercase();
+            if range == "*" {
+                Some((0, *quality))
+            } else if tag == range || tag.starts_with(&format!("{}-", range)) {
+                Some((range.len(), *quality))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, quality)| quality)
+}
+
+/// The language tag from `offered` that the client most prefers, from its `Accept-Language`
+/// header
+///
+/// # Examples
+///
+/// ```
+/// // Accept-Language: fr-CH, fr;q=0.9, en;q=0.8
+/// assert_eq!(preferred_language(request, &["en", "fr"]), Some("fr"));
+/// ```
+pub fn preferred_language<'o>(request: &Request, offered: &[&'o str]) -> Option<&'o str> {
+    let header = request.headers().get_one("Accept-Language").unwrap_or("");
+    let ranges = parse_weighted(header);
+    if ranges.is_empty() {
+        return offered.first().cloned();
+    }
+
+    let weights: Vec<Option<f32>> = offered.iter().map(|tag| language_weight(&ranges, tag)).collect();
+    best_offer(&weights).map(|i| offered[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    fn client() -> Client {
+        Client::new(rocket::custom(Config::new(Environment::Development))).unwrap()
+    }
+
+    fn assert_weights(header: &str, expected: &[(&str, f32)]) {
+        let parsed = parse_weighted(header);
+        assert_eq!(parsed.len(), expected.len(), "{:?} parsed as {:?}", header, parsed);
+        for ((range, quality), (expected_range, expected_quality)) in parsed.iter().zip(expected) {
+            assert_eq!(range, expected_range);
+            assert!((quality - expected_quality).abs() < 1e-6, "{} has q={}", range, quality);
+        }
+    }
+
+    #[test]
+    fn quality_values() {
+        assert_weights("text/html", &[("text/html", 1.0)]);
+        assert_weights("text/html;q=0.5", &[("text/html", 0.5)]);
+        assert_weights("text/html ; Q = 0.5 ; level=1", &[("text/html", 0.5)]);
+        assert_weights("text/html;level=1", &[("text/html", 1.0)]);
+        assert_weights("text/html;q=0, */*;q=0.1", &[("text/html", 0.0), ("*/*", 0.1)]);
+        assert_weights("en, fr;q=1", &[("en", 1.0), ("fr", 1.0)]);
+    }
+
+    #[test]
+    fn malformed_entries_are_ignored() {
+        assert_weights("", &[]);
+        assert_weights(", ;q=0.5,", &[]);
+        assert_weights("text/html;q=abc, application/json", &[("application/json", 1.0)]);
+        assert_weights("text/html;q=1.5, text/plain;q=-1", &[]);
+        assert_weights("text/html;q=, text/plain", &[("text/plain", 1.0)]);
+    }
+
+    fn content_type(accept: Option<&str>, offered: &[ContentType]) -> Option<ContentType> {
+        let client = client();
+        let request = match accept {
+            Some(accept) => client.get("/").header(Header::new("Accept", accept.to_string())),
+            None => client.get("/"),
+        };
+        preferred_content_type(request.inner(), offered)
+    }
+
+    #[test]
+    fn content_type_by_quality() {
+        let offered = [ContentType::JSON, ContentType::HTML];
+        let prefer = |accept| content_type(Some(accept), &offered);
+
+        assert_eq!(prefer("application/json;q=0.9, text/html"), Some(ContentType::HTML));
+        assert_eq!(prefer("application/json, text/html;q=0.9"), Some(ContentType::JSON));
+        // Ties go to the earliest offer, not the earliest range
+        assert_eq!(prefer("text/html, application/json"), Some(ContentType::JSON));
+        assert_eq!(prefer("TEXT/HTML"), Some(ContentType::HTML));
+        assert_eq!(prefer("image/png"), None);
+    }
+
+    #[test]
+    fn content_type_wildcards() {
+        let offered = [ContentType::HTML, ContentType::Plain];
+        let prefer = |accept| content_type(Some(accept), &offered);
+
+        assert_eq!(prefer("*/*"), Some(ContentType::HTML));
+        assert_eq!(prefer("text/*;q=0.5, text/plain"), Some(ContentType::Plain));
+        // The most specific range wins, even when a wildcard has a higher quality
+        assert_eq!(prefer("text/html;q=0.1, */*"), Some(ContentType::Plain));
+        assert_eq!(prefer("*/*, text/*;q=0.2, text/plain;q=0.3"), Some(ContentType::Plain));
+    }
+
+    #[test]
+    fn content_type_q_zero_excludes() {
+        let offered = [ContentType::HTML, ContentType::JSON];
+
+        assert_eq!(content_type(Some("text/html;q=0, */*"), &offered), Some(ContentType::JSON));
+        assert_eq!(content_type(Some("*/*;q=0"), &offered), None);
+        assert_eq!(content_type(Some("text/*;q=0, application/json;q=0"), &offered), None);
+    }
+
+    #[test]
+    fn content_type_without_a_usable_header() {
+        let offered = [ContentType::HTML, ContentType::JSON];
+
+        assert_eq!(content_type(None, &offered), Some(ContentType::HTML));
+        assert_eq!(content_type(Some(""), &offered), Some(ContentType::HTML));
+        assert_eq!(content_type(Some("html, json;q=abc"), &offered), Some(ContentType::HTML));
+        assert_eq!(content_type(Some("/html, text/"), &offered), Some(ContentType::HTML));
+        assert_eq!(content_type(None, &[]), None);
+    }
+
+    fn language<'o>(accept: Option<&str>, offered: &[&'o str]) -> Option<&'o str> {
+        let client = client();
+        let request = match accept {
+            Some(accept) => client.get("/").header(Header::new("Accept-Language", accept.to_string())),
+            None => client.get("/"),
+        };
+        preferred_language(request.inner(), offered)
+    }
+
+    #[test]
+    fn language_by_quality() {
+        let offered = ["en", "fr"];
+
+        assert_eq!(language(Some("fr-CH, fr;q=0.9, en;q=0.8"), &offered), Some("fr"));
+        assert_eq!(language(Some("de, en;q=0.5"), &offered), Some("en"));
+        assert_eq!(language(Some("fr, en"), &offered), Some("en"));
+        assert_eq!(language(Some("FR"), &offered), Some("fr"));
+        assert_eq!(language(Some("de"), &offered), None);
+    }
+
+    #[test]
+    fn language_prefixes_and_wildcards() {
+        assert_eq!(language(Some("en"), &["fr", "en-GB"]), Some("en-GB"));
+        assert_eq!(language(Some("en-GB"), &["en"]), None);
+        // `e` isn't a prefix of `en-GB`, only whole subtags are
+        assert_eq!(language(Some("e"), &["en-GB"]), None);
+        assert_eq!(language(Some("en-GB;q=0, en"), &["en-GB", "en-US"]), Some("en-US"));
+        assert_eq!(language(Some("*;q=0.1, de"), &["en", "de"]), Some("de"));
+        assert_eq!(language(Some("*, en;q=0"), &["en", "de"]), Some("de"));
+        assert_eq!(language(Some("*;q=0"), &["en"]), None);
+    }
+
+    #[test]
+    fn language_without_a_usable_header() {
+        assert_eq!(language(None, &["en", "fr"]), Some("en"));
+        assert_eq!(language(Some("fr;q=abc"), &["en", "fr"]), Some("en"));
+        assert_eq!(language(None, &[]), None);
+    }
+}
@@ -0,0 +1,409 @@
+//! Path parameters that only accept well formed, URL safe values.
+//!
+//! Each of these is checked against the raw (still percent-encoded) segment, so encoded
+//! characters such as `%2e%2e` are always rejected rather than being decoded first. Rejected
+//! segments cause the route to forward, which ends in `404 Not Found` rather than `400 Bad
+//! Request` when no other route matches, so that malformed and unknown IDs look the same to
+//! clients.
+use rocket::http::RawStr;
+use rocket::request::FromParam;
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+/// The maximum length of a `Slug`, see `DefaultSlugLength`
+pub trait SlugLength {
+    const MAX: usize;
+}
+
+/// Allows slugs of up to 64 characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DefaultSlugLength;
+
+impl SlugLength for DefaultSlugLength {
+    const MAX: usize = 64;
+}
+
+/// A path segment made up of lowercase ASCII letters, digits and dashes, e.g. `my-first-post`,
+/// with at most `L::MAX` characters
+///
+/// # Examples
+///
+/// ```
+/// #[get("/posts/<slug>")]
+/// fn post(slug: Slug) -> Option<Template> {
+///     posts::find(slug.as_str()).map(|post| Template::render("post", post))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Slug<L: SlugLength = DefaultSlugLength>(String, PhantomData<L>);
+
+impl<L: SlugLength> Slug<L> {
+    /// Check that `value` is a valid slug
+    pub fn parse(value: &str) -> Option<Slug<L>> {
+        let is_valid = !value.is_empty()
+            && value.len() <= L::MAX
+            && value
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+
+        if is_valid {
+            Some(Slug(value.to_string(), PhantomData))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a, L: SlugLength> FromParam<'a> for Slug<L> {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        Slug::parse(param.as_str()).ok_or(param)
+    }
+}
+
+impl<L: SlugLength> fmt::Display for Slug<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<L: SlugLength> Serialize for Slug<L> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+/// The format of a `PublicId`. Parsing doesn't stop early on the first invalid character, so
+/// the time taken doesn't reveal how much of an ID was valid.
+pub trait IdFormat {
+    type Value: fmt::Debug + Clone + PartialEq;
+
+    fn parse(value: &str) -> Option<Self::Value>;
+    fn format(value: &Self::Value) -> String;
+}
+
+/// IDs written as hyphenated UUIDs, e.g. `936da01f-9abd-4d9d-80c7-02af85c822a8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UuidFormat;
+
+/// IDs written as a `u64` in base 62 (`0-9`, `A-Z` then `a-z`), e.g. `LygHa16AHYF`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Base62Format;
+
+/// Positions of the hyphens in a hyphenated UUID
+const UUID_HYPHENS: [usize; 4] = [8, 13, 18, 23];
+
+const BASE62_ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// The number of base 62 digits needed for `u64::max_value()`
+const BASE62_MAX_DIGITS: usize = 11;
+
+/// `0xFF` if `lo <= c <= hi`, otherwise `0`, without branching on `c`
+fn range_mask(c: u8, lo: u8, hi: u8) -> u8 {
+    let offset = u16::from(c.wrapping_sub(lo));
+    (offset.wrapping_sub(u16::from(hi - lo) + 1) >> 8) as u8
+}
+
+/// The value of a hex digit, and `0xFF` if it is one (otherwise `0`)
+fn hex_digit(c: u8) -> (u8, u8) {
+    let digit = range_mask(c, b'0', b'9');
+    let lower = range_mask(c, b'a', b'f');
+    let upper = range_mask(c, b'A', b'F');
+
+    let value = (digit & c.wrapping_sub(b'0'))
+        | (lower & c.wrapping_sub(b'a' - 10))
+        | (upper & c.wrapping_sub(b'A' - 10));
+    (value, digit | lower | upper)
+}
+
+/// The value of a base 62 digit, and `0xFF` if it is one (otherwise `0`)
+fn base62_digit(c: u8) -> (u8, u8) {
+    let digit = range_mask(c, b'0', b'9');
+    let upper = range_mask(c, b'A', b'Z');
+    let lower = range_mask(c, b'a', b'z');
+
+    let value = (digit & c.wrapping_sub(b'0'))
+        | (upper & c.wrapping_sub(b'A' - 10))
+        | (lower & c.wrapping_sub(b'a' - 36));
+    (value, digit | upper | lower)
+}
+
+impl IdFormat for UuidFormat {
+    type Value = Uuid;
+
+    fn parse(value: &str) -> Option<Uuid> {
+        let value = value.as_bytes();
+        if value.len() != 36 {
+            return None;
+        }
+
+        let mut bytes = [0u8; 16];
+        let mut valid = 0xFF;
+        let mut nibble = 0;
+        for (i, &c) in value.iter().enumerate() {
+            if UUID_HYPHENS.contains(&i) {
+                valid &= range_mask(c, b'-', b'-');
+                continue;
+            }
+
+            let (digit, is_digit) = hex_digit(c);
+            valid &= is_digit;
+            bytes[nibble / 2] |= digit << (4 * (1 - nibble % 2));
+            nibble += 1;
+        }
+
+        if valid == 0xFF {
+            Some(Uuid::from_bytes(bytes))
+        } else {
+            None
+        }
+    }
+
+    fn format(value: &Uuid) -> String {
+        value.to_hyphenated().to_string()
+    }
+}
+
+impl IdFormat for Base62Format {
+    type Value = u64;
+
+    fn parse(value: &str) -> Option<u64> {
+        let value = value.as_bytes();
+        // Leading zeros are rejected so that each ID has exactly one form
+        if value.is_empty() || value.len() > BASE62_MAX_DIGITS || (value.len() > 1 && value[0] == b'0') {
+            return None;
+        }
+
+        let mut total: u128 = 0;
+        let mut valid = 0xFF;
+        for &c in value {
+            let (digit, is_digit) = base62_digit(c);
+            valid &= is_digit;
+            total = total * 62 + u128::from(digit);
+        }
+
+        if valid == 0xFF && total <= u128::from(u64::max_value()) {
+            Some(total as u64)
+        } else {
+            None
+        }
+    }
+
+    fn format(value: &u64) -> String {
+        let mut remaining = *value;
+        let mut digits = Vec::with_capacity(BASE62_MAX_DIGITS);
+        loop {
+            digits.push(BASE62_ALPHABET[(remaining % 62) as usize]);
+            remaining /= 62;
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        digits.reverse();
+        String::from_utf8(digits).unwrap_or_default()
+    }
+}
+
+/// An identifier that is safe to expose in URLs, in the format chosen by `F`
+///
+/// # Examples
+///
+/// ```
+/// #[get("/orders/<id>")]
+/// fn order(id: PublicId) -> Option<Json<Order>> {
+///     orders::find(id.value()).map(Json)
+/// }
+///
+/// #[get("/short/<id>")]
+/// fn short_link(id: PublicId<Base62Format>) -> Option<Redirect> {
+///     links::find(*id.value()).map(|link| Redirect::to(link.target))
+/// }
+/// ```
+pub struct PublicId<F: IdFormat = UuidFormat>(F::Value, PhantomData<F>);
+
+impl<F: IdFormat> PublicId<F> {
+    pub fn new(value: F::Value) -> PublicId<F> {
+        PublicId(value, PhantomData)
+    }
+
+    pub fn parse(value: &str) -> Option<PublicId<F>> {
+        F::parse(value).map(PublicId::new)
+    }
+
+    pub fn value(&self) -> &F::Value {
+        &self.0
+    }
+}
+
+impl<F: IdFormat> fmt::Debug for PublicId<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PublicId").field(&self.0).finish()
+    }
+}
+
+impl<F: IdFormat> Clone for PublicId<F> {
+    fn clone(&self) -> Self {
+        PublicId::new(self.0.clone())
+    }
+}
+
+impl<F: IdFormat> PartialEq for PublicId<F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<'a, F: IdFormat> FromParam<'a> for PublicId<F> {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        PublicId::parse(param.as_str()).ok_or(param)
+    }
+}
+
+impl<F: IdFormat> fmt::Display for PublicId<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&F::format(&self.0))
+    }
+}
+
+impl<F: IdFormat> Serialize for PublicId<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use rocket::handler::Outcome;
+    use rocket::http::{Method, Status};
+    use rocket::local::Client;
+    use rocket::{Data, Request, Route};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    struct ShortSlug;
+
+    impl SlugLength for ShortSlug {
+        const MAX: usize = 8;
+    }
+
+    #[test]
+    fn slug_boundaries() {
+        assert!(Slug::<DefaultSlugLength>::parse("my-first-post-2").is_some());
+        assert!(Slug::<DefaultSlugLength>::parse(&"a".repeat(64)).is_some());
+        assert!(Slug::<DefaultSlugLength>::parse(&"a".repeat(65)).is_none());
+        assert!(Slug::<ShortSlug>::parse("eight-ch").is_some());
+        assert!(Slug::<ShortSlug>::parse("nine-char").is_none());
+
+        for invalid in &["", "My-Post", "my_post", "my post", "café", "..", "%2e%2e", "a/b"] {
+            assert!(Slug::<DefaultSlugLength>::parse(invalid).is_none(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn uuid_ids() {
+        let id = "936da01f-9abd-4d9d-80c7-02af85c822a8";
+        let parsed = PublicId::<UuidFormat>::parse(id).unwrap();
+        assert_eq!(*parsed.value(), Uuid::parse_str(id).unwrap());
+        assert_eq!(parsed.to_string(), id);
+        // Uppercase is accepted, but always written in lowercase
+        assert_eq!(PublicId::<UuidFormat>::parse(&id.to_uppercase()), Some(parsed));
+
+        for invalid in &[
+            "936da01f9abd4d9d80c702af85c822a8",
+            "936da01f-9abd-4d9d-80c7-02af85c822a",
+            "936da01f-9abd-4d9d-80c7-02af85c822a8a",
+            "936da01f-9abd-4d9d-80c7_02af85c822a8",
+            "936da01g-9abd-4d9d-80c7-02af85c822a8",
+            "{936da01f-9abd-4d9d-80c7-02af85c822}",
+        ] {
+            assert!(PublicId::<UuidFormat>::parse(invalid).is_none(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn base62_ids() {
+        let parse = |value: &str| PublicId::<Base62Format>::parse(value).map(|id| *id.value());
+        assert_eq!(parse("0"), Some(0));
+        assert_eq!(parse("10"), Some(62));
+        assert_eq!(parse("1LY7VK"), Some(1_234_567_890));
+        assert_eq!(parse("LygHa16AHYF"), Some(u64::max_value()));
+
+        // One more than `u64::max_value()`, a leading zero, and characters outside the alphabet
+        for invalid in &["LygHa16AHYG", "zzzzzzzzzzzz", "01", "", "1LY7V-", "%2e%2e"] {
+            assert_eq!(parse(invalid), None, "{:?} was accepted", invalid);
+        }
+
+        for value in &[0, 61, 62, 1_234_567_890, u64::max_value()] {
+            let id = PublicId::<Base62Format>::new(*value);
+            assert_eq!(PublicId::<Base62Format>::parse(&id.to_string()), Some(id));
+        }
+    }
+
+    #[test]
+    fn serialize_as_strings() {
+        let slug = Slug::<DefaultSlugLength>::parse("hello-world").unwrap();
+        assert_eq!(serde_json::to_string(&slug).unwrap(), "\"hello-world\"");
+
+        let id = PublicId::<Base62Format>::new(1_234_567_890);
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"1LY7VK\"");
+
+        let uuid = PublicId::<UuidFormat>::parse("936da01f-9abd-4d9d-80c7-02af85c822a8").unwrap();
+        assert_eq!(
+            serde_json::to_string(&uuid).unwrap(),
+            "\"936da01f-9abd-4d9d-80c7-02af85c822a8\""
+        );
+    }
+
+    fn post<'r>(request: &'r Request, data: Data) -> Outcome<'r> {
+        match request.get_param::<Slug>(0) {
+            Some(Ok(slug)) => Outcome::from(request, slug.to_string()),
+            _ => Outcome::forward(data),
+        }
+    }
+
+    fn order<'r>(request: &'r Request, data: Data) -> Outcome<'r> {
+        match request.get_param::<PublicId<Base62Format>>(0) {
+            Some(Ok(id)) => Outcome::from(request, id.value().to_string()),
+            _ => Outcome::forward(data),
+        }
+    }
+
+    #[test]
+    fn rejected_params_are_not_found() {
+        let routes = vec![
+            Route::new(Method::Get, "/posts/<slug>", post),
+            Route::new(Method::Get, "/orders/<id>", order),
+        ];
+        let client = Client::new(rocket::custom(Config::new(Environment::Development)).mount("/", routes)).unwrap();
+
+        let mut found = client.get("/posts/hello-world").dispatch();
+        assert_eq!(found.status(), Status::Ok);
+        assert_eq!(found.body_string(), Some(String::from("hello-world")));
+        assert_eq!(client.get("/orders/1LY7VK").dispatch().body_string(), Some(String::from("1234567890")));
+
+        for path in &[
+            "/posts/Hello-World",
+            "/posts/%2e%2e",
+            "/posts/%2E%2E",
+            "/posts/..%2fadmin",
+            "/posts/hello%2dworld",
+            "/orders/LygHa16AHYG",
+            "/orders/%2e%2e",
+        ] {
+            assert_eq!(client.get(*path).dispatch().status(), Status::NotFound, "{}", path);
+        }
+        let long = format!("/posts/{}", "a".repeat(65));
+        assert_eq!(client.get(long).dispatch().status(), Status::NotFound);
+    }
+}
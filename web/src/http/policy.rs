@@ -0,0 +1,566 @@
+//! Access policies for groups of routes, declared in `Settings::route_policies`.
+//!
+//! Each policy applies to a path prefix, and the policy with the longest prefix that matches
+//! a request decides whether it is allowed. Prefixes only match whole path segments, so
+//! `/admin` covers `/admin` and `/admin/users` but not `/administrator`. Prefixes are matched
+//! against the path that rocket routes (see `routed_path`), so `//admin/users` is covered too.
+//!
+//! # Examples
+//!
+//! ```toml
+//! [route_policies."/admin"]
+//! auth = "api_key"
+//! allow_cidrs = ["10.20.0.0/16"]
+//! require_https = true
+//!
+//! # Carve out an exception under the protected prefix
+//! [route_policies."/admin/health"]
+//! auth = "none"
+//! ```
+use crate::app::Settings;
+use crate::http::guards::{client_addr, is_forwarded_https, routed_path, ApiKey, BasicAuth, Session, API_KEY_HEADER};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::handler::Outcome;
+use rocket::http::uri::Origin;
+use rocket::http::{Header, Method, Status};
+use rocket::{Data, Request, Response, Rocket, Route};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// The route that rejected requests are rewritten to, so that they never reach their handler
+const DENIED_ROUTE: &'static str = "/__route_policy/denied";
+
+/// The realm sent to clients when basic auth is required
+const BASIC_REALM: &'static str = "app";
+
+/// The kind of authentication that a route policy requires
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// No authentication is required
+    None,
+    /// Requests must pass the `ApiKey` guard
+    ApiKey,
+    /// Requests must pass the `BasicAuth` guard
+    Basic,
+    /// Requests must pass the `Session` guard
+    Session,
+}
+
+impl Default for AuthMode {
+    fn default() -> AuthMode {
+        AuthMode::None
+    }
+}
+
+/// The requirements for requests to routes under a path prefix
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoutePolicy {
+    #[serde(default)]
+    pub auth: AuthMode,
+    /// When not empty, only clients with an IP address in one of these ranges are allowed.
+    /// Forwarded addresses are only used for requests from `Settings::trusted_proxies`
+    #[serde(default)]
+    pub allow_cidrs: Vec<Cidr>,
+    /// Whether requests must have been made over HTTPS, as reported by the
+    /// `X-Forwarded-Proto` header from one of `Settings::trusted_proxies`
+    #[serde(default)]
+    pub require_https: bool,
+}
+
+/// A range of IP addresses, written as `address/prefix_length` (e.g. `10.0.0.0/8` or
+/// `fd00::/8`). A bare address is treated as a range containing only that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::max_value().checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::max_value().checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            // Clients connecting over IPv6 to a dual stack socket may appear as mapped IPv4
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4() {
+                Some(ip) => self.contains(IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(ip)) => self.contains(IpAddr::V6(ip.to_ipv6_mapped())),
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.splitn(2, '/');
+        let network: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| format!("invalid address in CIDR range {:?}", s))?;
+
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => match len.parse::<u8>() {
+                Ok(len) if len <= max_len => len,
+                _ => return Err(format!("invalid prefix length in CIDR range {:?}", s)),
+            },
+            None => max_len,
+        };
+
+        Ok(Cidr { network, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Why a request was rejected by its route policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Denial {
+    HttpsRequired,
+    AddressNotAllowed,
+    Unauthenticated(AuthMode),
+}
+
+/// Enforces `Settings::route_policies` before requests are routed. Rejected requests are
+/// answered with `403 Forbidden` when they fail the HTTPS or address checks, or with
+/// `401 Unauthorized` (and a `WWW-Authenticate` header, where the auth mode has one) when they
+/// fail authentication. Their handlers are never run.
+pub struct RoutePolicies {
+    policies: Vec<(String, RoutePolicy)>,
+}
+
+impl RoutePolicies {
+    pub fn new(policies: HashMap<String, RoutePolicy>) -> RoutePolicies {
+        let mut policies: Vec<(String, RoutePolicy)> = policies
+            .into_iter()
+            .map(|(prefix, policy)| (prefix.trim_end_matches('/').to_string(), policy))
+            .collect();
+
+        // Longest prefixes first, so the first match is the most specific policy
+        policies.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+
+        RoutePolicies { policies }
+    }
+
+    /// The policies in `Settings::route_policies`, with each prefix put under
+    /// `Settings::route_prefix` so that it matches the paths that routes are mounted at
+    pub fn from_settings(settings: &Settings) -> RoutePolicies {
+        let route_prefix = settings.route_prefix();
+        let policies = settings
+            .route_policies
+            .iter()
+            .map(|(prefix, policy)| (format!("{}{}", route_prefix, prefix), policy.clone()))
+            .collect();
+        RoutePolicies::new(policies)
+    }
+
+    /// Find the policy with the longest prefix that matches `path`
+    fn policy_for(&self, path: &str) -> Option<&RoutePolicy> {
+        self.policies
+            .iter()
+            .find(|(prefix, _)| {
+                path.starts_with(prefix.as_str())
+                    && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+            })
+            .map(|(_, policy)| policy)
+    }
+
+    fn check(&self, request: &Request) -> Option<Denial> {
+        let policy = self.policy_for(&routed_path(request))?;
+
+        if policy.require_https && !is_forwarded_https(request) {
+            return Some(Denial::HttpsRequired);
+        }
+
+        if !policy.allow_cidrs.is_empty() {
+            let allowed = client_addr(request)
+                .map(|ip| policy.allow_cidrs.iter().any(|cidr| cidr.contains(ip)))
+                .unwrap_or(false);
+
+            if !allowed {
+                return Some(Denial::AddressNotAllowed);
+            }
+        }
+
+        let authenticated = match policy.auth {
+            AuthMode::None => true,
+            AuthMode::ApiKey => request.guard::<ApiKey>().is_success(),
+            AuthMode::Basic => request.guard::<BasicAuth>().is_success(),
+            AuthMode::Session => request.guard::<Session>().is_success(),
+        };
+
+        if authenticated {
+            None
+        } else {
+            Some(Denial::Unauthenticated(policy.auth))
+        }
+    }
+}
+
+impl Fairing for RoutePolicies {
+    fn info(&self) -> Info {
+        Info {
+            name: "Route Policies",
+            kind: Kind::Attach | Kind::Request,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket.mount("/", vec![Route::new(Method::Get, DENIED_ROUTE, denied)]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        if let Some(denial) = self.check(request) {
+            request.local_cache(|| Some(denial));
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(DENIED_ROUTE).expect("valid denied route"));
+        }
+    }
+}
+
+/// Respond to a request that was rewritten by `RoutePolicies` after failing its policy
+fn denied<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    let denial = match request.local_cache(|| None::<Denial>) {
+        Some(denial) => *denial,
+        // The route was requested directly, rather than by a rejected request
+        None => return Outcome::failure(Status::NotFound),
+    };
+
+    let mut response = Response::build();
+    match denial {
+        Denial::HttpsRequired => {
+            response.status(Status::Forbidden).sized_body(Cursor::new("HTTPS is required"));
+        }
+        Denial::AddressNotAllowed => {
+            response.status(Status::Forbidden).sized_body(Cursor::new("Forbidden"));
+        }
+        Denial::Unauthenticated(mode) => {
+            response.status(Status::Unauthorized).sized_body(Cursor::new("Unauthorized"));
+
+            match mode {
+                AuthMode::ApiKey => {
+                    response.header(Header::new(
+                        "WWW-Authenticate",
+                        format!("ApiKey header=\"{}\"", API_KEY_HEADER),
+                    ));
+                }
+                AuthMode::Basic => {
+                    response.header(Header::new(
+                        "WWW-Authenticate",
+                        format!("Basic realm=\"{}\", charset=\"UTF-8\"", BASIC_REALM),
+                    ));
+                }
+                AuthMode::None | AuthMode::Session => (),
+            }
+        }
+    }
+
+    Outcome::Success(response.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use rocket::http::Cookie;
+    use rocket::local::Client;
+    use std::net::SocketAddr;
+
+    const TRUSTED_PROXY: &str = "10.0.0.1:4000";
+    const OUTSIDER: &str = "203.0.113.5:4000";
+
+    fn ok<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, "ok")
+    }
+
+    fn client(policies: Vec<(&str, RoutePolicy)>) -> Client {
+        let mut users = HashMap::new();
+        users.insert(String::from("admin"), String::from("hunter2"));
+        let settings = Settings::builder()
+            .unwrap()
+            .set("api_keys", vec!["secret"])
+            .unwrap()
+            .set("basic_auth_users", users)
+            .unwrap()
+            .set("trusted_proxies", vec!["10.0.0.1"])
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let policies = policies
+            .into_iter()
+            .map(|(prefix, policy)| (prefix.to_string(), policy))
+            .collect();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(settings)
+            .attach(RoutePolicies::new(policies))
+            .mount("/", vec![Route::new(Method::Get, "/<path..>", ok)]);
+        Client::new(rocket).unwrap()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn policy(auth: AuthMode) -> RoutePolicy {
+        RoutePolicy {
+            auth,
+            ..RoutePolicy::default()
+        }
+    }
+
+    #[test]
+    fn longest_prefix_takes_precedence() {
+        let client = client(vec![
+            ("/admin", policy(AuthMode::ApiKey)),
+            ("/admin/health", policy(AuthMode::None)),
+        ]);
+
+        assert_eq!(client.get("/admin/users").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/admin").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/admin/health").dispatch().status(), Status::Ok);
+        assert_eq!(client.get("/admin/health/db").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn prefixes_only_match_whole_segments() {
+        let client = client(vec![("/admin", policy(AuthMode::ApiKey))]);
+
+        assert_eq!(client.get("/administrator").dispatch().status(), Status::Ok);
+        assert_eq!(client.get("/posts/admin").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn extra_slashes_do_not_bypass_a_policy() {
+        let client = client(vec![("/admin", policy(AuthMode::ApiKey))]);
+
+        for path in &["//admin", "//admin/users", "/admin//users", "///admin/", "/admin/"] {
+            assert_eq!(client.get(*path).dispatch().status(), Status::Unauthorized, "{}", path);
+        }
+    }
+
+    fn prefixed_client(strip_prefix: bool) -> Client {
+        let mut settings = Settings::builder()
+            .unwrap()
+            .set("mount_prefix", "/myapp")
+            .unwrap()
+            .set("strip_prefix_header", strip_prefix)
+            .unwrap()
+            .set("api_keys", vec!["secret"])
+            .unwrap()
+            .build()
+            .unwrap();
+        settings
+            .route_policies
+            .insert(String::from("/admin"), policy(AuthMode::ApiKey));
+
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .attach(RoutePolicies::from_settings(&settings))
+            .manage(settings)
+            .mount("/", vec![Route::new(Method::Get, "/<path..>", ok)]);
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn policies_are_under_the_mount_prefix() {
+        let client = prefixed_client(false);
+
+        assert_eq!(client.get("/myapp/admin").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/myapp/admin/users").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/myapp/posts").dispatch().status(), Status::Ok);
+
+        let authorized = client
+            .get("/myapp/admin/users")
+            .header(Header::new(API_KEY_HEADER, "secret"))
+            .dispatch();
+        assert_eq!(authorized.status(), Status::Ok);
+    }
+
+    #[test]
+    fn policies_are_at_the_root_when_the_proxy_strips_the_prefix() {
+        let client = prefixed_client(true);
+
+        assert_eq!(client.get("/admin").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/admin/users").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/myapp/admin").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn api_key_mode() {
+        let client = client(vec![("/api", policy(AuthMode::ApiKey))]);
+
+        let response = client.get("/api/posts").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some(format!("ApiKey header=\"{}\"", API_KEY_HEADER).as_str())
+        );
+
+        let wrong = client.get("/api/posts").header(Header::new(API_KEY_HEADER, "guess")).dispatch();
+        assert_eq!(wrong.status(), Status::Unauthorized);
+
+        let right = client.get("/api/posts").header(Header::new(API_KEY_HEADER, "secret")).dispatch();
+        assert_eq!(right.status(), Status::Ok);
+    }
+
+    #[test]
+    fn basic_mode() {
+        let client = client(vec![("/admin", policy(AuthMode::Basic))]);
+        let credentials =
+            |user_pass: &str| Header::new("Authorization", format!("Basic {}", base64::encode(user_pass)));
+
+        let response = client.get("/admin").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.headers().get_one("WWW-Authenticate"),
+            Some("Basic realm=\"app\", charset=\"UTF-8\"")
+        );
+
+        let wrong = client.get("/admin").header(credentials("admin:guess")).dispatch();
+        assert_eq!(wrong.status(), Status::Unauthorized);
+
+        let right = client.get("/admin").header(credentials("admin:hunter2")).dispatch();
+        assert_eq!(right.status(), Status::Ok);
+    }
+
+    #[test]
+    fn session_mode() {
+        let client = client(vec![("/account", policy(AuthMode::Session))]);
+
+        let response = client.get("/account").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(!response.headers().contains("WWW-Authenticate"));
+
+        let signed_in = client
+            .get("/account")
+            .private_cookie(Cookie::new(crate::http::guards::SESSION_COOKIE, "user-1"))
+            .dispatch();
+        assert_eq!(signed_in.status(), Status::Ok);
+    }
+
+    #[test]
+    fn allow_cidrs_checks_the_peer_address() {
+        let client = client(vec![(
+            "/internal",
+            RoutePolicy {
+                allow_cidrs: vec!["10.20.0.0/16".parse().unwrap()],
+                ..RoutePolicy::default()
+            },
+        )]);
+
+        let inside = client.get("/internal").remote(addr("10.20.1.1:4000")).dispatch();
+        assert_eq!(inside.status(), Status::Ok);
+
+        let outside = client.get("/internal").remote(addr(OUTSIDER)).dispatch();
+        assert_eq!(outside.status(), Status::Forbidden);
+
+        // Without a peer address there is nothing to check
+        assert_eq!(client.get("/internal").dispatch().status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn allow_cidrs_rejects_spoofed_real_ip() {
+        let client = client(vec![(
+            "/internal",
+            RoutePolicy {
+                allow_cidrs: vec!["10.20.0.0/16".parse().unwrap()],
+                ..RoutePolicy::default()
+            },
+        )]);
+
+        let spoofed = client
+            .get("/internal")
+            .remote(addr(OUTSIDER))
+            .header(Header::new("X-Real-IP", "10.20.1.1"))
+            .dispatch();
+        assert_eq!(spoofed.status(), Status::Forbidden);
+
+        let forwarded = client
+            .get("/internal")
+            .remote(addr(TRUSTED_PROXY))
+            .header(Header::new("X-Real-IP", "10.20.1.1"))
+            .dispatch();
+        assert_eq!(forwarded.status(), Status::Ok);
+
+        let forwarded_outsider = client
+            .get("/internal")
+            .remote(addr(TRUSTED_PROXY))
+            .header(Header::new("X-Forwarded-For", "10.20.1.1, 203.0.113.5"))
+            .dispatch();
+        assert_eq!(forwarded_outsider.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn require_https_only_trusts_proxies() {
+        let client = client(vec![(
+            "/secure",
+            RoutePolicy {
+                require_https: true,
+                ..RoutePolicy::default()
+            },
+        )]);
+
+        let plain = client.get("/secure").remote(addr(OUTSIDER)).dispatch();
+        assert_eq!(plain.status(), Status::Forbidden);
+
+        let spoofed = client
+            .get("/secure")
+            .remote(addr(OUTSIDER))
+            .header(Header::new("X-Forwarded-Proto", "https"))
+            .dispatch();
+        assert_eq!(spoofed.status(), Status::Forbidden);
+
+        let proxied = client
+            .get("/secure")
+            .remote(addr(TRUSTED_PROXY))
+            .header(Header::new("X-Forwarded-Proto", "https"))
+            .dispatch();
+        assert_eq!(proxied.status(), Status::Ok);
+    }
+
+    #[test]
+    fn cidr_parsing_and_matching() {
+        let range: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains("10.255.0.1".parse().unwrap()));
+        assert!(!range.contains("11.0.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.2.3".parse().unwrap()));
+
+        let single: Cidr = "192.168.1.1".parse().unwrap();
+        assert_eq!(single.to_string(), "192.168.1.1/32");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("nonsense/8".parse::<Cidr>().is_err());
+    }
+}
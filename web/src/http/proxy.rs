@@ -0,0 +1,879 @@
+//! A reverse proxy that forwards every request under `Settings::proxy_prefix` to the service at
+//! `Settings::proxy_upstream`, so that an internal service can be served from the app's own
+//! origin.
+//!
+//! The method, the rest of the path after the prefix, the query string and the end-to-end
+//! headers are forwarded, along with `X-Forwarded-For` (the peer's IP, appended to the incoming
+//! header when the peer is one of `Settings::trusted_proxies`, and replacing it otherwise) and
+//! `X-Request-Id` (see `RequestId`). Hop-by-hop headers, such as `Connection`, are stripped
+//! from both the request and the response, as RFC 7230 requires of proxies. The app's own
+//! credentials (`Cookie`, `Authorization` and `X-Api-Key`) are never forwarded, so the
+//! upstream can't act as the app's users. Paths with `.` or `..` segments (however they are
+//! encoded) are rejected with `400 Bad Request`, as they could reach outside the upstream's
+//! base path. Request bodies are streamed to the upstream as they arrive, and responses are
+//! streamed back.
+//!
+//! Successful `GET` responses are cached in memory for as long as the upstream's `Cache-Control`
+//! allows (`max-age` or `s-maxage`), but for at most `Settings::proxy_cache_max_ttl`. Responses
+//! marked `no-store`, `no-cache` or `private`, or with `Vary: *`, and bodies over
+//! `MAX_CACHED_BODY_BYTES`, are not cached. A cached response is only served to requests that
+//! have the same values for the headers named in its `Vary` header. Requests that carry
+//! credentials only share responses that the upstream marks `public` or gives an `s-maxage`.
+//! Every response says whether it came from the cache in an `X-Cache` header.
+//!
+//! When the upstream can't be reached, the request fails with `502 Bad Gateway`.
+use crate::app::Settings;
+use crate::http::guards::{is_from_trusted_proxy, RequestId, API_KEY_HEADER, REQUEST_ID_HEADER};
+
+use bytes::Bytes;
+use rocket::handler::{Handler, Outcome};
+use rocket::http::uri::Segments;
+use rocket::http::{Header, Method, RawStr, Status};
+use rocket::request::Request;
+use rocket::State;
+use rocket::response::Response;
+use rocket::{Data, Route};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Headers that only apply to a single connection, and so are never forwarded
+const HOP_BY_HOP_HEADERS: [&'static str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "trailers",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Request headers that carry the app's credentials, which are never forwarded, and which stop
+/// a request from sharing cached responses unless the upstream allows it
+const CREDENTIAL_HEADERS: [&'static str; 3] = ["authorization", "cookie", API_KEY_HEADER];
+
+/// The most paths the proxy cache holds responses for at once
+const CACHE_CAPACITY: usize = 256;
+
+/// The largest response body that the proxy will cache. Larger responses are streamed to the
+/// client without being cached.
+pub const MAX_CACHED_BODY_BYTES: usize = 1024 * 1024;
+
+/// How long the proxy waits to connect to the upstream before responding `502 Bad Gateway`
+const CONNECT_TIMEOUT_MILLIS: u64 = 5_000;
+
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    expires: Instant,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+    /// The (lowercase) headers named by the response's `Vary` header, with the values that
+    /// the request it answered had for them
+    vary: Vec<(String, Option<String>)>,
+    /// Whether the upstream allows the response to be shared with requests that carry
+    /// credentials, see `is_shared`
+    shared: bool,
+}
+
+impl CachedResponse {
+    /// Whether this response can answer `request`
+    fn matches(&self, request: &Request, credentialed: bool) -> bool {
+        (self.shared || !credentialed)
+            && self
+                .vary
+                .iter()
+                .all(|(name, value)| request_header(request, name) == *value)
+    }
+}
+
+/// Proxied `GET` responses, keyed by the path and query that they were requested with. Each
+/// path can have a response for every combination of the headers that its `Vary` names.
+#[derive(Debug, Default)]
+struct ProxyCache {
+    entries: Mutex<HashMap<String, Vec<CachedResponse>>>,
+}
+
+impl ProxyCache {
+    fn get(&self, key: &str, request: &Request, credentialed: bool) -> Option<CachedResponse> {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let now = Instant::now();
+        let (found, is_empty) = match entries.get_mut(key) {
+            Some(variants) => {
+                variants.retain(|cached| cached.expires > now);
+                let found = variants
+                    .iter()
+                    .find(|cached| cached.matches(request, credentialed))
+                    .cloned();
+                (found, variants.is_empty())
+            }
+            None => return None,
+        };
+        if is_empty {
+            entries.remove(key);
+        }
+        found
+    }
+
+    fn insert(&self, key: String, response: CachedResponse) {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if entries.len() >= CACHE_CAPACITY && !entries.contains_key(&key) {
+            let now = Instant::now();
+            for variants in entries.values_mut() {
+                variants.retain(|cached| cached.expires > now);
+            }
+            entries.retain(|_, variants| !variants.is_empty());
+        }
+        if entries.len() >= CACHE_CAPACITY && !entries.contains_key(&key) {
+            let soonest = entries
+                .iter()
+                .filter_map(|(key, variants)| {
+                    let expires = variants.iter().map(|cached| cached.expires).min()?;
+                    Some((key, expires))
+                })
+                .min_by_key(|(_, expires)| *expires)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+
+        let variants = entries.entry(key).or_insert_with(Vec::new);
+        variants.retain(|cached| cached.vary != response.vary);
+        variants.push(response);
+    }
+}
+
+/// The values of the header `name` in `request`, joined as they would be in a single header
+fn request_header(request: &Request, name: &str) -> Option<String> {
+    let values: Vec<&str> = request.headers().get(name).collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+/// Whether `request` carries any of the app's credentials
+fn is_credentialed(request: &Request) -> bool {
+    CREDENTIAL_HEADERS.iter().any(|name| request.headers().contains(name))
+}
+
+/// The `X-Forwarded-For` header to send upstream for `request`, which came with `incoming`. The
+/// peer's address is appended to the incoming header when the peer is one of
+/// `Settings::trusted_proxies`; anyone else's header is dropped, so that clients can't make up
+/// the addresses that they forwarded for.
+fn forwarded_for_header(request: &Request, incoming: Option<String>) -> Option<String> {
+    let peer = request.remote()?.ip();
+    let trusted = request
+        .guard::<State<Settings>>()
+        .succeeded()
+        .map(|settings| is_from_trusted_proxy(request, &settings))
+        .unwrap_or(false);
+
+    match incoming {
+        Some(previous) if trusted => Some(format!("{}, {}", previous, peer)),
+        _ => Some(peer.to_string()),
+    }
+}
+
+/// The handler for proxied routes. See the module documentation.
+#[derive(Clone)]
+pub struct ReverseProxy {
+    upstream: String,
+    max_ttl: Duration,
+    cache: Arc<ProxyCache>,
+}
+
+impl ReverseProxy {
+    /// A proxy to `upstream`, e.g. `http://127.0.0.1:9000/api`, which paths are appended to
+    pub fn new(upstream: &str, max_ttl: Duration) -> ReverseProxy {
+        ReverseProxy {
+            upstream: upstream.trim_end_matches('/').to_string(),
+            max_ttl,
+            cache: Arc::default(),
+        }
+    }
+
+    /// The proxy configured by `Settings::proxy_upstream`, if there is one
+    pub fn from_settings(settings: &Settings) -> Option<ReverseProxy> {
+        settings
+            .proxy_upstream
+            .as_ref()
+            .map(|upstream| ReverseProxy::new(upstream, settings.proxy_cache_max_ttl.as_duration()))
+    }
+
+    /// Routes for every method, matching the mount point itself and every path beneath it
+    pub fn routes(self) -> Vec<Route> {
+        let methods = [
+            Method::Get,
+            Method::Head,
+            Method::Post,
+            Method::Put,
+            Method::Patch,
+            Method::Delete,
+            Method::Options,
+        ];
+
+        methods
+            .iter()
+            .flat_map(|method| {
+                vec![
+                    Route::ranked(20, *method, "/", self.clone()),
+                    Route::ranked(20, *method, "/<path..>", self.clone()),
+                ]
+            })
+            .collect()
+    }
+
+    /// The path (after the mount point) and query of `request`, as sent by the client, or
+    /// `400 Bad Request` if the path has a `.` or `..` segment. The upstream's URL parser would
+    /// resolve those (even percent encoded), which could reach outside its base path.
+    fn path_and_query(request: &Request) -> Result<String, Status> {
+        let segments = match request.get_segments::<Segments>(0) {
+            Some(Ok(segments)) => segments.collect::<Vec<&str>>(),
+            _ => Vec::new(),
+        };
+
+        for segment in &segments {
+            let decoded = RawStr::from_str(segment).percent_decode_lossy();
+            // An encoded slash or backslash could hide a dot segment inside this one
+            if decoded.split(|c| c == '/' || c == '\\').any(|part| part == "." || part == "..") {
+                return Err(Status::BadRequest);
+            }
+        }
+
+        let path = segments.join("/");
+        Ok(match request.uri().query() {
+            Some(query) => format!("/{}?{}", path, query),
+            None => format!("/{}", path),
+        })
+    }
+
+    fn forward<'r>(&self, request: &'r Request, data: Data, path_and_query: &str) -> Result<Response<'r>, Status> {
+        let mut upstream = ureq::request(request.method().as_str(), &format!("{}{}", self.upstream, path_and_query));
+        upstream.timeout_connect(CONNECT_TIMEOUT_MILLIS);
+
+        let skipped = connection_headers(request.headers().get("Connection"));
+        let mut forwarded_for = None;
+        for header in request.headers().iter() {
+            let name = header.name().as_str().to_ascii_lowercase();
+            // `Content-Length` is left to ureq, which streams bodies with chunked encoding
+            if name == "host" || name == "content-length" || skipped.contains(&name) {
+                continue;
+            }
+            if CREDENTIAL_HEADERS.iter().any(|credential| credential.eq_ignore_ascii_case(&name)) {
+                continue;
+            }
+            if name == "x-request-id" {
+                continue;
+            }
+            if name == "x-forwarded-for" {
+                forwarded_for = Some(header.value().to_string());
+                continue;
+            }
+            upstream.set(header.name().as_str(), header.value());
+        }
+
+        if let Some(forwarded_for) = forwarded_for_header(request, forwarded_for) {
+            upstream.set("X-Forwarded-For", &forwarded_for);
+        }
+        upstream.set(REQUEST_ID_HEADER, &RequestId::of(request).0);
+
+        let response = match request.method() {
+            Method::Post | Method::Put | Method::Patch => upstream.send(data.open()),
+            _ => upstream.call(),
+        };
+
+        if let Some(e) = response.synthetic_error() {
+            tracing::warn!(upstream = %self.upstream, "Proxied request failed: {}", e);
+            return Err(Status::BadGateway);
+        }
+
+        let status = response.status();
+        let skipped = connection_headers(response.all("Connection").into_iter());
+        let headers: Vec<(String, String)> = response
+            .headers_names()
+            .into_iter()
+            .filter(|name| {
+                let name = name.to_ascii_lowercase();
+                name != "content-length" && !skipped.contains(&name)
+            })
+            .flat_map(|name| {
+                response
+                    .all(&name)
+                    .into_iter()
+                    .map(|value| (name.clone(), value.to_string()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let shared = is_shared(&headers);
+        let vary = vary_values(request, &headers);
+        let cacheable = request.method() == Method::Get && status == 200 && (shared || !is_credentialed(request));
+        let ttl = match vary {
+            Some(_) if cacheable => cache_ttl(&headers, self.max_ttl),
+            _ => None,
+        };
+
+        let mut body = response.into_reader();
+        let mut builder = response_with(status, &headers);
+        builder.raw_header("X-Cache", "MISS");
+
+        if let Some(ttl) = ttl {
+            // Read one byte more than can be cached, to find out whether there is more
+            let mut buffered = Vec::new();
+            (&mut body)
+                .take(MAX_CACHED_BODY_BYTES as u64 + 1)
+                .read_to_end(&mut buffered)
+                .map_err(|_| Status::BadGateway)?;
+
+            if buffered.len() <= MAX_CACHED_BODY_BYTES {
+                let body = Bytes::from(buffered);
+                self.cache.insert(
+                    path_and_query.to_string(),
+                    CachedResponse {
+                        expires: Instant::now() + ttl,
+                        status,
+                        headers,
+                        body: body.clone(),
+                        vary: vary.unwrap_or_default(),
+                        shared,
+                    },
+                );
+                return builder.sized_body(Cursor::new(body)).ok();
+            }
+
+            return builder.streamed_body(Cursor::new(buffered).chain(body)).ok();
+        }
+
+        builder.streamed_body(body).ok()
+    }
+}
+
+impl Handler for ReverseProxy {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        let path_and_query = match ReverseProxy::path_and_query(request) {
+            Ok(path_and_query) => path_and_query,
+            Err(status) => return Outcome::Failure(status),
+        };
+
+        if request.method() == Method::Get {
+            if let Some(cached) = self.cache.get(&path_and_query, request, is_credentialed(request)) {
+                let response = response_with(cached.status, &cached.headers)
+                    .raw_header("X-Cache", "HIT")
+                    .sized_body(Cursor::new(cached.body))
+                    .finalize();
+                return Outcome::Success(response);
+            }
+        }
+
+        match self.forward(request, data, &path_and_query) {
+            Ok(response) => Outcome::Success(response),
+            Err(status) => Outcome::Failure(status),
+        }
+    }
+}
+
+/// A response builder with the upstream's status and headers
+fn response_with<'r>(status: u16, headers: &[(String, String)]) -> rocket::response::ResponseBuilder<'r> {
+    let mut builder = Response::build();
+    builder.status(Status::from_code(status).unwrap_or(Status::new(status, "")));
+    for (name, value) in headers {
+        builder.header_adjoin(Header::new(name.clone(), value.clone()));
+    }
+    builder
+}
+
+/// The (lowercase) names of the headers that must not be forwarded: the hop-by-hop headers,
+/// and any others named in the `Connection` header
+fn connection_headers<'h, I: Iterator<Item = &'h str>>(connection: I) -> Vec<String> {
+    connection
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .chain(HOP_BY_HOP_HEADERS.iter().map(|name| name.to_string()))
+        .collect()
+}
+
+/// The (lowercase) values in every header called `name`, split on commas
+fn header_list(headers: &[(String, String)], name: &str) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| value.split(',').map(|item| item.trim().to_ascii_lowercase()))
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Whether a response with the given headers can be shared with requests that carry
+/// credentials, which it can when its `Cache-Control` is `public` or has an `s-maxage`
+fn is_shared(headers: &[(String, String)]) -> bool {
+    header_list(headers, "cache-control")
+        .iter()
+        .any(|directive| directive == "public" || directive.starts_with("s-maxage="))
+}
+
+/// The headers named by the `Vary` header in `headers`, with their values in `request`, or
+/// `None` for `Vary: *`, which can't be cached
+fn vary_values(request: &Request, headers: &[(String, String)]) -> Option<Vec<(String, Option<String>)>> {
+    let mut names = header_list(headers, "vary");
+    if names.iter().any(|name| name == "*") {
+        return None;
+    }
+
+    names.sort();
+    names.dedup();
+    Some(
+        names
+            .into_iter()
+            .map(|name| {
+                let value = request_header(request, &name);
+                (name, value)
+            })
+            .collect(),
+    )
+}
+
+/// How long a response with the given headers can be cached for, from its `Cache-Control`
+/// header, bounded by `max_ttl`. `None` if it can't be cached.
+fn cache_ttl(headers: &[(String, String)], max_ttl: Duration) -> Option<Duration> {
+    let directives = header_list(headers, "cache-control");
+
+    let uncacheable = ["no-store", "no-cache", "private"];
+    if directives.iter().any(|directive| uncacheable.contains(&directive.as_str())) {
+        return None;
+    }
+
+    // `s-maxage` is meant for shared caches like this one, so it takes precedence
+    let max_age = |name: &str| {
+        directives
+            .iter()
+            .filter_map(|directive| {
+                let mut parts = directive.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(directive), Some(seconds)) if directive == name => seconds.trim().parse::<u64>().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+    };
+
+    max_age("s-maxage")
+        .or_else(|| max_age("max-age"))
+        .filter(|seconds| *seconds > 0)
+        .map(|seconds| Duration::from_secs(seconds).min(max_ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use rocket::config::{Config, Environment};
+    use rocket::http::ContentType;
+    use rocket::local::Client;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn client() -> Client {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("trusted_proxies", vec!["10.0.0.1"])
+            .unwrap()
+            .build()
+            .unwrap();
+        Client::new(rocket::custom(Config::new(Environment::Development)).manage(settings)).unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_uses_the_peer_address() {
+        let client = client();
+        let request = client.get("/").remote("203.0.113.5:4000".parse().unwrap());
+
+        assert_eq!(forwarded_for_header(request.inner(), None), Some(String::from("203.0.113.5")));
+        // A client connecting directly can't add addresses of its own
+        assert_eq!(
+            forwarded_for_header(request.inner(), Some(String::from("198.51.100.7"))),
+            Some(String::from("203.0.113.5"))
+        );
+    }
+
+    #[test]
+    fn forwarded_for_appends_to_trusted_proxies() {
+        let client = client();
+        let request = client.get("/").remote("10.0.0.1:4000".parse().unwrap());
+
+        assert_eq!(
+            forwarded_for_header(request.inner(), Some(String::from("198.51.100.7"))),
+            Some(String::from("198.51.100.7, 10.0.0.1"))
+        );
+        assert_eq!(forwarded_for_header(request.inner(), None), Some(String::from("10.0.0.1")));
+    }
+
+    #[test]
+    fn forwarded_for_needs_a_peer() {
+        let client = client();
+        let request = client.get("/");
+
+        assert_eq!(forwarded_for_header(request.inner(), Some(String::from("198.51.100.7"))), None);
+    }
+
+    #[test]
+    fn cache_ttl_from_cache_control() {
+        let ttl = |value: &str| {
+            let headers = vec![(String::from("Cache-Control"), String::from(value))];
+            cache_ttl(&headers, Duration::from_secs(60))
+        };
+
+        assert_eq!(ttl("max-age=30"), Some(Duration::from_secs(30)));
+        assert_eq!(ttl("public, MAX-AGE=30"), Some(Duration::from_secs(30)));
+        assert_eq!(ttl("max-age=30, s-maxage=10"), Some(Duration::from_secs(10)));
+        // Bounded by the maximum
+        assert_eq!(ttl("max-age=3600"), Some(Duration::from_secs(60)));
+        assert_eq!(ttl("max-age=0"), None);
+        assert_eq!(ttl("max-age=soon"), None);
+        assert_eq!(ttl("public"), None);
+        assert_eq!(ttl("max-age=30, no-store"), None);
+        assert_eq!(ttl("max-age=30, no-cache"), None);
+        assert_eq!(ttl("private, max-age=30"), None);
+        assert_eq!(cache_ttl(&[], Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn connection_names_more_hop_by_hop_headers() {
+        let skipped = connection_headers(vec!["close, X-Secret", " X-Other "].into_iter());
+
+        assert!(skipped.contains(&String::from("x-secret")));
+        assert!(skipped.contains(&String::from("x-other")));
+        assert!(skipped.contains(&String::from("keep-alive")));
+        assert!(skipped.contains(&String::from("transfer-encoding")));
+        assert!(!skipped.contains(&String::from("")));
+    }
+
+    /// A local HTTP server standing in for the upstream service, which records each request it
+    /// receives and answers with `respond(request number, request)`
+    struct MockUpstream {
+        url: String,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockUpstream {
+        fn start(respond: fn(usize, &str) -> String) -> MockUpstream {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/internal", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+
+            let recorded = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let request = read_request(&mut stream);
+                    let number = {
+                        let mut recorded = recorded.lock().unwrap();
+                        recorded.push(request.clone());
+                        recorded.len()
+                    };
+                    let _ = stream.write_all(respond(number, &request).as_bytes());
+                }
+            });
+
+            MockUpstream { url, requests }
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    /// Read a request's head, and its body when it has one
+    fn read_request(stream: &mut TcpStream) -> String {
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&received).to_ascii_lowercase();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let complete = if text.contains("transfer-encoding: chunked") {
+                    text.ends_with("0\r\n\r\n")
+                } else {
+                    let length = text[..end]
+                        .lines()
+                        .find(|line| line.starts_with("content-length:"))
+                        .and_then(|line| line["content-length:".len()..].trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    received.len() >= end + 4 + length
+                };
+                if complete {
+                    break;
+                }
+            }
+
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => received.extend_from_slice(&buffer[..read]),
+            }
+        }
+        String::from_utf8_lossy(&received).into_owned()
+    }
+
+    fn mock_response(number: usize, request: &str) -> String {
+        let (cache_control, vary) = if request.starts_with("GET /internal/nostore") {
+            ("no-store", "")
+        } else if request.starts_with("GET /internal/public") {
+            ("public, max-age=60", "")
+        } else if request.starts_with("GET /internal/localized") {
+            ("max-age=60", "Vary: Accept-Language\r\n")
+        } else if request.starts_with("GET /internal/anything") {
+            ("max-age=60", "Vary: *\r\n")
+        } else {
+            ("max-age=60", "")
+        };
+        let body = format!("response {}", number);
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain\r\n\
+             Content-Length: {}\r\n\
+             Cache-Control: {}\r\n\
+             {}\
+             X-Served-By: mock\r\n\
+             X-Upstream-Secret: 1\r\n\
+             Keep-Alive: timeout=5\r\n\
+             Connection: close, X-Upstream-Secret\r\n\
+             \r\n\
+             {}",
+            body.len(),
+            cache_control,
+            vary,
+            body
+        )
+    }
+
+    fn proxy_app(upstream: &str) -> TestApp {
+        TestApp::builder()
+            .setting("proxy_prefix", "/api/v1")
+            .setting("proxy_upstream", upstream)
+            .build()
+            .unwrap()
+    }
+
+    /// The value of the header `name` in the raw `request`, which ureq may have written in any case
+    fn raw_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request.lines().skip(1).take_while(|line| !line.is_empty()).find_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(header), Some(value)) if header.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+                _ => None,
+            }
+        })
+    }
+
+    #[test]
+    fn forwards_requests_to_the_upstream() {
+        let upstream = MockUpstream::start(mock_response);
+        let app = proxy_app(&upstream.url);
+
+        let mut response = app
+            .client()
+            .get("/api/v1/users/42?active=true")
+            .remote("203.0.113.5:4000".parse().unwrap())
+            .header(Header::new("X-Custom", "yes"))
+            .header(Header::new(REQUEST_ID_HEADER, "req-1234"))
+            .header(Header::new("X-Forwarded-For", "198.51.100.7"))
+            .header(Header::new("Connection", "X-Secret"))
+            .header(Header::new("X-Secret", "hidden"))
+            .header(Header::new("Keep-Alive", "timeout=5"))
+            .header(Header::new("Cookie", "session=abc; xsrf_token=def"))
+            .header(Header::new("Authorization", "Bearer user-token"))
+            .header(Header::new("X-Api-Key", "app-key"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some(String::from("response 1")));
+        let headers = response.headers();
+        assert_eq!(headers.get_one("X-Served-By"), Some("mock"));
+        assert_eq!(headers.get_one("X-Cache"), Some("MISS"));
+        assert_eq!(headers.get_one("X-Upstream-Secret"), None);
+        assert_eq!(headers.get_one("Keep-Alive"), None);
+        assert_eq!(headers.get_one("Connection"), None);
+
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert!(request.starts_with("GET /internal/users/42?active=true HTTP/1.1\r\n"), "{}", request);
+        assert_eq!(raw_header(request, "X-Custom"), Some("yes"));
+        assert_eq!(raw_header(request, "X-Request-Id"), Some("req-1234"));
+        assert_eq!(raw_header(request, "X-Forwarded-For"), Some("203.0.113.5"));
+        assert_eq!(raw_header(request, "X-Secret"), None);
+        assert_eq!(raw_header(request, "Keep-Alive"), None);
+        // The app's credentials stay with the app
+        assert_eq!(raw_header(request, "Cookie"), None);
+        assert_eq!(raw_header(request, "Authorization"), None);
+        assert_eq!(raw_header(request, "X-Api-Key"), None);
+    }
+
+    #[test]
+    fn request_bodies_are_forwarded() {
+        let upstream = MockUpstream::start(mock_response);
+        let app = proxy_app(&upstream.url);
+
+        let response = app
+            .client()
+            .post("/api/v1/orders")
+            .header(ContentType::JSON)
+            .body(r#"{"item":"book","quantity":2}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-Cache"), Some("MISS"));
+
+        let requests = upstream.requests();
+        assert!(requests[0].starts_with("POST /internal/orders HTTP/1.1\r\n"));
+        assert_eq!(raw_header(&requests[0], "Content-Type"), Some("application/json"));
+        assert!(requests[0].contains(r#"{"item":"book","quantity":2}"#));
+
+        // Only `GET` responses are cached
+        app.client().post("/api/v1/orders").body("again").dispatch();
+        assert_eq!(upstream.requests().len(), 2);
+    }
+
+    #[test]
+    fn get_responses_are_cached() {
+        let upstream = MockUpstream::start(mock_response);
+        let app = proxy_app(&upstream.url);
+        let get = |uri: &str| {
+            let mut response = app.client().get(uri.to_string()).dispatch();
+            let cache = response.headers().get_one("X-Cache").map(String::from);
+            (cache, response.body_string())
+        };
+
+        assert_eq!(get("/api/v1/users"), (Some(String::from("MISS")), Some(String::from("response 1"))));
+        assert_eq!(get("/api/v1/users"), (Some(String::from("HIT")), Some(String::from("response 1"))));
+        // Each query is cached separately
+        assert_eq!(get("/api/v1/users?page=2"), (Some(String::from("MISS")), Some(String::from("response 2"))));
+        assert_eq!(upstream.requests().len(), 2);
+
+        // Responses the upstream marks uncacheable are fetched every time
+        assert_eq!(get("/api/v1/nostore"), (Some(String::from("MISS")), Some(String::from("response 3"))));
+        assert_eq!(get("/api/v1/nostore"), (Some(String::from("MISS")), Some(String::from("response 4"))));
+        assert_eq!(upstream.requests().len(), 4);
+    }
+
+    /// The `X-Cache` header and body of the response to `request`
+    fn cached(request: rocket::local::LocalRequest) -> (Option<String>, Option<String>) {
+        let mut response = request.dispatch();
+        let cache = response.headers().get_one("X-Cache").map(String::from);
+        (cache, response.body_string())
+    }
+
+    fn miss(number: usize) -> (Option<String>, Option<String>) {
+        (Some(String::from("MISS")), Some(format!("response {}", number)))
+    }
+
+    fn hit(number: usize) -> (Option<String>, Option<String>) {
+        (Some(String::from("HIT")), Some(format!("response {}", number)))
+    }
+
+    #[test]
+    fn credentialed_responses_are_not_shared() {
+        let upstream = MockUpstream::start(mock_response);
+        let app = proxy_app(&upstream.url);
+        let as_user = |token: &str| {
+            app.client()
+                .get("/api/v1/me")
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+        };
+
+        assert_eq!(cached(as_user("alice")), miss(1));
+        assert_eq!(cached(as_user("bob")), miss(2));
+        assert_eq!(cached(as_user("alice")), miss(3));
+        let with_cookie = app.client().get("/api/v1/me").header(Header::new("Cookie", "session=abc"));
+        assert_eq!(cached(with_cookie), miss(4));
+
+        // Nothing was stored for anonymous requests to see either
+        assert_eq!(cached(app.client().get("/api/v1/me")), miss(5));
+        assert_eq!(cached(app.client().get("/api/v1/me")), hit(5));
+        assert_eq!(cached(as_user("alice")), miss(6));
+
+        // Unless the upstream says the response can be shared
+        let public = |token: &str| {
+            app.client()
+                .get("/api/v1/public")
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+        };
+        assert_eq!(cached(public("alice")), miss(7));
+        assert_eq!(cached(public("bob")), hit(7));
+        assert_eq!(upstream.requests().len(), 7);
+    }
+
+    #[test]
+    fn cached_responses_vary_on_request_headers() {
+        let upstream = MockUpstream::start(mock_response);
+        let app = proxy_app(&upstream.url);
+        let in_language = |language: &str| {
+            app.client()
+                .get("/api/v1/localized")
+                .header(Header::new("Accept-Language", language.to_string()))
+        };
+
+        assert_eq!(cached(in_language("en")), miss(1));
+        assert_eq!(cached(in_language("fr")), miss(2));
+        assert_eq!(cached(in_language("en")), hit(1));
+        assert_eq!(cached(in_language("fr")), hit(2));
+        assert_eq!(cached(app.client().get("/api/v1/localized")), miss(3));
+        assert_eq!(cached(app.client().get("/api/v1/localized")), hit(3));
+
+        // `Vary: *` can't be matched by any request
+        assert_eq!(cached(app.client().get("/api/v1/anything")), miss(4));
+        assert_eq!(cached(app.client().get("/api/v1/anything")), miss(5));
+    }
+
+    #[test]
+    fn dot_segments_are_rejected() {
+        let upstream = MockUpstream::start(mock_response);
+        let app = proxy_app(&upstream.url);
+
+        for uri in &[
+            "/api/v1/../admin",
+            "/api/v1/users/../../admin",
+            "/api/v1/%2e%2e/admin",
+            "/api/v1/%2E%2e/admin",
+            "/api/v1/.%2E/admin",
+            "/api/v1/users/%2e",
+            "/api/v1/users/..%2Fadmin",
+            "/api/v1/users/..%5Cadmin",
+        ] {
+            let response = app.client().get(uri.to_string()).dispatch();
+            assert_eq!(response.status(), Status::BadRequest, "{}", uri);
+        }
+        assert!(upstream.requests().is_empty());
+
+        // Dots inside a segment are just part of its name
+        let response = app.client().get("/api/v1/files/report..v2.pdf").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(upstream.requests()[0].starts_with("GET /internal/files/report..v2.pdf HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn unreachable_upstreams_are_a_bad_gateway() {
+        // Nothing listens on the port once the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let app = proxy_app(&format!("http://{}/internal", addr));
+
+        let response = app.client().get("/api/v1/users").dispatch();
+        assert_eq!(response.status(), Status::BadGateway);
+    }
+}
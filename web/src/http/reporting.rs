@@ -0,0 +1,476 @@
+//! Reporting unhandled failures (panics, `500 Internal Server Error` responses and failed
+//! background jobs) to an error tracking backend, without tying the app to one vendor.
+//!
+//! The backend is chosen at startup from the `error_reporter_dsn` extra (i.e.
+//! `APP_ERROR_REPORTER_DSN`):
+//!
+//! - unset or `log://`: events are logged with `tracing` at error level
+//! - `file:///path/to/errors.log`: events are appended to the file as JSON lines, for a
+//!   collector to ship elsewhere
+//!
+//! Other backends can implement `ErrorReporter` and be managed as the app's `ErrorReporting`
+//! instead. Events carry the ID of the request that failed (see `RequestId`) when there was one.
+use crate::app::Settings;
+use crate::http::guards::RequestId;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::request::State;
+use rocket::response::{self, Response};
+use rocket::{Catcher, Data, Request};
+use serde_derive::Serialize;
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// The extra that selects the error reporting backend
+pub const ERROR_REPORTER_DSN: &'static str = "error_reporter_dsn";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorLevel {
+    Error,
+    /// The failure killed the thread that it happened on, i.e. a panic
+    Fatal,
+}
+
+/// The request that was being handled when a failure happened
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RequestContext {
+    pub method: String,
+    pub uri: String,
+    pub request_id: String,
+}
+
+impl RequestContext {
+    pub fn of(request: &Request) -> RequestContext {
+        RequestContext {
+            method: request.method().to_string(),
+            uri: request.uri().to_string(),
+            request_id: RequestId::of(request).0,
+        }
+    }
+}
+
+/// A single unhandled failure
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorEvent {
+    pub level: ErrorLevel,
+    pub message: String,
+    pub request: Option<RequestContext>,
+    pub backtrace: Option<String>,
+    /// Where the failure came from, e.g. `source = "panic"` or `job = "send_emails"`
+    pub tags: HashMap<String, String>,
+}
+
+impl ErrorEvent {
+    pub fn new<S: Into<String>>(level: ErrorLevel, message: S) -> ErrorEvent {
+        ErrorEvent {
+            level,
+            message: message.into(),
+            request: None,
+            backtrace: None,
+            tags: HashMap::new(),
+        }
+    }
+
+    pub fn with_request(mut self, request: RequestContext) -> Self {
+        self.request = Some(request);
+        self
+    }
+
+    pub fn with_tag<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// A backend that unhandled failures are sent to. `report` can be called from any thread,
+/// including while a thread is panicking, so it shouldn't panic itself.
+pub trait ErrorReporter: Send + Sync {
+    fn report(&self, event: ErrorEvent);
+}
+
+/// Logs each event with `tracing`, which is the default backend
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogReporter;
+
+impl ErrorReporter for LogReporter {
+    fn report(&self, event: ErrorEvent) {
+        let request_id = event.request.as_ref().map(|request| request.request_id.as_str());
+        tracing::error!(
+            level = ?event.level,
+            request_id = request_id.unwrap_or("-"),
+            tags = ?event.tags,
+            "{}",
+            event.message
+        );
+    }
+}
+
+/// Appends each event to a file as a line of JSON
+pub struct FileReporter {
+    file: Mutex<File>,
+}
+
+impl FileReporter {
+    pub fn open(path: &str) -> std::io::Result<FileReporter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileReporter { file: Mutex::new(file) })
+    }
+}
+
+impl ErrorReporter for FileReporter {
+    fn report(&self, event: ErrorEvent) {
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = writeln!(file, "{}", line) {
+            tracing::error!("Failed to write error report: {}", e);
+        }
+    }
+}
+
+/// The app's error reporter, kept in managed state
+#[derive(Clone)]
+pub struct ErrorReporting(Arc<dyn ErrorReporter>);
+
+impl ErrorReporting {
+    pub fn new(reporter: Arc<dyn ErrorReporter>) -> ErrorReporting {
+        ErrorReporting(reporter)
+    }
+
+    /// Create the backend selected by the `error_reporter_dsn` extra, falling back to
+    /// `LogReporter` (with a warning) if it can't be used
+    pub fn from_settings(settings: &Settings) -> ErrorReporting {
+        let dsn = settings.extra(ERROR_REPORTER_DSN).unwrap_or("log://");
+        if dsn == "log://" {
+            return ErrorReporting::new(Arc::new(LogReporter));
+        }
+
+        if dsn.starts_with("file://") {
+            let path = &dsn["file://".len()..];
+            match FileReporter::open(path) {
+                Ok(reporter) => return ErrorReporting::new(Arc::new(reporter)),
+                Err(e) => tracing::warn!(
+                    path,
+                    "Failed to open the error report file, logging errors instead: {}",
+                    e
+                ),
+            }
+        } else {
+            tracing::warn!(dsn, "Unknown error reporter, logging errors instead");
+        }
+
+        ErrorReporting::new(Arc::new(LogReporter))
+    }
+
+    pub fn report(&self, event: ErrorEvent) {
+        self.0.report(event)
+    }
+
+    /// Report every panic, on any thread, before the default panic hook runs. Panics while a
+    /// request is being handled (see `ErrorContext`) carry that request's context.
+    pub fn install_panic_hook(&self) {
+        let reporter = self.0.clone();
+        let default_hook = panic::take_hook();
+
+        panic::set_hook(Box::new(move |info| {
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| String::from("panic"));
+            let message = match info.location() {
+                Some(location) => format!("{} at {}:{}", message, location.file(), location.line()),
+                None => message,
+            };
+
+            let mut event = ErrorEvent::new(ErrorLevel::Fatal, message).with_tag("source", "panic");
+            event.backtrace = Some(format!("{:?}", backtrace::Backtrace::new()));
+            event.request = CURRENT_REQUEST.with(|current| current.borrow().clone());
+            if let Some(name) = thread::current().name() {
+                event = event.with_tag("thread", name);
+            }
+            reporter.report(event);
+
+            default_hook(info);
+        }));
+    }
+
+    /// Run a background job on a new thread, reporting it if it returns an error. Jobs that
+    /// panic are reported by the panic hook instead, so each failure is reported once. Errors
+    /// don't carry a backtrace, so the report has the error's chain of causes in its message.
+    pub fn spawn_job<F>(&self, name: &'static str, job: F) -> JoinHandle<()>
+    where
+        F: FnOnce() -> Result<(), crate::Error> + Send + 'static,
+    {
+        let reporter = self.0.clone();
+        thread::spawn(move || {
+            if let Err(e) = job() {
+                let mut message = e.to_string();
+                let mut source = e.source();
+                while let Some(cause) = source {
+                    message.push_str(&format!(": {}", cause));
+                    source = cause.source();
+                }
+
+                let event = ErrorEvent::new(ErrorLevel::Error, message)
+                    .with_tag("source", "job")
+                    .with_tag("job", name);
+                reporter.report(event);
+            }
+        })
+    }
+}
+
+thread_local! {
+    /// The request being handled on this thread, for reports of panics in handlers
+    static CURRENT_REQUEST: RefCell<Option<RequestContext>> = RefCell::new(None);
+}
+
+/// Records the request that each worker thread is handling, so that reports of panics in
+/// handlers can say which request caused them. Rocket handles each request on a single thread,
+/// from the request fairings through to the response fairings.
+pub struct ErrorContext;
+
+impl Fairing for ErrorContext {
+    fn info(&self) -> Info {
+        Info {
+            name: "Error Context",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let context = RequestContext::of(request);
+        CURRENT_REQUEST.with(|current| *current.borrow_mut() = Some(context));
+    }
+
+    fn on_response(&self, _: &Request, _: &mut Response) {
+        CURRENT_REQUEST.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+/// A catcher for `500 Internal Server Error` that reports the failure, and responds with a
+/// JSON body holding the request ID so that users can quote it
+pub fn error_catchers() -> Vec<Catcher> {
+    vec![Catcher::new(500, internal_server_error)]
+}
+
+fn internal_server_error<'r>(request: &'r Request) -> response::Result<'r> {
+    let context = RequestContext::of(request);
+    let event = ErrorEvent::new(ErrorLevel::Error, format!("{} {} failed", context.method, context.uri))
+        .with_request(context.clone())
+        .with_tag("source", "catcher");
+
+    match request.guard::<State<ErrorReporting>>().succeeded() {
+        Some(reporting) => reporting.report(event),
+        None => LogReporter.report(event),
+    }
+
+    let body = json!({
+        "error": Status::InternalServerError.reason,
+        "request_id": context.request_id,
+    });
+    Response::build()
+        .status(Status::InternalServerError)
+        .header(ContentType::JSON)
+        .sized_body(Cursor::new(body.to_string()))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use crate::http::guards::REQUEST_ID_HEADER;
+    use rocket::config::{Config, Environment};
+    use rocket::handler::Outcome;
+    use rocket::http::{Header, Method};
+    use rocket::local::Client;
+    use rocket::Route;
+    use serde_json::Value;
+    use std::fs;
+
+    /// Keeps every event that it is sent
+    #[derive(Default)]
+    struct MemoryReporter {
+        events: Mutex<Vec<ErrorEvent>>,
+    }
+
+    impl MemoryReporter {
+        /// The events whose message contains `marker`, as other tests may be panicking too
+        fn events(&self, marker: &str) -> Vec<ErrorEvent> {
+            let events = self.events.lock().unwrap();
+            events.iter().filter(|event| event.message.contains(marker)).cloned().collect()
+        }
+    }
+
+    impl ErrorReporter for MemoryReporter {
+        fn report(&self, event: ErrorEvent) {
+            if let Ok(mut events) = self.events.lock() {
+                events.push(event);
+            }
+        }
+    }
+
+    fn fail<'r>(_: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::failure(Status::InternalServerError)
+    }
+
+    #[test]
+    fn catcher_reports_each_failed_request() {
+        let reporter = Arc::new(MemoryReporter::default());
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(ErrorReporting::new(reporter.clone()))
+            .attach(ErrorContext)
+            .register(error_catchers())
+            .mount("/", vec![Route::new(Method::Get, "/fail", fail)]);
+        let client = Client::new(rocket).unwrap();
+
+        let mut response = client
+            .get("/fail?page=2")
+            .header(Header::new(REQUEST_ID_HEADER, "req-1234"))
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(body, json!({ "error": "Internal Server Error", "request_id": "req-1234" }));
+
+        let events = reporter.events("");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, ErrorLevel::Error);
+        assert_eq!(events[0].message, "GET /fail?page=2 failed");
+        assert_eq!(
+            events[0].request,
+            Some(RequestContext {
+                method: String::from("GET"),
+                uri: String::from("/fail?page=2"),
+                request_id: String::from("req-1234"),
+            })
+        );
+        assert_eq!(events[0].tags.get("source").map(String::as_str), Some("catcher"));
+
+        // Without an ID from the client, the response quotes the one that was generated
+        let mut response = client.get("/fail").dispatch();
+        let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let events = reporter.events("");
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[1].request.as_ref().map(|request| request.request_id.as_str()),
+            body["request_id"].as_str()
+        );
+    }
+
+    #[test]
+    fn failed_jobs_are_reported_once() {
+        let reporter = Arc::new(MemoryReporter::default());
+        let reporting = ErrorReporting::new(reporter.clone());
+
+        reporting.spawn_job("send_emails", || Err("the mail server is down".into())).join().unwrap();
+        reporting.spawn_job("send_emails", || Ok(())).join().unwrap();
+
+        let events = reporter.events("");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, ErrorLevel::Error);
+        assert_eq!(events[0].message, "the mail server is down");
+        assert_eq!(events[0].request, None);
+        assert_eq!(events[0].tags.get("source").map(String::as_str), Some("job"));
+        assert_eq!(events[0].tags.get("job").map(String::as_str), Some("send_emails"));
+    }
+
+    #[test]
+    fn panics_are_reported_with_their_request() {
+        let reporter = Arc::new(MemoryReporter::default());
+        let reporting = ErrorReporting::new(reporter.clone());
+        reporting.install_panic_hook();
+
+        // A panic while handling a request, on a named worker thread
+        let worker = thread::Builder::new()
+            .name(String::from("worker-1"))
+            .spawn(|| {
+                let context = RequestContext {
+                    method: String::from("POST"),
+                    uri: String::from("/orders"),
+                    request_id: String::from("req-5678"),
+                };
+                CURRENT_REQUEST.with(|current| *current.borrow_mut() = Some(context));
+                panic!("reported handler panic");
+            })
+            .unwrap();
+        assert!(worker.join().is_err());
+
+        // A job that panics is reported by the hook, and not again as a failed job
+        let job = reporting.spawn_job("import", || panic!("reported job panic"));
+        assert!(job.join().is_err());
+
+        // Put the default hook back, for the tests that panic on purpose
+        drop(panic::take_hook());
+
+        let handler = reporter.events("reported handler panic");
+        assert_eq!(handler.len(), 1);
+        assert_eq!(handler[0].level, ErrorLevel::Fatal);
+        assert!(handler[0].message.contains("reporting.rs"));
+        assert!(handler[0].backtrace.is_some());
+        assert_eq!(handler[0].request.as_ref().map(|request| request.request_id.as_str()), Some("req-5678"));
+        assert_eq!(handler[0].tags.get("source").map(String::as_str), Some("panic"));
+        assert_eq!(handler[0].tags.get("thread").map(String::as_str), Some("worker-1"));
+
+        let job = reporter.events("reported job panic");
+        assert_eq!(job.len(), 1);
+        assert_eq!(job[0].request, None);
+        assert_eq!(job[0].tags.get("source").map(String::as_str), Some("panic"));
+    }
+
+    #[test]
+    fn dsn_selects_the_file_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("errors.log");
+        let app = TestApp::builder()
+            .extra(ERROR_REPORTER_DSN, format!("file://{}", path.display()))
+            .mount("/", vec![Route::new(Method::Get, "/fail", fail)])
+            .build()
+            .unwrap();
+
+        let response = app
+            .client()
+            .get("/fail")
+            .header(Header::new(REQUEST_ID_HEADER, "req-file"))
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+
+        let log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["level"], "error");
+        assert_eq!(lines[0]["request"]["request_id"], "req-file");
+        assert_eq!(lines[0]["tags"]["source"], "catcher");
+    }
+
+    #[test]
+    fn unusable_dsns_fall_back_to_logging() {
+        let dir = tempfile::tempdir().unwrap();
+        for dsn in &[
+            String::from("sentry://key@example.com/1"),
+            format!("file://{}", dir.path().join("missing").join("errors.log").display()),
+        ] {
+            let settings = Settings::builder().unwrap().extra(ERROR_REPORTER_DSN, dsn.as_str()).build().unwrap();
+            // Reporting still works, it just goes to the log
+            ErrorReporting::from_settings(&settings).report(ErrorEvent::new(ErrorLevel::Error, "logged"));
+        }
+        assert!(!dir.path().join("missing").exists());
+    }
+}
@@ -0,0 +1,584 @@
+//! Server-side session data, for per-user state that is too large to keep in a cookie (cookies
+//! are capped at around 4 KB).
+//!
+//! The private `session` cookie (see the `Session` guard) only carries the session ID, and the
+//! data for each session is kept in a `SessionStore`, chosen by `Settings::session_store`:
+//!
+//! - `memory` (the default): kept in memory, and lost on restart
+//! - `file`: one JSON file per session in `Settings::session_dir`, which survives restarts and
+//!   can be shared by several instances of the app on one machine
+//!
+//! Sessions expire `Settings::session_ttl` after they were last saved. Expired sessions are
+//! never loaded, and are removed by a background job (see `Sessions::spawn_sweeper`).
+use crate::app::Settings;
+use crate::http::guards::{Session, SESSION_COOKIE};
+use crate::http::reporting::{ErrorEvent, ErrorLevel, ErrorReporting};
+
+use rocket::http::{Cookie, Status};
+use rocket::request::{self, FromRequest, Request, State};
+use rocket::Outcome;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// How often expired sessions are removed from the store
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Somewhere to keep the data for each session. Every method can be called from many worker
+/// threads at once; when two requests save the same session, the last save wins.
+pub trait SessionStore: Send + Sync {
+    /// The data saved for session `id`, or `None` if there is none or it has expired
+    fn load(&self, id: &str) -> io::Result<Option<Map<String, Value>>>;
+
+    /// Replace the data for session `id`, which expires after `ttl`
+    fn save(&self, id: &str, data: &Map<String, Value>, ttl: Duration) -> io::Result<()>;
+
+    /// Remove session `id`, if it exists
+    fn delete(&self, id: &str) -> io::Result<()>;
+
+    /// Remove every expired session, returning how many were removed
+    fn sweep(&self) -> io::Result<usize>;
+}
+
+/// Keeps sessions in memory
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (Instant, Map<String, Value>)>>,
+}
+
+impl MemoryStore {
+    fn sessions(&self) -> std::sync::MutexGuard<HashMap<String, (Instant, Map<String, Value>)>> {
+        match self.sessions.lock() {
+            Ok(sessions) => sessions,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<Map<String, Value>>> {
+        Ok(self
+            .sessions()
+            .get(id)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, data)| data.clone()))
+    }
+
+    fn save(&self, id: &str, data: &Map<String, Value>, ttl: Duration) -> io::Result<()> {
+        self.sessions()
+            .insert(id.to_string(), (Instant::now() + ttl, data.clone()));
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        self.sessions().remove(id);
+        Ok(())
+    }
+
+    fn sweep(&self) -> io::Result<usize> {
+        let now = Instant::now();
+        let mut sessions = self.sessions();
+        let before = sessions.len();
+        sessions.retain(|_, (expires, _)| *expires > now);
+        Ok(before - sessions.len())
+    }
+}
+
+/// Keeps each session in a `{id}.json` file in a directory. Saves are written to a temporary
+/// file and renamed over the old one, so a session is never read half written.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Use `dir` for session files, creating it if it doesn't exist
+    pub fn open<P: Into<PathBuf>>(dir: P) -> io::Result<FileStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    /// The file for session `id`. IDs are only ever generated by `Sessions::start`, but they
+    /// come from a cookie, so anything that couldn't be one is rejected rather than being
+    /// allowed to point outside of the directory.
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid session ID"));
+        }
+        Ok(self.dir.join(format!("{}.json", id)))
+    }
+
+    /// Read a session file, returning its expiry time (in seconds since the unix epoch) and data
+    fn read(path: &PathBuf) -> io::Result<Option<(u64, Map<String, Value>)>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut file: Map<String, Value> = serde_json::from_slice(&contents)?;
+        let expires_at = file.get("expires_at").and_then(Value::as_u64).unwrap_or(0);
+        match file.remove("data") {
+            Some(Value::Object(data)) => Ok(Some((expires_at, data))),
+            _ => Ok(Some((expires_at, Map::new()))),
+        }
+    }
+}
+
+/// The current time, in seconds since the unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<Map<String, Value>>> {
+        Ok(FileStore::read(&self.path(id)?)?
+            .filter(|(expires_at, _)| *expires_at > unix_now())
+            .map(|(_, data)| data))
+    }
+
+    fn save(&self, id: &str, data: &Map<String, Value>, ttl: Duration) -> io::Result<()> {
+        let path = self.path(id)?;
+        let contents = json!({ "expires_at": unix_now() + ttl.as_secs(), "data": data });
+
+        // Each save uses its own temporary file, so that concurrent saves can't interleave
+        let temporary = self.dir.join(format!(".{}.{}.tmp", id, Uuid::new_v4().to_simple()));
+        fs::write(&temporary, contents.to_string())?;
+        fs::rename(&temporary, &path).map_err(|e| {
+            let _ = fs::remove_file(&temporary);
+            e
+        })
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn sweep(&self) -> io::Result<usize> {
+        let now = unix_now();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+
+            // Files that can't be read as sessions are left for someone to look at
+            if let Ok(Some((expires_at, _))) = FileStore::read(&path) {
+                if expires_at <= now && fs::remove_file(&path).is_ok() {
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// The app's session store, kept in managed state
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    ttl: Duration,
+}
+
+impl Sessions {
+    pub fn new(store: Arc<dyn SessionStore>, ttl: Duration) -> Sessions {
+        Sessions { store, ttl }
+    }
+
+    /// Create the store selected by `Settings::session_store`, falling back to a `MemoryStore`
+    /// (with a warning) if the session directory can't be created
+    pub fn from_settings(settings: &Settings) -> Sessions {
+        let ttl = settings.session_ttl.as_duration();
+        if settings.session_store == "file" {
+            match FileStore::open(&settings.session_dir) {
+                Ok(store) => return Sessions::new(Arc::new(store), ttl),
+                Err(e) => tracing::warn!(
+                    session_dir = %settings.session_dir,
+                    "Failed to open the session directory, keeping sessions in memory instead: {}",
+                    e
+                ),
+            }
+        }
+
+        Sessions::new(Arc::new(MemoryStore::default()), ttl)
+    }
+
+    pub fn store(&self) -> &dyn SessionStore {
+        &*self.store
+    }
+
+    /// Start a new, empty session for the client, setting the session cookie with the same
+    /// attributes as the app's other cookies. Any previous session is left to expire.
+    pub fn start(&self, request: &Request) -> SessionData {
+        let id = Uuid::new_v4().to_simple().to_string();
+        request.cookies().add_private(session_cookie(request, id.clone()));
+
+        SessionData {
+            id,
+            data: Map::new(),
+            sessions: self.clone(),
+        }
+    }
+
+    /// Remove expired sessions every minute on a background job, reporting (and then
+    /// retrying after the next interval) if the store fails. The job only holds a weak
+    /// reference to the store, and stops once every `Sessions` for it has been dropped, so
+    /// building the app more than once (e.g. in tests) doesn't leave sweepers behind.
+    pub fn spawn_sweeper(&self, reporting: &ErrorReporting) -> JoinHandle<()> {
+        self.spawn_sweeper_every(reporting, SWEEP_INTERVAL)
+    }
+
+    fn spawn_sweeper_every(&self, reporting: &ErrorReporting, interval: Duration) -> JoinHandle<()> {
+        let store: Weak<dyn SessionStore> = Arc::downgrade(&self.store);
+        let reporter = reporting.clone();
+        reporting.spawn_job("session_sweeper", move || loop {
+            thread::sleep(interval);
+            let store = match store.upgrade() {
+                Some(store) => store,
+                None => return Ok(()),
+            };
+            match store.sweep() {
+                Ok(removed) if removed > 0 => tracing::debug!(removed, "Removed expired sessions"),
+                Ok(_) => (),
+                Err(e) => reporter.report(
+                    ErrorEvent::new(ErrorLevel::Error, format!("Failed to remove expired sessions: {}", e))
+                        .with_tag("source", "job")
+                        .with_tag("job", "session_sweeper"),
+                ),
+            }
+        })
+    }
+}
+
+/// The session cookie for session `id`, with the same attributes as the app's other cookies
+pub(crate) fn session_cookie(request: &Request, id: String) -> Cookie<'static> {
+    match request.guard::<State<Settings>>().succeeded() {
+        Some(settings) => Cookie::build(SESSION_COOKIE, id)
+            .path("/")
+            .http_only(true)
+            .secure(settings.cookie_secure)
+            .same_site(settings.cookie_same_site())
+            .finish(),
+        None => Cookie::build(SESSION_COOKIE, id).path("/").http_only(true).finish(),
+    }
+}
+
+/// The stored data for the request's session (see the `Session` guard), which is empty for
+/// sessions with nothing saved yet. Changes are only kept once `save` is called.
+///
+/// Requests without a session are rejected with `401 Unauthorized`, like `Session`, and
+/// requests whose session can't be loaded from the store with `500 Internal Server Error`.
+///
+/// # Examples
+///
+/// ```
+/// #[post("/wizard/step/<n>", data = "<answers>")]
+/// fn step(n: u32, answers: Json<Value>, mut session: SessionData) -> io::Result<Status> {
+///     session.set(&format!("step_{}", n), answers.into_inner());
+///     session.save()?;
+///     Ok(Status::NoContent)
+/// }
+/// ```
+pub struct SessionData {
+    id: String,
+    data: Map<String, Value>,
+    sessions: Sessions,
+}
+
+impl SessionData {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The value stored under `key`, or `None` if there isn't one or it isn't a `T`
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.data
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    pub fn set<T: Serialize>(&mut self, key: &str, value: T) {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.data.insert(key.to_string(), value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.data.remove(key)
+    }
+
+    /// Write the session's data to the store, restarting its expiry time
+    pub fn save(&self) -> io::Result<()> {
+        self.sessions.store.save(&self.id, &self.data, self.sessions.ttl)
+    }
+
+    /// Remove the session's data from the store. The client's cookie is left in place, and
+    /// will find an empty session from now on.
+    pub fn destroy(self) -> io::Result<()> {
+        self.sessions.store.delete(&self.id)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SessionData {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let id = match request.guard::<Session>() {
+            Outcome::Success(Session(id)) => id,
+            _ => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        let sessions = match request.guard::<State<Sessions>>() {
+            Outcome::Success(sessions) => sessions.inner().clone(),
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        match sessions.store.load(&id) {
+            Ok(data) => Outcome::Success(SessionData {
+                id,
+                data: data.unwrap_or_default(),
+                sessions,
+            }),
+            Err(e) => {
+                tracing::error!("Failed to load session: {}", e);
+                Outcome::Failure((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use rocket::handler::Outcome;
+    use rocket::http::Method;
+    use rocket::{Data, Route};
+
+    const LIVE: Duration = Duration::from_secs(3600);
+    const EXPIRED: Duration = Duration::from_secs(0);
+
+    fn data(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(data) => data,
+            _ => panic!("session data must be an object"),
+        }
+    }
+
+    /// The behaviour that every `SessionStore` must have
+    fn store_suite(store: &dyn SessionStore) {
+        assert_eq!(store.load("missing").unwrap(), None);
+        store.delete("missing").unwrap();
+
+        let cart = data(json!({ "cart": [1, 2, 3], "step": 2 }));
+        store.save("one", &cart, LIVE).unwrap();
+        assert_eq!(store.load("one").unwrap(), Some(cart.clone()));
+
+        let replaced = data(json!({ "step": 3 }));
+        store.save("one", &replaced, LIVE).unwrap();
+        assert_eq!(store.load("one").unwrap(), Some(replaced));
+
+        store.save("two", &cart, LIVE).unwrap();
+        store.delete("one").unwrap();
+        assert_eq!(store.load("one").unwrap(), None);
+        assert_eq!(store.load("two").unwrap(), Some(cart.clone()));
+
+        // Expired sessions are never loaded, and are removed by sweeping
+        store.save("old-1", &cart, EXPIRED).unwrap();
+        store.save("old-2", &cart, EXPIRED).unwrap();
+        assert_eq!(store.load("old-1").unwrap(), None);
+        assert_eq!(store.sweep().unwrap(), 2);
+        assert_eq!(store.sweep().unwrap(), 0);
+        assert_eq!(store.load("two").unwrap(), Some(cart.clone()));
+
+        // Saving again restarts the expiry time
+        store.save("old-1", &cart, EXPIRED).unwrap();
+        store.save("old-1", &cart, LIVE).unwrap();
+        assert_eq!(store.sweep().unwrap(), 0);
+        assert_eq!(store.load("old-1").unwrap(), Some(cart));
+    }
+
+    /// Many threads saving the same session at once leave one complete save behind
+    fn concurrent_saves_suite(store: Arc<dyn SessionStore>) {
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for save in 0..20 {
+                        let saved = data(json!({ "writer": writer, "save": save, "padding": "x".repeat(4096) }));
+                        store.save("shared", &saved, LIVE).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let loaded = store.load("shared").unwrap().unwrap();
+        assert_eq!(loaded["save"], 19);
+        assert!(loaded["writer"].as_u64().unwrap() < 8);
+        assert_eq!(loaded["padding"].as_str().map(str::len), Some(4096));
+    }
+
+    #[test]
+    fn memory_store() {
+        store_suite(&MemoryStore::default());
+        concurrent_saves_suite(Arc::new(MemoryStore::default()));
+    }
+
+    #[test]
+    fn sweeper_stops_when_the_sessions_are_dropped() {
+        let store = Arc::new(MemoryStore::default());
+        store.save("expired", &data(json!({ "step": 1 })), EXPIRED).unwrap();
+
+        let reporting = ErrorReporting::new(Arc::new(crate::http::reporting::LogReporter));
+        let sessions = Sessions::new(store.clone(), LIVE);
+        let sweeper = sessions.spawn_sweeper_every(&reporting, Duration::from_millis(10));
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(store.sweep().unwrap(), 0);
+
+        drop(sessions);
+        drop(store);
+        sweeper.join().unwrap();
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        store_suite(&FileStore::open(dir.path().join("sessions")).unwrap());
+
+        let concurrent = tempfile::tempdir().unwrap();
+        concurrent_saves_suite(Arc::new(FileStore::open(concurrent.path()).unwrap()));
+        // Every temporary file was renamed into place
+        let files: Vec<_> = fs::read_dir(concurrent.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(files, vec![String::from("shared.json")]);
+    }
+
+    #[test]
+    fn file_store_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = data(json!({ "wizard": { "step": 4 } }));
+        FileStore::open(dir.path()).unwrap().save("kept", &saved, LIVE).unwrap();
+
+        assert_eq!(FileStore::open(dir.path()).unwrap().load("kept").unwrap(), Some(saved));
+    }
+
+    #[test]
+    fn file_store_rejects_ids_outside_its_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path().join("sessions")).unwrap();
+        let saved = data(json!({}));
+
+        for id in &["", "../escaped", "a/b", "a\\b", "..", "id.json"] {
+            let error = store.save(id, &saved, LIVE).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?} was accepted", id);
+            assert_eq!(store.load(id).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(!dir.path().join("escaped.json").exists());
+    }
+
+    #[test]
+    fn file_store_sweep_leaves_unreadable_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path()).unwrap();
+        fs::write(dir.path().join("broken.json"), "not json").unwrap();
+        fs::write(dir.path().join("notes.txt"), "not a session").unwrap();
+        store.save("expired", &data(json!({})), EXPIRED).unwrap();
+
+        assert_eq!(store.sweep().unwrap(), 1);
+        assert!(dir.path().join("broken.json").exists());
+        assert!(dir.path().join("notes.txt").exists());
+        assert!(store.load("broken").is_err());
+    }
+
+    fn start<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let sessions = request.guard::<State<Sessions>>().unwrap();
+        let mut session = sessions.start(request);
+        session.set("step", 2);
+        session.save().unwrap();
+        Outcome::from(request, session.id().to_string())
+    }
+
+    fn step<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let step = request
+            .guard::<SessionData>()
+            .map(|session| session.get::<u32>("step").map(|step| step.to_string()).unwrap_or_default());
+        match step {
+            rocket::Outcome::Success(step) => Outcome::from(request, step),
+            rocket::Outcome::Failure((status, _)) => Outcome::failure(status),
+            rocket::Outcome::Forward(_) => Outcome::failure(Status::NotFound),
+        }
+    }
+
+    /// Start a session, then read it back with the `SessionData` guard
+    fn round_trip(app: &TestApp) {
+        let mut started = app.client().get("/start").dispatch();
+        assert_eq!(started.status(), Status::Ok);
+        let id = started.body_string().unwrap();
+        assert!(started.headers().get("Set-Cookie").any(|cookie| cookie.starts_with("session=")));
+
+        let mut response = app
+            .client()
+            .get("/step")
+            .private_cookie(Cookie::new(SESSION_COOKIE, id.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some(String::from("2")));
+
+        // A session with nothing saved is empty, rather than an error
+        let mut response = app
+            .client()
+            .get("/step")
+            .private_cookie(Cookie::new(SESSION_COOKIE, "nothing-saved"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some(String::new()));
+
+        let response = app.client().get("/step").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    fn session_app(store: &str, dir: &str) -> TestApp {
+        TestApp::builder()
+            .setting("session_store", store)
+            .setting("session_dir", dir)
+            .mount(
+                "/",
+                vec![Route::new(Method::Get, "/start", start), Route::new(Method::Get, "/step", step)],
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn session_data_works_over_either_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = dir.path().join("sessions");
+
+        round_trip(&session_app("memory", &sessions.to_string_lossy()));
+        assert!(!sessions.exists());
+
+        round_trip(&session_app("file", &sessions.to_string_lossy()));
+        assert_eq!(fs::read_dir(&sessions).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,148 @@
+//! Server-sent events, sent with `VaryingResponse::Sse`. Enabled with the `sse` feature.
+use std::io::{self, Cursor, Read};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// The comment that is written when a stream with a heartbeat has been quiet, which clients
+/// ignore
+pub const HEARTBEAT: &'static str = ": heartbeat\n\n";
+
+/// A single server-sent event
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SseEvent {
+    /// The event type, which clients listen for with `addEventListener`. Clients treat events
+    /// without a type as `message` events
+    pub event: Option<String>,
+    /// The ID that clients send back in `Last-Event-ID` when they reconnect
+    pub id: Option<String>,
+    pub data: String,
+}
+
+impl SseEvent {
+    pub fn data<S: Into<String>>(data: S) -> SseEvent {
+        SseEvent {
+            data: data.into(),
+            ..SseEvent::default()
+        }
+    }
+
+    pub fn with_event<S: Into<String>>(mut self, event: S) -> SseEvent {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn with_id<S: Into<String>>(mut self, id: S) -> SseEvent {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Format this event for an event stream. Multi-line data is sent as one `data:` line per
+    /// line, which clients join back together.
+    pub fn encode(&self) -> String {
+        let mut encoded = String::new();
+        // Line breaks in the event type or ID would start new fields, so they are dropped
+        if let Some(ref event) = self.event {
+            encoded.push_str(&format!("event: {}\n", event.replace(|c: char| c == '\r' || c == '\n', "")));
+        }
+        if let Some(ref id) = self.id {
+            encoded.push_str(&format!("id: {}\n", id.replace(|c: char| c == '\r' || c == '\n', "")));
+        }
+        for line in self.data.lines() {
+            encoded.push_str(&format!("data: {}\n", line));
+        }
+        if self.data.is_empty() {
+            encoded.push_str("data:\n");
+        }
+
+        encoded.push('\n');
+        encoded
+    }
+}
+
+/// The body of a `VaryingResponse::Sse`, which writes each event sent on its channel
+///
+/// Proxies and load balancers often close connections that have been idle for a while (e.g.
+/// 60 seconds for nginx's default `proxy_read_timeout`). With `with_heartbeat`, a comment line
+/// (which clients ignore) is written whenever no event has been sent for that long, so that
+/// quiet streams stay open. Rocket buffers the body as it writes it, so heartbeats also help
+/// to push out events that are still waiting in its buffer.
+///
+/// # Examples
+///
+/// ```
+/// #[get("/updates")]
+/// fn updates(feed: State<Feed>) -> VaryingResponse {
+///     let (sender, stream) = SseStream::channel();
+///     feed.subscribe(sender);
+///     VaryingResponse::Sse(stream.with_heartbeat(Duration::from_secs(15)))
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SseStream {
+    receiver: mpsc::Receiver<SseEvent>,
+    heartbeat: Option<Duration>,
+    pending: Cursor<Vec<u8>>,
+}
+
+impl SseStream {
+    pub fn new(receiver: mpsc::Receiver<SseEvent>) -> SseStream {
+        SseStream {
+            receiver,
+            heartbeat: None,
+            pending: Cursor::new(Vec::new()),
+        }
+    }
+
+    /// Create a stream, and the sender for its events
+    pub fn channel() -> (mpsc::Sender<SseEvent>, SseStream) {
+        let (sender, receiver) = mpsc::channel();
+        (sender, SseStream::new(receiver))
+    }
+
+    /// A stream of the events from `events`, each sent as the data of a `message` event.
+    /// `events` is read on a background thread, which is left once it runs out or the client
+    /// goes away, so that it can block between events without holding up heartbeats.
+    pub fn from_iter(events: Box<dyn Iterator<Item = String> + Send>) -> SseStream {
+        let (sender, stream) = SseStream::channel();
+        thread::spawn(move || {
+            for event in events {
+                if sender.send(SseEvent::data(event)).is_err() {
+                    break;
+                }
+            }
+        });
+        stream
+    }
+
+    /// Write a comment line whenever no event has been sent for `interval`
+    pub fn with_heartbeat(mut self, interval: Duration) -> SseStream {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// Wait for the next chunk of the stream, or `None` once the senders are gone
+    fn next_chunk(&self) -> Option<String> {
+        match self.heartbeat {
+            Some(interval) => match self.receiver.recv_timeout(interval) {
+                Ok(event) => Some(event.encode()),
+                Err(RecvTimeoutError::Timeout) => Some(String::from(HEARTBEAT)),
+                Err(RecvTimeoutError::Disconnected) => None,
+            },
+            None => self.receiver.recv().ok().map(|event| event.encode()),
+        }
+    }
+}
+
+impl Read for SseStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.position() as usize >= self.pending.get_ref().len() {
+            match self.next_chunk() {
+                Some(chunk) => self.pending = Cursor::new(chunk.into_bytes()),
+                None => return Ok(0),
+            }
+        }
+
+        self.pending.read(buf)
+    }
+}
@@ -0,0 +1,248 @@
+//! Statistics from the app's in-memory subsystems, such as the idempotency store, so that their
+//! size can be monitored.
+//!
+//! Each subsystem implements `Introspect` and registers itself with the `StatsRegistry` in
+//! managed state, usually from its fairing's `on_attach`. With the `admin` feature, the
+//! combined document is served at `GET /admin/stats` to requests with a valid `ApiKey`.
+use serde_json::{json, Map, Value};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+
+#[cfg(feature = "admin")]
+use crate::http::guards::ApiKey;
+#[cfg(feature = "admin")]
+use rocket::handler::Outcome;
+#[cfg(feature = "admin")]
+use rocket::http::{ContentType, Method, Status};
+#[cfg(feature = "admin")]
+use rocket::request::State;
+#[cfg(feature = "admin")]
+use rocket::{Data, Request, Response, Route};
+#[cfg(feature = "admin")]
+use std::io::Cursor;
+
+/// A subsystem that can report statistics about itself. `stats` is called while the stats
+/// endpoint is handling a request, so it should only hold locks for as long as it takes to
+/// read a few counters.
+pub trait Introspect: Send + Sync {
+    fn stats(&self) -> Value;
+}
+
+/// The subsystems that report statistics, by name
+#[derive(Default)]
+pub struct StatsRegistry {
+    components: RwLock<Vec<(&'static str, Arc<dyn Introspect>)>>,
+}
+
+impl StatsRegistry {
+    pub fn new() -> StatsRegistry {
+        StatsRegistry::default()
+    }
+
+    /// Add `component` to the registry, replacing any component with the same name
+    pub fn register(&self, name: &'static str, component: Arc<dyn Introspect>) {
+        let mut components = match self.components.write() {
+            Ok(components) => components,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        components.retain(|(existing, _)| *existing != name);
+        components.push((name, component));
+    }
+
+    /// The stats of every component, as an object keyed by name. A component that panics is
+    /// reported as `{ "error": "panicked" }`, rather than failing the whole document.
+    pub fn collect(&self) -> Value {
+        // Cloned so that the lock isn't held while the components are collecting
+        let components = match self.components.read() {
+            Ok(components) => components.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+
+        let stats: Map<String, Value> = components
+            .into_iter()
+            .map(|(name, component)| {
+                let stats = panic::catch_unwind(AssertUnwindSafe(|| component.stats()))
+                    .unwrap_or_else(|_| json!({ "error": "panicked" }));
+                (name.to_string(), stats)
+            })
+            .collect();
+
+        Value::Object(stats)
+    }
+}
+
+/// The routes for the admin endpoints, mounted at `/admin`
+#[cfg(feature = "admin")]
+pub fn admin_routes() -> Vec<Route> {
+    vec![Route::new(Method::Get, "/stats", stats)]
+}
+
+#[cfg(feature = "admin")]
+fn stats<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    if let rocket::Outcome::Failure((status, _)) = request.guard::<ApiKey>() {
+        return Outcome::failure(status);
+    }
+
+    let stats = match request.guard::<State<StatsRegistry>>().succeeded() {
+        Some(registry) => registry.collect(),
+        None => Value::Object(Map::new()),
+    };
+
+    Outcome::Success(
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(Cursor::new(stats.to_string()))
+            .finalize(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Always reports the same stats
+    struct Fixed(Value);
+
+    impl Introspect for Fixed {
+        fn stats(&self) -> Value {
+            self.0.clone()
+        }
+    }
+
+    struct Panicking;
+
+    impl Introspect for Panicking {
+        fn stats(&self) -> Value {
+            panic!("the component's state is broken")
+        }
+    }
+
+    fn registry() -> StatsRegistry {
+        let registry = StatsRegistry::new();
+        registry.register("cache", Arc::new(Fixed(json!({ "entries": 3, "hits": 10, "misses": 2 }))));
+        registry.register("rate_limiter", Arc::new(Fixed(json!({ "buckets": 7 }))));
+        registry
+    }
+
+    #[test]
+    fn collects_every_component() {
+        assert_eq!(StatsRegistry::new().collect(), json!({}));
+        assert_eq!(
+            registry().collect(),
+            json!({
+                "cache": { "entries": 3, "hits": 10, "misses": 2 },
+                "rate_limiter": { "buckets": 7 },
+            })
+        );
+    }
+
+    #[test]
+    fn registering_a_name_again_replaces_it() {
+        let registry = registry();
+        registry.register("cache", Arc::new(Fixed(json!({ "entries": 0 }))));
+
+        assert_eq!(
+            registry.collect(),
+            json!({ "cache": { "entries": 0 }, "rate_limiter": { "buckets": 7 } })
+        );
+    }
+
+    #[test]
+    fn panicking_components_are_reported_as_errored() {
+        let registry = registry();
+        registry.register("broadcaster", Arc::new(Panicking));
+
+        let stats = registry.collect();
+        assert_eq!(stats["broadcaster"], json!({ "error": "panicked" }));
+        assert_eq!(stats["cache"]["entries"], 3);
+        assert_eq!(stats["rate_limiter"]["buckets"], 7);
+
+        // The registry can still be used afterwards
+        registry.register("sessions", Arc::new(Fixed(json!({ "active": 1 }))));
+        assert_eq!(registry.collect()["sessions"]["active"], 1);
+    }
+
+    #[cfg(feature = "admin")]
+    mod admin {
+        use super::*;
+        use crate::app::test_support::TestApp;
+        use crate::app::Settings;
+        use crate::http::guards::API_KEY_HEADER;
+        use rocket::config::{Config, Environment};
+        use rocket::http::Header;
+        use rocket::local::Client;
+
+        fn client() -> Client {
+            let settings = Settings::builder()
+                .unwrap()
+                .set("api_keys", vec!["secret"])
+                .unwrap()
+                .build()
+                .unwrap();
+            let registry = registry();
+            registry.register("broadcaster", Arc::new(Panicking));
+
+            let rocket = rocket::custom(Config::new(Environment::Development))
+                .manage(settings)
+                .manage(registry)
+                .mount("/admin", admin_routes());
+            Client::new(rocket).unwrap()
+        }
+
+        #[test]
+        fn stats_need_an_api_key() {
+            let client = client();
+
+            let response = client.get("/admin/stats").dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+
+            let response = client
+                .get("/admin/stats")
+                .header(Header::new(API_KEY_HEADER, "wrong"))
+                .dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+
+        #[test]
+        fn stats_are_one_document() {
+            let client = client();
+            let mut response = client
+                .get("/admin/stats")
+                .header(Header::new(API_KEY_HEADER, "secret"))
+                .dispatch();
+
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.content_type(), Some(ContentType::JSON));
+            let stats: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+            assert_eq!(
+                stats,
+                json!({
+                    "cache": { "entries": 3, "hits": 10, "misses": 2 },
+                    "rate_limiter": { "buckets": 7 },
+                    "broadcaster": { "error": "panicked" },
+                })
+            );
+        }
+
+        #[test]
+        fn app_serves_stats() {
+            let app = TestApp::builder().build().unwrap();
+            assert!(app.state::<StatsRegistry>().unwrap().collect().get("long_poll").is_some());
+
+            let response = app.client().get("/admin/stats").dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+    }
+
+    #[cfg(not(feature = "admin"))]
+    #[test]
+    fn stats_are_not_served_without_the_admin_feature() {
+        use crate::app::test_support::TestApp;
+        use rocket::http::Status;
+
+        let app = TestApp::builder().build().unwrap();
+        let response = app.client().get("/admin/stats").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
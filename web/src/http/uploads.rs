@@ -0,0 +1,777 @@
+//! Accepting file uploads, with every stored file inspected before the handler sees it.
+//!
+//! The `Upload` data guard streams the request body into `Settings::upload_dir`, under a
+//! generated name, and takes the name the client gave the file from the request's
+//! `Content-Disposition` header (e.g. `attachment; filename="report.pdf"`). Each of the app's
+//! `UploadInspectors` then runs over the stored file, in order:
+//!
+//! - `SizeCheck` rejects empty files, and files over `Settings::upload_max_size`
+//! - `MimeSniffer` detects the file's type from its first bytes, and rejects files whose type
+//!   doesn't agree with their extension, as strictly as `Settings::upload_sniff` says (see
+//!   `SniffStrictness`)
+//! - `ExternalScanner` runs `Settings::upload_scanner_command` (e.g. `clamscan --no-summary
+//!   {path}`) when it's set, and moves files that it exits non-zero for into
+//!   `Settings::upload_quarantine_dir`
+//!
+//! A file that an inspector rejects is deleted (or left in quarantine), and the request fails
+//! with `422 Unprocessable Entity`, or `413 Payload Too Large` for files over the limit. Other
+//! inspectors can be added with `UploadInspectors::with`. The detected type and the scanner's
+//! verdict are kept in `Upload::inspection`.
+use crate::app::Settings;
+
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::http::Status;
+use rocket::request::{Request, State};
+use rocket::Outcome;
+use serde_derive::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often `ExternalScanner` checks whether the scanner has exited
+const SCANNER_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A file that has been stored but not yet handed to a handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+    pub path: PathBuf,
+    /// The file name that the client gave, without any directories
+    pub claimed_name: String,
+    pub size: u64,
+}
+
+impl StoredFile {
+    /// The lowercase extension of `claimed_name`, if it has one
+    pub fn claimed_extension(&self) -> Option<String> {
+        Path::new(&self.claimed_name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+    }
+}
+
+/// What the scanner made of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanVerdict {
+    Clean,
+    /// The scanner exited with the given status, and the file was quarantined
+    Rejected(i32),
+}
+
+/// What the inspectors found out about an upload
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Inspection {
+    /// The MIME type detected from the file's contents, if it has a known signature
+    pub detected_type: Option<&'static str>,
+    /// The external scanner's verdict, if one is configured
+    pub scanner_verdict: Option<ScanVerdict>,
+}
+
+/// Why an upload was rejected
+#[derive(Debug)]
+pub enum UploadError {
+    Io(io::Error),
+    Empty,
+    /// The file was over the limit, in bytes
+    TooLarge(u64),
+    /// The file's contents don't match the type its extension claims
+    TypeMismatch {
+        claimed: Option<String>,
+        detected: Option<&'static str>,
+    },
+    /// The scanner rejected the file, which was moved to the given path
+    Quarantined(PathBuf),
+    /// The scanner couldn't be run, or didn't finish in time
+    ScanFailed(String),
+}
+
+impl UploadError {
+    /// The status that the request fails with
+    pub fn status(&self) -> Status {
+        match self {
+            UploadError::Io(_) | UploadError::ScanFailed(_) => Status::InternalServerError,
+            UploadError::TooLarge(_) => Status::PayloadTooLarge,
+            _ => Status::UnprocessableEntity,
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::Io(e) => write!(f, "could not store upload: {}", e),
+            UploadError::Empty => write!(f, "the uploaded file is empty"),
+            UploadError::TooLarge(limit) => write!(f, "the uploaded file is larger than {} bytes", limit),
+            UploadError::TypeMismatch { claimed, detected } => write!(
+                f,
+                "the uploaded file's contents ({}) don't match its extension ({})",
+                detected.unwrap_or("unknown"),
+                claimed.as_ref().map(String::as_str).unwrap_or("none")
+            ),
+            UploadError::Quarantined(path) => write!(f, "the uploaded file was quarantined at {}", path.display()),
+            UploadError::ScanFailed(message) => write!(f, "could not scan upload: {}", message),
+        }
+    }
+}
+
+impl Error for UploadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UploadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> UploadError {
+        UploadError::Io(e)
+    }
+}
+
+/// A check run over every stored upload, before the handler sees it
+pub trait ContentInspector: Send + Sync {
+    /// Inspect `file`, recording anything learned in `inspection`, or reject it
+    fn inspect(&self, file: &StoredFile, inspection: &mut Inspection) -> Result<(), UploadError>;
+}
+
+/// Rejects empty files, and files over `max` bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeCheck {
+    pub max: u64,
+}
+
+impl ContentInspector for SizeCheck {
+    fn inspect(&self, file: &StoredFile, _: &mut Inspection) -> Result<(), UploadError> {
+        match file.size {
+            0 => Err(UploadError::Empty),
+            size if size > self.max => Err(UploadError::TooLarge(self.max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How closely an upload's contents must agree with its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniffStrictness {
+    Off,
+    /// Reject files whose contents have a known signature that their extension doesn't match,
+    /// e.g. an executable named `photo.png`
+    Lenient,
+    /// As for `Lenient`, and also reject files whose extension has a known signature that
+    /// their contents don't start with
+    Strict,
+}
+
+impl FromStr for SniffStrictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SniffStrictness, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(SniffStrictness::Off),
+            "lenient" => Ok(SniffStrictness::Lenient),
+            "strict" => Ok(SniffStrictness::Strict),
+            _ => Err(format!("expected \"off\", \"lenient\" or \"strict\", got {:?}", s)),
+        }
+    }
+}
+
+/// The MIME types that `MimeSniffer` recognises, with the extensions that each may be saved as
+const KNOWN_TYPES: [(&'static str, &'static [&'static str]); 10] = [
+    ("image/png", &["png"]),
+    ("image/jpeg", &["jpg", "jpeg"]),
+    ("image/gif", &["gif"]),
+    ("image/webp", &["webp"]),
+    ("application/pdf", &["pdf"]),
+    // Office documents are zip files too
+    ("application/zip", &["zip", "docx", "xlsx", "pptx", "odt", "ods", "jar"]),
+    ("application/gzip", &["gz", "tgz"]),
+    ("application/x-msdownload", &["exe", "dll"]),
+    ("application/x-executable", &[]),
+    ("video/mp4", &["mp4", "m4v", "m4a"]),
+];
+
+/// The MIME type of a file that starts with `header`, from its magic bytes
+pub fn sniff(header: &[u8]) -> Option<&'static str> {
+    let starts = |magic: &[u8]| header.starts_with(magic);
+    if starts(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if starts(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        Some("image/gif")
+    } else if starts(b"RIFF") && header.get(8..12) == Some(b"WEBP") {
+        Some("image/webp")
+    } else if starts(b"%PDF-") {
+        Some("application/pdf")
+    } else if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") {
+        Some("application/zip")
+    } else if starts(b"\x1f\x8b") {
+        Some("application/gzip")
+    } else if starts(b"MZ") {
+        Some("application/x-msdownload")
+    } else if starts(b"\x7fELF") {
+        Some("application/x-executable")
+    } else if header.get(4..8) == Some(b"ftyp") {
+        Some("video/mp4")
+    } else {
+        None
+    }
+}
+
+/// Detects each upload's type from its magic bytes, and checks it against the extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MimeSniffer {
+    pub strictness: SniffStrictness,
+}
+
+impl ContentInspector for MimeSniffer {
+    fn inspect(&self, file: &StoredFile, inspection: &mut Inspection) -> Result<(), UploadError> {
+        let mut header = Vec::with_capacity(16);
+        File::open(&file.path)?.take(16).read_to_end(&mut header)?;
+        inspection.detected_type = sniff(&header);
+
+        let extension = file.claimed_extension();
+        let extensions_of = |mime: &str| {
+            KNOWN_TYPES
+                .iter()
+                .find(|(known, _)| *known == mime)
+                .map(|(_, extensions)| *extensions)
+                .unwrap_or(&[])
+        };
+        let claimed_has_signature = extension.as_ref().map_or(false, |extension| {
+            KNOWN_TYPES
+                .iter()
+                .any(|(_, extensions)| extensions.contains(&extension.as_str()))
+        });
+
+        let agrees = match (self.strictness, inspection.detected_type) {
+            (SniffStrictness::Off, _) => true,
+            (_, Some(detected)) => extension
+                .as_ref()
+                .map_or(false, |extension| extensions_of(detected).contains(&extension.as_str())),
+            (SniffStrictness::Lenient, None) => true,
+            (SniffStrictness::Strict, None) => !claimed_has_signature,
+        };
+
+        if agrees {
+            Ok(())
+        } else {
+            Err(UploadError::TypeMismatch {
+                claimed: extension,
+                detected: inspection.detected_type,
+            })
+        }
+    }
+}
+
+/// Runs an external scanner over each upload, quarantining the files that it rejects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalScanner {
+    /// The command to run, split on whitespace, with `{path}` replaced by the file's path. The
+    /// path is added as the last argument when the command doesn't mention it.
+    pub command: String,
+    pub timeout: Duration,
+    pub quarantine_dir: PathBuf,
+}
+
+impl ExternalScanner {
+    fn command_for(&self, path: &Path) -> Option<Command> {
+        let path = path.to_string_lossy();
+        let mut parts = self.command.split_whitespace();
+        let mut command = Command::new(parts.next()?);
+
+        let mut mentions_path = false;
+        for part in parts {
+            mentions_path |= part.contains("{path}");
+            command.arg(part.replace("{path}", &path));
+        }
+        if !mentions_path {
+            command.arg(path.as_ref());
+        }
+
+        command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+        Some(command)
+    }
+
+    fn quarantine(&self, file: &StoredFile) -> Result<PathBuf, UploadError> {
+        fs::create_dir_all(&self.quarantine_dir)?;
+        let target = self.quarantine_dir.join(file.path.file_name().unwrap_or_default());
+        // A rename can't cross filesystems, in which case the file is copied instead
+        if fs::rename(&file.path, &target).is_err() {
+            fs::copy(&file.path, &target)?;
+            fs::remove_file(&file.path)?;
+        }
+        Ok(target)
+    }
+}
+
+impl ContentInspector for ExternalScanner {
+    fn inspect(&self, file: &StoredFile, inspection: &mut Inspection) -> Result<(), UploadError> {
+        let mut command = self
+            .command_for(&file.path)
+            .ok_or_else(|| UploadError::ScanFailed(String::from("the scanner command is empty")))?;
+        let mut child = command.spawn().map_err(|e| UploadError::ScanFailed(e.to_string()))?;
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            match child.try_wait()? {
+                Some(status) => break status,
+                None if Instant::now() >= deadline => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(UploadError::ScanFailed(format!("timed out after {:?}", self.timeout)));
+                }
+                None => thread::sleep(SCANNER_POLL_INTERVAL),
+            }
+        };
+
+        if status.success() {
+            inspection.scanner_verdict = Some(ScanVerdict::Clean);
+            return Ok(());
+        }
+
+        // Killed by a signal on unix, which has no exit code
+        inspection.scanner_verdict = Some(ScanVerdict::Rejected(status.code().unwrap_or(-1)));
+        let quarantined = self.quarantine(file)?;
+        tracing::warn!(
+            file = %file.claimed_name,
+            quarantined = %quarantined.display(),
+            "Upload rejected by scanner"
+        );
+        Err(UploadError::Quarantined(quarantined))
+    }
+}
+
+/// The inspectors that every upload is run through, kept in managed state
+#[derive(Clone, Default)]
+pub struct UploadInspectors {
+    inspectors: Vec<Arc<dyn ContentInspector>>,
+}
+
+impl UploadInspectors {
+    /// The built in inspectors, as configured by `settings`
+    pub fn from_settings(settings: &Settings) -> UploadInspectors {
+        let strictness = settings.upload_sniff.parse().unwrap_or(SniffStrictness::Lenient);
+        let inspectors = UploadInspectors::default()
+            .with(SizeCheck {
+                max: settings.upload_max_size.as_u64(),
+            })
+            .with(MimeSniffer { strictness });
+
+        match settings.upload_scanner_command {
+            Some(ref command) => inspectors.with(ExternalScanner {
+                command: command.clone(),
+                timeout: settings.upload_scanner_timeout.as_duration(),
+                quarantine_dir: PathBuf::from(&settings.upload_quarantine_dir),
+            }),
+            None => inspectors,
+        }
+    }
+
+    /// Add `inspector`, to run after the others
+    pub fn with<I: ContentInspector + 'static>(mut self, inspector: I) -> UploadInspectors {
+        self.inspectors.push(Arc::new(inspector));
+        self
+    }
+
+    /// Run every inspector over `file`, stopping at the first that rejects it
+    pub fn inspect(&self, file: &StoredFile) -> Result<Inspection, UploadError> {
+        let mut inspection = Inspection::default();
+        for inspector in &self.inspectors {
+            inspector.inspect(file, &mut inspection)?;
+        }
+        Ok(inspection)
+    }
+}
+
+/// An uploaded file that passed every inspector, see the module documentation. The file stays
+/// in `Settings::upload_dir` until the handler moves or deletes it.
+///
+/// # Examples
+///
+/// ```
+/// #[put("/avatars", data = "<upload>")]
+/// fn avatar(upload: Upload) -> io::Result<Json<Inspection>> {
+///     fs::rename(&upload.file.path, avatars::path_for(&upload.file.claimed_name))?;
+///     Ok(Json(upload.inspection))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upload {
+    pub file: StoredFile,
+    pub inspection: Inspection,
+}
+
+/// The file name in a `Content-Disposition` header, without any directories
+fn claimed_name(request: &Request) -> String {
+    let filename = request
+        .headers()
+        .get_one("Content-Disposition")
+        .and_then(|value| {
+            value
+                .split(';')
+                .map(str::trim)
+                .find(|param| param.to_ascii_lowercase().starts_with("filename="))
+                .map(|param| param["filename=".len()..].trim_matches('"').to_string())
+        })
+        .unwrap_or_default();
+
+    filename
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or("upload")
+        .to_string()
+}
+
+impl FromDataSimple for Upload {
+    type Error = UploadError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let (settings, inspectors) = match (
+            request.guard::<State<Settings>>().succeeded(),
+            request.guard::<State<UploadInspectors>>().succeeded(),
+        ) {
+            (Some(settings), Some(inspectors)) => (settings, inspectors),
+            _ => {
+                let e = io::Error::new(io::ErrorKind::Other, "uploads aren't configured");
+                return Outcome::Failure((Status::InternalServerError, UploadError::Io(e)));
+            }
+        };
+
+        let dir = PathBuf::from(&settings.upload_dir);
+        let path = dir.join(uuid::Uuid::new_v4().to_string());
+        // One byte over the limit is read, so that `SizeCheck` can tell the file was too large
+        let limit = settings.upload_max_size.as_u64() + 1;
+        let stored = fs::create_dir_all(&dir)
+            .and_then(|_| File::create(&path))
+            .and_then(|mut file| io::copy(&mut data.open().take(limit), &mut file));
+
+        let file = match stored {
+            Ok(size) => StoredFile {
+                path,
+                claimed_name: claimed_name(request),
+                size,
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                return Outcome::Failure((Status::InternalServerError, UploadError::Io(e)));
+            }
+        };
+
+        match inspectors.inspect(&file) {
+            Ok(inspection) => Outcome::Success(Upload { file, inspection }),
+            Err(e) => {
+                // Quarantined files have already been moved out of the upload directory
+                match e {
+                    UploadError::Quarantined(_) => (),
+                    _ => {
+                        let _ = fs::remove_file(&file.path);
+                    }
+                }
+                Outcome::Failure((e.status(), e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::test_support::TestApp;
+    use rocket::handler::Outcome;
+    use rocket::http::{Header, Method};
+    use rocket::Route;
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01";
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\0\x10JFIF\0\x01\x01";
+    const EXE: &[u8] = b"MZ\x90\0\x03\0\0\0\x04\0\0\0\xff\xff\0\0";
+    const TEXT: &[u8] = b"just some notes\n";
+    /// Contents that `REJECTING_SCANNER` exits non-zero for
+    const INFECTED: &[u8] = b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-TEST-FILE!";
+
+    /// A scanner that rejects files containing `EICAR`, as `clamscan` would
+    const REJECTING_SCANNER: &str = "#!/bin/sh\ngrep -q EICAR \"$1\" && exit 1\nexit 0\n";
+    const SLOW_SCANNER: &str = "#!/bin/sh\nsleep 5\n";
+
+    /// Store `contents` in `dir`, as a file uploaded with the name `claimed_name`
+    fn fixture(dir: &TempDir, claimed_name: &str, contents: &[u8]) -> StoredFile {
+        let path = dir.path().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&path, contents).unwrap();
+        StoredFile {
+            path,
+            claimed_name: claimed_name.to_string(),
+            size: contents.len() as u64,
+        }
+    }
+
+    fn scanner(dir: &TempDir, script: &str, timeout: Duration) -> ExternalScanner {
+        let path = dir.path().join("scan.sh");
+        fs::write(&path, script).unwrap();
+        ExternalScanner {
+            command: format!("sh {} {{path}}", path.display()),
+            timeout,
+            quarantine_dir: dir.path().join("quarantine"),
+        }
+    }
+
+    fn sniffed(strictness: SniffStrictness, claimed_name: &str, contents: &[u8]) -> Result<Inspection, UploadError> {
+        let dir = tempfile::tempdir().unwrap();
+        let file = fixture(&dir, claimed_name, contents);
+        let mut inspection = Inspection::default();
+        MimeSniffer { strictness }.inspect(&file, &mut inspection)?;
+        Ok(inspection)
+    }
+
+    #[test]
+    fn sniffs_magic_bytes() {
+        assert_eq!(sniff(PNG), Some("image/png"));
+        assert_eq!(sniff(JPEG), Some("image/jpeg"));
+        assert_eq!(sniff(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVEfmt "), None);
+        assert_eq!(sniff(b"%PDF-1.7"), Some("application/pdf"));
+        assert_eq!(sniff(b"PK\x03\x04\x14\0"), Some("application/zip"));
+        assert_eq!(sniff(EXE), Some("application/x-msdownload"));
+        assert_eq!(sniff(b"\x7fELF\x02\x01"), Some("application/x-executable"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff(TEXT), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn parses_strictness() {
+        assert_eq!(" Strict ".parse(), Ok(SniffStrictness::Strict));
+        assert_eq!("lenient".parse(), Ok(SniffStrictness::Lenient));
+        assert_eq!("OFF".parse(), Ok(SniffStrictness::Off));
+        assert!("paranoid".parse::<SniffStrictness>().is_err());
+    }
+
+    #[test]
+    fn checks_the_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let check = SizeCheck { max: 8 };
+        let mut inspection = Inspection::default();
+
+        let empty = fixture(&dir, "empty.txt", b"");
+        match check.inspect(&empty, &mut inspection) {
+            Err(UploadError::Empty) => (),
+            other => panic!("expected an empty file to be rejected, got {:?}", other),
+        }
+        let large = fixture(&dir, "large.txt", TEXT);
+        match check.inspect(&large, &mut inspection) {
+            Err(e @ UploadError::TooLarge(8)) => assert_eq!(e.status(), Status::PayloadTooLarge),
+            other => panic!("expected a large file to be rejected, got {:?}", other),
+        }
+        assert!(check.inspect(&fixture(&dir, "ok.txt", b"12345678"), &mut inspection).is_ok());
+    }
+
+    #[test]
+    fn contents_must_agree_with_the_extension() {
+        let inspection = sniffed(SniffStrictness::Lenient, "photo.PNG", PNG).unwrap();
+        assert_eq!(inspection.detected_type, Some("image/png"));
+        assert!(sniffed(SniffStrictness::Strict, "photo.jpeg", JPEG).is_ok());
+
+        // An executable is rejected whatever it's named, unless sniffing is off
+        for strictness in &[SniffStrictness::Lenient, SniffStrictness::Strict] {
+            for name in &["photo.png", "photo", "notes.txt"] {
+                match sniffed(*strictness, name, EXE) {
+                    Err(UploadError::TypeMismatch { detected, .. }) => {
+                        assert_eq!(detected, Some("application/x-msdownload"))
+                    }
+                    other => panic!("expected {} to be rejected, got {:?}", name, other),
+                }
+            }
+        }
+        let inspection = sniffed(SniffStrictness::Off, "photo.png", EXE).unwrap();
+        assert_eq!(inspection.detected_type, Some("application/x-msdownload"));
+
+        // Contents without a signature pass leniently, but not as a type that has one
+        assert_eq!(sniffed(SniffStrictness::Lenient, "photo.png", TEXT).unwrap().detected_type, None);
+        match sniffed(SniffStrictness::Strict, "photo.png", TEXT) {
+            Err(UploadError::TypeMismatch { claimed, detected }) => {
+                assert_eq!(claimed, Some(String::from("png")));
+                assert_eq!(detected, None);
+            }
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+        assert!(sniffed(SniffStrictness::Strict, "notes.txt", TEXT).is_ok());
+        assert!(sniffed(SniffStrictness::Strict, "notes", TEXT).is_ok());
+    }
+
+    #[test]
+    fn scanner_passes_clean_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let scanner = scanner(&dir, REJECTING_SCANNER, Duration::from_secs(10));
+        let file = fixture(&dir, "notes.txt", TEXT);
+        let mut inspection = Inspection::default();
+
+        scanner.inspect(&file, &mut inspection).unwrap();
+        assert_eq!(inspection.scanner_verdict, Some(ScanVerdict::Clean));
+        assert!(file.path.exists());
+        assert!(!dir.path().join("quarantine").exists());
+    }
+
+    #[test]
+    fn scanner_quarantines_rejected_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let scanner = scanner(&dir, REJECTING_SCANNER, Duration::from_secs(10));
+        let file = fixture(&dir, "notes.txt", INFECTED);
+        let mut inspection = Inspection::default();
+
+        match scanner.inspect(&file, &mut inspection) {
+            Err(UploadError::Quarantined(path)) => {
+                assert_eq!(path, dir.path().join("quarantine").join(file.path.file_name().unwrap()));
+                assert_eq!(fs::read(&path).unwrap(), INFECTED);
+            }
+            other => panic!("expected the file to be quarantined, got {:?}", other),
+        }
+        assert_eq!(inspection.scanner_verdict, Some(ScanVerdict::Rejected(1)));
+        assert!(!file.path.exists());
+    }
+
+    #[test]
+    fn scanner_failures_are_server_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = fixture(&dir, "notes.txt", TEXT);
+        let mut inspection = Inspection::default();
+
+        let slow = scanner(&dir, SLOW_SCANNER, Duration::from_millis(100));
+        let started = Instant::now();
+        match slow.inspect(&file, &mut inspection) {
+            Err(e @ UploadError::ScanFailed(_)) => assert_eq!(e.status(), Status::InternalServerError),
+            other => panic!("expected the scan to time out, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let missing = ExternalScanner {
+            command: String::from("/nonexistent/scanner"),
+            ..slow.clone()
+        };
+        let empty = ExternalScanner {
+            command: String::from("  "),
+            ..slow
+        };
+        for scanner in &[missing, empty] {
+            match scanner.inspect(&file, &mut inspection) {
+                Err(UploadError::ScanFailed(_)) => (),
+                other => panic!("expected {:?} to fail, got {:?}", scanner.command, other),
+            }
+        }
+
+        // Nothing is quarantined when the scanner never gave a verdict
+        assert_eq!(inspection.scanner_verdict, None);
+        assert!(file.path.exists());
+    }
+
+    #[test]
+    fn inspectors_stop_at_the_first_rejection() {
+        struct Unreachable;
+
+        impl ContentInspector for Unreachable {
+            fn inspect(&self, _: &StoredFile, _: &mut Inspection) -> Result<(), UploadError> {
+                panic!("inspected a file that was already rejected")
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let inspectors = UploadInspectors::default()
+            .with(SizeCheck { max: 1024 })
+            .with(MimeSniffer {
+                strictness: SniffStrictness::Strict,
+            })
+            .with(Unreachable);
+
+        match inspectors.inspect(&fixture(&dir, "empty.png", b"")) {
+            Err(UploadError::Empty) => (),
+            other => panic!("expected an empty file to be rejected, got {:?}", other),
+        }
+        match inspectors.inspect(&fixture(&dir, "photo.png", EXE)) {
+            Err(UploadError::TypeMismatch { .. }) => (),
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+    }
+
+    fn upload<'r>(request: &'r Request, data: Data) -> Outcome<'r> {
+        match Upload::from_data(request, data) {
+            rocket::Outcome::Success(upload) => {
+                let document = serde_json::json!({
+                    "claimed_name": upload.file.claimed_name,
+                    "size": upload.file.size,
+                    "stored": upload.file.path.exists(),
+                    "inspection": upload.inspection,
+                });
+                Outcome::from(request, document.to_string())
+            }
+            rocket::Outcome::Failure((status, _)) => Outcome::failure(status),
+            rocket::Outcome::Forward(_) => Outcome::failure(Status::NotFound),
+        }
+    }
+
+    fn upload_app(dir: &TempDir, scanner_script: &str) -> TestApp {
+        let script = dir.path().join("scan.sh");
+        fs::write(&script, scanner_script).unwrap();
+
+        TestApp::builder()
+            .setting("upload_dir", dir.path().join("uploads").to_string_lossy().into_owned())
+            .setting("upload_quarantine_dir", dir.path().join("quarantine").to_string_lossy().into_owned())
+            .setting("upload_scanner_command", format!("sh {}", script.display()))
+            .setting("upload_sniff", "strict")
+            .setting("upload_max_size", "1KB")
+            .mount("/", vec![Route::new(Method::Post, "/upload", upload)])
+            .build()
+            .unwrap()
+    }
+
+    fn files_in(dir: &Path) -> usize {
+        fs::read_dir(dir).map(|entries| entries.count()).unwrap_or(0)
+    }
+
+    #[test]
+    fn app_inspects_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = upload_app(&dir, REJECTING_SCANNER);
+        let post = |name: &str, contents: &[u8]| {
+            app.client()
+                .post("/upload")
+                .header(Header::new(
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}\"", name),
+                ))
+                .body(contents)
+                .dispatch()
+        };
+
+        let mut response = post("../../avatars/photo.png", PNG);
+        assert_eq!(response.status(), Status::Ok);
+        let document: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(document["claimed_name"], "photo.png");
+        assert_eq!(document["size"], PNG.len() as u64);
+        assert_eq!(document["stored"], true);
+        assert_eq!(document["inspection"]["detected_type"], "image/png");
+        assert_eq!(document["inspection"]["scanner_verdict"], "clean");
+        assert_eq!(files_in(&dir.path().join("uploads")), 1);
+
+        // Rejected files are deleted, or kept in quarantine
+        assert_eq!(post("photo.png", EXE).status(), Status::UnprocessableEntity);
+        assert_eq!(post("photo.png", TEXT).status(), Status::UnprocessableEntity);
+        assert_eq!(post("empty.txt", b"").status(), Status::UnprocessableEntity);
+        assert_eq!(post("large.txt", &[b'a'; 2048][..]).status(), Status::PayloadTooLarge);
+        assert_eq!(files_in(&dir.path().join("uploads")), 1);
+        assert_eq!(files_in(&dir.path().join("quarantine")), 0);
+
+        assert_eq!(post("notes.txt", INFECTED).status(), Status::UnprocessableEntity);
+        assert_eq!(files_in(&dir.path().join("uploads")), 1);
+        assert_eq!(files_in(&dir.path().join("quarantine")), 1);
+    }
+}
@@ -1,25 +1,1950 @@
+use crate::http::guards::{BasePath, Deadline, Pagination};
+use crate::app::Settings;
+#[cfg(feature = "metrics")]
+use crate::http::metrics::{DeadlineMetrics, FileMetrics};
+use crate::http::negotiation;
+use crate::http::redirect::SafeRedirect;
+#[cfg(feature = "sse")]
+use crate::http::sse::SseStream;
+use rocket_contrib::json::Json;
 use rocket_contrib::templates::Template;
 
-use rocket::http::Status;
-use rocket::request::Request;
+use rocket::http::uri::Uri;
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::handler::{Handler, Outcome};
+use rocket::request::{Request, State};
 use rocket::response::{Flash, NamedFile, Redirect, Responder, Response};
+use rocket::{Data, Route};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+
+/// The most deferred responses that are followed when sending a `VaryingResponse::Deferred`
+pub const MAX_DEFERRED_DEPTH: usize = 16;
 
 pub enum VaryingResponse {
     Template(Template),
+    /// A rendered template sent with `content_type`, for templates that aren't HTML such as
+    /// `sitemap.xml` or `manifest.webmanifest`
+    TemplateTyped {
+        template: Template,
+        content_type: ContentType,
+    },
+    /// A rendered template with a status other than `200 OK`, e.g. a `404 Not Found` page. See
+    /// `page`
+    Page {
+        template: String,
+        context: Value,
+        status: Status,
+    },
     File(NamedFile),
     Redirect(Redirect),
     Flash(Flash<Redirect>),
+    /// A redirect to `path`, which is relative to the root of the app and is put under the
+    /// request's `BasePath` when it is sent (see `BasePath::location_for`). `status` is one of
+    /// `303 See Other`, `307 Temporary Redirect` and `308 Permanent Redirect`, and `flash` is
+    /// an optional `(kind, message)` flash message.
+    AppRedirect {
+        path: String,
+        status: Status,
+        flash: Option<(String, String)>,
+    },
+    /// A `300 Multiple Choices` response, listing alternative `(uri, description)` pairs as
+    /// links in an HTML body
+    MultipleChoices(Vec<(Uri<'static>, String)>),
+    /// A file with an explicit `Content-Disposition`, see `file_inline` and `file_attachment`
+    DisposedFile(NamedFile, Disposition),
+    /// A file to be downloaded and saved as the given filename, rather than the name it's
+    /// stored under. The same as a `DisposedFile` with `Disposition::Attachment`, which
+    /// `file_attachment` opens.
+    Attachment(NamedFile, String),
+    /// Gzip the body of the wrapped response, when the client accepts gzip encoding with a
+    /// quality above 0. Streamed bodies (such as files) are read into memory to be compressed,
+    /// except for server-sent events, which stay open and are sent as they are
+    WithCompression(Box<VaryingResponse>),
+    /// A `413 Payload Too Large` response for a request body that was over the given limit, in
+    /// bytes, which is sent to the client in an `X-Max-Content-Length` header
+    PayloadTooLarge(u64),
+    /// A `308 Permanent Redirect`, which (unlike a `301 Moved Permanently`) tells clients to
+    /// repeat the request with the same method and body at the new URI, as in RFC 7538
+    PermanentRedirect(Uri<'static>),
+    /// A `409 Conflict` response, with an optional JSON body describing the conflict
+    Conflict(Option<Value>),
+    /// A `200 OK` response with a JSON body
+    Json(Value),
+    /// A `200 OK` response with an HTML body that is sent exactly as it is. Only for HTML that
+    /// the app built itself: anything that includes user input should be a `SafeHtml`
+    Html(String),
+    /// A `200 OK` response with an HTML fragment that may include user input, e.g. a comment
+    /// written in a rich text editor. It's cleaned with `ammonia::clean` before it's sent,
+    /// which removes scripts, event handler attributes and anything else that could run code
+    SafeHtml(String),
+    /// A `200 OK` response with a body that is already in memory, such as a cached or
+    /// memory-mapped file. The body is sent from the `Bytes` as it is, without being copied, so
+    /// clones of one `Bytes` can be sent to many clients at once.
+    Bytes(bytes::Bytes, ContentType),
+    /// A response that is only built when it is sent, see `deferred`. Handlers that only ever
+    /// defer can return a `Deferred` instead, which doesn't box the closure
+    Deferred(Box<dyn FnOnce() -> VaryingResponse + Send>),
+    /// A `text/event-stream` of server-sent events, which stays open until every sender for
+    /// the stream has been dropped
+    #[cfg(feature = "sse")]
+    Sse(SseStream),
+    /// A `text/event-stream` with each of `events` as the data of an event, and a heartbeat
+    /// comment whenever no event has been sent for `interval`. `events` is read on a
+    /// background thread, see `SseStream::from_iter`
+    #[cfg(feature = "sse")]
+    SseHeartbeat {
+        interval: std::time::Duration,
+        events: Box<dyn Iterator<Item = String> + Send>,
+    },
+}
+
+/// Whether a browser should display a file response itself, or download it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Disposition {
+    Inline,
+    /// Download the file, saving it with the given filename
+    Attachment(String),
+}
+
+impl Disposition {
+    /// Format this disposition as a `Content-Disposition` header value, following RFC 6266.
+    /// Filenames that can't be sent as a plain quoted string are sent with an ASCII fallback
+    /// in `filename`, and the full UTF-8 name percent-encoded in `filename*`.
+    pub fn header_value(&self) -> String {
+        let filename = match self {
+            Disposition::Inline => return String::from("inline"),
+            Disposition::Attachment(filename) => filename,
+        };
+
+        let is_plain = |c: char| (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' && c != '%';
+        if filename.chars().all(is_plain) {
+            return format!("attachment; filename=\"{}\"", filename);
+        }
+
+        let fallback: String = filename
+            .chars()
+            .map(|c| if is_plain(c) { c } else { '_' })
+            .collect();
+
+        let encoded: String = filename
+            .bytes()
+            .map(|b| match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (b as char).to_string(),
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                    (b as char).to_string()
+                }
+                b => format!("%{:02X}", b),
+            })
+            .collect();
+
+        format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+    }
+}
+
+impl VaryingResponse {
+    /// Open the file at `path`, to be displayed by the browser
+    pub fn file_inline<P: AsRef<Path>>(path: P) -> io::Result<VaryingResponse> {
+        Ok(VaryingResponse::DisposedFile(NamedFile::open(path)?, Disposition::Inline))
+    }
+
+    /// Open the file at `path`, to be downloaded by the browser and saved as `filename`
+    pub fn file_attachment<P: AsRef<Path>, S: Into<String>>(path: P, filename: S) -> io::Result<VaryingResponse> {
+        Ok(VaryingResponse::DisposedFile(
+            NamedFile::open(path)?,
+            Disposition::Attachment(filename.into()),
+        ))
+    }
+
+    /// Redirect to a target that has been checked by `SafeRedirect`, such as a `?next=`
+    /// parameter, with `303 See Other`. Paths are put under the request's `BasePath`.
+    pub fn safe_redirect(redirect: SafeRedirect) -> VaryingResponse {
+        VaryingResponse::AppRedirect {
+            path: redirect.target().to_string(),
+            status: Status::SeeOther,
+            flash: None,
+        }
+    }
+
+    /// Build the response with `make` when it is sent, rather than in the handler, so that
+    /// the work is skipped if a fairing replaces the response. `make` can return another
+    /// deferred response, but only up to `MAX_DEFERRED_DEPTH` are followed before responding
+    /// with `500 Internal Server Error`, so that a response which always defers itself can't
+    /// loop forever.
+    pub fn deferred<F>(make: F) -> VaryingResponse
+    where
+        F: FnOnce() -> VaryingResponse + Send + 'static,
+    {
+        VaryingResponse::Deferred(Box::new(make))
+    }
+
+    /// Redirect to `uri` with a flash message, using `307 Temporary Redirect` so that the
+    /// client repeats the request with the same method and body (e.g. a `POST` stays a
+    /// `POST`), rather than the `303 See Other` of `Flash::success` and friends. The flash
+    /// cookie is set on the redirect response and sent with the repeated request, so the
+    /// handler at `uri` can read it with `FlashMessage` as usual; clients that don't keep
+    /// cookies will lose the message, but not the redirect. Paths are put under the request's
+    /// `BasePath`.
+    pub fn flash_temporary<K, M>(uri: Uri<'static>, kind: K, message: M) -> VaryingResponse
+    where
+        K: Into<String>,
+        M: Into<String>,
+    {
+        VaryingResponse::AppRedirect {
+            path: uri.to_string(),
+            status: Status::TemporaryRedirect,
+            flash: Some((kind.into(), message.into())),
+        }
+    }
+
+    /// Redirect to `uri` with a flash message, using `308 Permanent Redirect` to keep the
+    /// method and body, as with `flash_temporary`. Clients may cache permanent redirects and
+    /// skip this handler next time, in which case no flash message is set.
+    pub fn flash_permanent<K, M>(uri: Uri<'static>, kind: K, message: M) -> VaryingResponse
+    where
+        K: Into<String>,
+        M: Into<String>,
+    {
+        VaryingResponse::AppRedirect {
+            path: uri.to_string(),
+            status: Status::PermanentRedirect,
+            flash: Some((kind.into(), message.into())),
+        }
+    }
+
+    /// Build the response with `json` if the client prefers JSON (from its `Accept` header),
+    /// and with `html` otherwise, including when the client accepts anything (`*/*`) or sent
+    /// no `Accept` header. Only the chosen closure is called.
+    pub fn from_negotiated<H, J>(request: &Request, html: H, json: J) -> VaryingResponse
+    where
+        H: FnOnce() -> VaryingResponse,
+        J: FnOnce() -> VaryingResponse,
+    {
+        match Either2::negotiated(request, html, json) {
+            Either2::A(response) | Either2::B(response) => response,
+        }
+    }
+
+    /// Render `template_name` with `context` for browsers, or send `json` to clients that
+    /// prefer JSON, so that one route can serve both. See `from_negotiated`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[get("/posts/<slug>")]
+    /// fn post(request: &Request, slug: Slug) -> Option<VaryingResponse> {
+    ///     let post = posts::find(slug.as_str())?;
+    ///     let json = json!(post);
+    ///     Some(VaryingResponse::render_or_json(request, "post", post, json))
+    /// }
+    /// ```
+    pub fn render_or_json<C: Serialize>(
+        request: &Request,
+        template_name: &'static str,
+        context: C,
+        json: Value,
+    ) -> VaryingResponse {
+        HtmlOrJson::render_or_json(request, template_name, context, json).into()
+    }
+
+    /// Render `template` with `context`, responding with `status`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[get("/posts/<slug>")]
+    /// fn post(slug: Slug) -> VaryingResponse {
+    ///     match posts::find(slug.as_str()) {
+    ///         Some(post) => VaryingResponse::Template(Template::render("post", post)),
+    ///         None => VaryingResponse::page("404", json!({ "slug": slug.as_str() }), Status::NotFound),
+    ///     }
+    /// }
+    /// ```
+    pub fn page<S: Into<String>>(template: S, context: Value, status: Status) -> VaryingResponse {
+        VaryingResponse::Page {
+            template: template.into(),
+            context,
+            status,
+        }
+    }
+
+    /// A `409 Conflict` response without a body
+    pub fn conflict() -> VaryingResponse {
+        VaryingResponse::Conflict(None)
+    }
+
+    /// A `409 Conflict` response with `detail` as its JSON body
+    pub fn conflict_with(detail: Value) -> VaryingResponse {
+        VaryingResponse::Conflict(Some(detail))
+    }
+}
+
+/// The responders that a `VaryingResponse` can hold and respond with unchanged
+type Delegated = Either3<Template, Redirect, Flash<Redirect>>;
+
+impl VaryingResponse {
+    /// Build this response while it is deferred, having already followed `depth` deferred
+    /// responses, failing once `MAX_DEFERRED_DEPTH` have been followed
+    fn undefer(self, mut depth: usize) -> Result<VaryingResponse, Status> {
+        let mut response = self;
+        while let VaryingResponse::Deferred(make) = response {
+            if depth == MAX_DEFERRED_DEPTH {
+                tracing::error!(max_depth = MAX_DEFERRED_DEPTH, "Deferred response was deferred too many times");
+                return Err(Status::InternalServerError);
+            }
+            response = make();
+            depth += 1;
+        }
+        Ok(response)
+    }
+
+    /// The responder held by this response, for the variants that respond exactly as their
+    /// responder would, or this response for every other variant
+    fn into_delegated(self) -> Result<Delegated, VaryingResponse> {
+        match self {
+            VaryingResponse::Template(template) => Ok(Either3::A(template)),
+            VaryingResponse::Redirect(redirect) => Ok(Either3::B(redirect)),
+            VaryingResponse::Flash(flash) => Ok(Either3::C(flash)),
+            response => Err(response),
+        }
+    }
 }
 
 impl<'r> Responder<'r> for VaryingResponse {
     fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
         use self::VaryingResponse::*;
 
+        let response = match self.undefer(0)?.into_delegated() {
+            Ok(delegated) => return delegated.respond_to(request),
+            Err(response) => response,
+        };
+
+        match response {
+            TemplateTyped { template, content_type } => {
+                let mut response = template.respond_to(request)?;
+                response.set_header(content_type);
+                Ok(response)
+            }
+            Page {
+                template,
+                context,
+                status,
+            } => {
+                let mut response = rocket_contrib::templates::Template::render(template, context).respond_to(request)?;
+                response.set_status(status);
+                Ok(response)
+            }
+            AppRedirect { path, status, flash } => {
+                let location = BasePath::of(request).location_for(&path);
+                let redirect = if status == Status::TemporaryRedirect {
+                    Redirect::temporary(location)
+                } else if status == Status::PermanentRedirect {
+                    Redirect::permanent(location)
+                } else {
+                    Redirect::to(location)
+                };
+
+                match flash {
+                    Some((kind, message)) => Flash::new(redirect, kind, message).respond_to(request),
+                    None => redirect.respond_to(request),
+                }
+            }
+            File(r) => Response::build_from(r.respond_to(request)?)
+                .header(accept_ranges())
+                .ok(),
+            MultipleChoices(choices) => {
+                let items: String = choices
+                    .iter()
+                    .map(|(uri, description)| {
+                        format!(
+                            "<li><a href=\"{}\">{}</a></li>",
+                            escape_html(&uri.to_string()),
+                            escape_html(description)
+                        )
+                    })
+                    .collect();
+
+                Response::build()
+                    .status(Status::MultipleChoices)
+                    .header(ContentType::HTML)
+                    .sized_body(Cursor::new(format!("<ul>{}</ul>", items)))
+                    .ok()
+            }
+            Attachment(file, filename) => DisposedFile(file, Disposition::Attachment(filename)).respond_to(request),
+            DisposedFile(file, disposition) => Response::build_from(file.respond_to(request)?)
+                .header(Header::new("Content-Disposition", disposition.header_value()))
+                .header(accept_ranges())
+                .ok(),
+            WithCompression(inner) => {
+                let mut response = (*inner).respond_to(request)?;
+                compress_response(request, &mut response);
+                Ok(response)
+            }
+            PayloadTooLarge(limit) => Response::build()
+                .status(Status::PayloadTooLarge)
+                .header(Header::new("X-Max-Content-Length", limit.to_string()))
+                .ok(),
+            PermanentRedirect(uri) => rocket::response::Redirect::permanent(uri.to_string()).respond_to(request),
+            Conflict(detail) => {
+                let mut response = Response::build();
+                response.status(Status::Conflict);
+                if let Some(detail) = detail {
+                    response
+                        .header(ContentType::JSON)
+                        .sized_body(Cursor::new(detail.to_string()));
+                }
+                response.ok()
+            }
+            // Responded to above
+            Template(_) | Redirect(_) | Flash(_) | Deferred(_) => Err(Status::InternalServerError),
+            Json(value) => Response::build()
+                .header(ContentType::JSON)
+                .sized_body(Cursor::new(value.to_string()))
+                .ok(),
+            Html(html) => Response::build()
+                .header(ContentType::HTML)
+                .sized_body(Cursor::new(html))
+                .ok(),
+            SafeHtml(html) => Html(ammonia::clean(&html)).respond_to(request),
+            Bytes(body, content_type) => Response::build()
+                .header(content_type)
+                .sized_body(Cursor::new(body))
+                .ok(),
+            #[cfg(feature = "sse")]
+            Sse(stream) => Response::build()
+                .header(ContentType::new("text", "event-stream"))
+                .header(Header::new("Cache-Control", "no-cache"))
+                .header(Header::new("Connection", "keep-alive"))
+                .streamed_body(stream)
+                .ok(),
+            #[cfg(feature = "sse")]
+            SseHeartbeat { interval, events } => {
+                Sse(SseStream::from_iter(events).with_heartbeat(interval)).respond_to(request)
+            }
+        }
+    }
+}
+
+/// A response that is only built by `make` when it is sent, like `VaryingResponse::deferred`
+/// but without boxing `make`. The response that `make` returns may be deferred again, up to
+/// `MAX_DEFERRED_DEPTH` times in all (including this one).
+///
+/// # Examples
+///
+/// ```
+/// #[get("/report")]
+/// fn report(cache: State<ReportCache>) -> Deferred<impl FnOnce() -> VaryingResponse + Send> {
+///     // Skipped entirely when a caching fairing replaces the response
+///     Deferred(move || VaryingResponse::Json(cache.build()))
+/// }
+/// ```
+pub struct Deferred<F>(pub F)
+where
+    F: FnOnce() -> VaryingResponse + Send + 'static;
+
+impl<'r, F> Responder<'r> for Deferred<F>
+where
+    F: FnOnce() -> VaryingResponse + Send + 'static,
+{
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        (self.0)().undefer(1)?.respond_to(request)
+    }
+}
+
+impl<F> From<Deferred<F>> for VaryingResponse
+where
+    F: FnOnce() -> VaryingResponse + Send + 'static,
+{
+    fn from(deferred: Deferred<F>) -> VaryingResponse {
+        VaryingResponse::Deferred(Box::new(deferred.0))
+    }
+}
+
+/// A response that is one of two responders, for handlers that respond in one of a few
+/// different ways without defining a type (or a `VaryingResponse` variant) for the
+/// combination. Each variant responds exactly as the responder it holds would.
+///
+/// # Examples
+///
+/// ```
+/// #[post("/login", data = "<form>")]
+/// fn login(form: Form<Login>) -> PageOrRedirect {
+///     match users::authenticate(&form) {
+///         Some(_) => Either2::B(Redirect::to("/")),
+///         None => Either2::A(Template::render("login", json!({ "failed": true }))),
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Either2<A, B> {
+    A(A),
+    B(B),
+}
+
+/// A response that is one of three responders, as for `Either2`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Either3<A, B, C> {
+    A(A),
+    B(B),
+    C(C),
+}
+
+/// A rendered template for browsers, or JSON for clients that prefer it, see `render_or_json`
+pub type HtmlOrJson = Either2<Template, Json<Value>>;
+
+/// A rendered page, or a redirect elsewhere (e.g. after a form was submitted)
+pub type PageOrRedirect = Either2<Template, Redirect>;
+
+impl<A, B> Either2<A, B> {
+    /// Build the response with `json` if the client prefers JSON (from its `Accept` header),
+    /// and with `html` otherwise, as in `VaryingResponse::from_negotiated`. Only the chosen
+    /// closure is called.
+    pub fn negotiated<H, J>(request: &Request, html: H, json: J) -> Either2<A, B>
+    where
+        H: FnOnce() -> A,
+        J: FnOnce() -> B,
+    {
+        let preferred = negotiation::preferred_content_type(request, &[ContentType::HTML, ContentType::JSON]);
+        if preferred == Some(ContentType::JSON) {
+            Either2::B(json())
+        } else {
+            Either2::A(html())
+        }
+    }
+}
+
+impl HtmlOrJson {
+    /// Render `template_name` with `context` for browsers, or send `json` to clients that
+    /// prefer JSON, like `VaryingResponse::render_or_json`
+    pub fn render_or_json<C: Serialize>(
+        request: &Request,
+        template_name: &'static str,
+        context: C,
+        json: Value,
+    ) -> HtmlOrJson {
+        Either2::negotiated(request, || Template::render(template_name, context), || Json(json))
+    }
+}
+
+impl From<Template> for VaryingResponse {
+    fn from(template: Template) -> VaryingResponse {
+        VaryingResponse::Template(template)
+    }
+}
+
+impl From<Redirect> for VaryingResponse {
+    fn from(redirect: Redirect) -> VaryingResponse {
+        VaryingResponse::Redirect(redirect)
+    }
+}
+
+impl From<Flash<Redirect>> for VaryingResponse {
+    fn from(flash: Flash<Redirect>) -> VaryingResponse {
+        VaryingResponse::Flash(flash)
+    }
+}
+
+impl From<NamedFile> for VaryingResponse {
+    fn from(file: NamedFile) -> VaryingResponse {
+        VaryingResponse::File(file)
+    }
+}
+
+impl From<Json<Value>> for VaryingResponse {
+    fn from(json: Json<Value>) -> VaryingResponse {
+        VaryingResponse::Json(json.into_inner())
+    }
+}
+
+/// Either of the responses, e.g. an `HtmlOrJson` or a `PageOrRedirect`
+impl<A: Into<VaryingResponse>, B: Into<VaryingResponse>> From<Either2<A, B>> for VaryingResponse {
+    fn from(response: Either2<A, B>) -> VaryingResponse {
+        match response {
+            Either2::A(a) => a.into(),
+            Either2::B(b) => b.into(),
+        }
+    }
+}
+
+impl<A, B, C> From<Either3<A, B, C>> for VaryingResponse
+where
+    A: Into<VaryingResponse>,
+    B: Into<VaryingResponse>,
+    C: Into<VaryingResponse>,
+{
+    fn from(response: Either3<A, B, C>) -> VaryingResponse {
+        match response {
+            Either3::A(a) => a.into(),
+            Either3::B(b) => b.into(),
+            Either3::C(c) => c.into(),
+        }
+    }
+}
+
+impl<'r, A: Responder<'r>, B: Responder<'r>> Responder<'r> for Either2<A, B> {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            Either2::A(a) => a.respond_to(request),
+            Either2::B(b) => b.respond_to(request),
+        }
+    }
+}
+
+impl<'r, A: Responder<'r>, B: Responder<'r>, C: Responder<'r>> Responder<'r> for Either3<A, B, C> {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
         match self {
-            Template(r) => r.respond_to(request),
-            File(r) => r.respond_to(request),
-            Redirect(r) => r.respond_to(request),
-            Flash(r) => r.respond_to(request),
+            Either3::A(a) => a.respond_to(request),
+            Either3::B(b) => b.respond_to(request),
+            Either3::C(c) => c.respond_to(request),
+        }
+    }
+}
+
+/// A file that is only served to authorized callers, responding with `403 Forbidden` (without
+/// reading the file) otherwise. Opening the `NamedFile` first means that requests for files
+/// that don't exist still get `404 Not Found`, whether or not they are authorized.
+///
+/// # Examples
+///
+/// ```
+/// #[get("/invoices/<id>")]
+/// fn invoice(id: u64, user: Session) -> io::Result<ProtectedFile> {
+///     let invoice = invoices::find(id)?;
+///     let file = NamedFile::open(invoice.path())?;
+///     Ok(ProtectedFile::new(file, invoice.owner == user.0))
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ProtectedFile {
+    pub file: NamedFile,
+    pub authorized: bool,
+}
+
+impl ProtectedFile {
+    pub fn new(file: NamedFile, authorized: bool) -> ProtectedFile {
+        ProtectedFile { file, authorized }
+    }
+}
+
+impl<'r> Responder<'r> for ProtectedFile {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        if !self.authorized {
+            return Err(Status::Forbidden);
         }
+
+        Response::build_from(self.file.respond_to(request)?)
+            .header(accept_ranges())
+            .ok()
+    }
+}
+
+/// A `204 No Content` response to a CORS preflight (`OPTIONS`) request, listing what the
+/// actual request may use. The `Access-Control-Allow-Origin` header is added by the
+/// `CorsHeaderFairing`, in the same way as for any other response.
+///
+/// # Examples
+///
+/// ```
+/// #[options("/posts")]
+/// fn posts_preflight() -> Preflight {
+///     Preflight {
+///         allowed_methods: vec![Method::Get, Method::Post],
+///         allowed_headers: vec![String::from("Content-Type")],
+///         max_age: 3600,
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preflight {
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<String>,
+    /// How long the browser may cache this response for, in seconds
+    pub max_age: u32,
+}
+
+impl<'r> Responder<'r> for Preflight {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        let methods: Vec<&str> = self.allowed_methods.iter().map(Method::as_str).collect();
+
+        Response::build()
+            .status(Status::NoContent)
+            .header(Header::new("Access-Control-Allow-Methods", methods.join(", ")))
+            .header(Header::new("Access-Control-Allow-Headers", self.allowed_headers.join(", ")))
+            .header(Header::new("Access-Control-Max-Age", self.max_age.to_string()))
+            .ok()
+    }
+}
+
+/// One page of a collection as a JSON array, for API list endpoints. The total number of
+/// items is sent in `X-Total-Count`, and the other pages are linked in a `Link` header (RFC
+/// 5988) with `rel="first"`, `rel="last"` and, when there are such pages, `rel="prev"` and
+/// `rel="next"`. The links point at the request's own path, keeping its other query params,
+/// with the `page` and `per_page` that the `Pagination` guard reads. The path is built with
+/// `BasePath`, so the links work behind a reverse proxy that serves the app under a prefix.
+///
+/// # Examples
+///
+/// ```
+/// #[get("/posts")]
+/// fn list_posts(pagination: Pagination) -> PagedJson<Post> {
+///     let posts = posts::list(pagination.offset, pagination.limit);
+///     PagedJson::new(posts, pagination, posts::count())
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PagedJson<T> {
+    pub items: Vec<T>,
+    pub pagination: Pagination,
+    /// The number of items in the whole collection
+    pub total: u64,
+}
+
+impl<T> PagedJson<T> {
+    pub fn new(items: Vec<T>, pagination: Pagination, total: u64) -> PagedJson<T> {
+        PagedJson {
+            items,
+            pagination,
+            total,
+        }
+    }
+
+    /// The `Link` header value for the pages around this one, linking to `path` with the
+    /// `query` params that aren't about pagination
+    fn links(&self, path: &str, query: Option<&str>) -> String {
+        let per_page = u64::from(self.pagination.limit.max(1));
+        let page = self.pagination.offset / per_page + 1;
+        let last = ((self.total + per_page - 1) / per_page).max(1);
+
+        let kept: Vec<&str> = query
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| {
+                let name = pair.splitn(2, '=').next().unwrap_or("");
+                !pair.is_empty() && name != "page" && name != "per_page"
+            })
+            .collect();
+        let link = |page: u64, rel: &str| {
+            let mut params = kept.clone();
+            let pagination = format!("page={}&per_page={}", page, per_page);
+            params.push(&pagination);
+            format!("<{}?{}>; rel=\"{}\"", path, params.join("&"), rel)
+        };
+
+        let mut links = vec![link(1, "first")];
+        if page > 1 {
+            links.push(link((page - 1).min(last), "prev"));
+        }
+        if page < last {
+            links.push(link(page + 1, "next"));
+        }
+        links.push(link(last, "last"));
+        links.join(", ")
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r> for PagedJson<T> {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        let body = serde_json::to_string(&self.items).map_err(|_| Status::InternalServerError)?;
+        // Routes are mounted under the route prefix, if any, and `BasePath` puts the prefix
+        // (or the one that a proxy forwarded) back in front of the rest of the path
+        let path = request.uri().path();
+        let route_prefix = request
+            .guard::<State<Settings>>()
+            .succeeded()
+            .map(|settings| settings.route_prefix().to_string())
+            .unwrap_or_default();
+        let relative = match path.get(route_prefix.len()..) {
+            Some(rest) if path.starts_with(route_prefix.as_str()) => rest,
+            _ => path,
+        };
+        let links = self.links(&BasePath::of(request).url_for(relative), request.uri().query());
+
+        Response::build()
+            .header(ContentType::JSON)
+            .header(Header::new("Link", links))
+            .header(Header::new("X-Total-Count", self.total.to_string()))
+            .sized_body(Cursor::new(body))
+            .ok()
+    }
+}
+
+/// The chunk size used by `LargeFile` when `Settings` aren't being managed, matching its default
+const DEFAULT_FILE_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// A file that may be too large to buffer, which is streamed to the client in chunks of
+/// `Settings::file_chunk_bytes`. Unlike `NamedFile`, a single `Range: bytes=...` request is
+/// answered with `206 Partial Content` (other range requests get the whole file), so responses
+/// advertise `Accept-Ranges: bytes`.
+///
+/// Files that don't exist get `404 Not Found`, and files that can't be opened for any other
+/// reason get `500 Internal Server Error`. If reading fails after the response has started
+/// (e.g. because the file was truncated), the body ends early rather than failing the whole
+/// response, and the client sees a short body. When metrics are enabled, the bytes written are
+/// counted in `FileMetrics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LargeFile {
+    path: PathBuf,
+}
+
+impl LargeFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> LargeFile {
+        LargeFile { path: path.into() }
+    }
+}
+
+/// Parse a `Range` header for a single range of a `len` byte file, as inclusive `(start, end)`
+/// offsets. `Ok(None)` means the header should be ignored, and `Err(())` that the range
+/// can't be satisfied.
+fn parse_range(header: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let ranges = match header.trim().splitn(2, '=').collect::<Vec<_>>().as_slice() {
+        [unit, ranges] if unit.trim().eq_ignore_ascii_case("bytes") => ranges.to_string(),
+        _ => return Ok(None),
+    };
+    if ranges.contains(',') {
+        return Ok(None);
+    }
+
+    let (start, end) = match ranges.trim().splitn(2, '-').collect::<Vec<_>>().as_slice() {
+        [start, end] => (start.trim().to_string(), end.trim().to_string()),
+        _ => return Ok(None),
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // `bytes=-500` is the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (len.saturating_sub(suffix), len.saturating_sub(1)),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+
+    if len == 0 || range.0 >= len {
+        return Err(());
+    }
+
+    Ok(Some(range))
+}
+
+/// The body of a `LargeFile`, which ends early instead of failing when the file can't be read
+struct FileBody<R> {
+    reader: R,
+    path: PathBuf,
+    #[cfg(feature = "metrics")]
+    counter: Option<std::sync::Arc<std::sync::atomic::AtomicU64>>,
+}
+
+impl<R: Read> Read for FileBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reader.read(buf) {
+            Ok(read) => {
+                #[cfg(feature = "metrics")]
+                {
+                    if let Some(ref counter) = self.counter {
+                        counter.fetch_add(read as u64, std::sync::atomic::Ordering::Relaxed);
+                    }
+                }
+                Ok(read)
+            }
+            Err(e) => {
+                tracing::error!(path = %self.path.display(), "Failed to read the file while streaming it: {}", e);
+                Ok(0)
+            }
+        }
+    }
+}
+
+impl<'r> Responder<'r> for LargeFile {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        let mut file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(Status::NotFound),
+            Err(_) => return Err(Status::InternalServerError),
+        };
+        let metadata = file.metadata().map_err(|_| Status::InternalServerError)?;
+        // Opening a directory succeeds on unix, but reading it doesn't
+        if !metadata.is_file() {
+            tracing::error!(path = %self.path.display(), "Large file is not a regular file");
+            return Err(Status::InternalServerError);
+        }
+        let len = metadata.len();
+
+        let chunk_size = request
+            .guard::<State<Settings>>()
+            .succeeded()
+            .map(|settings| settings.file_chunk_bytes.as_u64())
+            .unwrap_or(DEFAULT_FILE_CHUNK_BYTES)
+            .max(1);
+
+        let mut response = Response::build();
+        response.header(Header::new("Accept-Ranges", "bytes"));
+        if let Some(content_type) = self
+            .path
+            .extension()
+            .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+        {
+            response.header(content_type);
+        }
+
+        let range = match request.headers().get_one("Range") {
+            Some(header) => parse_range(header, len),
+            None => Ok(None),
+        };
+
+        let (start, length) = match range {
+            Ok(Some((start, end))) => {
+                response
+                    .status(Status::PartialContent)
+                    .header(Header::new("Content-Range", format!("bytes {}-{}/{}", start, end, len)));
+                (start, end - start + 1)
+            }
+            Ok(None) => (0, len),
+            Err(()) => {
+                return response
+                    .status(Status::RangeNotSatisfiable)
+                    .header(Header::new("Content-Range", format!("bytes */{}", len)))
+                    .ok();
+            }
+        };
+
+        file.seek(SeekFrom::Start(start)).map_err(|_| Status::InternalServerError)?;
+
+        let body = FileBody {
+            reader: file.take(length),
+            path: self.path,
+            #[cfg(feature = "metrics")]
+            counter: request
+                .guard::<State<FileMetrics>>()
+                .succeeded()
+                .map(|metrics| metrics.counter()),
+        };
+
+        response.chunked_body(body, chunk_size).ok()
+    }
+}
+
+/// The result of running slow work with `TimeBound::run`. Responds with the work's own
+/// response if it finished before the request's `Deadline`, or `503 Service Unavailable`
+/// with a `{"error":"timeout"}` body if it didn't.
+///
+/// # Examples
+///
+/// ```
+/// #[get("/report")]
+/// fn report(deadline: Deadline) -> TimeBound<Json<Report>> {
+///     TimeBound::run(deadline, || Json(reports::generate()))
+/// }
+/// ```
+#[derive(Debug)]
+pub enum TimeBound<R> {
+    Completed(R),
+    /// The deadline passed before the work finished
+    TimedOut,
+    /// The work panicked before producing a response
+    Failed,
+}
+
+impl<R: Send + 'static> TimeBound<R> {
+    /// Run `work` on its own thread, waiting for it until `deadline` has passed. Threads can't
+    /// be cancelled, so work that times out keeps running in the background and its result is
+    /// discarded.
+    pub fn run<F>(deadline: Deadline, work: F) -> TimeBound<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The receiver is gone if the request already timed out
+            let _ = sender.send(work());
+        });
+
+        match receiver.recv_timeout(deadline.remaining()) {
+            Ok(response) => TimeBound::Completed(response),
+            Err(RecvTimeoutError::Timeout) => TimeBound::TimedOut,
+            Err(RecvTimeoutError::Disconnected) => TimeBound::Failed,
+        }
+    }
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for TimeBound<R> {
+    fn respond_to(self, request: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            TimeBound::Completed(r) => r.respond_to(request),
+            TimeBound::Failed => Err(Status::InternalServerError),
+            TimeBound::TimedOut => {
+                #[cfg(feature = "metrics")]
+                {
+                    if let Some(metrics) = request.guard::<State<DeadlineMetrics>>().succeeded() {
+                        let route = request
+                            .route()
+                            .map(|route| format!("{} {}", route.method, route.uri))
+                            .unwrap_or_else(|| request.uri().path().to_string());
+                        metrics.record(route);
+                    }
+                }
+
+                Response::build()
+                    .status(Status::ServiceUnavailable)
+                    .header(ContentType::JSON)
+                    .sized_body(Cursor::new("{\"error\":\"timeout\"}"))
+                    .ok()
+            }
+        }
+    }
+}
+
+/// Wraps a handler that serves files, such as `StaticFiles`, so that its responses advertise
+/// whether range requests are supported
+#[derive(Clone)]
+pub struct AdvertiseRanges(pub Box<dyn Handler>);
+
+impl AdvertiseRanges {
+    /// Wrap the handler of each route in `routes`
+    pub fn wrap(routes: Vec<Route>) -> Vec<Route> {
+        routes
+            .into_iter()
+            .map(|mut route| {
+                route.handler = Box::new(AdvertiseRanges(route.handler));
+                route
+            })
+            .collect()
+    }
+}
+
+impl Handler for AdvertiseRanges {
+    fn handle<'r>(&self, request: &'r Request, data: Data) -> Outcome<'r> {
+        match self.0.handle(request, data) {
+            Outcome::Success(mut response) => {
+                response.set_header(accept_ranges());
+                Outcome::Success(response)
+            }
+            outcome => outcome,
+        }
+    }
+}
+
+/// The `Accept-Ranges` header for file responses. Rocket's `NamedFile` doesn't support range
+/// requests, so this says so explicitly (rather than leaving clients to guess) until it does.
+pub fn accept_ranges() -> Header<'static> {
+    Header::new("Accept-Ranges", "none")
+}
+
+/// Gzip the body of `response` for `WithCompression`, when `request` accepts gzip encoding
+fn compress_response(request: &Request, response: &mut Response) {
+    response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+
+    let accepts_gzip = request
+        .headers()
+        .get("Accept-Encoding")
+        .flat_map(negotiation::parse_weighted)
+        .any(|(encoding, quality)| encoding.eq_ignore_ascii_case("gzip") && quality > 0.0);
+    let is_event_stream = response.content_type() == Some(ContentType::new("text", "event-stream"));
+    if !accepts_gzip || is_event_stream || response.headers().contains("Content-Encoding") {
+        return;
+    }
+
+    if let Some(body) = response.body_bytes() {
+        match gzip(&body) {
+            Ok(compressed) => {
+                response.set_header(Header::new("Content-Encoding", "gzip"));
+                response.set_sized_body(Cursor::new(compressed));
+            }
+            Err(_) => response.set_sized_body(Cursor::new(body)),
+        }
+    }
+}
+
+/// Compress `bytes` with the default gzip compression level
+fn gzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// Escape the characters in `text` that have special meaning in HTML, so that it can be
+/// safely used as element content or a quoted attribute value
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use rocket::local::Client;
+    use rocket::response::Body;
+    use serde_json::json;
+
+    fn json_or_redirect(prefer_json: bool) -> Either2<Json<Value>, Redirect> {
+        if prefer_json {
+            Either2::A(Json(json!({ "ok": true })))
+        } else {
+            Either2::B(Redirect::to("/login"))
+        }
+    }
+
+    fn either2_a<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, json_or_redirect(true))
+    }
+
+    fn either2_b<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, json_or_redirect(false))
+    }
+
+    fn either3_c<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let response: Either3<Json<Value>, Redirect, String> = Either3::C(String::from("plain"));
+        Outcome::from(request, response)
+    }
+
+    fn negotiated<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let response = Either2::negotiated(request, || String::from("<p>html</p>"), || Json(json!({ "json": true })));
+        Outcome::from(request, response)
+    }
+
+    fn varying_from_either<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, VaryingResponse::from(json_or_redirect(false)))
+    }
+
+    fn varying_json<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, VaryingResponse::from(json_or_redirect(true)))
+    }
+
+    fn varying_flash<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        Outcome::from(request, VaryingResponse::from(Flash::success(Redirect::to("/done"), "Saved")))
+    }
+
+    fn client() -> Client {
+        let routes = vec![
+            Route::new(Method::Get, "/either2/a", either2_a),
+            Route::new(Method::Get, "/either2/b", either2_b),
+            Route::new(Method::Get, "/either3/c", either3_c),
+            Route::new(Method::Get, "/negotiated", negotiated),
+            Route::new(Method::Get, "/varying/redirect", varying_from_either),
+            Route::new(Method::Get, "/varying/json", varying_json),
+            Route::new(Method::Get, "/varying/flash", varying_flash),
+        ];
+        Client::new(rocket::custom(Config::new(Environment::Development)).mount("/", routes)).unwrap()
+    }
+
+    #[test]
+    fn either_responds_as_each_arm() {
+        let client = client();
+
+        let mut json = client.get("/either2/a").dispatch();
+        assert_eq!(json.status(), Status::Ok);
+        assert_eq!(json.content_type(), Some(ContentType::JSON));
+        assert_eq!(json.body_string(), Some(String::from("{\"ok\":true}")));
+
+        let redirect = client.get("/either2/b").dispatch();
+        assert_eq!(redirect.status(), Status::SeeOther);
+        assert_eq!(redirect.headers().get_one("Location"), Some("/login"));
+
+        let mut plain = client.get("/either3/c").dispatch();
+        assert_eq!(plain.status(), Status::Ok);
+        assert_eq!(plain.content_type(), Some(ContentType::Plain));
+        assert_eq!(plain.body_string(), Some(String::from("plain")));
+    }
+
+    #[test]
+    fn negotiated_either_follows_accept() {
+        let client = client();
+
+        let json = client.get("/negotiated").header(Header::new("Accept", "application/json")).dispatch();
+        assert_eq!(json.content_type(), Some(ContentType::JSON));
+
+        let html = client.get("/negotiated").header(Header::new("Accept", "text/html")).dispatch();
+        assert_eq!(html.content_type(), Some(ContentType::Plain));
+
+        let anything = client.get("/negotiated").header(Header::new("Accept", "*/*")).dispatch();
+        assert_eq!(anything.content_type(), Some(ContentType::Plain));
+    }
+
+    #[test]
+    fn varying_response_delegates_through_either() {
+        let client = client();
+
+        let redirect = client.get("/varying/redirect").dispatch();
+        assert_eq!(redirect.status(), Status::SeeOther);
+        assert_eq!(redirect.headers().get_one("Location"), Some("/login"));
+
+        let json = client.get("/varying/json").dispatch();
+        assert_eq!(json.status(), Status::Ok);
+        assert_eq!(json.content_type(), Some(ContentType::JSON));
+
+        let flash = client.get("/varying/flash").dispatch();
+        assert_eq!(flash.status(), Status::SeeOther);
+        assert_eq!(flash.headers().get_one("Location"), Some("/done"));
+        assert!(flash.cookies().iter().any(|cookie| cookie.name() == "_flash"));
+    }
+
+    fn gzip_client() -> Client {
+        Client::new(rocket::custom(Config::new(Environment::Development))).unwrap()
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let client = gzip_client();
+        let request = client.get("/").header(Header::new("Accept-Encoding", "br, gzip;q=0.8"));
+        let body = json!({ "items": vec!["compressible"; 64] });
+
+        let mut response = VaryingResponse::WithCompression(Box::new(VaryingResponse::Json(body.clone())))
+            .respond_to(request.inner())
+            .unwrap();
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+
+        let compressed = response.body_bytes().unwrap();
+        assert!(compressed.len() < body.to_string().len());
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, body.to_string());
+    }
+
+    #[test]
+    fn compression_needs_gzip_to_be_accepted() {
+        let client = gzip_client();
+        let request = client.get("/").header(Header::new("Accept-Encoding", "br"));
+
+        let mut response = VaryingResponse::WithCompression(Box::new(VaryingResponse::Json(json!({ "a": 1 }))))
+            .respond_to(request.inner())
+            .unwrap();
+        assert!(!response.headers().contains("Content-Encoding"));
+        assert_eq!(response.body_string(), Some(String::from("{\"a\":1}")));
+    }
+
+    #[test]
+    fn compression_follows_the_encoding_quality() {
+        let client = gzip_client();
+        let compressed = |accept_encoding: &'static str| {
+            let request = client.get("/").header(Header::new("Accept-Encoding", accept_encoding));
+            let response = VaryingResponse::WithCompression(Box::new(VaryingResponse::Json(json!({ "a": 1 }))))
+                .respond_to(request.inner())
+                .unwrap();
+            let is_gzip = response.headers().get_one("Content-Encoding") == Some("gzip");
+            is_gzip
+        };
+
+        assert!(compressed("GZIP"));
+        assert!(compressed("deflate, Gzip;q=0.5"));
+        assert!(!compressed("gzip;q=0"));
+        assert!(!compressed("gzip;q=0.0, br"));
+        assert!(!compressed("x-gzip"));
+    }
+
+    #[test]
+    fn compression_buffers_chunked_bodies() {
+        let client = gzip_client();
+        let request = client.get("/").header(Header::new("Accept-Encoding", "gzip"));
+        let text = "streamed ".repeat(256);
+
+        let mut response = Response::build().streamed_body(Cursor::new(text.clone())).finalize();
+        compress_response(request.inner(), &mut response);
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        match response.body() {
+            Some(Body::Sized(_, size)) => assert!(size < text.len() as u64),
+            _ => panic!("expected the compressed body to be sized"),
+        }
+
+        let compressed = response.body_bytes().unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, text);
+    }
+
+    fn paged(offset: u64, limit: u32, total: u64) -> PagedJson<u32> {
+        PagedJson::new(vec![1, 2], Pagination { offset, limit }, total)
+    }
+
+    #[test]
+    fn paged_json_links() {
+        let first_page = paged(0, 10, 25).links("/posts", None);
+        assert_eq!(
+            first_page,
+            "</posts?page=1&per_page=10>; rel=\"first\", </posts?page=2&per_page=10>; rel=\"next\", \
+             </posts?page=3&per_page=10>; rel=\"last\""
+        );
+
+        let middle = paged(10, 10, 25).links("/posts", Some("tag=rust&page=2&per_page=10"));
+        assert_eq!(
+            middle,
+            "</posts?tag=rust&page=1&per_page=10>; rel=\"first\", </posts?tag=rust&page=1&per_page=10>; rel=\"prev\", \
+             </posts?tag=rust&page=3&per_page=10>; rel=\"next\", </posts?tag=rust&page=3&per_page=10>; rel=\"last\""
+        );
+
+        // An empty collection still has one (empty) page
+        let empty = paged(0, 10, 0).links("/posts", None);
+        assert_eq!(
+            empty,
+            "</posts?page=1&per_page=10>; rel=\"first\", </posts?page=1&per_page=10>; rel=\"last\""
+        );
+    }
+
+    fn paged_client(mount_prefix: &str, strip_prefix: bool) -> Client {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("mount_prefix", mount_prefix)
+            .unwrap()
+            .set("strip_prefix_header", strip_prefix)
+            .unwrap()
+            .set("trusted_proxies", vec!["10.0.0.1"])
+            .unwrap()
+            .build()
+            .unwrap();
+        Client::new(rocket::custom(Config::new(Environment::Development)).manage(settings)).unwrap()
+    }
+
+    #[test]
+    fn paged_json_headers() {
+        let client = paged_client("/myapp", false);
+        let request = client.get("/myapp/posts?page=2&per_page=10");
+
+        let mut response = paged(10, 10, 25).respond_to(request.inner()).unwrap();
+        assert_eq!(response.headers().get_one("X-Total-Count"), Some("25"));
+        assert_eq!(
+            response.headers().get_one("Link"),
+            Some(
+                "</myapp/posts?page=1&per_page=10>; rel=\"first\", </myapp/posts?page=1&per_page=10>; rel=\"prev\", \
+                 </myapp/posts?page=3&per_page=10>; rel=\"next\", </myapp/posts?page=3&per_page=10>; rel=\"last\""
+            )
+        );
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        assert_eq!(response.body_string(), Some(String::from("[1,2]")));
+    }
+
+    #[test]
+    fn paged_json_links_use_the_base_path() {
+        // The proxy strips the prefix, so the request path doesn't have it
+        let client = paged_client("/myapp", true);
+        let request = client.get("/posts");
+        let response = paged(0, 10, 5).respond_to(request.inner()).unwrap();
+        assert_eq!(
+            response.headers().get_one("Link"),
+            Some("</myapp/posts?page=1&per_page=10>; rel=\"first\", </myapp/posts?page=1&per_page=10>; rel=\"last\"")
+        );
+
+        let request = client
+            .get("/posts")
+            .remote("10.0.0.1:4000".parse().unwrap())
+            .header(Header::new("X-Forwarded-Prefix", "/edge"));
+        let response = paged(0, 10, 5).respond_to(request.inner()).unwrap();
+        assert_eq!(
+            response.headers().get_one("Link"),
+            Some("</edge/posts?page=1&per_page=10>; rel=\"first\", </edge/posts?page=1&per_page=10>; rel=\"last\"")
+        );
+    }
+
+    #[test]
+    fn app_redirects_use_the_base_path() {
+        for strip_prefix in &[false, true] {
+            let client = paged_client("/myapp", *strip_prefix);
+            let settings = client.rocket().state::<Settings>().unwrap();
+            let request = client.get(if *strip_prefix { "/login" } else { "/myapp/login" });
+
+            let temporary = VaryingResponse::flash_temporary(Uri::parse("/posts/new").unwrap(), "error", "Try again")
+                .respond_to(request.inner())
+                .unwrap();
+            assert_eq!(temporary.status(), Status::TemporaryRedirect);
+            assert_eq!(temporary.headers().get_one("Location"), Some("/myapp/posts/new"));
+            assert!(temporary.cookies().iter().any(|cookie| cookie.name() == "_flash"));
+
+            let permanent = VaryingResponse::flash_permanent(Uri::parse("/posts").unwrap(), "info", "Moved")
+                .respond_to(request.inner())
+                .unwrap();
+            assert_eq!(permanent.status(), Status::PermanentRedirect);
+            assert_eq!(permanent.headers().get_one("Location"), Some("/myapp/posts"));
+
+            let safe = VaryingResponse::safe_redirect(SafeRedirect::new("/dashboard", settings, "/"))
+                .respond_to(request.inner())
+                .unwrap();
+            assert_eq!(safe.status(), Status::SeeOther);
+            assert_eq!(safe.headers().get_one("Location"), Some("/myapp/dashboard"));
+
+            // A target that is already under the base path isn't prefixed again
+            let already = SafeRedirect::new("/myapp/dashboard?tab=1", settings, "/")
+                .respond_to(request.inner())
+                .unwrap();
+            assert_eq!(already.headers().get_one("Location"), Some("/myapp/dashboard?tab=1"));
+        }
+    }
+
+    #[test]
+    fn app_redirects_use_a_forwarded_prefix() {
+        let client = paged_client("/myapp", true);
+        let request = client
+            .get("/login")
+            .remote("10.0.0.1:4000".parse().unwrap())
+            .header(Header::new("X-Forwarded-Prefix", "/edge"));
+
+        let response = VaryingResponse::flash_temporary(Uri::parse("/posts/new").unwrap(), "error", "Try again")
+            .respond_to(request.inner())
+            .unwrap();
+        assert_eq!(response.headers().get_one("Location"), Some("/edge/posts/new"));
+    }
+
+    /// A JSON response behind `depth` deferred responses
+    fn nested(depth: usize) -> VaryingResponse {
+        if depth == 0 {
+            VaryingResponse::Json(json!("built"))
+        } else {
+            VaryingResponse::deferred(move || nested(depth - 1))
+        }
+    }
+
+    fn always_defers() -> VaryingResponse {
+        VaryingResponse::deferred(always_defers)
+    }
+
+    #[test]
+    fn deferred_depth_is_limited() {
+        let client = gzip_client();
+        let request = client.get("/");
+
+        let mut response = nested(MAX_DEFERRED_DEPTH).respond_to(request.inner()).unwrap();
+        assert_eq!(response.body_string(), Some(String::from("\"built\"")));
+
+        assert_eq!(
+            nested(MAX_DEFERRED_DEPTH + 1).respond_to(request.inner()).err(),
+            Some(Status::InternalServerError)
+        );
+        assert_eq!(always_defers().respond_to(request.inner()).err(), Some(Status::InternalServerError));
+    }
+
+    #[test]
+    fn generic_deferred_counts_towards_the_depth() {
+        let client = gzip_client();
+        let request = client.get("/");
+
+        let within = Deferred(|| nested(MAX_DEFERRED_DEPTH - 1)).respond_to(request.inner());
+        assert_eq!(within.map(|response| response.status()), Ok(Status::Ok));
+
+        let beyond = Deferred(|| nested(MAX_DEFERRED_DEPTH)).respond_to(request.inner());
+        assert_eq!(beyond.err(), Some(Status::InternalServerError));
+
+        let boxed = VaryingResponse::from(Deferred(|| nested(MAX_DEFERRED_DEPTH - 1)));
+        assert!(boxed.respond_to(request.inner()).is_ok());
+    }
+
+    #[test]
+    fn deferred_is_built_when_sent() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let built = Arc::new(AtomicBool::new(false));
+        let flag = built.clone();
+        let response = Deferred(move || {
+            flag.store(true, Ordering::SeqCst);
+            VaryingResponse::Json(json!(null))
+        });
+        assert!(!built.load(Ordering::SeqCst));
+
+        let client = gzip_client();
+        let request = client.get("/");
+        assert!(response.respond_to(request.inner()).is_ok());
+        assert!(built.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "sse")]
+    #[test]
+    fn compression_skips_streamed_bodies() {
+        let client = gzip_client();
+        let request = client.get("/").header(Header::new("Accept-Encoding", "gzip"));
+        // The stream stays open while the sender is alive, so reading it into memory would block
+        let (_sender, stream) = SseStream::channel();
+
+        let mut response = VaryingResponse::WithCompression(Box::new(VaryingResponse::Sse(stream)))
+            .respond_to(request.inner())
+            .unwrap();
+        assert!(!response.headers().contains("Content-Encoding"));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+        match response.body() {
+            Some(Body::Chunked(..)) => (),
+            _ => panic!("expected the event stream to stay chunked"),
+        }
+    }
+
+    const UPLOAD_LIMIT: u64 = 1_048_576;
+
+    fn upload<'r>(request: &'r Request, data: Data) -> Outcome<'r> {
+        let mut body = Vec::new();
+        if data.open().take(UPLOAD_LIMIT + 1).read_to_end(&mut body).is_err() {
+            return Outcome::Failure(Status::BadRequest);
+        }
+
+        if body.len() as u64 > UPLOAD_LIMIT {
+            Outcome::from(request, VaryingResponse::PayloadTooLarge(UPLOAD_LIMIT))
+        } else {
+            Outcome::from(request, body.len().to_string())
+        }
+    }
+
+    #[test]
+    fn payload_too_large_sends_the_limit() {
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .mount("/", vec![Route::new(Method::Post, "/upload", upload)]);
+        let client = Client::new(rocket).unwrap();
+
+        let mut accepted = client.post("/upload").body(vec![0u8; UPLOAD_LIMIT as usize]).dispatch();
+        assert_eq!(accepted.status(), Status::Ok);
+        assert_eq!(accepted.body_string(), Some(UPLOAD_LIMIT.to_string()));
+
+        let mut rejected = client.post("/upload").body(vec![0u8; UPLOAD_LIMIT as usize + 1]).dispatch();
+        assert_eq!(rejected.status(), Status::PayloadTooLarge);
+        assert_eq!(rejected.headers().get_one("X-Max-Content-Length"), Some("1048576"));
+        assert_eq!(rejected.body_string(), None);
+    }
+
+    #[test]
+    fn bytes_are_sent_without_copying() {
+        let client = gzip_client();
+        let request = client.get("/");
+        // Large enough to be kept on the heap, rather than inline in the `Bytes`
+        let body = bytes::Bytes::from(vec![7u8; 4096]);
+        let sent = body.clone();
+        assert_eq!(sent.as_ptr(), body.as_ptr());
+
+        let mut response = VaryingResponse::Bytes(sent, ContentType::Binary)
+            .respond_to(request.inner())
+            .unwrap();
+        // The response still shares the buffer, rather than holding a copy of it
+        let body = body.try_mut().unwrap_err();
+        assert_eq!(response.content_type(), Some(ContentType::Binary));
+        assert_eq!(response.body_bytes(), Some(body.to_vec()));
+        // Reading the body drops the response's handle, leaving only this one
+        assert!(body.try_mut().is_ok());
+    }
+
+    #[test]
+    fn safe_html_is_cleaned() {
+        let client = gzip_client();
+        let request = client.get("/");
+        let fragment = "<p>Hello <b>world</b><script>alert('hi')</script></p><img src=\"/a.png\" onerror=\"steal()\">";
+
+        let mut safe = VaryingResponse::SafeHtml(String::from(fragment))
+            .respond_to(request.inner())
+            .unwrap();
+        assert_eq!(safe.content_type(), Some(ContentType::HTML));
+        let body = safe.body_string().unwrap();
+        assert!(!body.contains("<script"));
+        assert!(!body.contains("alert"));
+        assert!(!body.contains("onerror"));
+        assert!(body.contains("<p>Hello <b>world</b></p>"));
+        assert!(body.contains("<img src=\"/a.png\">"));
+
+        // `Html` is sent exactly as it is
+        let mut raw = VaryingResponse::Html(String::from(fragment))
+            .respond_to(request.inner())
+            .unwrap();
+        assert_eq!(raw.content_type(), Some(ContentType::HTML));
+        assert_eq!(raw.body_string(), Some(String::from(fragment)));
+    }
+
+    fn missing_post<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let page = VaryingResponse::page("404", json!({ "slug": "hello-world" }), Status::NotFound);
+        Outcome::from(request, page)
+    }
+
+    #[test]
+    fn page_renders_with_its_status() {
+        let app = crate::app::test_support::TestApp::builder()
+            .template("404.html.hbs", "<h1>No post called {{slug}}</h1>")
+            .mount("/", vec![Route::new(Method::Get, "/posts/hello-world", missing_post)])
+            .build()
+            .unwrap();
+
+        let mut response = app.client().get("/posts/hello-world").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        assert_eq!(response.body_string(), Some(String::from("<h1>No post called hello-world</h1>")));
+    }
+
+    #[test]
+    fn attachment_sets_the_download_filename() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tmp_xyz.csv");
+        fs::write(&path, "month,total\n2024-01,42\n").unwrap();
+        let client = gzip_client();
+        let request = client.get("/");
+
+        let attachment = VaryingResponse::Attachment(NamedFile::open(&path).unwrap(), String::from("report_2024-01.csv"));
+        let mut response = attachment.respond_to(request.inner()).unwrap();
+        assert_eq!(
+            response.headers().get_one("Content-Disposition"),
+            Some("attachment; filename=\"report_2024-01.csv\"")
+        );
+        assert_eq!(response.body_string(), Some(String::from("month,total\n2024-01,42\n")));
+
+        let unicode = VaryingResponse::Attachment(NamedFile::open(&path).unwrap(), String::from("résumé.csv"));
+        let response = unicode.respond_to(request.inner()).unwrap();
+        assert_eq!(
+            response.headers().get_one("Content-Disposition"),
+            Some("attachment; filename=\"r_sum_.csv\"; filename*=UTF-8''r%C3%A9sum%C3%A9.csv")
+        );
+    }
+
+    #[test]
+    fn conflict_status_and_body() {
+        let client = gzip_client();
+        let request = client.get("/");
+
+        let mut bare = VaryingResponse::conflict().respond_to(request.inner()).unwrap();
+        assert_eq!(bare.status(), Status::Conflict);
+        assert_eq!(bare.content_type(), None);
+        assert_eq!(bare.body_string(), None);
+
+        let detail = json!({ "error": "duplicate", "field": "email" });
+        let mut detailed = VaryingResponse::conflict_with(detail.clone())
+            .respond_to(request.inner())
+            .unwrap();
+        assert_eq!(detailed.status(), Status::Conflict);
+        assert_eq!(detailed.content_type(), Some(ContentType::JSON));
+        let body: Value = serde_json::from_str(&detailed.body_string().unwrap()).unwrap();
+        assert_eq!(body, detail);
+    }
+
+    #[test]
+    fn permanent_redirect_is_308() {
+        let client = gzip_client();
+        let request = client.post("/old");
+
+        let response = VaryingResponse::PermanentRedirect(Uri::parse("/new?page=2").unwrap())
+            .respond_to(request.inner())
+            .unwrap();
+        assert_eq!(response.status(), Status::PermanentRedirect);
+        assert_eq!(response.status().code, 308);
+        assert_eq!(response.headers().get_one("Location"), Some("/new?page=2"));
+    }
+
+    #[test]
+    fn multiple_choices_lists_links() {
+        let client = gzip_client();
+        let request = client.get("/report");
+        let choices = vec![
+            (Uri::parse("/report.pdf").unwrap(), String::from("PDF")),
+            (Uri::parse("/report.csv?a=1&b=2").unwrap(), String::from("CSV <spreadsheet>")),
+        ];
+
+        let mut response = VaryingResponse::MultipleChoices(choices).respond_to(request.inner()).unwrap();
+        assert_eq!(response.status(), Status::MultipleChoices);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let body = response.body_string().unwrap();
+        assert_eq!(body.matches("<a ").count(), 2);
+        assert_eq!(
+            body,
+            "<ul><li><a href=\"/report.pdf\">PDF</a></li>\
+             <li><a href=\"/report.csv?a=1&amp;b=2\">CSV &lt;spreadsheet&gt;</a></li></ul>"
+        );
+    }
+
+    #[cfg(feature = "sse")]
+    #[test]
+    fn sse_heartbeat_fills_quiet_gaps() {
+        use crate::http::sse::HEARTBEAT;
+        use std::time::Duration;
+
+        let client = gzip_client();
+        let request = client.get("/events");
+        // The second event is only ready well after the heartbeat interval has passed
+        let events = vec!["first", "second"].into_iter().enumerate().map(|(i, event)| {
+            if i > 0 {
+                thread::sleep(Duration::from_millis(350));
+            }
+            String::from(event)
+        });
+
+        let mut response = VaryingResponse::SseHeartbeat {
+            interval: Duration::from_millis(100),
+            events: Box::new(events),
+        }
+        .respond_to(request.inner())
+        .unwrap();
+        assert_eq!(response.content_type(), Some(ContentType::new("text", "event-stream")));
+
+        // The stream ends once the events run out
+        let body = response.body_string().unwrap();
+        let first = body.find("data: first\n\n").unwrap();
+        let second = body.find("data: second\n\n").unwrap();
+        let heartbeats = body[first..second].matches(HEARTBEAT).count();
+        assert!(heartbeats >= 1, "no heartbeat between the events in {:?}", body);
+    }
+
+    /// Serves the file at the managed path to requests with the owner's token
+    fn invoice<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let path = request.guard::<State<PathBuf>>().unwrap();
+        let authorized = request.headers().get_one("Authorization") == Some("Bearer owner");
+        match NamedFile::open(path.inner()) {
+            Ok(file) => Outcome::from(request, ProtectedFile::new(file, authorized)),
+            Err(_) => Outcome::Failure(Status::NotFound),
+        }
+    }
+
+    #[test]
+    fn protected_file_is_only_served_when_authorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invoice.txt");
+        fs::write(&path, "total: 42").unwrap();
+
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(path.clone())
+            .mount("/", vec![Route::new(Method::Get, "/invoice", invoice)]);
+        let client = Client::new(rocket).unwrap();
+
+        let mut owner = client.get("/invoice").header(Header::new("Authorization", "Bearer owner")).dispatch();
+        assert_eq!(owner.status(), Status::Ok);
+        assert_eq!(owner.headers().get_one("Accept-Ranges"), Some("none"));
+        assert_eq!(owner.body_string(), Some(String::from("total: 42")));
+
+        let other = client.get("/invoice").header(Header::new("Authorization", "Bearer someone")).dispatch();
+        assert_eq!(other.status(), Status::Forbidden);
+
+        let anonymous = client.get("/invoice").dispatch();
+        assert_eq!(anonymous.status(), Status::Forbidden);
+
+        // The file is opened before authorization is checked, so a missing file is still a 404
+        fs::remove_file(&path).unwrap();
+        let missing = client.get("/invoice").dispatch();
+        assert_eq!(missing.status(), Status::NotFound);
+    }
+
+    fn posts_preflight<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let preflight = Preflight {
+            allowed_methods: vec![Method::Get, Method::Post],
+            allowed_headers: vec![String::from("Content-Type"), String::from("X-Api-Key")],
+            max_age: 3600,
+        };
+        Outcome::from(request, preflight)
+    }
+
+    #[test]
+    fn preflight_headers() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("cors_allowed_origins", vec!["https://example.com"])
+            .unwrap()
+            .build()
+            .unwrap();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .attach(crate::http::fairings::CorsHeaderFairing::new(&settings))
+            .mount("/", vec![Route::new(Method::Options, "/posts", posts_preflight)]);
+        let client = Client::new(rocket).unwrap();
+
+        let mut response = client
+            .options("/posts")
+            .header(Header::new("Origin", "https://example.com"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let headers = response.headers();
+        assert_eq!(headers.get_one("Access-Control-Allow-Methods"), Some("GET, POST"));
+        assert_eq!(headers.get_one("Access-Control-Allow-Headers"), Some("Content-Type, X-Api-Key"));
+        assert_eq!(headers.get_one("Access-Control-Max-Age"), Some("3600"));
+        assert_eq!(headers.get_one("Access-Control-Allow-Origin"), Some("https://example.com"));
+        assert_eq!(headers.get_one("Vary"), Some("Origin"));
+        assert_eq!(response.body_string(), None);
+
+        // Origins that aren't allowed still get the preflight, but not the origin header
+        let other = client
+            .options("/posts")
+            .header(Header::new("Origin", "https://evil.example"))
+            .dispatch();
+        assert_eq!(other.status(), Status::NoContent);
+        assert!(!other.headers().contains("Access-Control-Allow-Origin"));
+    }
+
+    /// Sleeps for the number of milliseconds in the path, within the request's deadline
+    fn sleep_for<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+        let millis: u64 = match request.get_param(0) {
+            Some(Ok(millis)) => millis,
+            _ => return Outcome::failure(Status::BadRequest),
+        };
+        let work = TimeBound::run(Deadline::of(request), move || {
+            thread::sleep(std::time::Duration::from_millis(millis));
+            String::from("done")
+        });
+        Outcome::from(request, work)
+    }
+
+    #[test]
+    fn time_bound_gives_up_at_the_deadline() {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("request_deadline", "200ms")
+            .unwrap()
+            .build()
+            .unwrap();
+        let rocket = rocket::custom(Config::new(Environment::Development))
+            .manage(settings)
+            .mount("/", vec![Route::new(Method::Get, "/sleep/<millis>", sleep_for)]);
+        #[cfg(feature = "metrics")]
+        let rocket = rocket.manage(DeadlineMetrics::default());
+        let client = Client::new(rocket).unwrap();
+
+        let mut quick = client.get("/sleep/10").dispatch();
+        assert_eq!(quick.status(), Status::Ok);
+        assert_eq!(quick.body_string(), Some(String::from("done")));
+
+        let mut slow = client.get("/sleep/2000").dispatch();
+        assert_eq!(slow.status(), Status::ServiceUnavailable);
+        assert_eq!(slow.content_type(), Some(ContentType::JSON));
+        assert_eq!(slow.body_string(), Some(String::from("{\"error\":\"timeout\"}")));
+
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = client.rocket().state::<DeadlineMetrics>().unwrap();
+            assert_eq!(metrics.exceeded().get("GET /sleep/<millis>"), Some(&1));
+        }
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Ok(Some((990, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+        // Ignored, so the whole file is sent
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=9-1", 1000), Ok(None));
+    }
+
+    const LARGE_FILE_LEN: u64 = 3 * 1024 * 1024;
+
+    /// A sparse 3 MiB file, with a marker at the start and one at the end
+    fn large_file(dir: &Path) -> PathBuf {
+        let path = dir.join("large.bin");
+        let mut file = fs::File::create(&path).unwrap();
+        file.set_len(LARGE_FILE_LEN).unwrap();
+        file.write_all(b"start").unwrap();
+        file.seek(SeekFrom::End(-3)).unwrap();
+        file.write_all(b"end").unwrap();
+        path
+    }
+
+    fn large_file_client() -> Client {
+        let settings = Settings::builder()
+            .unwrap()
+            .set("file_chunk_bytes", "16KiB")
+            .unwrap()
+            .build()
+            .unwrap();
+        let rocket = rocket::custom(Config::new(Environment::Development)).manage(settings);
+        #[cfg(feature = "metrics")]
+        let rocket = rocket.manage(FileMetrics::default());
+        Client::new(rocket).unwrap()
+    }
+
+    #[test]
+    fn large_file_is_streamed_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = large_file(dir.path());
+        let client = large_file_client();
+        let request = client.get("/large.bin");
+
+        let mut response = LargeFile::new(&path).respond_to(request.inner()).unwrap();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Accept-Ranges"), Some("bytes"));
+        match response.body() {
+            Some(Body::Chunked(_, chunk_size)) => assert_eq!(chunk_size, 16 * 1024),
+            _ => panic!("expected a chunked body"),
+        }
+
+        let body = response.body_bytes().unwrap();
+        assert_eq!(body.len() as u64, LARGE_FILE_LEN);
+        assert!(body.starts_with(b"start"));
+        assert!(body.ends_with(b"end"));
+
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.rocket().state::<FileMetrics>().unwrap().bytes_served(), LARGE_FILE_LEN);
+    }
+
+    #[test]
+    fn large_file_ranges_are_streamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = large_file(dir.path());
+        let client = large_file_client();
+
+        let request = client.get("/large.bin").header(Header::new("Range", "bytes=-3"));
+        let mut tail = LargeFile::new(&path).respond_to(request.inner()).unwrap();
+        assert_eq!(tail.status(), Status::PartialContent);
+        assert_eq!(
+            tail.headers().get_one("Content-Range"),
+            Some(format!("bytes {}-{}/{}", LARGE_FILE_LEN - 3, LARGE_FILE_LEN - 1, LARGE_FILE_LEN).as_str())
+        );
+        match tail.body() {
+            Some(Body::Chunked(..)) => (),
+            _ => panic!("expected a chunked body"),
+        }
+        assert_eq!(tail.body_bytes(), Some(b"end".to_vec()));
+
+        let request = client.get("/large.bin").header(Header::new("Range", "bytes=2-4"));
+        let mut middle = LargeFile::new(&path).respond_to(request.inner()).unwrap();
+        assert_eq!(middle.status(), Status::PartialContent);
+        assert_eq!(middle.body_bytes(), Some(b"art".to_vec()));
+
+        let request = client
+            .get("/large.bin")
+            .header(Header::new("Range", format!("bytes={}-", LARGE_FILE_LEN)));
+        let unsatisfiable = LargeFile::new(&path).respond_to(request.inner()).unwrap();
+        assert_eq!(unsatisfiable.status(), Status::RangeNotSatisfiable);
+        assert_eq!(
+            unsatisfiable.headers().get_one("Content-Range"),
+            Some(format!("bytes */{}", LARGE_FILE_LEN).as_str())
+        );
+    }
+
+    /// Fails every read, like a file on a disk that has gone away
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "device went away"))
+        }
+    }
+
+    #[test]
+    fn large_file_that_goes_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = large_file_client();
+        let request = client.get("/large.bin");
+
+        // Missing files are not found, rather than an error
+        let missing = LargeFile::new(dir.path().join("missing.bin")).respond_to(request.inner());
+        assert_eq!(missing.err(), Some(Status::NotFound));
+        let directory = LargeFile::new(dir.path()).respond_to(request.inner());
+        assert_eq!(directory.err(), Some(Status::InternalServerError));
+
+        // A file that shrinks after the response has started gives a short body
+        let path = large_file(dir.path());
+        let mut response = LargeFile::new(&path).respond_to(request.inner()).unwrap();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(5).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(response.body_bytes(), Some(b"start".to_vec()));
+
+        // A read that fails ends the body, instead of failing the response
+        let mut body = FileBody {
+            reader: FailingReader,
+            path: PathBuf::from("gone.bin"),
+            #[cfg(feature = "metrics")]
+            counter: None,
+        };
+        let mut read = Vec::new();
+        assert_eq!(body.read_to_end(&mut read).unwrap(), 0);
     }
 }
@@ -0,0 +1,9 @@
+//! The app's settings, routes and fairings, which the `web` binary loads and launches. They
+//! live in a library so that the integration tests in `tests/` can build the app too.
+pub mod app;
+pub mod http;
+
+/// The crate's error type for failures that are reported rather than handled, such as the app
+/// failing to launch or a background job failing. Each cause is one of the crate's specific
+/// errors (e.g. `app::SettingsError`) or an I/O error, which callers can downcast to.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -1,13 +1,137 @@
-use rocket::{get, Rocket};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::process;
 
-use rocket_contrib::serve::{StaticFiles, Options};
+use web::app::diagnostics::WarningBudgetExceeded;
+use web::app::migrations::MigrationError;
+use web::app::{FairingRegistryError, SettingsError};
+use web::{app, http};
 
-pub(crate) mod app;
-pub(crate) mod http;
+/// Rocket couldn't start serving, e.g. because the port is already in use
+#[derive(Debug)]
+struct LaunchFailed(String);
+
+impl fmt::Display for LaunchFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for LaunchFailed {}
+
+/// Load the settings and launch the app, which only returns once it has failed to launch (or
+/// after running one of the commands below)
+fn run() -> Result<(), web::Error> {
+    let settings = app::Settings::new()?;
+
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        // `web fairings` lists the fairings that would be attached, in order, without launching
+        Some("fairings") => {
+            println!("{}", app::fairings().describe(&settings)?);
+            return Ok(());
+        }
+        // `web export --out <dir>` writes the public pages to a directory as a static site
+        Some("export") => {
+            let out = args
+                .iter()
+                .position(|arg| arg == "--out")
+                .and_then(|i| args.get(i + 1))
+                .map(String::as_str)
+                .unwrap_or("export");
+            print!("{}", app::export::export(settings, Path::new(out))?);
+            return Ok(());
+        }
+        // `web migrate-status` and `web migrate --dry-run` list the migrations that would run
+        Some("migrate-status") => {
+            app::migrations::print_status(&settings)?;
+            return Ok(());
+        }
+        Some("migrate") if args.iter().any(|arg| arg == "--dry-run") => {
+            app::migrations::print_status(&settings)?;
+            return Ok(());
+        }
+        Some("migrate") => {
+            app::migrations::migrate(&settings)?;
+            return Ok(());
+        }
+        // `web --check` builds the app without launching it, and lists the startup warnings
+        // (as JSON with `--format json`), failing if there are too many
+        Some("--check") => {
+            let json = args.windows(2).any(|pair| pair[0] == "--format" && pair[1] == "json");
+            let max = settings.max_startup_warnings;
+            let diagnostics = settings.diagnostics().clone();
+            app::diagnostics::preflight(&settings);
+            app::rocket(settings);
+
+            if json {
+                println!("{}", diagnostics.to_json(max));
+            } else {
+                print!("{}", diagnostics.to_text(max));
+            }
+            diagnostics.enforce_budget(max)?;
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    if settings.run_migrations {
+        app::migrations::migrate(&settings)?;
+    }
+
+    let max_startup_warnings = settings.max_startup_warnings;
+    let diagnostics = settings.diagnostics().clone();
+    app::diagnostics::preflight(&settings);
+
+    // The other listeners are only spawned once the main instance is known to be within budget
+    let listeners = settings.clone();
+    let rocket = app::rocket(settings);
+    diagnostics.enforce_budget(max_startup_warnings)?;
+    app::spawn_listeners(&listeners)?;
+    if let Some(reporting) = app::AppState::<http::reporting::ErrorReporting>::get(&rocket) {
+        reporting.install_panic_hook();
+    }
+
+    // `LaunchError` panics if it's dropped without being inspected, which formatting it does
+    let e = rocket.launch();
+    Err(LaunchFailed(e.to_string()).into())
+}
 
 fn main() {
-    let settings = app::Settings::new().unwrap();
-    Rocket::custom(settings.clone().into())
-        .mount(&settings.static_route, StaticFiles::new(settings.static_dir, Options::None))
-        .launch();
+    if let Err(e) = run() {
+        // Exit codes follow the conventions of sysexits.h
+        let code = if let Some(e) = e.downcast_ref::<SettingsError>() {
+            eprintln!("Failed to load settings: {}", e);
+            match e {
+                SettingsError::Io(_) => 74,
+                SettingsError::MissingRequired(_)
+                | SettingsError::InvalidValue { .. }
+                | SettingsError::Parse(_)
+                | SettingsError::InvalidField { .. }
+                | SettingsError::UnknownVariables(_)
+                | SettingsError::FeatureNotCompiled { .. } => 78,
+            }
+        } else if let Some(e) = e.downcast_ref::<MigrationError>() {
+            eprintln!("Failed to apply migrations: {}", e);
+            match e {
+                MigrationError::Io(_) => 74,
+                MigrationError::InvalidName(_) | MigrationError::DuplicateVersion(_) => 65,
+                MigrationError::UnsupportedDatabase(_) => 78,
+                MigrationError::Failed { .. } | MigrationError::NoBackend | MigrationError::Database(_) => 69,
+            }
+        } else if let Some(e) = e.downcast_ref::<WarningBudgetExceeded>() {
+            eprintln!("Aborting launch: {}", e);
+            78
+        } else if let Some(e) = e.downcast_ref::<FairingRegistryError>() {
+            eprintln!("Failed to order fairings: {}", e);
+            70
+        } else {
+            eprintln!("Failed to launch: {}", e);
+            69
+        };
+
+        process::exit(code);
+    }
 }
@@ -0,0 +1,54 @@
+//! `VaryingResponse::render_or_json` in a whole app, rendering a real template for browsers
+//! and sending JSON to API clients from the same route. Needs the `test-support` feature.
+#![cfg(feature = "test-support")]
+
+use rocket::handler::Outcome;
+use rocket::http::{Accept, ContentType, Header, Method, Status};
+use rocket::{Data, Request, Route};
+use serde_json::{json, Value};
+use web::app::test_support::TestApp;
+use web::http::wrappers::VaryingResponse;
+
+fn post<'r>(request: &'r Request, _: Data) -> Outcome<'r> {
+    let context = json!({ "title": "Hello", "body": "A first post" });
+    let api = json!({ "title": "Hello", "comments": 2 });
+    Outcome::from(request, VaryingResponse::render_or_json(request, "post", context, api))
+}
+
+fn app() -> TestApp {
+    TestApp::builder()
+        .template("post.html.hbs", "<article><h1>{{ title }}</h1><p>{{ body }}</p></article>")
+        .mount("/", vec![Route::new(Method::Get, "/posts/hello", post)])
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn browsers_get_the_rendered_template() {
+    let app = app();
+
+    let mut response = app.client().get("/posts/hello").header(Accept::HTML).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::HTML));
+    let body = response.body_string().unwrap();
+    assert!(body.contains("<article><h1>Hello</h1><p>A first post</p></article>"), "{}", body);
+}
+
+#[test]
+fn api_clients_get_json() {
+    let app = app();
+
+    let mut response = app.client().get("/posts/hello").header(Accept::JSON).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body, json!({ "title": "Hello", "comments": 2 }));
+
+    // A browser's usual header, which accepts anything, still gets the page
+    let response = app
+        .client()
+        .get("/posts/hello")
+        .header(Header::new("Accept", "text/html,application/xhtml+xml,*/*;q=0.8"))
+        .dispatch();
+    assert_eq!(response.content_type(), Some(ContentType::HTML));
+}
@@ -0,0 +1,30 @@
+//! Loading `Settings` from a real `config.toml`, in the working directory as when the binary is
+//! run. This changes the working directory of the whole test process, so it's kept in its own
+//! test binary, away from tests that read files relative to it.
+use std::env;
+use std::fs;
+use web::app::Settings;
+
+#[test]
+fn loads_config_file_from_working_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("config.toml"),
+        "static_dir = \"assets\"\nport = 8123\n",
+    )
+    .unwrap();
+
+    // Variables that would override the file, or point the settings at another directory
+    for var in &["APP_CONFIG_DIR", "APP_ENV", "APP_STATIC_DIR", "APP_PORT", "PORT"] {
+        env::remove_var(var);
+    }
+
+    let original_dir = env::current_dir().unwrap();
+    env::set_current_dir(dir.path()).unwrap();
+    let settings = Settings::new();
+    env::set_current_dir(original_dir).unwrap();
+
+    let settings = settings.unwrap();
+    assert_eq!(settings.static_dir, vec![String::from("assets")]);
+    assert_eq!(settings.effective_address().port(), 8123);
+}